@@ -0,0 +1,157 @@
+//! Compares forwarding large Raw rectangles by copying them through the proxy with
+//! splicing them, see [Config::splice_raw]. A viewer asks a local server for the whole
+//! screen through the proxy, which streams the rectangle since it is too large to be
+//! buffered.
+
+use std::net::SocketAddr;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+};
+
+use vncproxy::*;
+
+const WIDTH: u16 = 1920;
+const HEIGHT: u16 = 1080;
+/// 32 bit little endian true colour, which the viewer keeps.
+const FORMAT: [u8; 16] = [32, 24, 0, 1, 0, 255, 0, 255, 0, 255, 16, 8, 0, 0, 0, 0];
+const BYTES_PER_PIXEL: usize = 4;
+
+struct Bench;
+
+impl State for Bench {
+    fn icon(&self, _id: ClientId) -> Icon {
+        Icon::dot([0xff, 0, 0, 0xff], 16)
+    }
+
+    fn handle_event(&mut self, _event: Event) -> Redraw {
+        Redraw::None
+    }
+
+    fn enable_input(&self, _id: ClientId) -> bool {
+        true
+    }
+}
+
+/// Answers every update request with the whole screen as a single Raw rectangle.
+async fn serve(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.write_all(b"RFB 003.008\n").await?;
+    let mut version = [0; 12];
+    stream.read_exact(&mut version).await?;
+    stream.write_all(&[1, 1]).await?;
+    let _security_type = stream.read_u8().await?;
+    stream.write_u32(0).await?;
+    let _shared = stream.read_u8().await?;
+
+    let mut init = Vec::new();
+    init.extend(WIDTH.to_be_bytes());
+    init.extend(HEIGHT.to_be_bytes());
+    init.extend(FORMAT);
+    init.extend(5u32.to_be_bytes());
+    init.extend(b"bench");
+    stream.write_all(&init).await?;
+
+    let mut update = vec![0, 0, 0, 1, 0, 0, 0, 0];
+    update.extend(WIDTH.to_be_bytes());
+    update.extend(HEIGHT.to_be_bytes());
+    update.extend(0i32.to_be_bytes());
+    update.extend((0..WIDTH as usize * HEIGHT as usize * BYTES_PER_PIXEL).map(|i| i as u8));
+    loop {
+        let mut request = [0; 10];
+        stream.read_exact(&mut request).await?;
+        assert_eq!(request[0], 3, "only update requests are expected");
+        stream.write_all(&update).await?;
+    }
+}
+
+/// A viewer connected through the proxy.
+struct Viewer {
+    stream: TcpStream,
+    buf: Vec<u8>,
+}
+
+impl Viewer {
+    async fn connect(addr: SocketAddr) -> std::io::Result<Self> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut version = [0; 12];
+        stream.read_exact(&mut version).await?;
+        stream.write_all(b"RFB 003.008\n").await?;
+        let mut types = vec![0; stream.read_u8().await? as usize];
+        stream.read_exact(&mut types).await?;
+        stream.write_u8(1).await?;
+        assert_eq!(stream.read_u32().await?, 0, "security handshake failed");
+        stream.write_u8(1).await?;
+
+        let mut init = [0; 24];
+        stream.read_exact(&mut init).await?;
+        let name_len = u32::from_be_bytes(init[20..].try_into().unwrap());
+        let mut name = vec![0; name_len as usize];
+        stream.read_exact(&mut name).await?;
+        Ok(Self {
+            stream,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Requests the whole screen and reads the update, which holds the screen and the icon.
+    async fn frame(&mut self) -> std::io::Result<()> {
+        let mut request = vec![3, 0, 0, 0, 0, 0];
+        request.extend(WIDTH.to_be_bytes());
+        request.extend(HEIGHT.to_be_bytes());
+        self.stream.write_all(&request).await?;
+
+        let mut header = [0; 4];
+        self.stream.read_exact(&mut header).await?;
+        for _ in 0..u16::from_be_bytes([header[2], header[3]]) {
+            let mut rect = [0; 12];
+            self.stream.read_exact(&mut rect).await?;
+            assert_eq!(&rect[8..], [0; 4], "only Raw rectangles are expected");
+            let width = u16::from_be_bytes([rect[4], rect[5]]) as usize;
+            let height = u16::from_be_bytes([rect[6], rect[7]]) as usize;
+            self.buf.resize(width * height * BYTES_PER_PIXEL, 0);
+            self.stream.read_exact(&mut self.buf).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Starts a server and a proxy in front of it and connects a viewer.
+async fn setup(config: Config) -> Result<Viewer> {
+    let server = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = server.accept().await {
+            tokio::spawn(serve(stream));
+        }
+    });
+
+    let proxy = Proxy::bind("127.0.0.1:0".parse().unwrap(), server_addr, Bench, config).await?;
+    let proxy_addr = proxy.local_addr()?;
+    tokio::spawn(proxy.run());
+    Ok(Viewer::connect(proxy_addr).await?)
+}
+
+fn raw_rectangles(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("raw_rectangle");
+    group.throughput(Throughput::Bytes(
+        WIDTH as u64 * HEIGHT as u64 * BYTES_PER_PIXEL as u64,
+    ));
+    for (name, splice_raw) in [("copy", false), ("splice", true)] {
+        let config = Config {
+            splice_raw,
+            ..Config::default()
+        };
+        let mut viewer = rt.block_on(setup(config)).unwrap();
+        group.bench_function(name, |b| {
+            b.iter(|| rt.block_on(viewer.frame()).unwrap());
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, raw_rectangles);
+criterion_main!(benches);
@@ -1,38 +1,7 @@
-use std::{io::Cursor, sync::OnceLock};
-
-use image::{ImageReader, RgbaImage};
 use log::{debug, info};
 
 use vncproxy::*;
 
-struct Icons {
-    red: RgbaImage,
-    green: RgbaImage,
-    blue: RgbaImage,
-}
-
-fn icons() -> &'static Icons {
-    const RED_BYTES: &[u8] = include_bytes!("../res/red.png");
-    const GREEN_BYTES: &[u8] = include_bytes!("../res/green.png");
-    const BLUE_BYTES: &[u8] = include_bytes!("../res/blue.png");
-
-    fn load(bytes: &[u8]) -> RgbaImage {
-        ImageReader::new(Cursor::new(bytes))
-            .with_guessed_format()
-            .expect("could not guess image format")
-            .decode()
-            .expect("could not decode image file")
-            .into_rgba8()
-    }
-
-    static ICONS: OnceLock<Icons> = OnceLock::new();
-    ICONS.get_or_init(|| Icons {
-        red: load(RED_BYTES),
-        green: load(GREEN_BYTES),
-        blue: load(BLUE_BYTES),
-    })
-}
-
 #[derive(Debug, Clone, Copy)]
 pub enum Basic {
     Red,
@@ -42,37 +11,39 @@ pub enum Basic {
 
 impl State for Basic {
     fn icon(&self, _id: ClientId) -> Icon {
-        let icon = match self {
-            Basic::Red => &icons().red,
-            Basic::Green => &icons().green,
-            Basic::Blue => &icons().blue,
+        let color = match self {
+            Basic::Red => [0xff, 0, 0, 0xff],
+            Basic::Green => [0, 0xff, 0, 0xff],
+            Basic::Blue => [0, 0, 0xff, 0xff],
         };
 
-        Icon {
-            x: 0,
-            y: 0,
-            width: icon.width().try_into().unwrap(),
-            height: icon.height().try_into().unwrap(),
-            rgba_data: icon.as_raw(),
-        }
+        Icon::dot(color, 16)
     }
 
-    fn handle_event(&mut self, event: Event) -> bool {
+    fn handle_event(&mut self, event: Event) -> Redraw {
         debug!("client event {event:?}");
         match (event, &self) {
             (Event::Action { .. }, Basic::Red) => {
                 *self = Basic::Green;
-                true
+                Redraw::All
             }
             (Event::Action { .. }, Basic::Green) => {
                 *self = Basic::Blue;
-                true
+                Redraw::All
             }
             (Event::Action { .. }, Basic::Blue) => {
                 *self = Basic::Red;
-                true
+                Redraw::All
             }
-            (Event::Disconnect { .. }, _) => false,
+            (
+                Event::Connect { .. }
+                | Event::Disconnect { .. }
+                | Event::Encodings { .. }
+                | Event::Clipboard { .. }
+                | Event::Bell { .. }
+                | Event::SlowClient { .. },
+                _,
+            ) => Redraw::None,
         }
     }
 
@@ -86,9 +57,6 @@ async fn main() -> Result<()> {
     env_logger::init();
     info!("Running");
 
-    // preload
-    let _ = icons();
-
     run_proxy(
         "0.0.0.0:5911".parse().unwrap(),
         "127.0.0.1:5900".parse().unwrap(),
@@ -0,0 +1,176 @@
+//! A minimal VNC server drawing a moving square, for trying out the proxy without a
+//! desktop to share. Only the None security type and the Raw encoding are supported.
+//!
+//! Listens on port 5900, where the other examples expect the server, so e.g. running this
+//! next to `cargo run --example basic` and connecting a viewer to port 5911 shows the icon
+//! on top of the square.
+
+use std::time::Duration;
+
+use log::{debug, info};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::sleep,
+};
+
+const WIDTH: u16 = 640;
+const HEIGHT: u16 = 480;
+const SQUARE: u16 = 64;
+
+/// 32 bit little endian true colour, blue in the lowest byte.
+const SERVER_FORMAT: [u8; 16] = [32, 24, 0, 1, 0, 255, 0, 255, 0, 255, 16, 8, 0, 0, 0, 0];
+
+#[derive(Debug, Clone, Copy)]
+struct Format {
+    bits_per_pixel: u8,
+    big_endian: bool,
+    max: [u16; 3],
+    shift: [u8; 3],
+}
+
+impl Format {
+    fn parse(bytes: &[u8; 16]) -> Self {
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        Self {
+            bits_per_pixel: bytes[0],
+            big_endian: bytes[2] != 0,
+            max: [u16_at(4), u16_at(6), u16_at(8)],
+            shift: [bytes[10], bytes[11], bytes[12]],
+        }
+    }
+
+    fn encode(&self, rgb: [u8; 3], out: &mut Vec<u8>) {
+        let value = (0..3)
+            .map(|i| (rgb[i] as u32 * self.max[i] as u32 / 255) << self.shift[i])
+            .fold(0, |value, channel| value | channel);
+        let bytes = value.to_le_bytes();
+        let pixel = &bytes[..self.bits_per_pixel as usize / 8];
+        if self.big_endian {
+            out.extend(pixel.iter().rev());
+        } else {
+            out.extend_from_slice(pixel);
+        }
+    }
+}
+
+/// The color of a pixel at the given frame.
+fn scene(frame: u16, x: u16, y: u16) -> [u8; 3] {
+    let left = frame % (WIDTH - SQUARE);
+    let top = (HEIGHT - SQUARE) / 2;
+    if (left..left + SQUARE).contains(&x) && (top..top + SQUARE).contains(&y) {
+        [0xff, 0xff, 0xff]
+    } else {
+        [
+            (x as u32 * 255 / WIDTH as u32) as u8,
+            (y as u32 * 255 / HEIGHT as u32) as u8,
+            0x80,
+        ]
+    }
+}
+
+async fn write_rect(
+    stream: &mut TcpStream,
+    format: &Format,
+    frame: u16,
+    (x, y, width, height): (u16, u16, u16, u16),
+) -> std::io::Result<()> {
+    let mut msg = vec![0, 0, 0, 1];
+    for v in [x, y, width, height] {
+        msg.extend(v.to_be_bytes());
+    }
+    msg.extend(0i32.to_be_bytes());
+    for py in y..y + height {
+        for px in x..x + width {
+            format.encode(scene(frame, px, py), &mut msg);
+        }
+    }
+    stream.write_all(&msg).await
+}
+
+async fn serve(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.write_all(b"RFB 003.008\n").await?;
+    let mut version = [0; 12];
+    stream.read_exact(&mut version).await?;
+    stream.write_all(&[1, 1]).await?;
+    let _security_type = stream.read_u8().await?;
+    stream.write_u32(0).await?;
+    let _shared = stream.read_u8().await?;
+
+    let name = b"dummy";
+    let mut init = Vec::new();
+    init.extend(WIDTH.to_be_bytes());
+    init.extend(HEIGHT.to_be_bytes());
+    init.extend(SERVER_FORMAT);
+    init.extend((name.len() as u32).to_be_bytes());
+    init.extend(name);
+    stream.write_all(&init).await?;
+
+    let mut format = Format::parse(&SERVER_FORMAT);
+    let mut frame = 0;
+    loop {
+        match stream.read_u8().await? {
+            0 => {
+                let mut msg = [0; 19];
+                stream.read_exact(&mut msg).await?;
+                format = Format::parse(msg[3..].try_into().unwrap());
+                debug!("pixel format {format:?}");
+            }
+            2 => {
+                let _pad = stream.read_u8().await?;
+                let count = stream.read_u16().await?;
+                let mut encodings = vec![0; count as usize * 4];
+                stream.read_exact(&mut encodings).await?;
+            }
+            3 => {
+                let incremental = stream.read_u8().await? != 0;
+                let mut area = [0; 8];
+                stream.read_exact(&mut area).await?;
+                if incremental {
+                    // only the band the square moves in changes
+                    sleep(Duration::from_millis(40)).await;
+                    frame += 4;
+                    let top = (HEIGHT - SQUARE) / 2;
+                    write_rect(&mut stream, &format, frame, (0, top, WIDTH, SQUARE)).await?;
+                } else {
+                    write_rect(&mut stream, &format, frame, (0, 0, WIDTH, HEIGHT)).await?;
+                }
+            }
+            4 => {
+                let mut msg = [0; 7];
+                stream.read_exact(&mut msg).await?;
+            }
+            5 => {
+                let mut msg = [0; 5];
+                stream.read_exact(&mut msg).await?;
+            }
+            6 => {
+                let mut msg = [0; 7];
+                stream.read_exact(&mut msg).await?;
+                let len = u32::from_be_bytes(msg[3..].try_into().unwrap());
+                let mut text = vec![0; len as usize];
+                stream.read_exact(&mut text).await?;
+            }
+            m => {
+                return Err(std::io::Error::other(format!("unsupported message {m}")));
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::init();
+    let listener = TcpListener::bind("127.0.0.1:5900").await?;
+    info!("Running");
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        info!("Connection from {addr}");
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream).await {
+                info!("Connection from {addr} closed: {e}");
+            }
+        });
+    }
+}
@@ -0,0 +1,48 @@
+//! Exchanges RFB messages over an in-memory pipe, as could be done over any other
+//! transport, such as TLS or WebSockets.
+
+use tokio::io::{duplex, split};
+
+use vncproxy::rfb::{io::RfbIo, Encoding, Rectangle, C2S, S2C};
+use vncproxy::Result;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let (client, server) = duplex(0x1000);
+    let mut client = RfbIo::new(client);
+    let (server_rx, server_tx) = split(server);
+    let (mut server_rx, mut server_tx) = (RfbIo::new(server_rx), RfbIo::new(server_tx));
+
+    client
+        .write_message(C2S::FramebufferUpdateRequest {
+            incremental: false,
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        })
+        .await?;
+    let request: C2S = server_rx.read_message().await?;
+    println!("server received {request:?}");
+
+    server_tx
+        .write_message(S2C::FramebufferUpdate { count: 1 })
+        .await?;
+    server_tx
+        .write_message(Rectangle {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+            encoding: Encoding::Raw,
+        })
+        .await?;
+    server_tx.write_data(vec![0xff; 8].into()).await?;
+
+    let update: S2C = client.read_message().await?;
+    let rect: Rectangle = client.read_message().await?;
+    let pixels = client.read_data(8).await?;
+    println!("client received {update:?} with {rect:?} and {pixels:?}");
+
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+use log::{debug, info};
+
+use vncproxy::*;
+
+const COLORS: [[u8; 4]; 3] = [[0xff, 0, 0, 0xff], [0, 0xff, 0, 0xff], [0, 0, 0xff, 0xff]];
+
+/// Every client has its own dot, which only changes when that client clicks it.
+#[derive(Debug, Clone, Default)]
+pub struct PerClient {
+    colors: HashMap<ClientId, usize>,
+}
+
+impl State for PerClient {
+    fn icon(&self, id: ClientId) -> Icon {
+        let color = self.colors.get(&id).copied().unwrap_or_default();
+        Icon::dot(COLORS[color], 16)
+    }
+
+    fn handle_event(&mut self, event: Event) -> Redraw {
+        debug!("client event {event:?}");
+        match event {
+            Event::Connect { id } => {
+                // start with a different color than the previous client
+                self.colors.insert(id, id % COLORS.len());
+                Redraw::Only(vec![id])
+            }
+            Event::Action { id } => {
+                let color = self.colors.entry(id).or_default();
+                *color = (*color + 1) % COLORS.len();
+                Redraw::Only(vec![id])
+            }
+            Event::Disconnect { id } => {
+                self.colors.remove(&id);
+                Redraw::None
+            }
+            Event::Encodings { .. }
+            | Event::Clipboard { .. }
+            | Event::Bell { .. }
+            | Event::SlowClient { .. } => Redraw::None,
+        }
+    }
+
+    fn enable_input(&self, _id: ClientId) -> bool {
+        true
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+    info!("Running");
+
+    run_proxy(
+        "0.0.0.0:5911".parse().unwrap(),
+        "127.0.0.1:5900".parse().unwrap(),
+        PerClient::default(),
+    )
+    .await
+}
@@ -0,0 +1,27 @@
+//! Feeds arbitrary bytes into the message parsers, which must fail with an error instead
+//! of panicking. The first byte selects the parser.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vncproxy::rfb::{parse, ClientInit, PixelFormat, Rectangle, ServerInit, C2S, S2C};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&kind, bytes)) = data.split_first() else {
+        return;
+    };
+    match kind % 5 {
+        0 => drop(parse::<C2S>(bytes)),
+        1 => drop(parse::<S2C>(bytes)),
+        2 => drop(parse::<ServerInit>(bytes)),
+        3 => drop(parse::<ClientInit>(bytes)),
+        _ => {
+            // a rectangle followed by the pixel format its payload is in
+            if let Ok((rect, len)) = parse::<Rectangle>(bytes) {
+                if let Ok((format, _)) = parse::<PixelFormat>(&bytes[len..]) {
+                    let _ = rect.payload_size(&format);
+                }
+            }
+        }
+    }
+});
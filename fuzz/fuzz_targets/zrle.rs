@@ -0,0 +1,21 @@
+//! Feeds arbitrary pixel formats, sizes and zlib data into the ZRLE decoder, which must
+//! fail with an error instead of panicking.
+//!
+//! The input starts with a pixel format as sent in `SetPixelFormat`, followed by the width
+//! and height of the rectangle as big endian `u16`s and the zlib data.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vncproxy::{rfb::parse, PixelFormat, ZrleDecoder};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok((format, len)) = parse::<PixelFormat>(data) else {
+        return;
+    };
+    let Some(([w0, w1, h0, h1], zlib_data)) = data[len..].split_first_chunk::<4>() else {
+        return;
+    };
+    let (width, height) = (u16::from_be_bytes([*w0, *w1]), u16::from_be_bytes([*h0, *h1]));
+    let _ = ZrleDecoder::new().decode(zlib_data, width, height, &format);
+});
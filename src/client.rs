@@ -1,32 +1,74 @@
 use std::{
+    collections::HashMap,
+    future::{pending, Future},
+    io, mem,
     net::SocketAddr,
-    ops::Deref,
+    pin::Pin,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
-    time::Instant,
+    time::SystemTime,
 };
 
-use bytes::Bytes;
-use log::debug;
+use bytes::{BufMut, Bytes, BytesMut};
+use log::{debug, info, warn};
 use tokio::{
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpStream,
     },
     select,
-    sync::{mpsc, watch},
-    task::JoinHandle,
+    sync::{broadcast, mpsc, watch, Notify},
+    task::{AbortHandle, JoinHandle},
+    time::{sleep, sleep_until, timeout, timeout_at, Duration, Instant},
 };
 
+use crate::proxy_protocol::ProxyHeader;
 use crate::rfb::{io::RfbIo, *};
-use crate::{ClientId, Error, Event, Result, State};
+#[cfg(target_os = "linux")]
+use crate::splice::Pipe;
+use crate::stats::{MessageCounters, OverlayCounters};
+use crate::tight;
+use crate::zrle::{ZrleEncoder, TILE_SIZE};
+use crate::{
+    Anchor, ClientId, Config, CutTextOverflow, Error, Event, HandshakeInfo, HandshakePhase, Icon,
+    IconZOrder, Input, Result, State, Target,
+};
+
+/// Maximum amount of pixel data buffered per framebuffer update.
+const MAX_BUFFERED: usize = 1 << 20;
+/// Size of the pieces large rectangles are forwarded in.
+const CHUNK_SIZE: usize = 64 << 10;
+/// Most rectangles [S2CHandler::send_icon] sends for the icon, see [S2CHandler::icon_rects].
+const MAX_ICON_RECTS: usize = 2;
+/// Distance the pointer has to move before an icon following it is redrawn.
+const POINTER_STEP: u16 = 4;
+
+const SECURITY_NONE: u8 = 1;
+const SECURITY_VNC_AUTH: u8 = 2;
+/// Security types whose handshake the proxy can forward.
+const RELAYED_SECURITY_TYPES: [u8; 2] = [SECURITY_NONE, SECURITY_VNC_AUTH];
+/// Reason given to 3.8 clients rejected by [State::authorize].
+const REJECTED: &str = "access denied";
 
 pub struct Client<S: State> {
     pub id: ClientId,
     pub event_tx: mpsc::Sender<Event>,
     pub state_rx: watch::Receiver<S>,
+    pub config: Arc<Config>,
+    pub counters: Arc<MessageCounters>,
+    pub shutdown: Arc<Notify>,
+    /// Tells the connection that its icon may have changed, see [crate::Redraw::Only].
+    pub redraw: Arc<Notify>,
+    /// Tells the connection to show the farewell message before it is closed.
+    pub farewell: Arc<Notify>,
+    pub inputs_tx: broadcast::Sender<Option<Input>>,
+    /// Set once an event could not be sent because the proxy stopped handling them.
+    pub events_closed: Arc<AtomicBool>,
+    pub overlay: Arc<OverlayCounters>,
+    /// Shared by all clients.
+    pub overlay_total: Arc<OverlayCounters>,
 }
 
 impl<S: State> Clone for Client<S> {
@@ -35,22 +77,97 @@ impl<S: State> Clone for Client<S> {
             id: self.id,
             event_tx: self.event_tx.clone(),
             state_rx: self.state_rx.clone(),
+            config: self.config.clone(),
+            counters: self.counters.clone(),
+            shutdown: self.shutdown.clone(),
+            redraw: self.redraw.clone(),
+            farewell: self.farewell.clone(),
+            inputs_tx: self.inputs_tx.clone(),
+            events_closed: self.events_closed.clone(),
+            overlay: self.overlay.clone(),
+            overlay_total: self.overlay_total.clone(),
         }
     }
 }
 
 impl<S: State> Client<S> {
-    pub async fn handle(self, stream: TcpStream, target: SocketAddr) -> Result<()> {
+    pub async fn handle(self, stream: TcpStream, target: Target) -> Result<()> {
+        let connection = async {
+            match target {
+                Target::Direct(addr) => self.proxy(stream, addr).await,
+                Target::Mirror(session) => self.mirror(stream, session).await,
+                Target::Pool(pool) => {
+                    // the client counts as connected to the server until it is gone
+                    let lease = pool.acquire();
+                    info!("Client {} goes to {}", self.id, lease.addr());
+                    self.proxy(stream, lease.addr()).await
+                }
+            }
+        };
+        tokio::pin!(connection);
+
+        let session_end = async {
+            match self.config.max_session_duration {
+                Some(duration) => sleep(duration).await,
+                None => pending().await,
+            }
+        };
+
+        let res = select! {
+            res = &mut connection => res,
+            _ = self.shutdown.notified() => {
+                self.close(connection, "disconnected by the proxy").await
+            }
+            _ = session_end => {
+                self.close(connection, "maximum session duration reached").await
+            }
+        };
+
+        self.send_event(Event::Disconnect { id: self.id }).await;
+
+        res
+    }
+
+    /// Closes the connection, showing the farewell message first if there is one.
+    async fn close(
+        &self,
+        connection: Pin<&mut impl Future<Output = Result<()>>>,
+        reason: &str,
+    ) -> Result<()> {
+        info!("Disconnecting client {}: {reason}", self.id);
+        if self.config.farewell_message.is_some() {
+            self.farewell.notify_one();
+            let _ = timeout(self.config.farewell_grace, connection).await;
+        }
+        Ok(())
+    }
+
+    async fn proxy(&self, stream: TcpStream, target: SocketAddr) -> Result<()> {
         let server = TcpStream::connect(target).await?;
+        // deprecated in newer tokio versions for blocking the thread on close, which is
+        // what the option asks for
+        #[allow(deprecated)]
+        if let Some(linger) = self.config.linger {
+            stream.set_linger(Some(linger))?;
+            server.set_linger(Some(linger))?;
+        }
 
+        let peer_addr = stream.peer_addr()?;
         let (client_rx, client_tx) = stream.into_split();
-        let (mut client_rx, mut client_tx) = (RfbIo::new(client_rx), RfbIo::new(client_tx));
+        let client_rx = RfbIo::new(client_rx)
+            .capturing(self.config.capture_len())
+            .with_read_timeout(self.config.read_timeout);
+        let (mut client_rx, mut client_tx) = (client_rx, self.client_writer(client_tx));
 
         let (server_rx, server_tx) = server.into_split();
-        let (mut server_rx, mut server_tx) = (RfbIo::new(server_rx), RfbIo::new(server_tx));
+        let server_rx = RfbIo::new(server_rx)
+            .capturing(self.config.capture_len())
+            .with_read_timeout(self.config.read_timeout);
+        let (mut server_rx, mut server_tx) = (server_rx, RfbIo::new(server_tx));
 
-        let pixel_format = self
+        let server_init = self
             .handshake(
+                peer_addr,
                 &mut client_rx,
                 &mut client_tx,
                 &mut server_rx,
@@ -58,11 +175,28 @@ impl<S: State> Client<S> {
             )
             .await?;
 
-        let (fmt_tx, fmt_rx) = watch::channel(pixel_format);
+        self.counters.connected();
+        self.send_event(Event::Connect { id: self.id }).await;
 
-        let (fbreq_tx, fbreq_rx) = mpsc::channel::<C2S>(1);
+        let pixel_format = server_init.pixel_format;
+        let (fmt_tx, fmt_rx) = watch::channel(Formats {
+            server: pixel_format.clone(),
+            client: pixel_format,
+        });
+        let (pointer_tx, pointer_rx) = watch::channel(None);
 
-        let forward_request = Arc::new(AtomicBool::new(true));
+        let requests = Arc::new(Requests::default());
+        let pending_request = Arc::new(Mutex::new(None));
+        let continuous_updates = Arc::new(Mutex::new(None));
+        let backlog = Arc::new(Backlog::default());
+        let framebuffer_size = Arc::new(Mutex::new((
+            server_init.framebuffer_width,
+            server_init.framebuffer_height,
+        )));
+        let damage = Arc::new(Mutex::new(None));
+        let resize_encoding = Arc::new(Mutex::new(None));
+        let cursor_supported = Arc::new(AtomicBool::new(false));
+        let zrle_only = Arc::new(AtomicBool::new(false));
 
         // client to server
         let mut c2s_handler = C2SHandler {
@@ -70,12 +204,27 @@ impl<S: State> Client<S> {
             client_rx,
             server_tx,
             fmt_tx,
-            fbreq_tx,
-            forward_request: forward_request.clone(),
+            requests: requests.clone(),
+            pending_request: pending_request.clone(),
+            continuous_updates: continuous_updates.clone(),
+            backlog: backlog.clone(),
+            deferred_request: None,
+            pointer_tx,
+            framebuffer_size: framebuffer_size.clone(),
+            damage: damage.clone(),
+            resize_encoding: resize_encoding.clone(),
+            cursor_supported: cursor_supported.clone(),
+            zrle_only: zrle_only.clone(),
             mouse_pressed: false,
+            pressed_on_icon: false,
+            keys_down: HashMap::new(),
         };
 
-        let c2s: JoinHandle<Result<()>> = tokio::spawn(async move { c2s_handler.handle().await });
+        let c2s: JoinHandle<Result<()>> = tokio::spawn(async move {
+            let res = c2s_handler.handle().await;
+            let _ = c2s_handler.server_tx.shutdown().await;
+            res
+        });
 
         // server to client
         let mut s2c_handler = S2CHandler {
@@ -83,269 +232,3697 @@ impl<S: State> Client<S> {
             server_rx,
             client_tx,
             fmt_rx,
-            fbreq_rx,
-            forward_request,
-            icon_sent: false,
+            requests,
+            pending_request,
+            continuous_updates,
+            sent_icon: None,
+            initial_update: true,
+            pending_rects: Vec::new(),
+            last_update: None,
+            palette: Palette::default(),
+            backlog,
+            pointer_rx,
+            framebuffer_size,
+            damage,
+            resize_encoding,
+            cursor_supported,
+            zrle_only,
+            zrle_stream: ZrleStream::Unused,
+            server_cursor: None,
         };
 
-        let s2c: JoinHandle<Result<()>> = tokio::spawn(async move { s2c_handler.handle().await });
+        let s2c: JoinHandle<Result<()>> = tokio::spawn(async move {
+            let res = s2c_handler.handle().await;
+            // deliver whatever is left for the client, such as the last icon
+            let _ = s2c_handler.client_tx.shutdown().await;
+            res
+        });
+        let _tasks = AbortOnDrop([c2s.abort_handle(), s2c.abort_handle()]);
 
-        let res = select! {
+        select! {
             r = c2s => r.unwrap(),
             r = s2c => r.unwrap(),
-        };
+        }
+    }
 
-        self.event_tx
-            .send(Event::Disconnect { id: self.id })
-            .await
-            .unwrap();
+    async fn handshake(
+        &self,
+        peer_addr: SocketAddr,
+        client_rx: &mut RfbIo<OwnedReadHalf>,
+        client_tx: &mut RfbIo<OwnedWriteHalf>,
+        server_rx: &mut RfbIo<OwnedReadHalf>,
+        server_tx: &mut RfbIo<OwnedWriteHalf>,
+    ) -> Result<ServerInit> {
+        let deadline = Instant::now() + self.config.handshake_timeout;
 
-        res
+        let (peer_addr, version, server_version) = within(deadline, async {
+            let peer_addr = self.read_proxy_header(client_rx, peer_addr).await?;
+            let (version, server_version) = self
+                .handshake_version(client_rx, client_tx, server_rx, server_tx)
+                .await?;
+            Ok((peer_addr, version, server_version))
+        })
+        .await
+        .map_err(|e| e.in_phase(HandshakePhase::Version))?;
+
+        let info = within(
+            deadline,
+            self.handshake_security(
+                peer_addr,
+                (version, server_version),
+                client_rx,
+                client_tx,
+                server_rx,
+                server_tx,
+            ),
+        )
+        .await
+        .map_err(|e| e.in_phase(HandshakePhase::Security))?;
+
+        within(
+            deadline,
+            self.handshake_init(info, client_rx, client_tx, server_rx, server_tx),
+        )
+        .await
+        .map_err(|e| e.in_phase(HandshakePhase::Init))
     }
 
-    async fn handshake(
+    /// Forwards the protocol versions and returns the minor versions used with the client
+    /// and with the server, which differ if [Config::backend_version] is set.
+    async fn handshake_version(
         &self,
         client_rx: &mut RfbIo<OwnedReadHalf>,
         client_tx: &mut RfbIo<OwnedWriteHalf>,
         server_rx: &mut RfbIo<OwnedReadHalf>,
         server_tx: &mut RfbIo<OwnedWriteHalf>,
-    ) -> Result<PixelFormat> {
+    ) -> Result<(u16, u16)> {
         let server_version: Version = server_rx.read_message().await?;
-        client_tx.write_message(dbg!(server_version)).await?;
+        if server_version.parse().is_none() {
+            return Err(Error::Protocol(format!(
+                "invalid server version {server_version:?}"
+            )));
+        }
+        client_tx.write_message(server_version).await?;
 
         let client_version: Version = client_rx.read_message().await?;
-        server_tx.write_message(dbg!(client_version)).await?;
+        let Some(version) = minor_version(&client_version) else {
+            return Err(Error::Protocol(format!(
+                "unsupported client version {client_version:?}"
+            )));
+        };
 
-        let version = b"RFB 003.003\n";
+        let Some((major, minor)) = self.config.backend_version else {
+            server_tx.write_message(client_version).await?;
+            return Ok((version, version));
+        };
+        let backend_version = Version::new(major.into(), minor.into());
+        let Some(server_version) = minor_version(&backend_version) else {
+            return Err(Error::Protocol(format!(
+                "unsupported backend version {backend_version:?}"
+            )));
+        };
+        server_tx
+            .write_message(Version::new(3, server_version))
+            .await?;
 
-        let sec_type = match version {
-            b"RFB 003.003\n" => {
-                let sec_type: SecurityResult = server_rx.read_message().await?;
-                client_tx.write_message(dbg!(sec_type)).await?;
+        Ok((version, server_version))
+    }
 
-                if sec_type.0 == 0 {
-                    let err = server_rx.read_message().await?;
-                    Err(Error::Protocol(err))
-                } else {
-                    Ok(sec_type.0)
-                }
+    /// Forwards the security handshake, offering only the types the proxy can relay and
+    /// [Config::security_types] allows, and lets the [State] authorize the client before
+    /// it is told the result. `versions` are the minor versions used with the client and
+    /// with the server, whose framings of the handshake are translated into each other.
+    /// Returns what is known about the client so far.
+    async fn handshake_security(
+        &self,
+        peer_addr: SocketAddr,
+        (version, server_version): (u16, u16),
+        client_rx: &mut RfbIo<OwnedReadHalf>,
+        client_tx: &mut RfbIo<OwnedWriteHalf>,
+        server_rx: &mut RfbIo<OwnedReadHalf>,
+        server_tx: &mut RfbIo<OwnedWriteHalf>,
+    ) -> Result<HandshakeInfo> {
+        // 3.3 servers pick the security type themselves
+        let server_types = if server_version == 3 {
+            let sec_type: SecurityResult = server_rx.read_message().await?;
+            match sec_type.0 {
+                0 => Vec::new(),
+                sec_type => vec![u8::try_from(sec_type).unwrap_or(0)],
             }
-            _ => {
-                let sec_types: SecurityTypes = server_rx.read_message().await?;
-                let has_err = sec_types.0.is_empty();
-                client_tx.write_message(dbg!(sec_types)).await?;
+        } else {
+            let sec_types: SecurityTypes = server_rx.read_message().await?;
+            sec_types.types().to_vec()
+        };
+        if server_types.is_empty() {
+            let reason: String = server_rx.read_message().await?;
+            Self::refuse_security(version, client_tx, reason.clone()).await?;
+            return Err(Error::Protocol(reason));
+        }
 
-                if has_err {
-                    let err = server_rx.read_message().await?;
-                    Err(Error::Protocol(err))
-                } else {
-                    let sec_type: SecurityType = client_rx.read_message().await?;
-                    server_tx.write_message(dbg!(sec_type)).await?;
-                    Ok(sec_type.0 as _)
-                }
+        let offered = self.offered_security_types(&server_types);
+        if offered.is_empty() {
+            let reason = format!("no allowed security type in {server_types:?}");
+            Self::refuse_security(version, client_tx, reason.clone()).await?;
+            return Err(Error::Protocol(reason));
+        }
+
+        let sec_type = if version == 3 {
+            // the proxy picks for 3.3 clients, by the order of preference
+            let sec_type = offered[0];
+            client_tx
+                .write_message(SecurityResult(sec_type as _))
+                .await?;
+            sec_type
+        } else {
+            client_tx
+                .write_message(SecurityTypes::new(&offered)?)
+                .await?;
+            let sec_type: SecurityType = client_rx.read_message().await?;
+            if !offered.contains(&sec_type.0) {
+                return Err(Error::Protocol(format!(
+                    "client chose security type {} which was not offered",
+                    sec_type.0
+                )));
             }
-        }?;
+            sec_type.0
+        };
+        if server_version != 3 {
+            server_tx.write_message(SecurityType(sec_type)).await?;
+        }
 
-        assert_eq!(sec_type, 1);
+        if sec_type == SECURITY_VNC_AUTH {
+            let challenge = server_rx.read_data(16).await?;
+            client_tx.write_data(challenge).await?;
+            let response = client_rx.read_data(16).await?;
+            server_tx.write_data(response).await?;
+        }
 
-        if version == b"RFB 003.008\n" {
+        // only 3.8 sends a result for the None type
+        let sends_result = |version| version == 8 || sec_type != SECURITY_NONE;
+        let sec_res = if sends_result(server_version) {
             let sec_res: SecurityResult = server_rx.read_message().await?;
-            client_tx.write_message(dbg!(sec_res)).await?;
+            // only 3.8 follows a failure with the reason
+            let reason = match sec_res.0 {
+                0 => None,
+                _ if server_version == 8 => Some(server_rx.read_message().await?),
+                _ => Some("authentication failed".to_string()),
+            };
+            Some((sec_res, reason))
+        } else {
+            None
+        };
+
+        let (sec_res, reason) = sec_res.unwrap_or((SecurityResult(0), None));
+        let info = HandshakeInfo {
+            peer_addr,
+            version: (3, version),
+            security_type: sec_type,
+            shared: None,
+        };
+        if reason.is_none() {
+            self.authorize(&info, sends_result(version), client_tx)
+                .await?;
+        }
+        if sends_result(version) {
+            client_tx.write_message(sec_res).await?;
+            if let (Some(reason), 8) = (&reason, version) {
+                client_tx.write_message(reason.clone()).await?;
+            }
+        }
+        if let Some(reason) = reason {
+            return Err(Error::Protocol(reason));
+        }
+
+        Ok(info)
+    }
+
+    /// Asks the [State] whether to let the client in. A rejected client is sent a failed
+    /// `SecurityResult` if `sends_result`, which 3.8 clients get the reason along with.
+    pub(crate) async fn authorize(
+        &self,
+        info: &HandshakeInfo,
+        sends_result: bool,
+        client_tx: &mut RfbIo<OwnedWriteHalf>,
+    ) -> Result<()> {
+        if self.state_rx.borrow().authorize(self.id, info) {
+            return Ok(());
+        }
+
+        if sends_result {
+            client_tx.queue_message(SecurityResult(1));
+            if info.version == (3, 8) {
+                client_tx.queue_message(REJECTED.to_string());
+            }
+            client_tx.flush().await?;
+        }
+        Err(Error::Protocol(format!("client rejected: {info:?}")))
+    }
+
+    /// Tells the client that the security handshake failed before a type was chosen.
+    async fn refuse_security(
+        version: u16,
+        client_tx: &mut RfbIo<OwnedWriteHalf>,
+        reason: String,
+    ) -> Result<()> {
+        if version == 3 {
+            client_tx.queue_message(SecurityResult(0));
+        } else {
+            client_tx.queue_message(SecurityTypes::new(&[])?);
+        }
+        client_tx.write_message(reason).await
+    }
+
+    /// Returns the security types to offer out of the ones offered by the server.
+    fn offered_security_types(&self, server: &[u8]) -> Vec<u8> {
+        let relayable = |t: &u8| RELAYED_SECURITY_TYPES.contains(t);
+        match &self.config.security_types {
+            Some(allowed) => allowed
+                .iter()
+                .filter(|t| server.contains(t) && relayable(t))
+                .copied()
+                .collect(),
+            None => server.iter().filter(|t| relayable(t)).copied().collect(),
         }
+    }
 
+    /// Forwards the init messages, once the [State] has authorized the client again with
+    /// its shared flag. Returns the `ServerInit` as sent to the client.
+    async fn handshake_init(
+        &self,
+        info: HandshakeInfo,
+        client_rx: &mut RfbIo<OwnedReadHalf>,
+        client_tx: &mut RfbIo<OwnedWriteHalf>,
+        server_rx: &mut RfbIo<OwnedReadHalf>,
+        server_tx: &mut RfbIo<OwnedWriteHalf>,
+    ) -> Result<ServerInit> {
         let client_init: ClientInit = client_rx.read_message().await?;
-        server_tx.write_message(dbg!(client_init)).await?;
+        let info = HandshakeInfo {
+            shared: Some(client_init.shared),
+            ..info
+        };
+        // the client has been told that it passed, so it is just disconnected
+        self.authorize(&info, false, client_tx).await?;
+        server_tx.write_message(client_init).await?;
+
+        let mut server_init: ServerInit = server_rx.read_message().await?;
+        server_init.framebuffer_height = server_init
+            .framebuffer_height
+            .saturating_add(self.config.overlay_margin);
+        if let Some(name) = &self.config.server_name_override {
+            server_init.name = ascii_name(name);
+        }
+
+        let server_format = server_init.pixel_format.clone();
+        let server_init = self
+            .state_rx
+            .borrow()
+            .rewrite_server_init(self.id, server_init);
+        if server_init.pixel_format != server_format {
+            if !server_init.pixel_format.true_colour {
+                return Err(Error::Protocol(
+                    "cannot pin a colour map pixel format".to_string(),
+                ));
+            }
+            if !server_init.pixel_format.is_valid() {
+                return Err(Error::Protocol(format!(
+                    "invalid pixel format {:?}",
+                    server_init.pixel_format
+                )));
+            }
+            server_tx
+                .write_message(C2S::SetPixelFormat(server_init.pixel_format.clone()))
+                .await?;
+        }
+        client_tx.write_message(server_init.clone()).await?;
+
+        Ok(server_init)
+    }
+
+    /// Reads the PROXY protocol header if one is expected and returns the address of the
+    /// original client, falling back to the address of the connection.
+    pub(crate) async fn read_proxy_header(
+        &self,
+        client_rx: &mut RfbIo<OwnedReadHalf>,
+        peer_addr: SocketAddr,
+    ) -> Result<SocketAddr> {
+        if !self.config.expect_proxy_protocol {
+            return Ok(peer_addr);
+        }
+
+        let header: ProxyHeader = client_rx.read_message().await?;
+        let addr = header.source.unwrap_or(peer_addr);
+        info!("Client {} is {addr}, proxied by {peer_addr}", self.id);
+        Ok(addr)
+    }
+
+    /// Returns the farewell message, as clipboard text and as an icon.
+    pub(crate) fn farewell_message(&self) -> Option<(String, Icon)> {
+        let message = self.config.farewell_message.as_ref()?;
+        let icon = Icon::text(message, [0xff; 4], [0x80, 0, 0, 0xff]);
+        Some((ascii_name(message), icon))
+    }
+
+    /// Returns the icon of the client as chosen by the state, before it is placed.
+    pub(crate) fn icon(&self) -> Icon {
+        let state = self.state_rx.borrow();
+        let icon = state.icon(self.id);
+        if self.config.grey_view_only && !state.enable_input(self.id) {
+            icon.greyed()
+        } else {
+            icon
+        }
+    }
+
+    /// Whether the icon is drawn and clicks on it are handled. Once the proxy has stopped
+    /// handling events, the connection carries on as a transparent one.
+    pub(crate) fn overlay_enabled(&self) -> bool {
+        !self.config.disable_overlay && !self.event_tx.is_closed()
+    }
+
+    /// Passes an event on to the [State], waiting while the proxy is busy.
+    pub(crate) async fn send_event(&self, event: Event) {
+        if self.event_tx.send(event).await.is_err() {
+            self.events_closed();
+        }
+    }
+
+    /// Wraps the sending half of a client connection, reporting slow writes if enabled.
+    pub(crate) fn client_writer(&self, tx: OwnedWriteHalf) -> RfbIo<OwnedWriteHalf> {
+        let tx = RfbIo::new(tx);
+        let Some(threshold) = self.config.slow_write_threshold else {
+            return tx;
+        };
+        let (id, event_tx) = (self.id, self.event_tx.clone());
+        tx.on_slow_write(threshold, move |elapsed| {
+            warn!("Writing to client {id} took {elapsed:?}");
+            // dropped if the events are not handled or do not keep up either
+            let _ = event_tx.try_send(Event::SlowClient { id });
+        })
+    }
+
+    /// Passes an event on to the [State], dropping it while the proxy is busy.
+    pub(crate) fn try_send_event(&self, event: Event) {
+        if let Err(mpsc::error::TrySendError::Closed(_)) = self.event_tx.try_send(event) {
+            self.events_closed();
+        }
+    }
+
+    fn events_closed(&self) {
+        if !self.events_closed.swap(true, Ordering::Relaxed) {
+            warn!(
+                "Events of client {} are no longer handled, forwarding without the overlay",
+                self.id
+            );
+        }
+    }
+
+    /// Whether clicks on the icon are turned into actions. An icon shown as the cursor is
+    /// always under the pointer, so it cannot be clicked.
+    pub(crate) fn icon_interactive(&self) -> bool {
+        !self.icon_hidden()
+            && !self.icon().is_cursor()
+            && self.state_rx.borrow().icon_interactive(self.id)
+    }
+
+    /// Whether the icon is left out for a view-only client, see
+    /// [Config::hide_icon_when_view_only].
+    pub(crate) fn icon_hidden(&self) -> bool {
+        self.config.hide_icon_when_view_only && !self.state_rx.borrow().enable_input(self.id)
+    }
+
+    /// Counts something the overlay did, both for this client and in total.
+    pub(crate) fn count_overlay(&self, count: impl Fn(&OverlayCounters)) {
+        count(&self.overlay);
+        count(&self.overlay_total);
+    }
+
+    pub(crate) fn send_action(&self) {
+        self.try_send_event(Event::Action { id: self.id });
+    }
+
+    /// Passes keyboard, pointer and clipboard input on to [crate::ProxyHandle::inputs].
+    pub(crate) fn send_input(&self, message: &C2S) {
+        let is_input = matches!(
+            message,
+            C2S::KeyEvent { .. } | C2S::PointerEvent { .. } | C2S::CutText(_)
+        );
+        if !is_input || self.inputs_tx.receiver_count() == 0 {
+            return;
+        }
 
-        let server_init: ServerInit = server_rx.read_message().await?;
-        let pixel_format = server_init.pixel_format.clone();
-        client_tx.write_message(dbg!(server_init)).await?;
+        let mut message = message.clone();
+        self.state_rx.borrow().redact_input(self.id, &mut message);
+        // there may be no subscribers left
+        let _ = self.inputs_tx.send(Some(Input {
+            id: self.id,
+            time: SystemTime::now(),
+            message,
+        }));
+    }
 
-        Ok(pixel_format)
+    pub(crate) fn send_clipboard(&self, text: &str, from_server: bool) {
+        if self.state_rx.borrow().wants_clipboard_events() {
+            self.try_send_event(Event::Clipboard {
+                id: self.id,
+                text: text.to_string(),
+                from_server,
+            });
+        }
     }
 
-    fn send_action(&self) {
-        let _ = self.event_tx.try_send(Event::Action { id: self.id });
+    pub(crate) fn send_encodings(&self, encodings: &[Encoding]) {
+        if self.state_rx.borrow().wants_encoding_events() {
+            self.try_send_event(Event::Encodings {
+                id: self.id,
+                encodings: encodings.to_vec(),
+            });
+        }
     }
 }
 
-struct C2SHandler<S: State> {
-    client: Client<S>,
-    client_rx: RfbIo<OwnedReadHalf>,
-    server_tx: RfbIo<OwnedWriteHalf>,
-    fmt_tx: watch::Sender<PixelFormat>,
-    fbreq_tx: mpsc::Sender<C2S>,
-    forward_request: Arc<AtomicBool>,
-    mouse_pressed: bool,
+/// Reads a message with `parse`, but reads clipboard text longer than
+/// [Config::max_cut_text_len] only up to the limit, and drops the rest as it arrives.
+/// `cut_text_type` is the type of `CutText` messages in this direction. Returns `None`
+/// if the clipboard text is rejected.
+pub(crate) async fn read_limited<M>(
+    rx: &mut RfbIo<OwnedReadHalf>,
+    config: &Config,
+    cut_text_type: u8,
+    parse: impl FnMut(&mut Bytes) -> std::result::Result<M, DecodeError>,
+    cut_text: impl Fn(String) -> M,
+) -> Result<Option<M>> {
+    match read_cut_text_limited(rx, config, cut_text_type, parse, cut_text).await {
+        // some peers declare more clipboard text than they send
+        Err(Error::Io(e))
+            if e.kind() == io::ErrorKind::TimedOut
+                && (rx.peek_buffered() == Some(cut_text_type) || rx.is_skipping()) =>
+        {
+            Err(Error::Protocol(
+                "clipboard text ended before its declared length".to_string(),
+            ))
+        }
+        res => res,
+    }
 }
 
-impl<S: State> C2SHandler<S> {
-    async fn handle(&mut self) -> Result<()> {
-        loop {
-            let message: C2S = self.client_rx.read_message().await?;
-            let message = match message {
-                C2S::SetEncodings(e) => {
-                    debug!("encodings: {e:?}");
-                    Some(C2S::SetEncodings(vec![
-                        Encoding::Raw,
-                        Encoding::Cursor,
-                        Encoding::CopyRect,
-                        Encoding::Zrle,
-                    ]))
-                }
+async fn read_cut_text_limited<M>(
+    rx: &mut RfbIo<OwnedReadHalf>,
+    config: &Config,
+    cut_text_type: u8,
+    mut parse: impl FnMut(&mut Bytes) -> std::result::Result<M, DecodeError>,
+    cut_text: impl Fn(String) -> M,
+) -> Result<Option<M>> {
+    let Some(max_len) = config.max_cut_text_len else {
+        return rx.read_with(parse).await.map(Some);
+    };
+    let keep = match config.cut_text_overflow {
+        CutTextOverflow::Truncate => max_len,
+        CutTextOverflow::Reject => 0,
+    };
 
-                C2S::SetPixelFormat(pixel_format) => {
-                    debug!("pixel format: {pixel_format:?}");
-                    let _ = self.fmt_tx.send_replace(pixel_format.clone());
-                    Some(C2S::SetPixelFormat(pixel_format))
-                }
+    rx.read_skipping(
+        |buf| match read_long_cut_text(buf, cut_text_type, max_len, keep)? {
+            Some((text, rest)) => {
+                warn!(
+                    "Clipboard text of {} bytes exceeds the limit of {max_len}",
+                    text.len() + rest
+                );
+                let message = match config.cut_text_overflow {
+                    CutTextOverflow::Truncate => Some(cut_text(text)),
+                    CutTextOverflow::Reject => None,
+                };
+                Ok((message, rest))
+            }
+            None => Ok((Some(parse(buf)?), 0)),
+        },
+    )
+    .await
+}
 
-                C2S::PointerEvent { button_mask, x, y } => {
-                    let mouse_pressed_new = (button_mask & 1) > 0;
-                    let click = self.mouse_pressed && !mouse_pressed_new;
-                    self.mouse_pressed = mouse_pressed_new;
-
-                    let mut forward = self.client.state_rx.borrow().enable_input(self.client.id);
-                    if click {
-                        let icon = self.client.state_rx.borrow().icon(self.client.id);
-                        if icon.in_bounds(x, y) {
-                            self.client.send_action();
-                            forward = false;
-                        }
-                    }
+/// Replaces all characters which are not encoded the same in Latin-1 and UTF-8.
+pub(crate) fn ascii_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii() { c } else { '?' })
+        .collect()
+}
 
-                    if forward {
-                        Some(C2S::PointerEvent { button_mask, x, y })
-                    } else {
-                        None
-                    }
-                }
+/// Returns the minor number of the protocol version 3.3, 3.7 or 3.8 to use for a version.
+pub(crate) fn minor_version(version: &Version) -> Option<u16> {
+    match version.parse()? {
+        // other 3.x versions are to be interpreted as 3.3 (RFC 6143, 7.1.1)
+        (3, 7) => Some(7),
+        (3, minor) if minor >= 8 => Some(8),
+        (3, _) => Some(3),
+        _ => None,
+    }
+}
 
-                req @ C2S::FramebufferUpdateRequest { .. } => {
-                    let _ = self.fbreq_tx.try_send(req.clone());
+pub(crate) async fn within<T>(deadline: Instant, f: impl Future<Output = Result<T>>) -> Result<T> {
+    timeout_at(deadline, f)
+        .await
+        .unwrap_or_else(|_| Err(Error::Protocol("timed out".to_string())))
+}
 
-                    // if there is a pending proxy update, do not forward the request
-                    self.forward_request.load(Ordering::SeqCst).then_some(req)
-                }
+/// The pixel format the server sends and the one the client expects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Formats {
+    server: PixelFormat,
+    client: PixelFormat,
+}
 
-                m => Some(m),
-            };
+/// Whose zlib stream the ZRLE rectangles sent to a client belong to. All of them share one
+/// stream, so once it is taken, rectangles of the other kind would be garbage to the client.
+enum ZrleStream {
+    Unused,
+    Server,
+    Proxy(ZrleEncoder),
+}
 
-            if let Some(message) = message {
-                self.server_tx.write_message(message).await?;
-            }
+/// Aborts the tasks of a connection once it is closed, from either side or by the proxy.
+struct AbortOnDrop([AbortHandle; 2]);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for handle in &self.0 {
+            handle.abort();
         }
     }
 }
 
-struct S2CHandler<S: State> {
-    client: Client<S>,
-    server_rx: RfbIo<OwnedReadHalf>,
-    client_tx: RfbIo<OwnedWriteHalf>,
-    fmt_rx: watch::Receiver<PixelFormat>,
-    fbreq_rx: mpsc::Receiver<C2S>,
-    forward_request: Arc<AtomicBool>,
-    icon_sent: bool,
+/// An area of the framebuffer, such as the one a client receives continuous updates for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Region {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
 }
 
-impl<S: State> S2CHandler<S> {
-    async fn handle(&mut self) -> Result<()> {
-        // removing this leads to issues, why?
-        self.client.state_rx.mark_unchanged();
-
-        loop {
-            select! {
-                m = self.server_rx.read_message() => { self.handle_message(m?).await?; },
-                Ok(_) = self.client.state_rx.changed() => { self.handle_state_changed().await?; },
-            };
+impl Region {
+    fn of_icon(icon: &Icon) -> Self {
+        Self {
+            x: icon.x,
+            y: icon.y,
+            width: icon.width,
+            height: icon.height,
         }
     }
 
-    async fn handle_message(&mut self, message: S2C) -> Result<()> {
-        if let S2C::FramebufferUpdate { count } = message {
-            let _fbreq = self.next_request().await;
+    fn intersects(&self, icon: &Icon) -> bool {
+        icon.intersects(self.x, self.y, self.width, self.height)
+    }
 
-            // TODO only send if intersects?
-            let send_icon = self.fmt_rx.borrow().bits_per_pixel == 32;
-            let message = if send_icon {
-                S2C::FramebufferUpdate { count: count + 1 }
-            } else {
-                message
-            };
+    fn right(&self) -> u16 {
+        self.x.saturating_add(self.width)
+    }
 
-            self.client_tx.write_message(message).await?;
+    fn bottom(&self) -> u16 {
+        self.y.saturating_add(self.height)
+    }
+
+    /// Returns the bounding box of both regions.
+    fn union(&self, other: &Region) -> Self {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = self.right().max(other.right());
+        let bottom = self.bottom().max(other.bottom());
+        Self {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+}
 
-            for _ in 0..count {
-                let rect: Rectangle = self.server_rx.read_message().await?;
-                self.client_tx.write_message(rect.clone()).await?;
+/// Number of bytes read from the server which have not been written to the client yet.
+#[derive(Debug, Default)]
+struct Backlog {
+    bytes: AtomicUsize,
+    drained: Notify,
+}
 
-                match rect.encoding {
-                    Encoding::Zrle => {
-                        let data: Zrle = self.server_rx.read_message().await?;
-                        self.client_tx.write_message(data).await?;
-                    }
-                    Encoding::DesktopSize => {}
-                    _ => {
-                        let payload_size = rect.payload_size(self.fmt_rx.borrow().deref());
-                        let data = self.server_rx.read_data(payload_size).await?;
-                        self.client_tx.write_data(data).await?;
-                    }
-                }
-            }
+impl Backlog {
+    fn set(&self, bytes: usize) {
+        self.bytes.store(bytes, Ordering::SeqCst);
+        self.drained.notify_waiters();
+    }
+
+    fn is_below(&self, limit: usize) -> bool {
+        self.bytes.load(Ordering::SeqCst) <= limit
+    }
 
-            if send_icon {
-                self.send_icon().await?;
+    async fn wait_below(&self, limit: usize) {
+        loop {
+            // register before checking, so no update is missed
+            let drained = self.drained.notified();
+            if self.is_below(limit) {
+                return;
             }
+            drained.await;
+        }
+    }
+}
+
+/// Pairs the framebuffer update requests of a client with the updates answering them,
+/// which come either from the server or from the proxy.
+///
+/// A request is either forwarded to the server or, if the proxy is waiting to send an
+/// update of its own, handed to that update instead. Both decisions are made under one
+/// lock, so a request can neither be answered twice nor be lost in between.
+#[derive(Debug, Default)]
+struct Requests {
+    inner: Mutex<RequestsInner>,
+    granted: Notify,
+}
+
+#[derive(Debug, Default)]
+struct RequestsInner {
+    /// A request has been forwarded to the server and not been answered yet. Requests
+    /// forwarded in the meantime are answered by the same update by most servers.
+    outstanding: bool,
+    /// An update is waiting for the next request.
+    waiting: bool,
+}
+
+impl Requests {
+    /// Accounts for a request of the client. Returns whether it has to be forwarded to
+    /// the server, which is not the case if it is taken by a waiting update.
+    fn receive(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if mem::take(&mut inner.waiting) {
+            self.granted.notify_one();
+            false
         } else {
-            self.client_tx.write_message(message).await?;
+            inner.outstanding = true;
+            true
         }
+    }
 
-        Ok(())
+    /// Takes the outstanding request, if any.
+    fn try_take(&self) -> bool {
+        mem::take(&mut self.inner.lock().unwrap().outstanding)
     }
 
-    async fn handle_state_changed(&mut self) -> Result<()> {
-        let send_icon = self.fmt_rx.borrow().bits_per_pixel == 32;
-        if !send_icon {
-            return Ok(());
+    /// Takes the outstanding request or waits for the next one. Returns whether a request
+    /// was available right away. Only one task may wait at a time.
+    async fn take(&self) -> bool {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if mem::take(&mut inner.outstanding) {
+                return true;
+            }
+            inner.waiting = true;
         }
+        // the permit is stored if the request arrives before we start waiting
+        self.granted.notified().await;
+        false
+    }
+}
 
-        let _fbreq = self.next_request().await;
-        self.client_tx
-            .write_message(S2C::FramebufferUpdate { count: 1 })
-            .await?;
+/// The colour map of a client using an indexed pixel format, as set by the server.
+struct Palette([[u16; 3]; 256]);
 
-        self.send_icon().await?;
-        Ok(())
+impl Default for Palette {
+    fn default() -> Self {
+        Self([[0; 3]; 256])
     }
+}
 
-    async fn send_icon(&mut self) -> Result<()> {
-        let icon = self.client.state_rx.borrow().icon(self.client.id);
-        let rect = Rectangle {
-            x: icon.x,
-            y: icon.y,
-            width: icon.width,
-            height: icon.height,
-            encoding: Encoding::Raw,
-        };
+impl Palette {
+    /// Applies a `SetColorMapEntries` update of RGB values starting at `first_color`.
+    fn update(&mut self, first_color: u16, colors: &[u8]) -> Result<()> {
+        let first = first_color as usize;
+        let count = colors.len() / 6;
+        if first + count > self.0.len() {
+            return Err(Error::Protocol(format!(
+                "colour map entries {first}..{} out of range",
+                first + count
+            )));
+        }
 
-        self.client_tx.write_message(rect).await?;
-        self.client_tx
-            .write_data(Bytes::from_static(icon.rgba_data))
-            .await?;
-        self.icon_sent = true;
+        for (entry, rgb) in self.0[first..].iter_mut().zip(colors.chunks_exact(6)) {
+            for (value, bytes) in entry.iter_mut().zip(rgb.chunks_exact(2)) {
+                *value = u16::from_be_bytes([bytes[0], bytes[1]]);
+            }
+        }
         Ok(())
     }
 
-    async fn next_request(&mut self) -> C2S {
-        if let Ok(c2s) = self.fbreq_rx.try_recv() {
-            c2s
-        } else {
-            let start = Instant::now();
-            // if there is no request available, disable forwarding until we get one
-            self.forward_request.store(false, Ordering::SeqCst);
-            let c2s = self.fbreq_rx.recv().await.unwrap();
-            self.forward_request.store(true, Ordering::SeqCst);
-            debug!("waited {:?} for request", start.elapsed());
-            c2s
-        }
+    /// Maps RGBA pixels to the indices of the closest colours in the map.
+    fn map_rgba(&self, rgba_data: &[u8]) -> Vec<u8> {
+        rgba_data
+            .chunks_exact(4)
+            .map(|pixel| self.nearest([pixel[0], pixel[1], pixel[2]]))
+            .collect()
+    }
+
+    fn nearest(&self, rgb: [u8; 3]) -> u8 {
+        let distance = |entry: &[u16; 3]| -> u32 {
+            entry
+                .iter()
+                .zip(rgb)
+                .map(|(value, c)| ((value >> 8) as i32 - c as i32).pow(2) as u32)
+                .sum()
+        };
+        (0..=u8::MAX)
+            .min_by_key(|i| distance(&self.0[*i as usize]))
+            .unwrap()
+    }
+}
+
+struct C2SHandler<S: State> {
+    client: Client<S>,
+    client_rx: RfbIo<OwnedReadHalf>,
+    server_tx: RfbIo<OwnedWriteHalf>,
+    fmt_tx: watch::Sender<Formats>,
+    requests: Arc<Requests>,
+    pending_request: Arc<Mutex<Option<C2S>>>,
+    continuous_updates: Arc<Mutex<Option<Region>>>,
+    backlog: Arc<Backlog>,
+    /// An update request held back while the client's backlog is too large, whether it is
+    /// incremental and the area it asks for. See [Config::max_client_backlog].
+    deferred_request: Option<(bool, Region)>,
+    /// The last pointer position which moved an icon following the pointer.
+    pointer_tx: watch::Sender<Option<(u16, u16)>>,
+    framebuffer_size: Arc<Mutex<(u16, u16)>>,
+    /// Area uncovered by moving the icon, which has to be requested from the server again.
+    damage: Arc<Mutex<Option<Region>>>,
+    /// How the client wants to be told about resizes, if at all.
+    resize_encoding: Arc<Mutex<Option<Encoding>>>,
+    /// Whether the client supports the Cursor pseudo-encoding, see [crate::Anchor::Cursor].
+    cursor_supported: Arc<AtomicBool>,
+    zrle_only: Arc<AtomicBool>,
+    mouse_pressed: bool,
+    /// Whether the current press of the left button started on the icon.
+    pressed_on_icon: bool,
+    /// Keys held by the client which were forwarded, and the keysym they were forwarded as.
+    keys_down: HashMap<u32, u32>,
+}
+
+impl<S: State> C2SHandler<S> {
+    fn icon(&self) -> Icon {
+        self.client.icon().placed(
+            *self.pointer_tx.borrow(),
+            *self.framebuffer_size.lock().unwrap(),
+        )
+    }
+
+    async fn handle(&mut self) -> Result<()> {
+        let limit = self.client.config.max_client_backlog.unwrap_or(usize::MAX);
+        loop {
+            select! {
+                m = read_limited(
+                    &mut self.client_rx,
+                    &self.client.config,
+                    6,
+                    C2S::read_from,
+                    C2S::CutText,
+                ) => {
+                    if let Some(m) = m? {
+                        self.handle_message(m).await?;
+                    }
+                },
+                _ = self.backlog.wait_below(limit), if self.deferred_request.is_some() => {
+                    if let Some((incremental, region)) = self.deferred_request.take() {
+                        self.forward_request(incremental, region).await?;
+                    }
+                },
+            };
+        }
+    }
+
+    async fn handle_message(&mut self, message: C2S) -> Result<()> {
+        self.client.counters.count_c2s(&message);
+        self.client.send_input(&message);
+        if let C2S::CutText(text) = &message {
+            self.client.send_clipboard(text, false);
+        }
+        let message = match message {
+            C2S::SetEncodings(e) => {
+                debug!("encodings: {e:?}");
+                self.client.send_encodings(&e);
+                let mut encodings = supported_encodings().to_vec();
+                if self.client.config.strip_cursor {
+                    encodings.retain(|e| *e != Encoding::Cursor);
+                }
+                if e.contains(&Encoding::ContinuousUpdates) {
+                    encodings.push(Encoding::ContinuousUpdates);
+                }
+                // GII messages are passed through without looking at them
+                if e.contains(&Encoding::Gii) {
+                    encodings.push(Encoding::Gii);
+                }
+                // Tight is passed through like ZRLE, but not every client supports it
+                if e.contains(&Encoding::Tight) {
+                    encodings.push(Encoding::Tight);
+                }
+                // resizes are passed on in whichever form the client prefers, so the
+                // server may use either
+                let resize = [Encoding::DesktopSize, Encoding::ExtendedDesktopSize]
+                    .into_iter()
+                    .find(|resize| e.contains(resize));
+                *self.resize_encoding.lock().unwrap() = resize;
+                self.cursor_supported
+                    .store(e.contains(&Encoding::Cursor), Ordering::Relaxed);
+                // the proxy encodes ZRLE for such clients itself, which cannot be
+                // mixed with the server's ZRLE, so the server sends Raw instead
+                let zrle_only = !e.contains(&Encoding::Raw) && e.contains(&Encoding::Zrle);
+                self.zrle_only.store(zrle_only, Ordering::Relaxed);
+                if zrle_only {
+                    encodings.retain(|e| *e != Encoding::Zrle);
+                }
+                if resize.is_some() {
+                    encodings.extend([Encoding::DesktopSize, Encoding::ExtendedDesktopSize]);
+                }
+                // ZRLE and Tight rectangles cannot be converted to another pixel format
+                if self
+                    .client
+                    .state_rx
+                    .borrow()
+                    .preferred_pixel_format()
+                    .is_some()
+                {
+                    encodings.retain(|e| !matches!(e, Encoding::Zrle | Encoding::Tight));
+                }
+                if self.client.config.force_raw {
+                    // pseudo-encodings carry no pixel data of their own
+                    encodings.retain(|e| {
+                        matches!(
+                            e,
+                            Encoding::Raw
+                                | Encoding::Cursor
+                                | Encoding::DesktopSize
+                                | Encoding::ExtendedDesktopSize
+                                | Encoding::ContinuousUpdates
+                                | Encoding::Gii
+                        )
+                    });
+                }
+                Some(C2S::SetEncodings(encodings))
+            }
+
+            C2S::SetPixelFormat(pixel_format) => {
+                debug!("pixel format: {pixel_format:?}");
+                let preferred = self.client.state_rx.borrow().preferred_pixel_format();
+                let server = preferred
+                    .filter(|preferred| {
+                        if !preferred.is_valid() {
+                            warn!("ignoring invalid preferred pixel format {preferred:?}");
+                        }
+                        pixel_format.true_colour && preferred.is_valid()
+                    })
+                    .unwrap_or_else(|| pixel_format.clone());
+                let _ = self.fmt_tx.send_replace(Formats {
+                    server: server.clone(),
+                    client: pixel_format,
+                });
+                Some(C2S::SetPixelFormat(server))
+            }
+
+            C2S::PointerEvent { button_mask, x, y } => {
+                let mouse_pressed_new = button_mask.left();
+                let click = self.mouse_pressed && !mouse_pressed_new;
+                let press = !self.mouse_pressed && mouse_pressed_new;
+                self.mouse_pressed = mouse_pressed_new;
+
+                self.pointer_tx.send_if_modified(|pointer| {
+                    let moved = pointer.is_none_or(|(px, py)| {
+                        px.abs_diff(x) >= POINTER_STEP || py.abs_diff(y) >= POINTER_STEP
+                    });
+                    if moved {
+                        *pointer = Some((x, y));
+                    }
+                    moved
+                });
+
+                if press {
+                    self.pressed_on_icon = self.icon().in_bounds(x, y);
+                }
+
+                let mut forward = self.client.state_rx.borrow().enable_input(self.client.id);
+                if click && self.client.icon_interactive() && self.client.overlay_enabled() {
+                    // like a button, unless a drag ending on the icon should count too
+                    let pressed =
+                        self.pressed_on_icon || !self.client.config.require_press_in_bounds;
+                    if pressed && self.icon().in_bounds(x, y) {
+                        self.client.send_action();
+                        self.client.count_overlay(|o| o.click(true));
+                        forward = false;
+                    }
+                }
+                if click && forward {
+                    self.client.count_overlay(|o| o.click(false));
+                }
+
+                if forward {
+                    // pointer events in the margin end up at the top of the content
+                    let y = y.saturating_sub(self.client.config.overlay_margin);
+                    Some(C2S::PointerEvent { button_mask, x, y })
+                } else {
+                    None
+                }
+            }
+
+            C2S::KeyEvent { down, key } => {
+                let forwarded = match self.keys_down.get(&key) {
+                    // a repeated press
+                    Some(&sent) if down => Some(sent),
+                    // releases the key as it was pressed, whatever the filter says now
+                    Some(_) => self.keys_down.remove(&key),
+                    None => {
+                        let state = self.client.state_rx.borrow();
+                        let filtered = state.filter_key(self.client.id, down, key);
+                        if let Some(sent) = filtered.filter(|_| down) {
+                            self.keys_down.insert(key, sent);
+                        }
+                        filtered
+                    }
+                };
+                forwarded.map(|key| C2S::KeyEvent { down, key })
+            }
+
+            C2S::FramebufferUpdateRequest {
+                incremental,
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let region = Region {
+                    x,
+                    y,
+                    width,
+                    height,
+                };
+                // the server only sends more data once the client has caught up, while
+                // input is still forwarded in the meantime
+                let limit = self.client.config.max_client_backlog;
+                if self.deferred_request.is_some()
+                    || limit.is_some_and(|limit| !self.backlog.is_below(limit))
+                {
+                    self.defer_request(incremental, region);
+                } else {
+                    self.forward_request(incremental, region).await?;
+                }
+                None
+            }
+
+            C2S::EnableContinuousUpdates {
+                enable,
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let region = Region {
+                    x,
+                    y,
+                    width,
+                    height,
+                };
+                *self.continuous_updates.lock().unwrap() = enable.then_some(region);
+                let (y, height) = self.to_server_span(y, height).unwrap_or((0, 0));
+                Some(C2S::EnableContinuousUpdates {
+                    enable,
+                    x,
+                    y,
+                    width,
+                    height,
+                })
+            }
+
+            m => Some(m),
+        };
+
+        if let Some(message) = message {
+            self.server_tx.write_message(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Holds back an update request until the client's backlog has drained. Requests
+    /// arriving in the meantime are merged into it, asking for everything they ask for.
+    fn defer_request(&mut self, incremental: bool, region: Region) {
+        self.deferred_request = Some(match self.deferred_request.take() {
+            Some((deferred_incremental, deferred)) => {
+                (incremental && deferred_incremental, deferred.union(&region))
+            }
+            None => (incremental, region),
+        });
+    }
+
+    async fn forward_request(&mut self, incremental: bool, region: Region) -> Result<()> {
+        let req = C2S::FramebufferUpdateRequest {
+            incremental,
+            x: region.x,
+            y: region.y,
+            width: region.width,
+            height: region.height,
+        };
+        // if there is a pending proxy update, do not forward the request
+        if !self.requests.receive() {
+            return Ok(());
+        }
+        if self.client.config.coalesce_requests {
+            // the server has not answered an identical request yet
+            let mut pending = self.pending_request.lock().unwrap();
+            if pending.as_ref() == Some(&req) {
+                return Ok(());
+            }
+            *pending = Some(req);
+        }
+
+        self.request_damage().await?;
+        let Some((y, height)) = self.to_server_span(region.y, region.height) else {
+            return Ok(());
+        };
+        self.server_tx
+            .write_message(C2S::FramebufferUpdateRequest {
+                incremental,
+                x: region.x,
+                y,
+                width: region.width,
+                height,
+            })
+            .await
+    }
+
+    /// Asks the server for the contents of the area the icon has been moved away from.
+    /// The server answers with a separate update, which waits for the next request.
+    async fn request_damage(&mut self) -> Result<()> {
+        let Some(damage) = self.damage.lock().unwrap().take() else {
+            return Ok(());
+        };
+        let Some((y, height)) = self.to_server_span(damage.y, damage.height) else {
+            return Ok(());
+        };
+
+        self.server_tx
+            .write_message(C2S::FramebufferUpdateRequest {
+                incremental: false,
+                x: damage.x,
+                y,
+                width: damage.width,
+                height,
+            })
+            .await
+    }
+
+    /// Translates a vertical span of the client's framebuffer into the server's,
+    /// clipping off the overlay margin. Returns `None` if it lies within the margin.
+    fn to_server_span(&self, y: u16, height: u16) -> Option<(u16, u16)> {
+        let margin = self.client.config.overlay_margin;
+        if margin == 0 {
+            return Some((y, height));
+        }
+
+        let start = y.max(margin);
+        let end = y.saturating_add(height);
+        (start < end).then(|| (start - margin, end - start))
+    }
+}
+
+struct S2CHandler<S: State> {
+    client: Client<S>,
+    server_rx: RfbIo<OwnedReadHalf>,
+    client_tx: RfbIo<OwnedWriteHalf>,
+    fmt_rx: watch::Receiver<Formats>,
+    requests: Arc<Requests>,
+    pending_request: Arc<Mutex<Option<C2S>>>,
+    continuous_updates: Arc<Mutex<Option<Region>>>,
+    sent_icon: Option<Icon>,
+    initial_update: bool,
+    pending_rects: Vec<(Rectangle, Bytes)>,
+    last_update: Option<Instant>,
+    palette: Palette,
+    backlog: Arc<Backlog>,
+    pointer_rx: watch::Receiver<Option<(u16, u16)>>,
+    framebuffer_size: Arc<Mutex<(u16, u16)>>,
+    damage: Arc<Mutex<Option<Region>>>,
+    resize_encoding: Arc<Mutex<Option<Encoding>>>,
+    cursor_supported: Arc<AtomicBool>,
+    /// Whether the client left out Raw from its encodings but accepts ZRLE, so pixel data
+    /// is sent to it as ZRLE instead.
+    zrle_only: Arc<AtomicBool>,
+    zrle_stream: ZrleStream,
+    /// The last cursor shape sent by the server, to be restored once the icon is no
+    /// longer shown as the cursor.
+    server_cursor: Option<(Rectangle, Bytes)>,
+}
+
+impl<S: State> S2CHandler<S> {
+    async fn handle(&mut self) -> Result<()> {
+        let max_colors = self.client.config.max_color_map_entries;
+        loop {
+            // merged updates are sent once the rate limit allows it
+            let flush_at = self
+                .next_update_at()
+                .filter(|_| !self.pending_rects.is_empty());
+
+            select! {
+                m = read_limited(
+                    &mut self.server_rx,
+                    &self.client.config,
+                    3,
+                    |buf| S2C::read_limited(buf, max_colors),
+                    S2C::CutText,
+                ) => {
+                    if let Some(m) = m? {
+                        self.handle_message(m).await?;
+                    }
+                },
+                Ok(_) = self.client.state_rx.changed() => {
+                    self.client.count_overlay(OverlayCounters::redraw);
+                    self.handle_state_changed().await?;
+                },
+                Ok(_) = self.pointer_rx.changed() => { self.handle_state_changed().await?; },
+                _ = self.client.redraw.notified() => {
+                    self.client.count_overlay(OverlayCounters::redraw);
+                    self.handle_state_changed().await?;
+                },
+                _ = sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                    self.flush_update(None).await?;
+                },
+                _ = self.client.farewell.notified() => {
+                    self.send_farewell().await?;
+                    // keep the message on screen until the connection is closed
+                    return pending().await;
+                },
+            };
+        }
+    }
+
+    async fn handle_message(&mut self, message: S2C) -> Result<()> {
+        self.client.counters.count_s2c(&message);
+        if let S2C::CutText(text) = &message {
+            self.client.send_clipboard(text, true);
+        }
+        if let S2C::FramebufferUpdate { count } = message {
+            self.pending_request.lock().unwrap().take();
+            let region = *self.continuous_updates.lock().unwrap();
+            if self.initial_update || region.is_some() || !self.pending_rects.is_empty() {
+                // servers may push the first update without waiting for a request,
+                // as well as any update when continuous updates are enabled.
+                // updates merged into a pending one do not need a request either.
+                self.initial_update = false;
+                self.requests.try_take();
+            } else {
+                self.next_request().await;
+            }
+
+            // merged updates leave room for the icon in the rectangle count
+            let merged = self.pending_rects.len() + count as usize + MAX_ICON_RECTS;
+            if !self.pending_rects.is_empty() && merged > u16::MAX as usize {
+                self.flush_update(None).await?;
+            }
+
+            let large = self.buffer_rects(count).await?;
+            if large.is_some() {
+                // streamed rectangles cannot be merged with later updates
+                if let Some(at) = self.next_update_at() {
+                    sleep_until(at).await;
+                }
+                self.flush_update(large).await?;
+                self.check_sync()?;
+                return Ok(());
+            }
+
+            self.check_sync()?;
+            if self.next_update_at().is_none_or(|at| at <= Instant::now()) {
+                self.flush_update(None).await?;
+            }
+        } else {
+            if let S2C::SetColorMapEntries {
+                first_color,
+                colors,
+            } = &message
+            {
+                self.palette.update(*first_color, colors)?;
+            }
+            if message == S2C::Bell {
+                self.client
+                    .try_send_event(Event::Bell { id: self.client.id });
+                if !self.client.state_rx.borrow().forward_bell(self.client.id) {
+                    return Ok(());
+                }
+            }
+            self.client_tx.write_message(message).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Fails if the bytes following an update do not start a server message, which means
+    /// the size of a rectangle was miscalculated and everything after it would be garbage.
+    /// Only bytes which have been received already are checked, so nothing is delayed.
+    fn check_sync(&self) -> Result<()> {
+        match self.server_rx.peek_buffered() {
+            Some(b) if !S2C::is_message_type(b) => {
+                warn!(
+                    "Unexpected byte {b:#04x} from server after update for client {}, \
+                     last rectangle: {:?}",
+                    self.client.id,
+                    self.pending_rects.last().map(|(rect, _)| rect),
+                );
+                Err(Error::Protocol("desync".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads the rectangles of an update into `pending_rects`. If they get too large to be
+    /// buffered, returns the first rectangle to be streamed and the number of rectangles
+    /// remaining after it.
+    async fn buffer_rects(&mut self, count: u16) -> Result<Option<(Rectangle, u16)>> {
+        let mut buffered: usize = self.pending_rects.iter().map(|(_, p)| p.len()).sum();
+        for i in 0..count {
+            let rect = self.read_rect().await?;
+            if rect.encoding == Encoding::Raw
+                && buffered + rect.payload_size(&self.fmt_rx.borrow().server)? > MAX_BUFFERED
+            {
+                return Ok(Some((rect, count - i - 1)));
+            }
+
+            let payload = self.read_payload(&rect).await?;
+            buffered += payload.len();
+
+            // a Raw rectangle replaces earlier ones with the same bounds,
+            // unless they could be the source of a CopyRect
+            let bounds = (rect.x, rect.y, rect.width, rect.height);
+            let has_copy = self
+                .pending_rects
+                .iter()
+                .any(|(r, _)| r.encoding == Encoding::CopyRect);
+            if rect.encoding == Encoding::Raw && !has_copy {
+                self.pending_rects.retain(|(r, _)| {
+                    r.encoding != Encoding::Raw || (r.x, r.y, r.width, r.height) != bounds
+                });
+            }
+            self.pending_rects.push((rect, payload));
+        }
+        self.backlog.set(buffered);
+        Ok(None)
+    }
+
+    /// Sends all pending rectangles and, if given, streams the remaining rectangles of the
+    /// current update from the server, redrawing the icon on top if needed.
+    async fn flush_update(&mut self, large: Option<(Rectangle, u16)>) -> Result<()> {
+        let mut rects = mem::take(&mut self.pending_rects);
+
+        // the rectangles are buffered so we know whether the icon needs
+        // to be redrawn before writing the rectangle count. an empty update
+        // cannot overwrite the icon, so it only carries the icon if it changed
+        let region = *self.continuous_updates.lock().unwrap();
+        let covers = |icon: &Icon, rect: &Rectangle| match rect.encoding {
+            _ if icon.is_cursor() => false,
+            Encoding::Cursor => false,
+            _ => icon.intersects(rect.x, rect.y, rect.width, rect.height),
+        };
+        let current =
+            (self.can_draw_icon() && !self.client.icon_hidden()).then(|| self.current_icon());
+        let as_cursor = current.as_ref().is_some_and(Icon::is_cursor);
+        let mut icon = current.filter(|icon| {
+            let overwritten =
+                (large.is_some() && !as_cursor) || rects.iter().any(|(rect, _)| covers(icon, rect));
+            (as_cursor || region.is_none_or(|r| r.intersects(icon)))
+                && (overwritten || self.sent_icon.as_ref() != Some(icon))
+        });
+
+        // the server's cursor shape is kept for later while the icon takes its place
+        if let Some(last) = rects
+            .iter()
+            .rposition(|(r, _)| r.encoding == Encoding::Cursor)
+        {
+            self.server_cursor = Some(rects[last].clone());
+        }
+        if as_cursor {
+            rects.retain(|(rect, _)| rect.encoding != Encoding::Cursor);
+        }
+
+        // if the last rectangle covering the icon contains all of it, the icon is drawn
+        // right after that rectangle, so the client does not show the bare region while
+        // painting the rest of the update
+        let icon_after = icon.as_ref().filter(|_| large.is_none()).and_then(|icon| {
+            let last = rects.iter().rposition(|(rect, _)| covers(icon, rect))?;
+            let (rect, _) = &rects[last];
+            icon.within(rect.x, rect.y, rect.width, rect.height)
+                .then_some(last)
+        });
+        // unless it would be painted over, the icon may also go first
+        let first = self.client.config.icon_z_order == IconZOrder::First
+            && large.is_none()
+            && icon
+                .as_ref()
+                .is_some_and(|icon| !rects.iter().any(|(rect, _)| covers(icon, rect)));
+
+        let count = rects.len()
+            + large
+                .as_ref()
+                .map_or(0, |(_, remaining)| *remaining as usize + 1);
+        let icon_count = icon.as_ref().map_or(0, |icon| self.icon_rects(icon));
+        // an update from the server may leave no room for the icon, which then follows
+        // in an update of its own
+        let separate = count + icon_count > u16::MAX as usize;
+        let count = if separate { count } else { count + icon_count };
+        let count = count
+            .try_into()
+            .map_err(|_| Error::Protocol(format!("{count} rectangles in one update")))?;
+        self.client_tx
+            .write_message(S2C::FramebufferUpdate { count })
+            .await?;
+        let (first, icon_after) = match separate {
+            true => (false, None),
+            false => (first, icon_after),
+        };
+
+        if first {
+            self.send_icon(icon.take().unwrap()).await?;
+        }
+
+        let mut backlog: usize = rects.iter().map(|(_, p)| p.len()).sum();
+        self.backlog.set(backlog);
+        for (i, (rect, payload)) in rects.into_iter().enumerate() {
+            backlog -= payload.len();
+            self.write_rect(rect, payload).await?;
+            self.backlog.set(backlog);
+            if icon_after == Some(i) {
+                self.send_icon(icon.take().unwrap()).await?;
+            }
+        }
+
+        if let Some((rect, remaining)) = large {
+            self.stream_rect(rect).await?;
+            for _ in 0..remaining {
+                let rect = self.read_rect().await?;
+                if as_cursor && rect.encoding == Encoding::Cursor {
+                    // the rectangle has been counted already, so the icon is sent again
+                    let payload = self.read_payload(&rect).await?;
+                    self.server_cursor = Some((rect, payload));
+                    let (rect, data) = self.icon_rect(&self.current_icon());
+                    self.client_tx.write_message(rect).await?;
+                    self.client_tx.write_data(data).await?;
+                    self.client.count_overlay(OverlayCounters::icon_drawn);
+                    continue;
+                }
+                self.stream_rect(rect).await?;
+            }
+        }
+
+        if let Some(icon) = icon {
+            if separate {
+                self.client_tx
+                    .write_message(S2C::FramebufferUpdate {
+                        count: icon_count as u16,
+                    })
+                    .await?;
+            }
+            self.send_icon(icon).await?;
+        }
+
+        self.last_update = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Returns the earliest time the next update may be sent to the client, if rate limited.
+    fn next_update_at(&self) -> Option<Instant> {
+        let max = self.client.config.max_updates_per_sec.filter(|n| *n > 0)?;
+        Some(self.last_update? + Duration::from_secs(1) / max)
+    }
+
+    /// Reads a rectangle header, moving it below the overlay margin. A resize is turned
+    /// into one of the kind the client supports, see [S2CHandler::read_payload].
+    async fn read_rect(&mut self) -> Result<Rectangle> {
+        let mut rect: Rectangle = self.server_rx.read_message().await?;
+        let margin = self.client.config.overlay_margin;
+        match rect.encoding {
+            // the position of a cursor is its hotspot
+            Encoding::Cursor => {}
+            Encoding::DesktopSize | Encoding::ExtendedDesktopSize => {
+                if rect.encoding == Encoding::ExtendedDesktopSize {
+                    // the screen layout is replaced by a single screen
+                    let screens = self
+                        .server_rx
+                        .read_with(|buf| {
+                            ensure_size(buf, 1)?;
+                            Ok(buf[0] as usize)
+                        })
+                        .await?;
+                    self.server_rx.read_data(4 + screens * 16).await?;
+                }
+                rect.height = rect.height.saturating_add(margin);
+                *self.framebuffer_size.lock().unwrap() = (rect.width, rect.height);
+
+                let resize = self
+                    .resize_encoding
+                    .lock()
+                    .unwrap()
+                    .unwrap_or(rect.encoding);
+                if resize != rect.encoding {
+                    // both kinds use x and y for details only sent in replies to the
+                    // client's own resize requests, which the proxy does not forward
+                    (rect.x, rect.y) = (0, 0);
+                    rect.encoding = resize;
+                }
+            }
+            _ => rect.y = rect.y.saturating_add(margin),
+        }
+        Ok(rect)
+    }
+
+    /// Forwards a rectangle without holding more than [CHUNK_SIZE] bytes of Raw pixel data.
+    async fn stream_rect(&mut self, rect: Rectangle) -> Result<()> {
+        if rect.encoding != Encoding::Raw {
+            let payload = self.read_payload(&rect).await?;
+            return self.write_rect(rect, payload).await;
+        }
+        if self.zrle_encoder().is_some() {
+            return self.stream_zrle(rect).await;
+        }
+
+        self.client_tx.write_message(rect.clone()).await?;
+        let formats = self.fmt_rx.borrow().clone();
+        let mut len = rect.payload_size(&formats.server)?;
+        #[cfg(target_os = "linux")]
+        if self.client.config.splice_raw && formats.server == formats.client {
+            match Pipe::new() {
+                Ok(pipe) => return self.splice_data(&pipe, len).await,
+                Err(e) => debug!("falling back to copying, no pipe to splice through: {e}"),
+            }
+        }
+        while len > 0 {
+            // chunks always contain whole pixels
+            let chunk = self.server_rx.read_data(len.min(CHUNK_SIZE)).await?;
+            len -= chunk.len();
+            let chunk = if formats.server != formats.client {
+                Bytes::from(formats.server.convert(&chunk, &formats.client))
+            } else {
+                chunk
+            };
+            self.client_tx.write_data(chunk).await?;
+        }
+        Ok(())
+    }
+
+    /// Forwards a Raw rectangle as ZRLE, reading a row of tiles at a time. Only the
+    /// compressed data is held until the rectangle is complete.
+    async fn stream_zrle(&mut self, mut rect: Rectangle) -> Result<()> {
+        let formats = self.fmt_rx.borrow().clone();
+        let stride = rect.width as usize * formats.server.bytes_per_pixel();
+        let mut rows = rect.height as usize;
+        while rows > 0 {
+            let band = rows.min(TILE_SIZE);
+            rows -= band;
+            let data = self.server_rx.read_data(band * stride).await?;
+            let data = if formats.server != formats.client {
+                Bytes::from(formats.server.convert(&data, &formats.client))
+            } else {
+                data
+            };
+            self.proxy_zrle()
+                .add_rows(&data, rect.width, &formats.client);
+        }
+        let data = self.proxy_zrle().finish();
+
+        rect.encoding = Encoding::Zrle;
+        self.client_tx.write_message(rect).await?;
+        self.client_tx.write_data(data).await
+    }
+
+    /// Writes a rectangle to the client, as ZRLE instead of Raw if the client left out
+    /// Raw from its encodings.
+    async fn write_rect(&mut self, mut rect: Rectangle, payload: Bytes) -> Result<()> {
+        let payload = match rect.encoding {
+            Encoding::Raw => {
+                let format = self.fmt_rx.borrow().client.clone();
+                match self.zrle_encoder() {
+                    Some(encoder) => {
+                        rect.encoding = Encoding::Zrle;
+                        encoder.encode(&payload, rect.width, &format)
+                    }
+                    None => payload,
+                }
+            }
+            Encoding::Zrle => {
+                if let ZrleStream::Proxy(_) = self.zrle_stream {
+                    return Err(Error::Protocol(
+                        "server sent ZRLE after the proxy encoded ZRLE for the client".to_string(),
+                    ));
+                }
+                self.zrle_stream = ZrleStream::Server;
+                payload
+            }
+            _ => payload,
+        };
+        self.client_tx.write_message(rect).await?;
+        self.client_tx.write_data(payload).await
+    }
+
+    /// Returns the encoder for pixel data to be sent as ZRLE, unless the client accepts
+    /// Raw or already continues the zlib stream of the server's ZRLE.
+    fn zrle_encoder(&mut self) -> Option<&mut ZrleEncoder> {
+        if !self.zrle_only.load(Ordering::Relaxed) {
+            return None;
+        }
+        if let ZrleStream::Unused = self.zrle_stream {
+            self.zrle_stream = ZrleStream::Proxy(ZrleEncoder::new());
+        }
+        match &mut self.zrle_stream {
+            ZrleStream::Proxy(encoder) => Some(encoder),
+            _ => None,
+        }
+    }
+
+    /// Returns the encoder of a rectangle being encoded, which is kept even if the client
+    /// changes its encodings in the meantime.
+    fn proxy_zrle(&mut self) -> &mut ZrleEncoder {
+        match &mut self.zrle_stream {
+            ZrleStream::Proxy(encoder) => encoder,
+            _ => unreachable!("the encoder is created before the first rectangle"),
+        }
+    }
+
+    /// Forwards `len` bytes from the server to the client through `pipe`, see
+    /// [Config::splice_raw].
+    #[cfg(target_os = "linux")]
+    async fn splice_data(&mut self, pipe: &Pipe, len: usize) -> Result<()> {
+        // some of the data may have been read along with the rectangle header
+        let buffered = self.server_rx.take_buffered(len);
+        let len = len - buffered.len();
+        self.client_tx.write_data(buffered).await?;
+
+        let from = self.server_rx.get_ref().as_ref();
+        let to = self.client_tx.get_ref().as_ref();
+        let writing = pipe.forward(from, to, len).await?;
+        self.client_tx.check_write_time(writing);
+        Ok(())
+    }
+
+    async fn read_payload(&mut self, rect: &Rectangle) -> Result<Bytes> {
+        let formats = self.fmt_rx.borrow().clone();
+        let convert = formats.server != formats.client;
+
+        let mut buf = BytesMut::new();
+        match rect.encoding {
+            Encoding::Zrle if convert => {
+                return Err(Error::Protocol(
+                    "cannot convert ZRLE rectangle to client pixel format".to_string(),
+                ));
+            }
+            Encoding::Zrle => {
+                let data: Zrle = self.server_rx.read_message().await?;
+                data.write_to(&mut buf);
+            }
+            Encoding::Tight if convert => {
+                return Err(Error::Protocol(
+                    "cannot convert Tight rectangle to client pixel format".to_string(),
+                ));
+            }
+            Encoding::Tight => {
+                let (width, height) = (rect.width, rect.height);
+                return self
+                    .server_rx
+                    .read_with(|buf| tight::read_rect(buf, width, height, &formats.server))
+                    .await;
+            }
+            Encoding::CopyRect => {
+                let mut data: CopyRect = self.server_rx.read_message().await?;
+                data.src_y = data.src_y.saturating_add(self.client.config.overlay_margin);
+                data.write_to(&mut buf);
+            }
+            Encoding::DesktopSize => {}
+            Encoding::ExtendedDesktopSize => {
+                // a single screen covering the whole framebuffer, including the margin
+                buf.put_u8(1);
+                buf.put_bytes(0, 3);
+                buf.put_u32(0);
+                buf.put_u16(0);
+                buf.put_u16(0);
+                buf.put_u16(rect.width);
+                buf.put_u16(rect.height);
+                buf.put_u32(0);
+            }
+            _ => {
+                let payload_size = rect.payload_size(&formats.server)?;
+                let data = self.server_rx.read_data(payload_size).await?;
+                return Ok(match rect.encoding {
+                    // cursor pixels are followed by a bitmask which needs no conversion
+                    Encoding::Raw | Encoding::Cursor if convert => {
+                        let pixels = rect.width as usize
+                            * rect.height as usize
+                            * formats.server.bytes_per_pixel();
+                        let mut converted =
+                            formats.server.convert(&data[..pixels], &formats.client);
+                        converted.extend_from_slice(&data[pixels..]);
+                        Bytes::from(converted)
+                    }
+                    _ => data,
+                });
+            }
+        }
+        Ok(buf.freeze())
+    }
+
+    async fn handle_state_changed(&mut self) -> Result<()> {
+        if !self.can_draw_icon() {
+            return Ok(());
+        }
+
+        if self.client.icon_hidden() {
+            return self.hide_icon().await;
+        }
+
+        // the icon is drawn for the first time along with the server's initial update,
+        // which must not be delayed by taking the client's first request for the icon
+        if self.last_update.is_none() {
+            return Ok(());
+        }
+
+        // the icon is sent along with the pending rectangles
+        if !self.pending_rects.is_empty() {
+            return Ok(());
+        }
+
+        // the client already shows this icon
+        let icon = self.current_icon();
+        if self.sent_icon.as_ref() == Some(&icon) {
+            return Ok(());
+        }
+
+        let region = *self.continuous_updates.lock().unwrap();
+        match region {
+            Some(region) if !icon.is_cursor() && !region.intersects(&icon) => return Ok(()),
+            Some(_) => {}
+            None => {
+                self.next_request().await;
+            }
+        }
+        let count = self.icon_rects(&icon).try_into().unwrap();
+        self.client_tx
+            .write_message(S2C::FramebufferUpdate { count })
+            .await?;
+
+        self.send_icon(icon).await?;
+        self.last_update = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Takes the icon off the client's screen, see [Config::hide_icon_when_view_only].
+    async fn hide_icon(&mut self) -> Result<()> {
+        let Some(old) = self.sent_icon.take() else {
+            return Ok(());
+        };
+        if !old.is_cursor() {
+            // the server draws the area again with the client's next request
+            self.add_damage(Region::of_icon(&old));
+            return Ok(());
+        }
+
+        // the server's cursor replaces the icon, if it sent one
+        let Some((rect, data)) = self.server_cursor.clone() else {
+            return Ok(());
+        };
+        if self.continuous_updates.lock().unwrap().is_none() {
+            self.next_request().await;
+        }
+        self.client_tx
+            .write_message(S2C::FramebufferUpdate { count: 1 })
+            .await?;
+        self.client_tx.write_message(rect).await?;
+        self.client_tx.write_data(data).await?;
+        self.last_update = Some(Instant::now());
+        Ok(())
+    }
+
+    async fn send_farewell(&mut self) -> Result<()> {
+        let Some((text, icon)) = self.client.farewell_message() else {
+            return Ok(());
+        };
+
+        self.client_tx.write_message(S2C::CutText(text)).await?;
+        if self.can_draw_icon() {
+            let count = self.icon_rects(&icon).try_into().unwrap();
+            self.client_tx
+                .write_message(S2C::FramebufferUpdate { count })
+                .await?;
+            self.send_icon(icon).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether the icon can be drawn in the client's pixel format,
+    /// either as true colour of any layout or as 8 bit colour map indices.
+    fn can_draw_icon(&self) -> bool {
+        if !self.client.overlay_enabled() {
+            return false;
+        }
+
+        let format = &self.fmt_rx.borrow().client;
+        format.true_colour || format.bits_per_pixel == 8
+    }
+
+    /// Returns the icon of the client at the position it is to be drawn at, or as a
+    /// cursor if the client supports that.
+    fn current_icon(&self) -> Icon {
+        let icon = self.client.icon();
+        if icon.is_cursor() && self.cursor_supported.load(Ordering::Relaxed) {
+            return icon;
+        }
+        icon.placed(
+            *self.pointer_rx.borrow(),
+            *self.framebuffer_size.lock().unwrap(),
+        )
+    }
+
+    /// Number of rectangles [S2CHandler::send_icon] sends for the icon, at most
+    /// [MAX_ICON_RECTS].
+    fn icon_rects(&self, icon: &Icon) -> usize {
+        let was_cursor = self.sent_icon.as_ref().is_some_and(Icon::is_cursor);
+        let restore_cursor = was_cursor && !icon.is_cursor() && self.server_cursor.is_some();
+        1 + restore_cursor as usize
+    }
+
+    /// Returns the rectangle drawing the icon, or replacing the cursor with it.
+    fn icon_rect(&self, icon: &Icon) -> (Rectangle, Bytes) {
+        let (x, y, encoding) = match icon.anchor {
+            // the position of a cursor is its hotspot
+            Anchor::Cursor { hot_x, hot_y } => (hot_x, hot_y, Encoding::Cursor),
+            _ => (icon.x, icon.y, Encoding::Raw),
+        };
+        let rect = Rectangle {
+            x,
+            y,
+            width: icon.width,
+            height: icon.height,
+            encoding,
+        };
+
+        let format = self.fmt_rx.borrow().client.clone();
+        let data = if format.true_colour {
+            icon.pixels(&format)
+        } else {
+            Bytes::from(self.palette.map_rgba(&icon.rgba_data))
+        };
+        let data = if icon.is_cursor() {
+            let mut data = BytesMut::from(data);
+            data.extend_from_slice(&icon.cursor_mask());
+            data.freeze()
+        } else {
+            data
+        };
+        (rect, data)
+    }
+
+    async fn send_icon(&mut self, icon: Icon) -> Result<()> {
+        let restore_cursor = self.icon_rects(&icon) > 1;
+        let (rect, data) = self.icon_rect(&icon);
+        self.write_rect(rect, data).await?;
+        self.client.count_overlay(OverlayCounters::icon_drawn);
+
+        if let (true, Some((rect, data))) = (restore_cursor, self.server_cursor.clone()) {
+            self.client_tx.write_message(rect).await?;
+            self.client_tx.write_data(data).await?;
+        }
+
+        // the area the previous icon was drawn on now shows stale content
+        let region = (!icon.is_cursor()).then(|| Region::of_icon(&icon));
+        if let Some(old) = self.sent_icon.replace(icon).filter(|old| !old.is_cursor()) {
+            let old = Region::of_icon(&old);
+            if Some(old) != region {
+                self.add_damage(old);
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks an area as stale, so it is requested from the server again.
+    fn add_damage(&self, region: Region) {
+        let mut damage = self.damage.lock().unwrap();
+        *damage = Some(damage.map_or(region, |d| d.union(&region)));
+    }
+
+    /// Waits until the client has requested the update about to be sent.
+    async fn next_request(&mut self) {
+        let start = Instant::now();
+        if !self.requests.take().await {
+            debug!("waited {:?} for request", start.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::{Anchor, Redraw, ZrleDecoder};
+
+    /// Runs a client against a server whose end of the connection is scripted by the test.
+    async fn scripted(state: impl State, config: Config) -> (ClientTask, ServerConn) {
+        let server = TestServer::bind().await;
+        let client = ClientTask::spawn(state, config, Target::Direct(server.addr));
+        tokio::join!(client, server.accept_raw())
+    }
+
+    /// Relays the versions and the None security type between a scripted client and server,
+    /// up to the server's `SecurityResult`.
+    async fn relay_security(client: &mut ClientTask, server: &mut ServerConn) {
+        server.tx.write_message(Version::new(3, 8)).await.unwrap();
+        let _: Version = client.rx.read_message().await.unwrap();
+        client.tx.write_message(Version::new(3, 8)).await.unwrap();
+        let _: Version = server.rx.read_message().await.unwrap();
+        let types = SecurityTypes::new(&[1]).unwrap();
+        server.tx.write_message(types.clone()).await.unwrap();
+        assert_eq!(
+            client.rx.read_message::<SecurityTypes>().await.unwrap(),
+            types
+        );
+        client.tx.write_message(SecurityType(1)).await.unwrap();
+        assert_eq!(
+            server.rx.read_message::<SecurityType>().await.unwrap(),
+            SecurityType(1)
+        );
+    }
+
+    /// Completes the handshake of a scripted client and server, with [server_init].
+    async fn handshake(client: &mut ClientTask, server: &mut ServerConn) {
+        relay_security(client, server).await;
+        server.tx.write_message(SecurityResult(0)).await.unwrap();
+        let _: SecurityResult = client.rx.read_message().await.unwrap();
+        client
+            .tx
+            .write_message(ClientInit { shared: true })
+            .await
+            .unwrap();
+        let _: ClientInit = server.rx.read_message().await.unwrap();
+        server.tx.write_message(server_init()).await.unwrap();
+        let _: ServerInit = client.rx.read_message().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn versions_are_bridged() {
+        // a 3.3 client in front of a 3.8 server, then the other way round
+        for (version, server_version) in [(3, 8), (8, 3)] {
+            let config = Config {
+                backend_version: Some((3, server_version)),
+                ..Default::default()
+            };
+            let (mut client, mut server) = scripted(TestState::default(), config).await;
+            server.tx.write_message(Version::new(3, 8)).await.unwrap();
+            let _: Version = client.rx.read_message().await.unwrap();
+            client
+                .tx
+                .write_message(Version::new(3, version))
+                .await
+                .unwrap();
+            let chosen: Version = server.rx.read_message().await.unwrap();
+            assert_eq!(chosen, Version::new(3, server_version.into()));
+
+            if server_version == 8 {
+                let types = SecurityTypes::new(&[1]).unwrap();
+                server.tx.write_message(types).await.unwrap();
+            } else {
+                server.tx.write_message(SecurityResult(1)).await.unwrap();
+            }
+            if version == 3 {
+                let sec_type: SecurityResult = client.rx.read_message().await.unwrap();
+                assert_eq!(sec_type, SecurityResult(1));
+            } else {
+                let types: SecurityTypes = client.rx.read_message().await.unwrap();
+                assert_eq!(types.types(), [1]);
+                client.tx.write_message(SecurityType(1)).await.unwrap();
+            }
+            if server_version == 8 {
+                let sec_type: SecurityType = server.rx.read_message().await.unwrap();
+                assert_eq!(sec_type, SecurityType(1));
+                server.tx.write_message(SecurityResult(0)).await.unwrap();
+            }
+            // only a 3.8 client expects a result for the None type
+            if version == 8 {
+                let result: SecurityResult = client.rx.read_message().await.unwrap();
+                assert_eq!(result, SecurityResult(0));
+            }
+
+            client
+                .tx
+                .write_message(ClientInit { shared: true })
+                .await
+                .unwrap();
+            let _: ClientInit = server.rx.read_message().await.unwrap();
+            server.tx.write_message(server_init()).await.unwrap();
+            let init: ServerInit = client.rx.read_message().await.unwrap();
+            assert_eq!(init, server_init());
+            server.tx.write_message(S2C::Bell).await.unwrap();
+            assert_eq!(client.rx.read_message::<S2C>().await.unwrap(), S2C::Bell);
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_errors_name_their_phase() {
+        let (mut client, mut server) = scripted(TestState::default(), Config::default()).await;
+        server
+            .tx
+            .write_data(Bytes::from_static(b"SSH-2.0-xyz\n"))
+            .await
+            .unwrap();
+        let Err(Error::Handshake { phase, .. }) = client.result().await else {
+            panic!("expected a handshake error");
+        };
+        assert_eq!(phase, HandshakePhase::Version);
+
+        let (mut client, mut server) = scripted(TestState::default(), Config::default()).await;
+        server.tx.write_message(Version::new(3, 8)).await.unwrap();
+        let _: Version = client.rx.read_message().await.unwrap();
+        client.tx.write_message(Version::new(3, 8)).await.unwrap();
+        let _: Version = server.rx.read_message().await.unwrap();
+        // only a type the proxy cannot relay
+        server
+            .tx
+            .write_message(SecurityTypes::new(&[30]).unwrap())
+            .await
+            .unwrap();
+        let types: SecurityTypes = client.rx.read_message().await.unwrap();
+        assert_eq!(types.types(), []);
+        let reason: String = client.rx.read_message().await.unwrap();
+        assert_eq!(reason, "no allowed security type in [30]");
+        let Err(Error::Handshake { phase, detail }) = client.result().await else {
+            panic!("expected a handshake error");
+        };
+        assert_eq!(phase, HandshakePhase::Security);
+        assert_eq!(detail, reason);
+
+        // the client goes away instead of sending its ClientInit
+        let (mut client, mut server) = scripted(TestState::default(), Config::default()).await;
+        relay_security(&mut client, &mut server).await;
+        server.tx.write_message(SecurityResult(0)).await.unwrap();
+        let _: SecurityResult = client.rx.read_message().await.unwrap();
+        client.tx.shutdown().await.unwrap();
+        let Err(Error::Handshake { phase, .. }) = client.result().await else {
+            panic!("expected a handshake error");
+        };
+        assert_eq!(phase, HandshakePhase::Init);
+        assert_eq!(
+            client.events.recv().await,
+            Some(Event::Disconnect { id: 0 })
+        );
+    }
+
+    #[tokio::test]
+    async fn security_failures_are_forwarded_with_the_reason() {
+        let (mut client, mut server) = scripted(TestState::default(), Config::default()).await;
+        relay_security(&mut client, &mut server).await;
+        server.tx.queue_message(SecurityResult(1));
+        server
+            .tx
+            .write_message("too many attempts".to_string())
+            .await
+            .unwrap();
+
+        let result: SecurityResult = client.rx.read_message().await.unwrap();
+        assert_eq!(result, SecurityResult(1));
+        let reason: String = client.rx.read_message().await.unwrap();
+        assert_eq!(reason, "too many attempts");
+        assert_closed(&mut client.rx).await;
+        let Err(Error::Handshake { phase, detail }) = client.result().await else {
+            panic!("expected a handshake error");
+        };
+        assert_eq!(phase, HandshakePhase::Security);
+        assert_eq!(detail, reason);
+    }
+
+    #[tokio::test]
+    async fn security_refusals_are_forwarded_with_the_reason() {
+        for version in [3, 8] {
+            let (mut client, mut server) = scripted(TestState::default(), Config::default()).await;
+            server
+                .tx
+                .write_message(Version::new(3, version))
+                .await
+                .unwrap();
+            let _: Version = client.rx.read_message().await.unwrap();
+            client
+                .tx
+                .write_message(Version::new(3, version))
+                .await
+                .unwrap();
+            let _: Version = server.rx.read_message().await.unwrap();
+            // no security types at all
+            if version == 3 {
+                server.tx.queue_message(SecurityResult(0));
+            } else {
+                server.tx.queue_message(SecurityTypes::new(&[]).unwrap());
+            }
+            server
+                .tx
+                .write_message("maintenance".to_string())
+                .await
+                .unwrap();
+
+            if version == 3 {
+                let sec_type: SecurityResult = client.rx.read_message().await.unwrap();
+                assert_eq!(sec_type, SecurityResult(0));
+            } else {
+                let types: SecurityTypes = client.rx.read_message().await.unwrap();
+                assert_eq!(types.types(), []);
+            }
+            let reason: String = client.rx.read_message().await.unwrap();
+            assert_eq!(reason, "maintenance");
+            assert_closed(&mut client.rx).await;
+            let Err(Error::Handshake { phase, detail }) = client.result().await else {
+                panic!("expected a handshake error");
+            };
+            assert_eq!(phase, HandshakePhase::Security);
+            assert_eq!(detail, reason);
+        }
+    }
+
+    #[tokio::test]
+    async fn offered_security_types_are_filtered() {
+        /// Returns the types a client is offered when the server offers None and VNC
+        /// authentication.
+        async fn offered(allowed: Vec<u8>) -> (ClientTask, ServerConn, Vec<u8>) {
+            let config = Config {
+                security_types: Some(allowed),
+                ..Default::default()
+            };
+            let (mut client, mut server) = scripted(TestState::default(), config).await;
+            server.tx.write_message(Version::new(3, 8)).await.unwrap();
+            let _: Version = client.rx.read_message().await.unwrap();
+            client.tx.write_message(Version::new(3, 8)).await.unwrap();
+            let _: Version = server.rx.read_message().await.unwrap();
+            let types = SecurityTypes::new(&[1, 2]).unwrap();
+            server.tx.write_message(types).await.unwrap();
+            let types: SecurityTypes = client.rx.read_message().await.unwrap();
+            let types = types.types().to_vec();
+            (client, server, types)
+        }
+
+        assert_eq!(offered(vec![2, 1]).await.2, [2, 1]);
+
+        let (mut client, mut server, types) = offered(vec![2]).await;
+        assert_eq!(types, [2]);
+        client.tx.write_message(SecurityType(2)).await.unwrap();
+        assert_eq!(
+            server.rx.read_message::<SecurityType>().await.unwrap(),
+            SecurityType(2)
+        );
+        // the challenge and the response are relayed
+        let challenge = Bytes::from_static(&[7; 16]);
+        server.tx.write_data(challenge.clone()).await.unwrap();
+        assert_eq!(client.rx.read_data(16).await.unwrap(), challenge);
+        let response = Bytes::from_static(&[9; 16]);
+        client.tx.write_data(response.clone()).await.unwrap();
+        assert_eq!(server.rx.read_data(16).await.unwrap(), response);
+        server.tx.write_message(SecurityResult(0)).await.unwrap();
+        let result: SecurityResult = client.rx.read_message().await.unwrap();
+        assert_eq!(result, SecurityResult(0));
+
+        // nothing left to offer
+        let (mut client, _server, types) = offered(vec![30]).await;
+        assert_eq!(types, []);
+        let reason: String = client.rx.read_message().await.unwrap();
+        assert_eq!(reason, "no allowed security type in [1, 2]");
+        let Err(Error::Handshake { phase, .. }) = client.result().await else {
+            panic!("expected a handshake error");
+        };
+        assert_eq!(phase, HandshakePhase::Security);
+    }
+
+    #[tokio::test]
+    async fn rejected_clients_are_told_why() {
+        struct Reject;
+        impl State for Reject {
+            fn icon(&self, _id: ClientId) -> Icon {
+                Icon::solid(1, 1, [0; 4])
+            }
+            fn handle_event(&mut self, _event: Event) -> crate::Redraw {
+                crate::Redraw::None
+            }
+            fn enable_input(&self, _id: ClientId) -> bool {
+                true
+            }
+            fn authorize(&self, _id: ClientId, info: &HandshakeInfo) -> bool {
+                assert_eq!(info.version, (3, 8));
+                assert_eq!(info.security_type, 1);
+                assert_eq!(info.shared, None);
+                false
+            }
+        }
+
+        let (mut client, mut server) = scripted(Reject, Config::default()).await;
+        relay_security(&mut client, &mut server).await;
+        server.tx.write_message(SecurityResult(0)).await.unwrap();
+
+        let reply = soon(client.rx.read_data(4 + 4 + REJECTED.len()))
+            .await
+            .unwrap();
+        assert_eq!(reply[..], *b"\0\0\0\x01\0\0\0\x0daccess denied");
+        assert_closed(&mut client.rx).await;
+        let Err(Error::Handshake { phase, .. }) = client.result().await else {
+            panic!("expected a handshake error");
+        };
+        assert_eq!(phase, HandshakePhase::Security);
+        // the server never gets a ClientInit
+        assert_closed(&mut server.rx).await;
+    }
+
+    #[tokio::test]
+    async fn exclusive_clients_can_be_rejected() {
+        struct SharedOnly;
+        impl State for SharedOnly {
+            fn icon(&self, _id: ClientId) -> Icon {
+                Icon::solid(1, 1, [0; 4])
+            }
+            fn handle_event(&mut self, _event: Event) -> crate::Redraw {
+                crate::Redraw::None
+            }
+            fn enable_input(&self, _id: ClientId) -> bool {
+                true
+            }
+            fn authorize(&self, _id: ClientId, info: &HandshakeInfo) -> bool {
+                info.shared != Some(false)
+            }
+        }
+
+        let (mut client, mut server) = scripted(SharedOnly, Config::default()).await;
+        relay_security(&mut client, &mut server).await;
+        server.tx.write_message(SecurityResult(0)).await.unwrap();
+        let result: SecurityResult = client.rx.read_message().await.unwrap();
+        assert_eq!(result, SecurityResult(0));
+        client
+            .tx
+            .write_message(ClientInit { shared: false })
+            .await
+            .unwrap();
+
+        assert_closed(&mut client.rx).await;
+        let Err(Error::Handshake { phase, .. }) = client.result().await else {
+            panic!("expected a handshake error");
+        };
+        assert_eq!(phase, HandshakePhase::Init);
+        assert_closed(&mut server.rx).await;
+    }
+
+    fn request(incremental: bool, width: u16) -> C2S {
+        C2S::FramebufferUpdateRequest {
+            incremental,
+            x: 0,
+            y: 0,
+            width,
+            height: 48,
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_requests_are_merged() {
+        let config = Config {
+            coalesce_requests: true,
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let (mut client, mut server) = proxy.connect().await;
+
+        client.send(request(true, 64)).await;
+        client.send(request(true, 64)).await;
+        client.send(request(true, 32)).await;
+        assert_eq!(server.read().await, request(true, 64));
+        assert_eq!(server.read().await, request(true, 32));
+
+        // once answered, the same request is forwarded again
+        server.send_raw(0, 0, 1, 1, 0).await;
+        client.read_update().await;
+        client.send(request(true, 32)).await;
+        assert_eq!(server.read().await, request(true, 32));
+    }
+
+    fn raw(x: u16, y: u16, width: u16, height: u16) -> Rectangle {
+        Rectangle {
+            x,
+            y,
+            width,
+            height,
+            encoding: Encoding::Raw,
+        }
+    }
+
+    /// Returns the rectangles of an update without their payloads.
+    async fn read_rects(client: &mut TestClient) -> Vec<Rectangle> {
+        let update = client.read_update().await;
+        update.into_iter().map(|(rect, _)| rect).collect()
+    }
+
+    #[tokio::test]
+    async fn icon_is_only_resent_when_drawn_over() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        let icon = raw(0, 0, 2, 2);
+
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(10, 10, 4, 4, 0).await;
+        assert_eq!(
+            read_rects(&mut client).await,
+            [raw(10, 10, 4, 4), icon.clone()]
+        );
+
+        client.request(true).await;
+        server.read_request().await;
+        server.send_raw(20, 20, 1, 1, 0).await;
+        assert_eq!(read_rects(&mut client).await, [raw(20, 20, 1, 1)]);
+
+        client.request(true).await;
+        server.read_request().await;
+        server.send_raw(1, 1, 4, 4, 0).await;
+        assert_eq!(read_rects(&mut client).await, [raw(1, 1, 4, 4), icon]);
+    }
+
+    #[tokio::test]
+    async fn empty_updates_only_carry_a_changed_icon() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(10, 10, 4, 4, 0).await;
+        assert_eq!(read_rects(&mut client).await.len(), 2);
+
+        for _ in 0..5 {
+            client.request(true).await;
+            server.read_request().await;
+            server.send_update(vec![]).await;
+            assert_eq!(read_rects(&mut client).await, []);
+        }
+
+        proxy.handle.update_state(|state| {
+            state.icon = Icon::solid(2, 2, [0, 0xff, 0, 0xff]);
+            true
+        });
+        client.request(true).await;
+        assert_eq!(read_rects(&mut client).await, [raw(0, 0, 2, 2)]);
+        for _ in 0..5 {
+            client.request(true).await;
+            server.read_request().await;
+            server.send_update(vec![]).await;
+            assert_eq!(read_rects(&mut client).await, []);
+        }
+    }
+
+    #[tokio::test]
+    async fn desynced_servers_are_disconnected() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        client.request(false).await;
+        server.read_request().await;
+
+        // a 2x2 rectangle followed by one pixel too many
+        let mut update = BytesMut::new();
+        S2C::FramebufferUpdate { count: 1 }.write_to(&mut update);
+        raw(0, 0, 2, 2).write_to(&mut update);
+        update.extend_from_slice(&[0xaa; 5 * 4]);
+        server.tx.write_data(update.freeze()).await.unwrap();
+
+        assert_closed(&mut client.rx).await;
+        assert_closed(&mut server.rx).await;
+    }
+
+    #[tokio::test]
+    async fn icon_can_go_first() {
+        let config = Config {
+            icon_z_order: IconZOrder::First,
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let (mut client, mut server) = proxy.connect().await;
+        client.request(false).await;
+        server.read_request().await;
+        let pixels = |n: usize| Bytes::from(vec![0; n * 4]);
+        let rects = vec![
+            (raw(10, 10, 1, 1), pixels(1)),
+            (raw(20, 20, 2, 1), pixels(2)),
+        ];
+        server.send_update(rects).await;
+        assert_eq!(
+            read_rects(&mut client).await,
+            [raw(0, 0, 2, 2), raw(10, 10, 1, 1), raw(20, 20, 2, 1)]
+        );
+
+        // but not if it would be painted over
+        client.request(false).await;
+        server.read_request().await;
+        let rects = vec![
+            (raw(0, 0, 4, 4), pixels(16)),
+            (raw(20, 20, 2, 1), pixels(2)),
+        ];
+        server.send_update(rects).await;
+        assert_eq!(
+            read_rects(&mut client).await,
+            [raw(0, 0, 4, 4), raw(0, 0, 2, 2), raw(20, 20, 2, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn icon_follows_the_rectangle_containing_it() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        let icon = raw(0, 0, 2, 2);
+        let rect = |x, y, size| {
+            (
+                raw(x, y, size, size),
+                Bytes::from(vec![0; size as usize * size as usize * 4]),
+            )
+        };
+
+        client.request(false).await;
+        server.read_request().await;
+        server
+            .send_update(vec![rect(0, 0, 10), rect(20, 20, 4)])
+            .await;
+        assert_eq!(
+            read_rects(&mut client).await,
+            [raw(0, 0, 10, 10), icon.clone(), raw(20, 20, 4, 4)]
+        );
+
+        // a rectangle only covering part of the icon is not enough
+        client.request(true).await;
+        server.read_request().await;
+        server
+            .send_update(vec![rect(1, 1, 4), rect(20, 20, 4)])
+            .await;
+        assert_eq!(
+            read_rects(&mut client).await,
+            [raw(1, 1, 4, 4), raw(20, 20, 4, 4), icon]
+        );
+    }
+
+    #[tokio::test]
+    async fn short_clipboard_text_times_out() {
+        for limit in [None, Some(5)] {
+            let config = Config {
+                read_timeout: Some(Duration::from_millis(100)),
+                max_cut_text_len: limit,
+                ..Default::default()
+            };
+            let (mut client, mut server) = scripted(TestState::default(), config).await;
+            handshake(&mut client, &mut server).await;
+
+            // 10 of 100 bytes
+            let mut cut_text = vec![3, 0, 0, 0, 0, 0, 0, 100];
+            cut_text.extend_from_slice(b"0123456789");
+            server.tx.write_data(cut_text.into()).await.unwrap();
+            let res = client.result().await;
+            let Err(Error::Protocol(reason)) = res else {
+                panic!("expected a protocol error, got {res:?}");
+            };
+            assert_eq!(reason, "clipboard text ended before its declared length");
+        }
+    }
+
+    #[tokio::test]
+    async fn long_clipboard_text_is_limited() {
+        let text = "a".repeat(5 << 20);
+        let after = "after".to_string();
+        for overflow in [CutTextOverflow::Truncate, CutTextOverflow::Reject] {
+            let config = Config {
+                max_cut_text_len: Some(1000),
+                cut_text_overflow: overflow,
+                ..Default::default()
+            };
+            let proxy = TestProxy::start(TestState::default(), config).await;
+            let (mut client, mut server) = proxy.connect().await;
+            let expected = match overflow {
+                CutTextOverflow::Truncate => vec![text[..1000].to_string(), after.clone()],
+                CutTextOverflow::Reject => vec![after.clone()],
+            };
+
+            let (_, received) = soon(async {
+                tokio::join!(
+                    async {
+                        client.tx.queue_message(C2S::CutText(text.clone()));
+                        client.tx.queue_message(C2S::CutText(after.clone()));
+                        client.tx.flush().await.unwrap();
+                    },
+                    async {
+                        let mut received = Vec::new();
+                        for _ in &expected {
+                            let C2S::CutText(text) = server.read().await else {
+                                panic!("expected clipboard text");
+                            };
+                            received.push(text);
+                        }
+                        received
+                    },
+                )
+            })
+            .await;
+            assert_eq!(received, expected);
+
+            let (_, received) = soon(async {
+                tokio::join!(
+                    async {
+                        server.tx.queue_message(S2C::CutText(text.clone()));
+                        server.tx.queue_message(S2C::CutText(after.clone()));
+                        server.tx.flush().await.unwrap();
+                    },
+                    async {
+                        let mut received = Vec::new();
+                        for _ in &expected {
+                            let S2C::CutText(text) = client.read().await else {
+                                panic!("expected clipboard text");
+                            };
+                            received.push(text);
+                        }
+                        received
+                    },
+                )
+            })
+            .await;
+            assert_eq!(received, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn initial_update_is_forwarded_without_a_request() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+
+        server.send_raw(10, 10, 1, 1, 0).await;
+        assert_eq!(
+            read_rects(&mut client).await,
+            [raw(10, 10, 1, 1), raw(0, 0, 2, 2)]
+        );
+
+        // later updates wait for a request
+        server.send_raw(20, 20, 1, 1, 0).await;
+        assert_silent(&mut client.rx).await;
+        client.request(true).await;
+        assert_eq!(read_rects(&mut client).await, [raw(20, 20, 1, 1)]);
+    }
+
+    #[tokio::test]
+    async fn initial_update_of_3_3_servers_is_forwarded() {
+        let (client, mut server) = scripted(TestState::default(), Config::default()).await;
+        let ClientTask {
+            mut rx,
+            mut tx,
+            task,
+            ..
+        } = client;
+        server.tx.write_message(Version::new(3, 3)).await.unwrap();
+        let _: Version = rx.read_message().await.unwrap();
+        tx.write_message(Version::new(3, 3)).await.unwrap();
+        let _: Version = server.rx.read_message().await.unwrap();
+        // the server picks None, which has no result in 3.3
+        server.tx.write_message(SecurityResult(1)).await.unwrap();
+        assert_eq!(
+            rx.read_message::<SecurityResult>().await.unwrap(),
+            SecurityResult(1)
+        );
+        tx.write_message(ClientInit { shared: true }).await.unwrap();
+        let _: ClientInit = server.rx.read_message().await.unwrap();
+        server.tx.write_message(server_init()).await.unwrap();
+        let init: ServerInit = rx.read_message().await.unwrap();
+
+        // pushed before the client asked for anything
+        server.send_raw(10, 10, 1, 1, 0).await;
+        let format = init.pixel_format.clone();
+        let mut client = TestClient {
+            rx,
+            tx,
+            init,
+            format,
+        };
+        assert_eq!(
+            read_rects(&mut client).await,
+            [raw(10, 10, 1, 1), raw(0, 0, 2, 2)]
+        );
+        assert!(!task.is_finished());
+    }
+
+    /// Returns the encodings the server is asked for when a client sends `encodings`.
+    async fn forwarded_encodings(config: Config, encodings: Vec<Encoding>) -> Vec<Encoding> {
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let (mut client, mut server) = proxy.connect().await;
+        client.send(C2S::SetEncodings(encodings)).await;
+        let C2S::SetEncodings(forwarded) = server.read().await else {
+            panic!("expected the encodings");
+        };
+        forwarded
+    }
+
+    #[tokio::test]
+    async fn supported_encodings_are_advertised() {
+        use Encoding::*;
+
+        // whatever the client supports
+        for encodings in [vec![Hextile, Rre, Raw], vec![], vec![Zrle, Raw, CopyRect]] {
+            let forwarded = forwarded_encodings(Config::default(), encodings).await;
+            assert_eq!(forwarded, supported_encodings());
+        }
+    }
+
+    #[tokio::test]
+    async fn encodings_are_reported_as_sent() {
+        use Encoding::*;
+
+        let encodings = vec![Hextile, Unknown(1234), Raw];
+        for wanted in [false, true] {
+            let state = TestState {
+                encoding_events: wanted,
+                ..Default::default()
+            };
+            let proxy = TestProxy::start(state, Config::default()).await;
+            let (mut client, mut server) = proxy.connect().await;
+            client.send(C2S::SetEncodings(encodings.clone())).await;
+            let forwarded = server.read().await;
+            assert_ne!(forwarded, C2S::SetEncodings(encodings.clone()));
+
+            drop(client);
+            let disconnect = Event::Disconnect { id: 0 };
+            eventually(|| proxy.events().contains(&disconnect)).await;
+            let reported = proxy
+                .events()
+                .into_iter()
+                .filter(|e| matches!(e, Event::Encodings { .. }))
+                .collect::<Vec<_>>();
+            let expected = Event::Encodings {
+                id: 0,
+                encodings: encodings.clone(),
+            };
+            assert_eq!(reported, if wanted { vec![expected] } else { vec![] });
+        }
+    }
+
+    #[tokio::test]
+    async fn gii_messages_are_passed_through() {
+        let encodings = vec![Encoding::Raw, Encoding::Gii];
+        let forwarded = forwarded_encodings(Config::default(), encodings).await;
+        assert!(forwarded.contains(&Encoding::Gii));
+        let forwarded = forwarded_encodings(Config::default(), vec![Encoding::Raw]).await;
+        assert!(!forwarded.contains(&Encoding::Gii));
+
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        let version = Gii {
+            big_endian: true,
+            subtype: 1,
+            payload: Bytes::from_static(&[0, 1, 0, 1]),
+        };
+        server
+            .tx
+            .write_message(S2C::Gii(version.clone()))
+            .await
+            .unwrap();
+        assert_eq!(client.read().await, S2C::Gii(version));
+        let version = Gii {
+            big_endian: false,
+            subtype: 1,
+            payload: Bytes::from_static(&[1, 0]),
+        };
+        client.send(C2S::Gii(version.clone())).await;
+        assert_eq!(server.read().await, C2S::Gii(version));
+    }
+
+    #[tokio::test]
+    async fn keys_can_be_blocked_and_remapped() {
+        const SUPER_L: u32 = 0xffeb;
+        const CONTROL_L: u32 = 0xffe3;
+
+        /// Blocks the Super key, or all keys, and turns `a` into `b`.
+        struct Kiosk {
+            block_all: bool,
+        }
+
+        impl State for Kiosk {
+            fn icon(&self, _id: ClientId) -> Icon {
+                Icon::solid(2, 2, [0xff; 4])
+            }
+
+            fn handle_event(&mut self, _event: Event) -> Redraw {
+                Redraw::None
+            }
+
+            fn enable_input(&self, _id: ClientId) -> bool {
+                true
+            }
+
+            fn filter_key(&self, _id: ClientId, _down: bool, key: u32) -> Option<u32> {
+                match key {
+                    _ if self.block_all => None,
+                    SUPER_L => None,
+                    0x61 => Some(0x62),
+                    key => Some(key),
+                }
+            }
+        }
+
+        let proxy = TestProxy::start(Kiosk { block_all: false }, Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        let key = |down, key| C2S::KeyEvent { down, key };
+
+        client.send(key(true, SUPER_L)).await;
+        client.send(key(true, 0x61)).await;
+        client.send(key(false, 0x61)).await;
+        client.send(key(false, SUPER_L)).await;
+        assert_eq!(server.read().await, key(true, 0x62));
+        assert_eq!(server.read().await, key(false, 0x62));
+
+        // a held modifier is released even once keys are blocked
+        client.send(key(true, CONTROL_L)).await;
+        assert_eq!(server.read().await, key(true, CONTROL_L));
+        proxy.handle.update_state(|state| {
+            state.block_all = true;
+            false
+        });
+        client.send(key(true, 0x78)).await;
+        client.send(key(false, 0x78)).await;
+        client.send(key(false, CONTROL_L)).await;
+        assert_eq!(server.read().await, key(false, CONTROL_L));
+        assert_silent(&mut server.rx).await;
+    }
+
+    #[tokio::test]
+    async fn icon_can_be_sent_as_the_cursor() {
+        // opaque but for the last column, 10 pixels wide so the mask has 2 bytes per row
+        let mut rgba = [0, 0xff, 0, 0xff].repeat(30);
+        for row in 0..3 {
+            rgba[(row * 10 + 9) * 4 + 3] = 0x7f;
+        }
+        let icon = Icon {
+            rgba_data: Bytes::from(rgba),
+            ..Icon::solid(10, 3, [0; 4])
+        };
+        let state = TestState {
+            icon: icon.as_cursor(1, 2),
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(state, Config::default()).await;
+
+        let (mut client, mut server) = proxy.connect().await;
+        client
+            .send(C2S::SetEncodings(vec![Encoding::Raw, Encoding::Cursor]))
+            .await;
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(30, 30, 1, 1, 0).await;
+        let update = client.read_update().await;
+        assert_eq!(update.len(), 2);
+        let (rect, payload) = &update[1];
+        assert_eq!(
+            *rect,
+            Rectangle {
+                x: 1,
+                y: 2,
+                width: 10,
+                height: 3,
+                encoding: Encoding::Cursor,
+            }
+        );
+        let (pixels, mask) = payload.split_at(10 * 3 * 4);
+        assert_eq!(pixels, [0, 0xff, 0, 0].repeat(30));
+        assert_eq!(mask, [0xff, 0x80].repeat(3));
+
+        // drawn into the framebuffer for clients without cursor shapes
+        let (mut client, mut server) = proxy.connect().await;
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(30, 30, 1, 1, 0).await;
+        let rects = read_rects(&mut client).await;
+        assert_eq!(rects[1].encoding, Encoding::Raw);
+        assert_eq!((rects[1].width, rects[1].height), (10, 3));
+    }
+
+    #[tokio::test]
+    async fn cursor_can_be_stripped() {
+        use Encoding::*;
+
+        let encodings = vec![Zrle, Raw, Cursor];
+        let forwarded = forwarded_encodings(Config::default(), encodings.clone()).await;
+        assert_eq!(forwarded, [Raw, Cursor, CopyRect, Zrle]);
+
+        let config = Config {
+            strip_cursor: true,
+            ..Default::default()
+        };
+        let forwarded = forwarded_encodings(config, encodings).await;
+        assert_eq!(forwarded, [Raw, CopyRect, Zrle]);
+    }
+
+    #[tokio::test]
+    async fn only_raw_rectangles_can_be_requested() {
+        use Encoding::*;
+
+        let config = Config {
+            force_raw: true,
+            ..Default::default()
+        };
+        let encodings = vec![Tight, Zrle, Hextile, Raw, CopyRect, Cursor, DesktopSize];
+        let forwarded = forwarded_encodings(config, encodings).await;
+        // pseudo-encodings are kept
+        assert_eq!(forwarded, [Raw, Cursor, DesktopSize, ExtendedDesktopSize]);
+    }
+
+    #[tokio::test]
+    async fn icon_is_left_out_of_the_continuous_updates_region() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        let enable = |enable| C2S::EnableContinuousUpdates {
+            enable,
+            x: 10,
+            y: 10,
+            width: 20,
+            height: 20,
+        };
+
+        client.send(enable(true)).await;
+        assert_eq!(server.read().await, enable(true));
+        server.send_raw(12, 12, 2, 2, 0).await;
+        assert_eq!(read_rects(&mut client).await, [raw(12, 12, 2, 2)]);
+        // even if the update draws over the icon
+        server.send_raw(0, 0, 4, 4, 0).await;
+        assert_eq!(read_rects(&mut client).await, [raw(0, 0, 4, 4)]);
+
+        client.send(enable(false)).await;
+        assert_eq!(server.read().await, enable(false));
+        client.request(true).await;
+        server.read_request().await;
+        server.send_raw(0, 0, 4, 4, 0).await;
+        assert_eq!(
+            read_rects(&mut client).await,
+            [raw(0, 0, 4, 4), raw(0, 0, 2, 2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn stalled_handshakes_time_out() {
+        let config = Config {
+            handshake_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let (mut client, mut server) = scripted(TestState::default(), config).await;
+        server.tx.write_message(Version::new(3, 8)).await.unwrap();
+        let _: Version = client.rx.read_message().await.unwrap();
+
+        let Err(Error::Handshake { phase, detail }) = client.result().await else {
+            panic!("expected a handshake error");
+        };
+        assert_eq!(phase, HandshakePhase::Version);
+        assert_eq!(detail, "timed out");
+        assert_closed(&mut server.rx).await;
+        assert_closed(&mut client.rx).await;
+    }
+
+    #[tokio::test]
+    async fn pixel_data_is_converted_from_the_preferred_format() {
+        let state = TestState {
+            preferred_pixel_format: Some(PixelFormat::default()),
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(state, Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+
+        client.set_pixel_format(PixelFormat::rgb565()).await;
+        assert_eq!(
+            server.read().await,
+            C2S::SetPixelFormat(PixelFormat::default())
+        );
+        client.request(false).await;
+        server.read_request().await;
+        // red
+        let pixel = Bytes::from_static(&[0, 0, 0xff, 0]);
+        server.send_update(vec![(raw(10, 10, 1, 1), pixel)]).await;
+
+        let red = Bytes::from([0, 0xf8].repeat(4));
+        assert_eq!(
+            client.read_update().await,
+            [(raw(10, 10, 1, 1), red.slice(..2)), (raw(0, 0, 2, 2), red),]
+        );
+    }
+
+    #[tokio::test]
+    async fn icon_follows_pixel_format_changes() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(10, 10, 1, 1, 0).await;
+        let red = Bytes::from([0, 0, 0xff, 0].repeat(4));
+        assert_eq!(client.read_update().await[1], (raw(0, 0, 2, 2), red));
+
+        client.set_pixel_format(PixelFormat::rgb565()).await;
+        assert_eq!(
+            server.read().await,
+            C2S::SetPixelFormat(PixelFormat::rgb565())
+        );
+        // the whole screen is sent again in the new format, the icon along with it
+        client.request(false).await;
+        server.read_request().await;
+        let black = Bytes::from(vec![0; 4 * 4 * 2]);
+        server
+            .send_update(vec![(raw(0, 0, 4, 4), black.clone())])
+            .await;
+        let red = Bytes::from([0, 0xf8].repeat(4));
+        assert_eq!(
+            client.read_update().await,
+            [(raw(0, 0, 4, 4), black), (raw(0, 0, 2, 2), red)]
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_pixel_formats_are_refused() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+
+        let pixel_format = PixelFormat {
+            red_shift: 32,
+            ..Default::default()
+        };
+        client.send(C2S::SetPixelFormat(pixel_format)).await;
+        assert_closed(&mut client.rx).await;
+        assert_closed(&mut server.rx).await;
+    }
+
+    #[tokio::test]
+    async fn large_rectangles_are_streamed() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let init = ServerInit {
+            framebuffer_width: 1600,
+            framebuffer_height: 1600,
+            ..server_init()
+        };
+        let (mut client, mut server) = proxy.connect_with(init).await;
+        client.request(false).await;
+        server.read_request().await;
+
+        // 10 MB, of which the client gets the first part before the server sends the rest
+        let rect = raw(0, 0, 1600, 1600);
+        let len = rect.payload_size(&server.format).unwrap();
+        let payload = Bytes::from((0..len).map(|i| i as u8).collect::<Vec<_>>());
+        let head = 2 * CHUNK_SIZE;
+        server.tx.queue_message(S2C::FramebufferUpdate { count: 1 });
+        server.tx.queue_message(rect.clone());
+        let (sent, start) = soon(async {
+            tokio::join!(server.tx.write_data(payload.slice(..head)), async {
+                assert_eq!(client.read().await, S2C::FramebufferUpdate { count: 2 });
+                assert_eq!(client.rx.read_message::<Rectangle>().await.unwrap(), rect);
+                client.rx.read_data(CHUNK_SIZE).await
+            })
+        })
+        .await;
+        sent.unwrap();
+        assert_eq!(start.unwrap(), payload.slice(..CHUNK_SIZE));
+
+        let (sent, rest) = soon(async {
+            tokio::join!(
+                server.tx.write_data(payload.slice(head..)),
+                client.rx.read_data(len - CHUNK_SIZE),
+            )
+        })
+        .await;
+        sent.unwrap();
+        assert!(rest.unwrap() == payload.slice(CHUNK_SIZE..));
+        // the icon is drawn again on top
+        let icon = soon(client.rx.read_message::<Rectangle>()).await.unwrap();
+        assert_eq!(icon, raw(0, 0, 2, 2));
+    }
+
+    #[tokio::test]
+    async fn slow_clients_are_reported() {
+        let config = Config {
+            slow_write_threshold: Some(Duration::from_millis(100)),
+            ..Config::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let init = ServerInit {
+            framebuffer_width: 1600,
+            framebuffer_height: 1600,
+            ..server_init()
+        };
+        let (mut client, mut server) = proxy.connect_with(init).await;
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(0, 0, 1, 1, 0).await;
+        client.read_update().await;
+        assert!(!proxy.events().contains(&Event::SlowClient { id: 0 }));
+
+        // 10 MB, which the client only starts reading after a while
+        client.request(false).await;
+        server.read_request().await;
+        let rect = raw(0, 0, 1600, 1600);
+        let len = rect.payload_size(&server.format).unwrap();
+        server.tx.queue_message(S2C::FramebufferUpdate { count: 1 });
+        server.tx.queue_message(rect);
+        let (sent, received) = soon(async {
+            tokio::join!(server.tx.write_data(Bytes::from(vec![0; len])), async {
+                sleep(Duration::from_millis(300)).await;
+                client.read_update().await
+            })
+        })
+        .await;
+        sent.unwrap();
+        assert_eq!(received.len(), 2);
+        eventually(|| proxy.events().contains(&Event::SlowClient { id: 0 })).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn large_rectangles_can_be_spliced() {
+        let config = Config {
+            splice_raw: true,
+            slow_write_threshold: Some(Duration::from_millis(100)),
+            ..Config::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let init = ServerInit {
+            framebuffer_width: 1600,
+            framebuffer_height: 1600,
+            ..server_init()
+        };
+        let (mut client, mut server) = proxy.connect_with(init).await;
+        client.request(false).await;
+        server.read_request().await;
+
+        // 10 MB, written along with the headers so that the proxy reads some of it with them
+        let rect = raw(0, 0, 1600, 1600);
+        let len = rect.payload_size(&server.format).unwrap();
+        let payload = Bytes::from((0..len).map(|i| (i / 3) as u8).collect::<Vec<_>>());
+        server.tx.queue_message(S2C::FramebufferUpdate { count: 1 });
+        server.tx.queue_message(rect.clone());
+        let (sent, received) = soon(async {
+            tokio::join!(server.tx.write_data(payload.clone()), async {
+                assert_eq!(client.read().await, S2C::FramebufferUpdate { count: 2 });
+                assert_eq!(client.rx.read_message::<Rectangle>().await.unwrap(), rect);
+                // the client falls behind, so that the spliced writes are slow
+                sleep(Duration::from_millis(300)).await;
+                client.rx.read_data(len).await
+            })
+        })
+        .await;
+        sent.unwrap();
+        assert!(received.unwrap() == payload);
+        let icon = soon(client.rx.read_message::<Rectangle>()).await.unwrap();
+        assert_eq!(icon, raw(0, 0, 2, 2));
+        eventually(|| proxy.events().contains(&Event::SlowClient { id: 0 })).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn rectangles_are_copied_to_clients_of_another_pixel_format() {
+        let config = Config {
+            splice_raw: true,
+            ..Config::default()
+        };
+        let state = TestState {
+            preferred_pixel_format: Some(PixelFormat::default()),
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(state, config).await;
+        let init = ServerInit {
+            framebuffer_width: 1024,
+            framebuffer_height: 1024,
+            ..server_init()
+        };
+        let (mut client, mut server) = proxy.connect_with(init).await;
+        client.set_pixel_format(PixelFormat::rgb565()).await;
+        assert_eq!(
+            server.read().await,
+            C2S::SetPixelFormat(PixelFormat::default())
+        );
+        client.request(false).await;
+        server.read_request().await;
+
+        // 4 MB of white pixels, which become 2 MB
+        let rect = raw(0, 0, 1024, 1024);
+        let len = rect.payload_size(&server.format).unwrap();
+        server.tx.queue_message(S2C::FramebufferUpdate { count: 1 });
+        server.tx.queue_message(rect.clone());
+        let (sent, received) = soon(async {
+            tokio::join!(server.tx.write_data(Bytes::from(vec![0xff; len])), async {
+                assert_eq!(client.read().await, S2C::FramebufferUpdate { count: 2 });
+                assert_eq!(client.rx.read_message::<Rectangle>().await.unwrap(), rect);
+                client.rx.read_data(len / 2).await
+            })
+        })
+        .await;
+        sent.unwrap();
+        assert!(received.unwrap().iter().all(|&b| b == 0xff));
+    }
+
+    fn zrle(x: u16, y: u16, width: u16, height: u16) -> Rectangle {
+        Rectangle {
+            encoding: Encoding::Zrle,
+            ..raw(x, y, width, height)
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_without_raw_get_zrle() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let init = ServerInit {
+            framebuffer_width: 1024,
+            framebuffer_height: 1024,
+            ..server_init()
+        };
+        let (mut client, mut server) = proxy.connect_with(init).await;
+        client
+            .send(C2S::SetEncodings(vec![Encoding::Zrle, Encoding::CopyRect]))
+            .await;
+        // the server is asked for Raw, which the proxy encodes itself
+        let C2S::SetEncodings(forwarded) = server.read().await else {
+            panic!("expected the encodings");
+        };
+        assert!(forwarded.contains(&Encoding::Raw));
+        assert!(!forwarded.contains(&Encoding::Zrle));
+
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(10, 10, 4, 4, 0x40).await;
+        let update = client.read_update().await;
+        let rects: Vec<_> = update.iter().map(|(rect, _)| rect.clone()).collect();
+        assert_eq!(rects, [zrle(10, 10, 4, 4), zrle(0, 0, 2, 2)]);
+
+        // all rectangles continue the same zlib stream
+        let mut decoder = ZrleDecoder::new();
+        let format = client.format.clone();
+        let pixels = decoder.decode(&update[0].1, 4, 4, &format).unwrap();
+        assert_eq!(pixels, [0x40, 0x40, 0x40, 0].repeat(16));
+        let pixels = decoder.decode(&update[1].1, 2, 2, &format).unwrap();
+        assert_eq!(pixels, [0, 0, 0xff, 0].repeat(4));
+
+        // 4 MB, which is encoded a row of tiles at a time
+        client.request(true).await;
+        server.read_request().await;
+        let (_, update) = soon(async {
+            tokio::join!(
+                server.send_raw(0, 0, 1024, 1024, 0x80),
+                client.read_update()
+            )
+        })
+        .await;
+        assert_eq!(update[0].0, zrle(0, 0, 1024, 1024));
+        let pixels = decoder.decode(&update[0].1, 1024, 1024, &format).unwrap();
+        assert!(pixels.chunks(4).all(|pixel| pixel == [0x80, 0x80, 0x80, 0]));
+        assert_eq!(update[1].0, zrle(0, 0, 2, 2));
+        decoder.decode(&update[1].1, 2, 2, &format).unwrap();
+
+        // ZRLE of the server cannot be mixed into the stream
+        client.request(true).await;
+        server.read_request().await;
+        let data = Bytes::from_static(&[0, 0, 0, 0]);
+        server.send_update(vec![(zrle(0, 0, 1, 1), data)]).await;
+        client.read().await;
+        assert_closed(&mut client.rx).await;
+    }
+
+    #[tokio::test]
+    async fn clients_with_raw_get_raw() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        client
+            .send(C2S::SetEncodings(vec![Encoding::Zrle, Encoding::Raw]))
+            .await;
+        let C2S::SetEncodings(forwarded) = server.read().await else {
+            panic!("expected the encodings");
+        };
+        assert!(forwarded.contains(&Encoding::Zrle));
+
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(10, 10, 4, 4, 0).await;
+        assert_eq!(
+            read_rects(&mut client).await,
+            [raw(10, 10, 4, 4), raw(0, 0, 2, 2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn icon_follows_a_full_update_separately() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        client.request(false).await;
+        server.read_request().await;
+
+        // rectangles of the same bounds are not merged after a CopyRect
+        let copy = Rectangle {
+            encoding: Encoding::CopyRect,
+            ..raw(10, 10, 1, 1)
+        };
+        let pixel = Bytes::from_static(&[0; 4]);
+        let mut rects = vec![(raw(0, 0, 1, 1), pixel.clone()); u16::MAX as usize];
+        rects[0] = (copy, pixel);
+        let (_, update) =
+            soon(async { tokio::join!(server.send_update(rects), client.read_update()) }).await;
+        assert_eq!(update.len(), u16::MAX as usize);
+        assert_eq!(read_rects(&mut client).await, [raw(0, 0, 2, 2)]);
+    }
+
+    #[tokio::test]
+    async fn updates_are_merged_down_to_the_rate_limit() {
+        let config = Config {
+            max_updates_per_sec: Some(10),
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let (mut client, mut server) = proxy.connect().await;
+        // lets the server send updates without waiting for requests
+        client
+            .send(C2S::EnableContinuousUpdates {
+                enable: true,
+                x: 0,
+                y: 0,
+                width: 64,
+                height: 48,
+            })
+            .await;
+        server.read().await;
+
+        // 100 updates per second for 300 ms
+        let start = Instant::now();
+        for fill in 0..30 {
+            server.send_raw(10, 10, 1, 1, fill).await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut updates = 0;
+        loop {
+            let update = client.read_update().await;
+            updates += 1;
+            if update[0].1[..] == [29; 4] {
+                break;
+            }
+        }
+        // one right away, then at most one per 100 ms
+        let max = 1 + start.elapsed().as_millis() / 100;
+        assert!(updates <= max, "{updates} updates, at most {max} expected");
+        assert_silent(&mut client.rx).await;
+    }
+
+    #[tokio::test]
+    async fn content_is_moved_below_the_overlay_margin() {
+        let config = Config {
+            overlay_margin: 16,
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let (mut client, mut server) = proxy.connect().await;
+        assert_eq!(client.init.framebuffer_height, 48 + 16);
+
+        client.request(false).await;
+        assert_eq!(server.read_request().await, request(false, 64));
+        server.send_raw(10, 10, 4, 4, 0).await;
+        assert_eq!(
+            read_rects(&mut client).await,
+            [raw(10, 26, 4, 4), raw(0, 0, 2, 2)]
+        );
+
+        let pointer = |y| C2S::PointerEvent {
+            button_mask: Buttons(0),
+            x: 30,
+            y,
+        };
+        client.send(pointer(20)).await;
+        assert_eq!(server.read().await, pointer(4));
+    }
+
+    /// Colour map entries with each channel set to `value`, once for every entry.
+    fn grey(values: &[u16]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes().repeat(3))
+            .collect()
+    }
+
+    #[test]
+    fn palette_updates_overlap() {
+        let mut palette = Palette::default();
+        palette.update(1, &grey(&[10, 11, 12])).unwrap();
+        palette.update(2, &grey(&[20, 21])).unwrap();
+        let entries: Vec<_> = palette.0[..5].iter().map(|entry| entry[0]).collect();
+        assert_eq!(entries, [0, 10, 20, 21, 0]);
+        assert_eq!(palette.0[3], [21; 3]);
+
+        palette.update(254, &grey(&[30, 31])).unwrap();
+        assert!(palette.update(255, &grey(&[40, 41])).is_err());
+        // nothing is applied of a rejected update
+        assert_eq!(palette.0[255], [31; 3]);
+    }
+
+    #[test]
+    fn icons_are_mapped_to_the_nearest_colour() {
+        let mut palette = Palette::default();
+        palette.update(1, &grey(&[0xffff, 0x8000])).unwrap();
+        let rgba = [
+            [0xff, 0xff, 0xff, 0xff],
+            [0x70, 0x70, 0x90, 0xff],
+            [8, 0, 0, 0xff],
+        ];
+        assert_eq!(palette.map_rgba(&rgba.concat()), [1, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn connections_are_transparent_once_events_are_no_longer_handled() {
+        let (mut client, mut server) = scripted(TestState::default(), Config::default()).await;
+        client.events.close();
+        handshake(&mut client, &mut server).await;
+
+        // no icon is drawn
+        let request = C2S::FramebufferUpdateRequest {
+            incremental: false,
+            x: 0,
+            y: 0,
+            width: 64,
+            height: 48,
+        };
+        client.tx.write_message(request).await.unwrap();
+        server.read_request().await;
+        server.send_raw(10, 10, 1, 1, 0).await;
+        let update: S2C = client.rx.read_message().await.unwrap();
+        assert_eq!(update, S2C::FramebufferUpdate { count: 1 });
+        let rect: Rectangle = client.rx.read_message().await.unwrap();
+        assert_eq!(rect, raw(10, 10, 1, 1));
+        client.rx.read_data(4).await.unwrap();
+
+        // and clicks where it would be are forwarded
+        for buttons in [1, 0] {
+            let pointer = C2S::PointerEvent {
+                button_mask: Buttons(buttons),
+                x: 1,
+                y: 1,
+            };
+            client.tx.write_message(pointer.clone()).await.unwrap();
+            assert_eq!(server.read().await, pointer);
+        }
+        server.tx.write_message(S2C::Bell).await.unwrap();
+        assert_eq!(client.rx.read_message::<S2C>().await.unwrap(), S2C::Bell);
+
+        // the connection ends like any other, with no event to send
+        client.tx.shutdown().await.unwrap();
+        let res = client.result().await;
+        assert!(matches!(res, Err(Error::Io(_))), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn undecodable_messages_are_captured_when_configured() {
+        let config = Config {
+            capture_on_error: true,
+            ..Default::default()
+        };
+        let (mut client, mut server) = scripted(TestState::default(), config).await;
+        handshake(&mut client, &mut server).await;
+
+        server
+            .tx
+            .write_data(Bytes::from_static(&[250, 1, 2, 3]))
+            .await
+            .unwrap();
+        let res = client.result().await;
+        let Err(Error::DecodeCaptured { source, bytes }) = res else {
+            panic!("expected the captured bytes, got {res:?}");
+        };
+        assert!(matches!(source, DecodeError::UnsupportedS2C(250)));
+        assert_eq!(bytes, "fa010203");
+    }
+
+    #[tokio::test]
+    async fn server_init_can_be_rewritten() {
+        /// Brands the server, pins a 16 bit pixel format and makes room below the screen.
+        struct Branded;
+
+        impl State for Branded {
+            fn icon(&self, _id: ClientId) -> Icon {
+                Icon::solid(2, 2, [0xff; 4])
+            }
+
+            fn handle_event(&mut self, _event: Event) -> Redraw {
+                Redraw::None
+            }
+
+            fn enable_input(&self, _id: ClientId) -> bool {
+                true
+            }
+
+            fn rewrite_server_init(&self, _id: ClientId, init: ServerInit) -> ServerInit {
+                ServerInit {
+                    framebuffer_height: init.framebuffer_height + 16,
+                    pixel_format: PixelFormat::rgb565(),
+                    name: "branded".to_string(),
+                    ..init
+                }
+            }
+        }
+
+        let (mut client, mut server) = scripted(Branded, Config::default()).await;
+        relay_security(&mut client, &mut server).await;
+        server.tx.write_message(SecurityResult(0)).await.unwrap();
+        let _: SecurityResult = client.rx.read_message().await.unwrap();
+        client
+            .tx
+            .write_message(ClientInit { shared: true })
+            .await
+            .unwrap();
+        let _: ClientInit = server.rx.read_message().await.unwrap();
+        server.tx.write_message(server_init()).await.unwrap();
+
+        assert_eq!(
+            server.read().await,
+            C2S::SetPixelFormat(PixelFormat::rgb565())
+        );
+        #[rustfmt::skip]
+        let expected = [
+            0, 64, 0, 64,
+            16, 16, 0, 1, 0, 31, 0, 63, 0, 31, 11, 5, 0, 0, 0, 0,
+            0, 0, 0, 7, b'b', b'r', b'a', b'n', b'd', b'e', b'd',
+        ];
+        let init = client.rx.read_data(expected.len()).await.unwrap();
+        assert_eq!(init, expected[..]);
+        assert_silent(&mut client.rx).await;
+    }
+
+    #[tokio::test]
+    async fn server_name_can_be_overridden() {
+        let config = Config {
+            server_name_override: Some("Büro Desktop".to_string()),
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let (client, _server) = proxy.connect().await;
+        assert_eq!(client.init.name, "B?ro Desktop");
+        assert_eq!(client.init.framebuffer_width, 64);
+    }
+
+    #[tokio::test]
+    async fn requests_wait_for_the_backlog() {
+        let config = Config {
+            max_client_backlog: Some(100),
+            // keeps the second update buffered for a while
+            max_updates_per_sec: Some(2),
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let (mut client, mut server) = proxy.connect().await;
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(10, 10, 1, 1, 0).await;
+        client.read_update().await;
+
+        client.request(true).await;
+        server.read_request().await;
+        // 256 bytes waiting for the client
+        server.send_raw(10, 10, 8, 8, 0).await;
+        client.request(true).await;
+        assert_silent(&mut server.rx).await;
+
+        assert_eq!(read_rects(&mut client).await, [raw(10, 10, 8, 8)]);
+        server.read_request().await;
+    }
+
+    #[tokio::test]
+    async fn input_passes_waiting_requests() {
+        let config = Config {
+            max_client_backlog: Some(100),
+            max_updates_per_sec: Some(2),
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let (mut client, mut server) = proxy.connect().await;
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(10, 10, 1, 1, 0).await;
+        client.read_update().await;
+
+        client.request(true).await;
+        server.read_request().await;
+        server.send_raw(10, 10, 8, 8, 0).await;
+        client.request(true).await;
+        client.request(false).await;
+        let key = C2S::KeyEvent {
+            down: true,
+            key: 0x61,
+        };
+        client.send(key.clone()).await;
+        assert_eq!(server.read().await, key);
+
+        // both requests are forwarded as one once the update has been written
+        assert_eq!(read_rects(&mut client).await, [raw(10, 10, 8, 8)]);
+        assert!(matches!(
+            server.read().await,
+            C2S::FramebufferUpdateRequest {
+                incremental: false,
+                ..
+            }
+        ));
+        assert_silent(&mut server.rx).await;
+    }
+
+    #[tokio::test]
+    async fn drags_ending_on_the_icon_are_no_clicks() {
+        for strict in [true, false] {
+            let config = Config {
+                require_press_in_bounds: strict,
+                ..Default::default()
+            };
+            let proxy = TestProxy::start(TestState::default(), config).await;
+            let (mut client, _server) = proxy.connect().await;
+            let pointer = |buttons, x, y| C2S::PointerEvent {
+                button_mask: Buttons(buttons),
+                x,
+                y,
+            };
+
+            // from the middle of the screen onto the icon
+            client.send(pointer(1, 20, 20)).await;
+            client.send(pointer(1, 1, 1)).await;
+            client.send(pointer(0, 1, 1)).await;
+            // a click on the icon
+            client.send(pointer(1, 1, 1)).await;
+            client.send(pointer(0, 1, 1)).await;
+
+            let actions = || {
+                let events = proxy.events().into_iter();
+                events.filter(|e| *e == Event::Action { id: 0 }).count()
+            };
+            let expected = if strict { 1 } else { 2 };
+            eventually(|| actions() >= expected).await;
+            // no further action follows
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(actions(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn clicks_on_non_interactive_icons_are_passed_on() {
+        // like a client watching someone else's lock, but allowed to use the session
+        let state = TestState {
+            icon_interactive: false,
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(state, Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        let pointer = |buttons, x, y| C2S::PointerEvent {
+            button_mask: Buttons(buttons),
+            x,
+            y,
+        };
+
+        client.send(pointer(1, 1, 1)).await;
+        client.send(pointer(0, 1, 1)).await;
+        assert_eq!(server.read().await, pointer(1, 1, 1));
+        assert_eq!(server.read().await, pointer(0, 1, 1));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!proxy.events().contains(&Event::Action { id: 0 }));
+
+        // the same click is taken by an interactive icon
+        proxy.handle.update_state(|state| {
+            state.icon_interactive = true;
+            false
+        });
+        client.send(pointer(1, 1, 1)).await;
+        client.send(pointer(0, 1, 1)).await;
+        assert_eq!(server.read().await, pointer(1, 1, 1));
+        eventually(|| proxy.events().contains(&Event::Action { id: 0 })).await;
+        assert_silent(&mut server.rx).await;
+    }
+
+    #[tokio::test]
+    async fn resizes_are_passed_on_as_the_client_supports() {
+        use Encoding::*;
+
+        let resize = |encoding| Rectangle {
+            x: 0,
+            y: 0,
+            width: 80,
+            height: 60,
+            encoding,
+        };
+        // a single screen covering the framebuffer
+        let screen = Bytes::from_static(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 80, 0, 60, 0, 0, 0, 0]);
+        let layout = Bytes::from([&[1, 0, 0, 0][..], &screen].concat());
+
+        for (supported, sent, received) in [
+            (
+                DesktopSize,
+                ExtendedDesktopSize,
+                (resize(DesktopSize), Bytes::new()),
+            ),
+            (
+                ExtendedDesktopSize,
+                DesktopSize,
+                (resize(ExtendedDesktopSize), screen.clone()),
+            ),
+        ] {
+            let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+            let (mut client, mut server) = proxy.connect().await;
+            client.send(C2S::SetEncodings(vec![Raw, supported])).await;
+            let C2S::SetEncodings(forwarded) = server.read().await else {
+                panic!("expected the encodings");
+            };
+            assert!(forwarded.contains(&DesktopSize) && forwarded.contains(&ExtendedDesktopSize));
+
+            client.request(false).await;
+            server.read_request().await;
+            let payload = match sent {
+                ExtendedDesktopSize => layout.clone(),
+                _ => Bytes::new(),
+            };
+            server.send_update(vec![(resize(sent), payload)]).await;
+            let update = client.read_update().await;
+            assert_eq!(update[0], received);
+        }
+    }
+
+    #[tokio::test]
+    async fn updates_pass_through_without_the_overlay() {
+        let config = Config {
+            disable_overlay: true,
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let (mut client, mut server) = proxy.connect().await;
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(0, 0, 4, 4, 0x11).await;
+        assert_eq!(
+            client.read_update().await,
+            [(raw(0, 0, 4, 4), Bytes::from(vec![0x11; 64]))]
+        );
+
+        // clicks on the icon's area go to the server
+        for buttons in [1, 0] {
+            let click = C2S::PointerEvent {
+                button_mask: Buttons(buttons),
+                x: 1,
+                y: 1,
+            };
+            client.send(click.clone()).await;
+            assert_eq!(server.read().await, click);
+        }
+        assert!(proxy.events().iter().all(|e| *e != Event::Action { id: 0 }));
+    }
+
+    #[tokio::test]
+    async fn icon_follows_the_pointer() {
+        let state = TestState {
+            icon: Icon {
+                anchor: Anchor::FollowPointer { dx: 2, dy: 2 },
+                ..TestState::default().icon
+            },
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(state, Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        let pointer = |x, y| C2S::PointerEvent {
+            button_mask: Buttons(0),
+            x,
+            y,
+        };
+
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(30, 30, 1, 1, 0).await;
+        assert_eq!(
+            read_rects(&mut client).await,
+            [raw(30, 30, 1, 1), raw(0, 0, 2, 2)]
+        );
+
+        client.request(true).await;
+        server.read_request().await;
+        client.send(pointer(20, 10)).await;
+        assert_eq!(server.read().await, pointer(20, 10));
+        assert_eq!(read_rects(&mut client).await, [raw(22, 12, 2, 2)]);
+
+        // the area the icon was drawn on is requested again
+        client.request(true).await;
+        assert_eq!(
+            server.read().await,
+            C2S::FramebufferUpdateRequest {
+                incremental: false,
+                x: 0,
+                y: 0,
+                width: 2,
+                height: 2,
+            }
+        );
+        assert_eq!(server.read().await, request(true, 64));
+
+        // small movements do not move the icon
+        client.send(pointer(21, 11)).await;
+        assert_eq!(server.read().await, pointer(21, 11));
+        assert_silent(&mut client.rx).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn requests_are_neither_lost_nor_answered_twice() {
+        let requests = Arc::new(Requests::default());
+        let (taken_tx, mut taken_rx) = mpsc::channel(1);
+        let updates = tokio::spawn({
+            let requests = requests.clone();
+            async move {
+                for _ in 0..10_000 {
+                    let taken = requests.take().await;
+                    taken_tx.send(taken).await.unwrap();
+                }
+            }
+        });
+
+        soon(async {
+            for i in 0..10_000 {
+                // let the update start waiting first every now and then
+                if i % 3 == 0 {
+                    tokio::task::yield_now().await;
+                }
+                let forwarded = requests.receive();
+                // a forwarded request is taken once the update arrives, otherwise the
+                // waiting update has been granted the request
+                assert_eq!(taken_rx.recv().await, Some(forwarded));
+            }
+        })
+        .await;
+        updates.await.unwrap();
+        assert!(!requests.try_take());
+    }
+
+    #[tokio::test]
+    async fn new_clients_get_the_icon_once_with_the_first_update() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        // changes from before the client connected do not count as changes for it
+        proxy.handle.update_state(|state| {
+            state.icon = Icon::solid(2, 2, [0, 0xff, 0, 0xff]);
+            true
+        });
+        let (mut client, mut server) = proxy.connect().await;
+        assert_silent(&mut client.rx).await;
+        // nor do changes before the first request
+        proxy.handle.update_state(|_| true);
+        assert_silent(&mut client.rx).await;
+
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(30, 30, 1, 1, 0).await;
+        let update = client.read_update().await;
+        assert_eq!(update.len(), 2);
+        assert_eq!(update[1].0, raw(0, 0, 2, 2));
+        assert_eq!(update[1].1, Bytes::from([0, 0xff, 0, 0].repeat(4)));
+
+        client.request(true).await;
+        server.read_request().await;
+        server.send_raw(30, 30, 1, 1, 0).await;
+        assert_eq!(read_rects(&mut client).await, [raw(30, 30, 1, 1)]);
     }
 }
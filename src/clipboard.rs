@@ -0,0 +1,197 @@
+//! A [State] wrapper which keeps the clipboard texts exchanged through the proxy.
+
+use std::collections::VecDeque;
+
+use crate::{
+    ClientId, Event, HandshakeInfo, Icon, Input, PixelFormat, Redraw, ServerInit, State, C2S,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClipboardEntry {
+    pub id: ClientId,
+    pub text: String,
+    /// Whether the server sent the text to the client, rather than the other way around.
+    pub from_server: bool,
+}
+
+/// Wraps another [State], recording the most recent clipboard texts sent by clients or
+/// by the server, e.g. for a shared clipboard history. The entries can be read with
+/// [crate::ProxyHandle::with_state]. Everything else is left to the inner state.
+#[derive(Debug, Clone)]
+pub struct ClipboardHistory<S> {
+    inner: S,
+    capacity: usize,
+    entries: VecDeque<ClipboardEntry>,
+}
+
+impl<S: State> ClipboardHistory<S> {
+    /// Keeps up to `capacity` entries, dropping the oldest ones.
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Returns the recorded entries, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = &ClipboardEntry> {
+        self.entries.iter()
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: State> State for ClipboardHistory<S> {
+    fn icon(&self, id: ClientId) -> Icon {
+        self.inner.icon(id)
+    }
+
+    fn handle_event(&mut self, event: Event) -> Redraw {
+        if let Event::Clipboard {
+            id,
+            text,
+            from_server,
+        } = &event
+        {
+            if self.capacity > 0 {
+                if self.entries.len() == self.capacity {
+                    self.entries.pop_front();
+                }
+                self.entries.push_back(ClipboardEntry {
+                    id: *id,
+                    text: text.clone(),
+                    from_server: *from_server,
+                });
+            }
+            if !self.inner.wants_clipboard_events() {
+                return Redraw::None;
+            }
+        }
+        self.inner.handle_event(event)
+    }
+
+    fn enable_input(&self, id: ClientId) -> bool {
+        self.inner.enable_input(id)
+    }
+
+    fn icon_interactive(&self, id: ClientId) -> bool {
+        self.inner.icon_interactive(id)
+    }
+
+    fn authorize(&self, id: ClientId, info: &HandshakeInfo) -> bool {
+        self.inner.authorize(id, info)
+    }
+
+    fn preferred_pixel_format(&self) -> Option<PixelFormat> {
+        self.inner.preferred_pixel_format()
+    }
+
+    fn wants_encoding_events(&self) -> bool {
+        self.inner.wants_encoding_events()
+    }
+
+    fn wants_clipboard_events(&self) -> bool {
+        true
+    }
+
+    fn forward_bell(&self, id: ClientId) -> bool {
+        self.inner.forward_bell(id)
+    }
+
+    fn redact_input(&self, id: ClientId, input: &mut C2S) {
+        self.inner.redact_input(id, input)
+    }
+
+    fn filter_key(&self, id: ClientId, down: bool, key: u32) -> Option<u32> {
+        self.inner.filter_key(id, down, key)
+    }
+
+    fn handle_input(&mut self, input: &Input) -> Redraw {
+        self.inner.handle_input(input)
+    }
+
+    fn rewrite_server_init(&self, id: ClientId, init: ServerInit) -> ServerInit {
+        self.inner.rewrite_server_init(id, init)
+    }
+
+    fn tick(&mut self) -> Redraw {
+        self.inner.tick()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::Config;
+
+    fn clipboard(id: ClientId, text: &str) -> Event {
+        Event::Clipboard {
+            id,
+            text: text.to_string(),
+            from_server: false,
+        }
+    }
+
+    #[test]
+    fn most_recent_entries_are_kept() {
+        let mut history = ClipboardHistory::new(TestState::default(), 3);
+        for (i, text) in ["a", "b", "c", "d", "e"].into_iter().enumerate() {
+            history.handle_event(clipboard(i, text));
+        }
+        let texts: Vec<_> = history.entries().map(|e| e.text.as_str()).collect();
+        assert_eq!(texts, ["c", "d", "e"]);
+        assert_eq!(history.entries().next().unwrap().id, 2);
+        // the inner state did not ask for them
+        assert!(history.inner().events.is_empty());
+
+        let mut history = ClipboardHistory::new(TestState::default(), 0);
+        history.handle_event(clipboard(0, "a"));
+        assert_eq!(history.entries().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn both_directions_are_recorded() {
+        let history = ClipboardHistory::new(TestState::default(), 10);
+        let proxy = TestProxy::start(history, Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+
+        client.send(C2S::CutText("from client".to_string())).await;
+        server.read().await;
+        server
+            .tx
+            .write_message(crate::rfb::S2C::CutText("from server".to_string()))
+            .await
+            .unwrap();
+        client.read().await;
+
+        let entries = || {
+            proxy
+                .handle
+                .with_state(|h| h.entries().cloned().collect::<Vec<_>>())
+        };
+        eventually(|| entries().len() == 2).await;
+        assert_eq!(
+            entries(),
+            [
+                ClipboardEntry {
+                    id: 0,
+                    text: "from client".to_string(),
+                    from_server: false,
+                },
+                ClipboardEntry {
+                    id: 0,
+                    text: "from server".to_string(),
+                    from_server: true,
+                },
+            ]
+        );
+    }
+}
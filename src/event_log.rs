@@ -0,0 +1,304 @@
+//! A compact binary log of [Event]s, for deployments where a text log grows too large.
+
+use std::{
+    io::{self, Read, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::{ClientId, Encoding, Event};
+
+const MAGIC: &[u8; 4] = b"VOEL";
+const VERSION: u8 = 1;
+/// Time, kind and client id.
+const RECORD_HEADER_LEN: usize = 8 + 1 + 4;
+
+const CONNECT: u8 = 0;
+const ACTION: u8 = 1;
+const ENCODINGS: u8 = 2;
+const CLIPBOARD: u8 = 3;
+const BELL: u8 = 4;
+const DISCONNECT: u8 = 5;
+const SLOW_CLIENT: u8 = 6;
+
+/// An event read back by [read_event_log].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggedEvent {
+    /// When the event was written, truncated to microseconds.
+    pub time: SystemTime,
+    pub event: Event,
+}
+
+/// Writes events to a compact binary log, e.g. a file fed from
+/// [crate::ProxyHandle::events]. Wrap files in a [io::BufWriter].
+///
+/// A log starts with the 4 bytes `VOEL` and a version byte, currently 1, followed by one
+/// record per event. All integers are big-endian. A record is
+///
+/// | bytes | content                                                                                  |
+/// |-------|------------------------------------------------------------------------------------------|
+/// | 4     | length of the rest of the record                                                         |
+/// | 8     | time in microseconds since the Unix epoch                                                |
+/// | 1     | kind: 0 connect, 1 action, 2 encodings, 3 clipboard, 4 bell, 5 disconnect, 6 slow client |
+/// | 4     | client id                                                                                |
+/// | ...   | payload                                                                                  |
+///
+/// Only encodings and clipboard events have a payload. For encodings, it is the encodings
+/// as 4 byte signed integers, as in a `SetEncodings` message. For clipboard events, it is
+/// 1 byte which is 1 if the text came from the server, followed by the text as UTF-8.
+#[derive(Debug)]
+pub struct EventLogWriter<W: Write> {
+    writer: W,
+    buf: BytesMut,
+}
+
+impl<W: Write> EventLogWriter<W> {
+    /// Starts a new log by writing its header.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(Self {
+            writer,
+            buf: BytesMut::new(),
+        })
+    }
+
+    /// Writes an event which happened now.
+    pub fn write(&mut self, event: &Event) -> io::Result<()> {
+        self.write_at(SystemTime::now(), event)
+    }
+
+    pub fn write_at(&mut self, time: SystemTime, event: &Event) -> io::Result<()> {
+        let micros = time
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| invalid_input("event time before the Unix epoch"))?
+            .as_micros();
+        let micros = u64::try_from(micros).map_err(|_| invalid_input("event time too late"))?;
+
+        let (kind, id) = match event {
+            Event::Connect { id } => (CONNECT, id),
+            Event::Action { id } => (ACTION, id),
+            Event::Encodings { id, .. } => (ENCODINGS, id),
+            Event::Clipboard { id, .. } => (CLIPBOARD, id),
+            Event::Bell { id } => (BELL, id),
+            Event::Disconnect { id } => (DISCONNECT, id),
+            Event::SlowClient { id } => (SLOW_CLIENT, id),
+        };
+        let id = u32::try_from(*id).map_err(|_| invalid_input("client id too large"))?;
+
+        self.buf.clear();
+        // the length is filled in once the payload is known
+        self.buf.put_u32(0);
+        self.buf.put_u64(micros);
+        self.buf.put_u8(kind);
+        self.buf.put_u32(id);
+        match event {
+            Event::Encodings { encodings, .. } => {
+                for encoding in encodings {
+                    self.buf.put_i32(encoding.to_i32());
+                }
+            }
+            Event::Clipboard {
+                text, from_server, ..
+            } => {
+                self.buf.put_u8(*from_server as u8);
+                self.buf.put_slice(text.as_bytes());
+            }
+            _ => {}
+        }
+
+        let len = u32::try_from(self.buf.len() - 4)
+            .map_err(|_| invalid_input("event too large for the log"))?;
+        self.buf[..4].copy_from_slice(&len.to_be_bytes());
+        self.writer.write_all(&self.buf)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads a log written by an [EventLogWriter], up to its end.
+pub fn read_event_log(mut reader: impl Read) -> io::Result<Vec<LoggedEvent>> {
+    let mut header = [0; 5];
+    reader.read_exact(&mut header)?;
+    if header[..4] != MAGIC[..] {
+        return Err(invalid_data("not an event log"));
+    }
+    if header[4] != VERSION {
+        return Err(invalid_data(format!(
+            "unsupported event log version {}",
+            header[4]
+        )));
+    }
+
+    let mut events = Vec::new();
+    loop {
+        let mut len = [0; 4];
+        // the log may only end between records
+        match reader.read_exact(&mut len[..1]) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(events),
+            res => res?,
+        }
+        reader.read_exact(&mut len[1..])?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len < RECORD_HEADER_LEN {
+            return Err(invalid_data("event record too short"));
+        }
+
+        let mut record = vec![0; len];
+        reader.read_exact(&mut record)?;
+        events.push(read_record(Bytes::from(record))?);
+    }
+}
+
+fn read_record(mut record: Bytes) -> io::Result<LoggedEvent> {
+    let time = UNIX_EPOCH + Duration::from_micros(record.get_u64());
+    let kind = record.get_u8();
+    let id = record.get_u32() as ClientId;
+    let event = match kind {
+        CONNECT => Event::Connect { id },
+        ACTION => Event::Action { id },
+        ENCODINGS => {
+            if !record.len().is_multiple_of(4) {
+                return Err(invalid_data("truncated encoding"));
+            }
+            let mut encodings = Vec::with_capacity(record.len() / 4);
+            while record.has_remaining() {
+                encodings.push(Encoding::from_i32(record.get_i32()));
+            }
+            Event::Encodings { id, encodings }
+        }
+        CLIPBOARD => {
+            if !record.has_remaining() {
+                return Err(invalid_data("clipboard event without direction"));
+            }
+            let from_server = record.get_u8() != 0;
+            let text = String::from_utf8(record.to_vec())
+                .map_err(|_| invalid_data("clipboard text is not UTF-8"))?;
+            Event::Clipboard {
+                id,
+                text,
+                from_server,
+            }
+        }
+        BELL => Event::Bell { id },
+        DISCONNECT => Event::Disconnect { id },
+        SLOW_CLIENT => Event::SlowClient { id },
+        kind => return Err(invalid_data(format!("unknown event kind {kind}"))),
+    };
+    Ok(LoggedEvent { time, event })
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(events: &[(SystemTime, Event)]) -> Vec<u8> {
+        let mut log = EventLogWriter::new(Vec::new()).unwrap();
+        for (time, event) in events {
+            log.write_at(*time, event).unwrap();
+        }
+        log.into_inner()
+    }
+
+    #[test]
+    fn events_are_read_back() {
+        let time = UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789);
+        let events = [
+            Event::Connect { id: 0 },
+            Event::Action { id: 1 },
+            Event::Encodings {
+                id: 2,
+                encodings: vec![Encoding::Zrle, Encoding::Cursor, Encoding::Unknown(-1000)],
+            },
+            Event::Encodings {
+                id: 2,
+                encodings: vec![],
+            },
+            Event::Clipboard {
+                id: 3,
+                text: "grüß".to_string(),
+                from_server: true,
+            },
+            Event::Clipboard {
+                id: 3,
+                text: String::new(),
+                from_server: false,
+            },
+            Event::Bell { id: 4 },
+            Event::SlowClient { id: 5 },
+            Event::Disconnect {
+                id: u32::MAX as ClientId,
+            },
+        ];
+        let log = write(&events.clone().map(|event| (time, event)));
+
+        let logged = read_event_log(&log[..]).unwrap();
+        let truncated = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let expected = events.map(|event| LoggedEvent {
+            time: truncated,
+            event,
+        });
+        assert_eq!(logged, expected);
+        assert_eq!(read_event_log(&write(&[])[..]).unwrap(), []);
+    }
+
+    #[test]
+    fn records_have_the_documented_layout() {
+        let event = Event::Clipboard {
+            id: 7,
+            text: "hi".to_string(),
+            from_server: true,
+        };
+        let log = write(&[(UNIX_EPOCH + Duration::from_micros(0x0102), event)]);
+        #[rustfmt::skip]
+        assert_eq!(log, [
+            b'V', b'O', b'E', b'L', 1,
+            0, 0, 0, 16,
+            0, 0, 0, 0, 0, 0, 1, 2,
+            3,
+            0, 0, 0, 7,
+            1, b'h', b'i',
+        ]);
+    }
+
+    #[test]
+    fn invalid_logs_are_rejected() {
+        let log = write(&[(UNIX_EPOCH, Event::Bell { id: 1 })]);
+        let error = |log: &[u8]| read_event_log(log).unwrap_err().kind();
+
+        assert_eq!(error(b"VOEX\x01"), io::ErrorKind::InvalidData);
+        assert_eq!(error(b"VOEL\x02"), io::ErrorKind::InvalidData);
+        // cut off within a record
+        assert_eq!(error(&log[..log.len() - 1]), io::ErrorKind::UnexpectedEof);
+        assert_eq!(error(&log[..7]), io::ErrorKind::UnexpectedEof);
+
+        let mut unknown = log.clone();
+        unknown[5 + 4 + 8] = 99;
+        assert_eq!(error(&unknown), io::ErrorKind::InvalidData);
+        let mut short = log;
+        short[5 + 3] = 12;
+        assert_eq!(error(&short), io::ErrorKind::InvalidData);
+
+        let mut writer = EventLogWriter::new(Vec::new()).unwrap();
+        let early = UNIX_EPOCH - Duration::from_secs(1);
+        let res = writer.write_at(early, &Event::Bell { id: 1 });
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let res = writer.write_at(UNIX_EPOCH, &Event::Bell { id: 1 << 32 });
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,438 @@
+use bytes::Bytes;
+
+use crate::rfb::PixelFormat;
+
+/// Width and height of a glyph in the built-in bitmap font.
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Icon {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    pub rgba_data: Bytes,
+    pub anchor: Anchor,
+}
+
+/// What the position of an [Icon] is relative to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Anchor {
+    /// The icon stays at its `x` and `y` coordinates.
+    #[default]
+    Fixed,
+    /// The icon is drawn at an offset from the last known pointer position of the client,
+    /// or at its `x` and `y` coordinates until the pointer has been moved.
+    FollowPointer { dx: i16, dy: i16 },
+    /// The icon replaces the shape of the client's cursor, with the pixel at `hot_x` and
+    /// `hot_y` at the pointer position, so it moves with the pointer without being drawn
+    /// into the framebuffer. Clients which do not support the Cursor pseudo-encoding get
+    /// the icon drawn at that position instead, like with [Anchor::FollowPointer].
+    Cursor { hot_x: u16, hot_y: u16 },
+}
+
+impl Icon {
+    /// Resolves the anchor of the icon into a fixed position, keeping the icon within a
+    /// framebuffer of the given size where possible.
+    pub(crate) fn placed(&self, pointer: Option<(u16, u16)>, (width, height): (u16, u16)) -> Self {
+        let offset = match self.anchor {
+            Anchor::Fixed => None,
+            Anchor::FollowPointer { dx, dy } => Some((dx as i32, dy as i32)),
+            Anchor::Cursor { hot_x, hot_y } => Some((-(hot_x as i32), -(hot_y as i32))),
+        };
+        let (x, y) = match (offset, pointer) {
+            (Some((dx, dy)), Some((px, py))) => {
+                let clip = |pos: u16, offset: i32, size: u16, max: u16| {
+                    let pos = pos as i32 + offset;
+                    pos.min(max as i32 - size as i32).max(0) as u16
+                };
+                (
+                    clip(px, dx, self.width, width),
+                    clip(py, dy, self.height, height),
+                )
+            }
+            _ => (self.x, self.y),
+        };
+        Self {
+            x,
+            y,
+            anchor: Anchor::Fixed,
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn in_bounds(&self, x: u16, y: u16) -> bool {
+        let (x, y) = (x as u32, y as u32);
+        let (ix, iy) = (self.x as u32, self.y as u32);
+        ix <= x && x < ix + self.width as u32 && iy <= y && y < iy + self.height as u32
+    }
+
+    pub(crate) fn intersects(&self, x: u16, y: u16, width: u16, height: u16) -> bool {
+        let (x, y, width, height) = (x as u32, y as u32, width as u32, height as u32);
+        let (ix, iy) = (self.x as u32, self.y as u32);
+        x < ix + self.width as u32
+            && ix < x + width
+            && y < iy + self.height as u32
+            && iy < y + height
+    }
+
+    /// Whether the icon lies entirely within the given rectangle.
+    pub(crate) fn within(&self, x: u16, y: u16, width: u16, height: u16) -> bool {
+        let (x, y, width, height) = (x as u32, y as u32, width as u32, height as u32);
+        let (ix, iy) = (self.x as u32, self.y as u32);
+        x <= ix
+            && ix + self.width as u32 <= x + width
+            && y <= iy
+            && iy + self.height as u32 <= y + height
+    }
+
+    pub(crate) fn is_cursor(&self) -> bool {
+        matches!(self.anchor, Anchor::Cursor { .. })
+    }
+
+    /// Returns the bitmask of a Cursor rectangle, marking the pixels which are at least
+    /// half opaque.
+    pub(crate) fn cursor_mask(&self) -> Vec<u8> {
+        let row_len = (self.width as usize).div_ceil(8);
+        let mut mask = vec![0; row_len * self.height as usize];
+        for (i, pixel) in self.rgba_data.chunks_exact(4).enumerate() {
+            let (x, y) = (i % self.width as usize, i / self.width as usize);
+            if pixel[3] >= 0x80 {
+                mask[y * row_len + x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        mask
+    }
+
+    /// Returns the pixels of the icon in a true colour pixel format.
+    pub(crate) fn pixels(&self, format: &PixelFormat) -> Bytes {
+        let rgba = PixelFormat::rgba8888();
+        if *format == rgba {
+            return self.rgba_data.clone();
+        }
+        Bytes::from(rgba.convert(&self.rgba_data, format))
+    }
+
+    /// Moves the icon to the given position.
+    pub fn at(self, x: u16, y: u16) -> Self {
+        Self { x, y, ..self }
+    }
+
+    /// Makes the icon follow the pointer, drawing it at the given offset from it.
+    pub fn follow_pointer(self, dx: i16, dy: i16) -> Self {
+        Self {
+            anchor: Anchor::FollowPointer { dx, dy },
+            ..self
+        }
+    }
+
+    /// Draws the icon in shades of grey, e.g. to show that it is disabled.
+    pub fn greyed(self) -> Self {
+        let mut data = self.rgba_data.to_vec();
+        for pixel in data.chunks_exact_mut(4) {
+            let [r, g, b, _] = [pixel[0], pixel[1], pixel[2], pixel[3]].map(u32::from);
+            let luma = ((299 * r + 587 * g + 114 * b) / 1000) as u8;
+            pixel[..3].fill(luma);
+        }
+        Self {
+            rgba_data: Bytes::from(data),
+            ..self
+        }
+    }
+
+    /// Shows the icon as the client's cursor, see [Anchor::Cursor].
+    pub fn as_cursor(self, hot_x: u16, hot_y: u16) -> Self {
+        Self {
+            anchor: Anchor::Cursor { hot_x, hot_y },
+            ..self
+        }
+    }
+
+    /// Renders a text badge using the built-in font at twice its native size.
+    pub fn text(text: &str, fg: [u8; 4], bg: [u8; 4]) -> Self {
+        Self::text_scaled(text, 2, fg, bg)
+    }
+
+    /// Renders a text badge using the built-in 5x7 font, with each font pixel
+    /// drawn as a `scale` x `scale` square. Lowercase letters are drawn as
+    /// uppercase, unsupported characters as `?`. Text which does not fit into the
+    /// largest possible icon, 65535 pixels wide, is cut off.
+    pub fn text_scaled(text: &str, scale: usize, fg: [u8; 4], bg: [u8; 4]) -> Self {
+        // one pixel of padding around the text and one pixel between glyphs
+        let rows = GLYPH_HEIGHT + 2;
+        let max = u16::MAX as usize;
+        let scale = scale.min(max / rows);
+        let fitting = (max / scale.max(1) - 1) / (GLYPH_WIDTH + 1);
+        let glyphs: Vec<_> = text.chars().take(fitting).map(glyph).collect();
+        let cols = glyphs.len() * (GLYPH_WIDTH + 1) + 1;
+
+        let (width, height) = (cols * scale, rows * scale);
+        let mut data = Vec::with_capacity(width * height * 4);
+        for py in 0..height {
+            for px in 0..width {
+                let (col, row) = (px / scale, py / scale);
+                let set = (1..=GLYPH_HEIGHT).contains(&row)
+                    && col % (GLYPH_WIDTH + 1) != 0
+                    && glyphs[col / (GLYPH_WIDTH + 1)][row - 1]
+                        & (1 << (GLYPH_WIDTH - col % (GLYPH_WIDTH + 1)))
+                        != 0;
+                data.extend_from_slice(if set { &fg } else { &bg });
+            }
+        }
+
+        Self::from_rgba(width as u16, height as u16, data)
+    }
+
+    /// Renders a rectangle filled with a single colour, e.g. as a placeholder.
+    pub fn solid(width: u16, height: u16, color: [u8; 4]) -> Self {
+        let data = color.repeat(width as usize * height as usize);
+        Self::from_rgba(width, height, data)
+    }
+
+    /// Renders a filled circle of the given radius on a transparent background. The
+    /// radius is limited to 32767 pixels, so the icon is at most 65534 pixels wide.
+    pub fn dot(color: [u8; 4], radius: u16) -> Self {
+        let radius = radius.min(u16::MAX / 2);
+        let size = 2 * radius as usize;
+        let r = radius as isize;
+        let mut data = Vec::with_capacity(size * size * 4);
+        for py in 0..size as isize {
+            for px in 0..size as isize {
+                // measure from the pixel center
+                let (dx, dy) = (2 * (px - r) + 1, 2 * (py - r) + 1);
+                let inside = dx * dx + dy * dy <= 4 * r * r;
+                data.extend_from_slice(if inside { &color } else { &[0; 4] });
+            }
+        }
+
+        Self::from_rgba(size as u16, size as u16, data)
+    }
+
+    fn from_rgba(width: u16, height: u16, data: Vec<u8>) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width,
+            height,
+            rgba_data: Bytes::from(data),
+            anchor: Anchor::Fixed,
+        }
+    }
+}
+
+/// Fails if the image is wider or higher than 65535 pixels.
+#[cfg(feature = "image")]
+impl TryFrom<&image::RgbaImage> for Icon {
+    type Error = std::num::TryFromIntError;
+
+    fn try_from(image: &image::RgbaImage) -> Result<Self, Self::Error> {
+        Ok(Self {
+            x: 0,
+            y: 0,
+            width: image.width().try_into()?,
+            height: image.height().try_into()?,
+            rgba_data: Bytes::copy_from_slice(image.as_raw()),
+            anchor: Anchor::Fixed,
+        })
+    }
+}
+
+/// Returns the rows of a glyph, with the leftmost pixel in bit 4.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '!' => [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FG: [u8; 4] = [0xff; 4];
+    const BG: [u8; 4] = [0, 0, 0, 0xff];
+
+    fn count(icon: &Icon, color: [u8; 4]) -> usize {
+        icon.rgba_data
+            .chunks_exact(4)
+            .filter(|p| *p == color)
+            .count()
+    }
+
+    #[test]
+    fn text_is_drawn_with_the_font() {
+        let icon = Icon::text("AB", FG, BG);
+        assert_eq!((icon.width, icon.height), (26, 18));
+        assert_eq!(icon.rgba_data.len(), 26 * 18 * 4);
+        // 18 pixels of the A and 20 of the B, each drawn as 2x2
+        assert_eq!(count(&icon, FG), 38 * 4);
+        assert_eq!(Icon::text("ab", FG, BG), icon);
+    }
+
+    #[test]
+    fn long_text_is_cut_off() {
+        let icon = Icon::text(&"W".repeat(20_000), FG, BG);
+        assert_eq!((icon.width, icon.height), (65534, 18));
+        assert_eq!(icon.rgba_data.len(), 65534 * 18 * 4);
+
+        let icon = Icon::text_scaled("A", 0, FG, BG);
+        assert_eq!((icon.width, icon.height), (0, 0));
+    }
+
+    #[test]
+    fn solid_icons_repeat_their_colour() {
+        let icon = Icon::solid(2, 2, [1, 2, 3, 4]);
+        assert_eq!((icon.x, icon.y, icon.width, icon.height), (0, 0, 2, 2));
+        assert_eq!(icon.rgba_data, [1, 2, 3, 4].repeat(4));
+        assert_eq!(icon.anchor, Anchor::Fixed);
+        assert!(Icon::solid(0, 5, [1, 2, 3, 4]).rgba_data.is_empty());
+    }
+
+    #[test]
+    fn greyed_icons_keep_their_alpha() {
+        let icon = Icon::solid(2, 1, [0xff, 0, 0, 0x80]).greyed();
+        assert_eq!((icon.width, icon.height), (2, 1));
+        assert_eq!(count(&icon, [76, 76, 76, 0x80]), 2);
+        let icon = Icon::solid(1, 1, [0xff; 4]).greyed();
+        assert_eq!(count(&icon, [0xff; 4]), 1);
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn images_are_converted() {
+        let image = image::RgbaImage::from_pixel(10, 10, image::Rgba([1, 2, 3, 4]));
+        let icon = Icon::try_from(&image).unwrap();
+        assert_eq!((icon.width, icon.height), (10, 10));
+        assert_eq!(icon.rgba_data.len(), 400);
+        assert_eq!(count(&icon, [1, 2, 3, 4]), 100);
+
+        let image = image::RgbaImage::new(65536, 1);
+        assert!(Icon::try_from(&image).is_err());
+    }
+
+    #[test]
+    fn pixels_are_packed_by_the_pixel_format() {
+        let gbr = PixelFormat {
+            red_shift: 0,
+            green_shift: 16,
+            blue_shift: 8,
+            ..PixelFormat::default()
+        };
+        let big_endian = PixelFormat {
+            big_endian: true,
+            ..PixelFormat::default()
+        };
+        let bgr233 = PixelFormat {
+            bits_per_pixel: 8,
+            depth: 8,
+            red_max: 7,
+            green_max: 7,
+            blue_max: 3,
+            red_shift: 0,
+            green_shift: 3,
+            blue_shift: 6,
+            ..PixelFormat::default()
+        };
+        let formats = [
+            PixelFormat::default(),
+            PixelFormat::rgba8888(),
+            PixelFormat::rgb565(),
+            gbr,
+            big_endian,
+            bgr233,
+        ];
+        let colors = [
+            [0, 0, 0],
+            [0xff, 0xff, 0xff],
+            [0xff, 0, 0],
+            [0x12, 0x80, 0xfe],
+        ];
+
+        for format in &formats {
+            for [r, g, b] in colors {
+                let pixel = Icon::solid(1, 1, [r, g, b, 0xff]).pixels(format);
+                assert_eq!(pixel.len(), format.bytes_per_pixel());
+
+                let mut value = 0;
+                for byte in pixel.iter().copied() {
+                    value = value << 8 | byte as u32;
+                }
+                if !format.big_endian {
+                    value = value.swap_bytes() >> (32 - 8 * pixel.len());
+                }
+                let channel = |shift: u8, max: u16| (value >> shift) & max as u32;
+                let scaled = |c: u8, max: u16| (c as u32 * max as u32 + 127) / 255;
+                assert_eq!(
+                    [
+                        channel(format.red_shift, format.red_max),
+                        channel(format.green_shift, format.green_max),
+                        channel(format.blue_shift, format.blue_max),
+                    ],
+                    [
+                        scaled(r, format.red_max),
+                        scaled(g, format.green_max),
+                        scaled(b, format.blue_max),
+                    ],
+                    "{format:?} {r} {g} {b}"
+                );
+            }
+        }
+
+        // TigerVNC's default format
+        let pixel = Icon::solid(1, 1, [1, 2, 3, 0xff]).pixels(&PixelFormat::default());
+        assert_eq!(pixel, &[3, 2, 1, 0][..]);
+    }
+
+    #[test]
+    fn icons_at_the_edge_contain_their_pixels() {
+        let icon = Icon {
+            x: 65530,
+            y: 65530,
+            ..Icon::solid(10, 10, FG)
+        };
+        assert!(icon.in_bounds(65530, 65535));
+        assert!(!icon.in_bounds(65529, 65535));
+        assert!(!icon.in_bounds(65535, 65529));
+    }
+}
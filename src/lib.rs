@@ -1,18 +1,59 @@
-use std::net::SocketAddr;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    future::pending,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
 
-use log::info;
+use log::{info, warn};
 use thiserror::Error;
 use tokio::{
     net::TcpListener,
     select,
-    sync::{mpsc, watch},
+    sync::{broadcast, mpsc, watch, Notify},
+    task::{JoinHandle, JoinSet},
+    time::{interval, Interval, MissedTickBehavior},
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
 };
 
 use client::Client;
-pub use rfb::DecodeError;
+pub use clipboard::{ClipboardEntry, ClipboardHistory};
+pub use event_log::{read_event_log, EventLogWriter, LoggedEvent};
+pub use icon::{Anchor, Icon};
+pub use lock::{Lock, LockPolicy};
+pub use pool::Balance;
+use pool::Pool;
+use rfb::C2S;
+pub use rfb::{supported_encodings, DecodeError, Encoding, PixelFormat, ServerInit};
+use stats::{MessageCounters, OverlayCounters};
+pub use stats::{MessageStats, OverlayStats, SessionInfo};
+pub use zrle::ZrleDecoder;
 
 mod client;
-mod rfb;
+mod clipboard;
+mod event_log;
+mod icon;
+mod lock;
+mod mirror;
+mod pool;
+mod proxy_protocol;
+pub mod rfb;
+#[cfg(target_os = "linux")]
+mod splice;
+mod stats;
+#[cfg(test)]
+mod testing;
+mod tight;
+mod zrle;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -20,37 +61,454 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("could not decode message")]
     Decode(#[from] DecodeError),
+    /// A decode error along with the bytes the message started with, see
+    /// [Config::capture_on_error].
+    #[error("could not decode message starting with {bytes}: {source}")]
+    DecodeCaptured { source: DecodeError, bytes: String },
     #[error("Protocol error: {0}")]
     Protocol(String),
+    #[error("connection closed after {got} of {expected} payload bytes")]
+    TruncatedPayload { expected: usize, got: usize },
+    #[error("Handshake failed in {phase:?} phase: {detail}")]
+    Handshake {
+        phase: HandshakePhase,
+        detail: String,
+    },
+    #[error("invalid configuration: {0}")]
+    Config(String),
+}
+
+impl Error {
+    fn in_phase(self, phase: HandshakePhase) -> Self {
+        let detail = match self {
+            Error::Handshake { .. } => return self,
+            Error::Io(e) => e.to_string(),
+            Error::Decode(e) => e.to_string(),
+            e @ Error::DecodeCaptured { .. } => e.to_string(),
+            Error::Protocol(msg) => msg,
+            e @ Error::TruncatedPayload { .. } => e.to_string(),
+            e @ Error::Config(_) => e.to_string(),
+        };
+        Error::Handshake { phase, detail }
+    }
+
+    /// Whether the error is the peer closing the connection.
+    fn is_disconnect(&self) -> bool {
+        match self {
+            Error::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::BrokenPipe
+            ),
+            Error::TruncatedPayload { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakePhase {
+    Version,
+    Security,
+    Init,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Number of bytes included in an [Error::DecodeCaptured].
+const CAPTURED_BYTES: usize = 64;
+
+/// Identifies a client connection. Ids are assigned in increasing order and never reused,
+/// unless [Config::recycle_client_ids] is set.
 pub type ClientId = usize;
 
 pub trait State: Send + Sync + 'static {
     fn icon(&self, id: ClientId) -> Icon;
-    fn handle_event(&mut self, event: Event) -> bool;
+    /// Updates the state after an event and returns which clients need their icon redrawn.
+    fn handle_event(&mut self, event: Event) -> Redraw;
     fn enable_input(&self, id: ClientId) -> bool;
+
+    /// Whether clicks on the icon of a client are turned into [Event::Action]s. Otherwise
+    /// the icon is only decoration and clicks on it are forwarded like any other.
+    fn icon_interactive(&self, _id: ClientId) -> bool {
+        true
+    }
+
+    /// Called once the client has passed the security handshake, before it is told the
+    /// result, and again with [HandshakeInfo::shared] once it has sent its `ClientInit`.
+    /// Returning `false` closes the connection. The first time, the client is told that
+    /// it was rejected if its protocol version has a way to say so, which is too late once
+    /// the result has been sent.
+    fn authorize(&self, _id: ClientId, _info: &HandshakeInfo) -> bool {
+        true
+    }
+
+    /// Pixel format to request from the server instead of the true colour format requested
+    /// by the client. Pixel data is converted before being forwarded, which rules out ZRLE.
+    fn preferred_pixel_format(&self) -> Option<PixelFormat> {
+        None
+    }
+
+    /// Whether to receive an [Event::Encodings] whenever a client sends its encodings.
+    fn wants_encoding_events(&self) -> bool {
+        false
+    }
+
+    /// Whether to receive an [Event::Clipboard] whenever clipboard text is exchanged.
+    fn wants_clipboard_events(&self) -> bool {
+        false
+    }
+
+    /// Whether to pass a bell from the server on to a client, e.g. to mute some clients.
+    /// An [Event::Bell] is sent either way.
+    fn forward_bell(&self, _id: ClientId) -> bool {
+        true
+    }
+
+    /// Called before input is passed to [ProxyHandle::inputs] subscribers, e.g. to blank
+    /// out keys typed into a password field. What is forwarded to the server is unchanged.
+    fn redact_input(&self, _id: ClientId, _input: &mut C2S) {}
+
+    /// Called for each key a client presses or releases, e.g. to block the Super key in a
+    /// kiosk. Returning `None` drops the key, `Some` forwards it as the returned keysym.
+    ///
+    /// A release is only filtered if the press was not forwarded. Otherwise the keysym the
+    /// press was forwarded as is released, so keys such as modifiers which made it to the
+    /// server are never left held when a later key of a combination is dropped.
+    fn filter_key(&self, _id: ClientId, _down: bool, key: u32) -> Option<u32> {
+        Some(key)
+    }
+
+    /// Called with the same input as [ProxyHandle::inputs], e.g. to notice idle clients.
+    /// Input arriving faster than it is handled is skipped.
+    fn handle_input(&mut self, _input: &Input) -> Redraw {
+        Redraw::None
+    }
+
+    /// Rewrites the `ServerInit` before it is sent to the client, after
+    /// [Config::overlay_margin] and [Config::server_name_override] have been applied.
+    ///
+    /// A changed true colour pixel format is requested from the server in turn. A larger
+    /// framebuffer only pads the screen like the overlay margin does. In mirror mode, the
+    /// framebuffer size of the session is kept.
+    fn rewrite_server_init(&self, _id: ClientId, init: ServerInit) -> ServerInit {
+        init
+    }
+
+    /// Called every [Config::tick_interval], e.g. to show a countdown or a blinking icon.
+    /// Ticks and events are handled one after the other.
+    fn tick(&mut self) -> Redraw {
+        Redraw::None
+    }
+}
+
+/// Allows proxies with different kinds of states to run side by side, see [run_proxies].
+impl State for Box<dyn State> {
+    fn icon(&self, id: ClientId) -> Icon {
+        (**self).icon(id)
+    }
+
+    fn handle_event(&mut self, event: Event) -> Redraw {
+        (**self).handle_event(event)
+    }
+
+    fn enable_input(&self, id: ClientId) -> bool {
+        (**self).enable_input(id)
+    }
+
+    fn icon_interactive(&self, id: ClientId) -> bool {
+        (**self).icon_interactive(id)
+    }
+
+    fn authorize(&self, id: ClientId, info: &HandshakeInfo) -> bool {
+        (**self).authorize(id, info)
+    }
+
+    fn preferred_pixel_format(&self) -> Option<PixelFormat> {
+        (**self).preferred_pixel_format()
+    }
+
+    fn wants_encoding_events(&self) -> bool {
+        (**self).wants_encoding_events()
+    }
+
+    fn wants_clipboard_events(&self) -> bool {
+        (**self).wants_clipboard_events()
+    }
+
+    fn forward_bell(&self, id: ClientId) -> bool {
+        (**self).forward_bell(id)
+    }
+
+    fn redact_input(&self, id: ClientId, input: &mut C2S) {
+        (**self).redact_input(id, input)
+    }
+
+    fn filter_key(&self, id: ClientId, down: bool, key: u32) -> Option<u32> {
+        (**self).filter_key(id, down, key)
+    }
+
+    fn handle_input(&mut self, input: &Input) -> Redraw {
+        (**self).handle_input(input)
+    }
+
+    fn rewrite_server_init(&self, id: ClientId, init: ServerInit) -> ServerInit {
+        (**self).rewrite_server_init(id, init)
+    }
+
+    fn tick(&mut self) -> Redraw {
+        (**self).tick()
+    }
+}
+
+/// Clients whose icon may have changed after [State::handle_event].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redraw {
+    All,
+    Only(Vec<ClientId>),
+    None,
 }
 
-#[derive(Debug)]
+impl From<bool> for Redraw {
+    fn from(changed: bool) -> Self {
+        if changed {
+            Redraw::All
+        } else {
+            Redraw::None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeInfo {
+    /// Address of the client, as reported by the load balancer if
+    /// [Config::expect_proxy_protocol] is set.
+    pub peer_addr: SocketAddr,
+    /// The protocol version chosen by the client as `(major, minor)`.
+    pub version: (u16, u16),
+    pub security_type: u8,
+    /// Whether the client asked to share the desktop with other clients, or `None` if it
+    /// has not sent its `ClientInit` yet, see [State::authorize].
+    pub shared: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
-    Action { id: ClientId },
-    Disconnect { id: ClientId },
+    Connect {
+        id: ClientId,
+    },
+    Action {
+        id: ClientId,
+    },
+    /// The encodings supported by a client, as sent by the client and before being
+    /// replaced by the ones the proxy supports. Only sent if
+    /// [State::wants_encoding_events] returns `true`.
+    Encodings {
+        id: ClientId,
+        encodings: Vec<Encoding>,
+    },
+    /// Clipboard text sent by a client, or by the server to a client. Only sent if
+    /// [State::wants_clipboard_events] returns `true`.
+    Clipboard {
+        id: ClientId,
+        text: String,
+        from_server: bool,
+    },
+    /// The server rang the bell of a client. Not sent in mirror mode, where bells are
+    /// ignored.
+    Bell {
+        id: ClientId,
+    },
+    /// Sent once the connection of a client is closed, also if it never completed the
+    /// handshake. This is always the last event for a client id.
+    Disconnect {
+        id: ClientId,
+    },
+    /// Sending data to a client took longer than [Config::slow_write_threshold], e.g.
+    /// because its connection is congested. Sent again only after a write was fast.
+    SlowClient {
+        id: ClientId,
+    },
+}
+
+/// Keyboard, pointer or clipboard input received from a client, see [ProxyHandle::inputs].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Input {
+    pub id: ClientId,
+    pub time: SystemTime,
+    /// A `KeyEvent`, `PointerEvent` or `CutText` message, after [State::redact_input].
+    pub message: C2S,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Drop framebuffer update requests identical to one the server has not yet replied to.
+    pub coalesce_requests: bool,
+    /// Do not advertise the Cursor pseudo-encoding to the server, so the cursor is drawn
+    /// into the framebuffer below the icon instead of by the client on top of it.
+    /// This makes the cursor lag behind the pointer by a round trip to the server.
+    pub strip_cursor: bool,
+    /// Reuse the ids of disconnected clients, smallest first. An id is only reused after
+    /// its [Event::Disconnect] has been handled.
+    pub recycle_client_ids: bool,
+    /// Time a client has to complete the handshake before it is disconnected.
+    pub handshake_timeout: Duration,
+    /// Limits the number of framebuffer updates sent to a client per second by merging
+    /// updates arriving in between.
+    pub max_updates_per_sec: Option<u32>,
+    /// Experimental: height of a strip added above the server's framebuffer, which is
+    /// reserved for icons so they never cover or get overwritten by the server's content.
+    /// Icon coordinates are relative to the enlarged framebuffer. Not supported in mirror
+    /// mode.
+    pub overlay_margin: u16,
+    /// Desktop name sent to clients instead of the server's. Characters outside ASCII are
+    /// replaced by `?`, as the name is meant to be Latin-1 but some clients expect UTF-8.
+    pub server_name_override: Option<String>,
+    /// Holds back framebuffer update requests of a client while more than this many bytes
+    /// read from the server are still waiting to be written to it, so a slow client makes
+    /// the proxy stop reading from the server instead of piling up updates.
+    pub max_client_backlog: Option<usize>,
+    /// Never draw the icon, turning the proxy into a transparent one which still reports
+    /// connects and disconnects and applies [State::enable_input].
+    pub disable_overlay: bool,
+    /// Expect every connection to start with a PROXY protocol header (version 1 or 2), as
+    /// sent by load balancers to pass on the address of the original client.
+    pub expect_proxy_protocol: bool,
+    /// Message shown to clients disconnected by [ProxyHandle::disconnect] or
+    /// [Config::max_session_duration], both as an icon and as clipboard text, for
+    /// [Config::farewell_grace] before the connection is closed.
+    pub farewell_message: Option<String>,
+    pub farewell_grace: Duration,
+    /// Security types offered to clients, in order of preference, e.g. `vec![2]` to only
+    /// allow VNC authentication. Types the server does not offer are left out. By default,
+    /// all types offered by the server which the proxy can relay are offered, currently
+    /// None (1) and VNC authentication (2). Not supported in mirror mode, whose clients
+    /// always use None.
+    pub security_types: Option<Vec<u8>>,
+    /// Time after which a client is disconnected, no matter whether it is active.
+    pub max_session_duration: Option<Duration>,
+    /// Number of colour map entries a server may send at once before its connection is
+    /// closed. The proxy only keeps track of the first 256 entries, which are all an
+    /// 8 bit pixel can refer to, and rejects updates beyond them anyway.
+    pub max_color_map_entries: u16,
+    /// Longest clipboard text forwarded in either direction, in bytes. Longer text is
+    /// handled according to [Config::cut_text_overflow] without being buffered.
+    pub max_cut_text_len: Option<usize>,
+    pub cut_text_overflow: CutTextOverflow,
+    /// Only count a click on the icon if the button was also pressed on it, like with a
+    /// real button. Otherwise a drag ending on the icon counts as well.
+    pub require_press_in_bounds: bool,
+    /// Only let the server send Raw rectangles, apart from pseudo-encodings such as Cursor,
+    /// e.g. to work around a server with a broken encoder.
+    pub force_raw: bool,
+    /// Draw the icon in shades of grey for clients whose input is disabled by
+    /// [State::enable_input], so that view-only clients can tell.
+    pub grey_view_only: bool,
+    /// Include the first bytes of a message which cannot be decoded in the error, as hex,
+    /// to help reproduce interoperability problems. Off by default, as the bytes may
+    /// contain anything a client typed.
+    pub capture_on_error: bool,
+    /// Time a client or the server may take to send the rest of a message once it has
+    /// started sending it, e.g. clipboard text shorter than its declared length, before the
+    /// connection is closed. Idle connections are not affected.
+    pub read_timeout: Option<Duration>,
+    /// Where the icon goes among the server's rectangles of an update.
+    pub icon_z_order: IconZOrder,
+    /// How long closing a connection to a client or to the server may block the closing
+    /// thread to deliver data still unsent (`SO_LINGER`). The system default applies if
+    /// not set, which delivers the data in the background.
+    pub linger: Option<Duration>,
+    /// How often [State::tick] is called, never by default.
+    pub tick_interval: Option<Duration>,
+    /// How clients are distributed across the servers given to [run_proxy_with_backends].
+    pub balance: Balance,
+    /// RFB version to use with the server, e.g. `(3, 8)`, instead of the one chosen by the
+    /// client. The server has to support it. Differences in the security handshake are
+    /// translated, so a 3.3 client can connect through a 3.8 connection to the server.
+    /// Not supported in mirror mode, which always uses the version offered by the server.
+    pub backend_version: Option<(u8, u8)>,
+    /// Do not draw the icon for clients whose input is disabled by [State::enable_input],
+    /// so they see the bare session. The icon shows up again once input is enabled and
+    /// the state asks for a redraw. Takes precedence over [Config::grey_view_only].
+    pub hide_icon_when_view_only: bool,
+    /// Forward the pixels of Raw rectangles too large to be buffered with `splice(2)`,
+    /// which saves copying them through the proxy. Only used on Linux, and only while the
+    /// client and the server use the same pixel format.
+    pub splice_raw: bool,
+    /// Time after which writing to a client counts as slow, which is logged and reported
+    /// as [Event::SlowClient]. For a spliced rectangle, see [Config::splice_raw], the time
+    /// spent writing all of its pixels counts.
+    pub slow_write_threshold: Option<Duration>,
+}
+
+/// When the icon is drawn within an update, see [Config::icon_z_order].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IconZOrder {
+    /// Before the server's rectangles, unless one of them may cover the icon, in which case
+    /// it is drawn like with [IconZOrder::Last] so it is not painted over.
+    First,
+    /// Right after the last rectangle covering the icon if that contains all of it,
+    /// otherwise after all of the server's rectangles.
+    #[default]
+    Last,
+}
+
+impl Config {
+    /// Number of bytes of an undecodable message to include in the error.
+    pub(crate) fn capture_len(&self) -> usize {
+        if self.capture_on_error {
+            CAPTURED_BYTES
+        } else {
+            0
+        }
+    }
 }
 
-pub struct Icon {
-    pub x: u16,
-    pub y: u16,
-    pub width: u16,
-    pub height: u16,
-    pub rgba_data: &'static [u8],
+/// What to do with clipboard text longer than [Config::max_cut_text_len].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CutTextOverflow {
+    /// Forward the beginning of the text, up to the limit.
+    #[default]
+    Truncate,
+    /// Drop the message.
+    Reject,
 }
 
-impl Icon {
-    fn in_bounds(&self, x: u16, y: u16) -> bool {
-        self.x <= x && x < self.x + self.width && self.y <= y && y < self.y + self.height
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            coalesce_requests: false,
+            strip_cursor: false,
+            recycle_client_ids: false,
+            handshake_timeout: Duration::from_secs(30),
+            max_updates_per_sec: None,
+            overlay_margin: 0,
+            server_name_override: None,
+            max_client_backlog: None,
+            disable_overlay: false,
+            expect_proxy_protocol: false,
+            farewell_message: None,
+            farewell_grace: Duration::from_secs(3),
+            security_types: None,
+            max_session_duration: None,
+            max_color_map_entries: 256,
+            max_cut_text_len: None,
+            cut_text_overflow: CutTextOverflow::default(),
+            require_press_in_bounds: true,
+            force_raw: false,
+            grey_view_only: false,
+            capture_on_error: false,
+            read_timeout: None,
+            icon_z_order: IconZOrder::default(),
+            linger: None,
+            tick_interval: None,
+            balance: Balance::default(),
+            backend_version: None,
+            hide_icon_when_view_only: false,
+            splice_raw: false,
+            slow_write_threshold: None,
+        }
     }
 }
 
@@ -59,34 +517,1402 @@ pub async fn run_proxy<S: State>(
     dest_addr: SocketAddr,
     initial: S,
 ) -> Result<()> {
-    let listener = TcpListener::bind(proxy_addr).await?;
-
-    let mut client_counter = 0;
-    let (event_tx, mut event_rx) = mpsc::channel(16);
-    let (state_tx, state_rx) = watch::channel(initial);
-
-    loop {
-        select! {
-            incoming = listener.accept() => {
-                let (stream, _) = incoming?;
-                info!("Connection from {}", stream.peer_addr()?);
-                let event_tx = event_tx.clone();
-                let state_rx = state_rx.clone();
-                let id = client_counter;
-                client_counter += 1;
-
-                tokio::spawn(async move {
-                    let client = Client {
-                        id,
-                        event_tx,
-                        state_rx,
+    run_proxy_with_config(proxy_addr, dest_addr, initial, Config::default()).await
+}
+
+pub async fn run_proxy_with_config<S: State>(
+    proxy_addr: SocketAddr,
+    dest_addr: SocketAddr,
+    initial: S,
+    config: Config,
+) -> Result<()> {
+    Proxy::bind(proxy_addr, dest_addr, initial, config)
+        .await?
+        .run()
+        .await
+}
+
+/// Connects each client to one of several servers showing the same content, picked
+/// according to [Config::balance]. Fails with [Error::Config] if `backends` is empty.
+pub async fn run_proxy_with_backends<S: State>(
+    proxy_addr: SocketAddr,
+    backends: Vec<SocketAddr>,
+    initial: S,
+) -> Result<()> {
+    run_proxy_with_backends_and_config(proxy_addr, backends, initial, Config::default()).await
+}
+
+pub async fn run_proxy_with_backends_and_config<S: State>(
+    proxy_addr: SocketAddr,
+    backends: Vec<SocketAddr>,
+    initial: S,
+    config: Config,
+) -> Result<()> {
+    Proxy::bind_backends(proxy_addr, backends, initial, config)
+        .await?
+        .run()
+        .await
+}
+
+/// Listens on several addresses, given as `(proxy_addr, state, dest_addr)`, each with its
+/// own state, e.g. a port with full input next to a view only one for the same server.
+/// Client ids are unique across all addresses. Returns once any of the proxies fails.
+pub async fn run_proxies(bindings: Vec<(SocketAddr, Box<dyn State>, SocketAddr)>) -> Result<()> {
+    run_proxies_with_config(bindings, Config::default()).await
+}
+
+pub async fn run_proxies_with_config(
+    bindings: Vec<(SocketAddr, Box<dyn State>, SocketAddr)>,
+    config: Config,
+) -> Result<()> {
+    let mut proxies: Vec<Proxy<Box<dyn State>>> = Vec::new();
+    for (proxy_addr, initial, dest_addr) in bindings {
+        let mut proxy = Proxy::bind(proxy_addr, dest_addr, initial, config.clone()).await?;
+        if let Some(first) = proxies.first() {
+            proxy.share_client_ids(&first.handle);
+        }
+        proxies.push(proxy);
+    }
+
+    let mut tasks = JoinSet::new();
+    for proxy in proxies {
+        tasks.spawn(proxy.run());
+    }
+    while let Some(res) = tasks.join_next().await {
+        res.unwrap()?;
+    }
+    Ok(())
+}
+
+/// Shares a single connection to the server among all clients, which can only watch.
+/// Returns an error once the connection to the server is lost.
+pub async fn run_proxy_mirror<S: State>(
+    proxy_addr: SocketAddr,
+    dest_addr: SocketAddr,
+    initial: S,
+) -> Result<()> {
+    run_proxy_mirror_with_config(proxy_addr, dest_addr, initial, Config::default()).await
+}
+
+pub async fn run_proxy_mirror_with_config<S: State>(
+    proxy_addr: SocketAddr,
+    dest_addr: SocketAddr,
+    initial: S,
+    config: Config,
+) -> Result<()> {
+    Proxy::bind_mirror(proxy_addr, dest_addr, initial, config)
+        .await?
+        .run()
+        .await
+}
+
+/// Where the connections of clients go.
+#[derive(Clone)]
+pub(crate) enum Target {
+    /// Each client gets its own connection to the server.
+    Direct(SocketAddr),
+    /// All clients watch the same session.
+    Mirror(Arc<mirror::Session>),
+    /// Each client gets its own connection to one of several servers.
+    Pool(Arc<Pool>),
+}
+
+pub struct Proxy<S: State> {
+    listener: TcpListener,
+    target: Target,
+    config: Arc<Config>,
+    handle: ProxyHandle<S>,
+    mirror_task: Option<JoinHandle<Result<()>>>,
+}
+
+impl<S: State> Proxy<S> {
+    pub async fn bind(
+        proxy_addr: SocketAddr,
+        dest_addr: SocketAddr,
+        initial: S,
+        config: Config,
+    ) -> Result<Self> {
+        Self::bind_target(proxy_addr, Target::Direct(dest_addr), initial, config).await
+    }
+
+    /// Distributes clients across several servers, see [run_proxy_with_backends].
+    /// Fails with [Error::Config] if `backends` is empty.
+    pub async fn bind_backends(
+        proxy_addr: SocketAddr,
+        backends: Vec<SocketAddr>,
+        initial: S,
+        config: Config,
+    ) -> Result<Self> {
+        let pool = Arc::new(Pool::new(backends, config.balance)?);
+        Self::bind_target(proxy_addr, Target::Pool(pool), initial, config).await
+    }
+
+    /// Connects to the server right away and shares that connection among all clients,
+    /// see [run_proxy_mirror]. Fails with [Error::Config] if [Config::backend_version],
+    /// [Config::security_types] or [Config::overlay_margin] is set, which mirror mode
+    /// does not support.
+    pub async fn bind_mirror(
+        proxy_addr: SocketAddr,
+        dest_addr: SocketAddr,
+        initial: S,
+        config: Config,
+    ) -> Result<Self> {
+        let unsupported = [
+            ("backend_version", config.backend_version.is_some()),
+            ("security_types", config.security_types.is_some()),
+            ("overlay_margin", config.overlay_margin != 0),
+        ];
+        if let Some((option, _)) = unsupported.iter().find(|(_, set)| *set) {
+            return Err(Error::Config(format!(
+                "{option} is not supported when mirroring"
+            )));
+        }
+        let (session, task) = mirror::Session::connect(dest_addr, &config).await?;
+        let mut proxy =
+            Self::bind_target(proxy_addr, Target::Mirror(session), initial, config).await?;
+        proxy.mirror_task = Some(task);
+        Ok(proxy)
+    }
+
+    async fn bind_target(
+        proxy_addr: SocketAddr,
+        target: Target,
+        initial: S,
+        config: Config,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(proxy_addr).await?;
+        let (events_tx, _) = broadcast::channel(64);
+        let (inputs_tx, _) = broadcast::channel(256);
+        // no receiver is kept around: the sender updates the state even while no client
+        // is connected, and each client subscribes on connect, starting from the latest state
+        let (state_tx, _) = watch::channel(initial);
+        let client_ids = ClientIdAllocator {
+            recycle: config.recycle_client_ids,
+            ..Default::default()
+        };
+        Ok(Self {
+            listener,
+            target,
+            config: Arc::new(config),
+            handle: ProxyHandle {
+                state_tx,
+                events_tx,
+                inputs_tx,
+                stopped: Default::default(),
+                client_ids: Arc::new(Mutex::new(client_ids)),
+                clients: Default::default(),
+                overlay_total: Default::default(),
+            },
+            mirror_task: None,
+        })
+    }
+
+    pub fn handle(&self) -> ProxyHandle<S> {
+        self.handle.clone()
+    }
+
+    /// Returns the address the proxy listens on, e.g. to find out the port chosen by the
+    /// system when binding to port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Takes client ids from the same pool as another proxy, so ids are unique across both.
+    /// Should be called before any client has connected.
+    pub fn share_client_ids<T: State>(&mut self, other: &ProxyHandle<T>) {
+        self.handle.client_ids = other.client_ids.clone();
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        let mut inputs = self.handle.inputs_tx.subscribe();
+        let state_tx = self.handle.state_tx.clone();
+
+        let mirror_task = self.mirror_task.take();
+        let mirror_closed = async {
+            match mirror_task {
+                Some(task) => task.await.unwrap(),
+                None => pending().await,
+            }
+        };
+        tokio::pin!(mirror_closed);
+
+        let mut ticks = self.config.tick_interval.map(|period| {
+            let mut ticks = interval(period);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticks
+        });
+
+        loop {
+            select! {
+                incoming = self.listener.accept() => {
+                    let (stream, _) = incoming?;
+                    info!("Connection from {}", stream.peer_addr()?);
+                    let event_tx = event_tx.clone();
+                    // a fresh receiver, so changes from before the client connected
+                    // are not mistaken for changes to what the client has seen
+                    let state_rx = state_tx.subscribe();
+                    let config = self.config.clone();
+                    let target = self.target.clone();
+                    let id = self.handle.client_ids.lock().unwrap().allocate();
+                    let entry = ClientEntry::default();
+                    let counters = entry.counters.clone();
+                    let overlay = entry.overlay.clone();
+                    let overlay_total = self.handle.overlay_total.clone();
+                    let shutdown = entry.shutdown.clone();
+                    let redraw = entry.redraw.clone();
+                    let farewell = Default::default();
+                    let events_closed = Default::default();
+                    let inputs_tx = self.handle.inputs_tx.clone();
+                    self.handle.clients.lock().unwrap().insert(id, entry);
+
+                    tokio::spawn(async move {
+                        let client = Client {
+                            id,
+                            event_tx,
+                            state_rx,
+                            config,
+                            counters,
+                            shutdown,
+                            redraw,
+                            farewell,
+                            events_closed,
+                            inputs_tx,
+                            overlay,
+                            overlay_total,
+                        };
+                        match client.handle(stream, target).await {
+                            Ok(()) => {}
+                            Err(e) if e.is_disconnect() => info!("Client {id} disconnected"),
+                            Err(e) => warn!("Client {id} failed: {e}"),
+                        }
+                    });
+                }
+                Some(event) = event_rx.recv() => {
+                    let released = match event {
+                        Event::Disconnect { id } => Some(id),
+                        _ => None,
                     };
-                    client.handle(stream, dest_addr).await.unwrap();
-                });
+
+                    // there may be no subscribers
+                    let _ = self.handle.events_tx.send(Some(event.clone()));
+                    self.handle.modify_state(|state| state.handle_event(event));
+
+                    if let Some(id) = released {
+                        self.handle.clients.lock().unwrap().remove(&id);
+                        self.handle.client_ids.lock().unwrap().release(id);
+                    }
+                }
+                // lagging behind skips input
+                Ok(Some(input)) = inputs.recv() => {
+                    self.handle.modify_state(|state| state.handle_input(&input));
+                }
+                _ = next_tick(&mut ticks) => self.handle.modify_state(|state| state.tick()),
+                res = &mut mirror_closed => return res,
             }
-            Some(event) = event_rx.recv() => {
-                state_tx.send_if_modified(|state| state.handle_event(event));
+        }
+    }
+}
+
+impl<S: State> Drop for Proxy<S> {
+    fn drop(&mut self) {
+        // ends the streams of subscribers, which keep the channels open through their handles
+        self.handle.stopped.store(true, Ordering::Release);
+        let _ = self.handle.events_tx.send(None);
+        let _ = self.handle.inputs_tx.send(None);
+    }
+}
+
+/// Waits for the next tick, forever if there are none.
+async fn next_tick(ticks: &mut Option<Interval>) {
+    match ticks {
+        Some(ticks) => {
+            ticks.tick().await;
+        }
+        None => pending().await,
+    }
+}
+
+/// A handle to a running [Proxy] which can be used from other tasks.
+pub struct ProxyHandle<S: State> {
+    state_tx: watch::Sender<S>,
+    /// `None` tells subscribers that the proxy has stopped.
+    events_tx: broadcast::Sender<Option<Event>>,
+    inputs_tx: broadcast::Sender<Option<Input>>,
+    stopped: Arc<AtomicBool>,
+    client_ids: Arc<Mutex<ClientIdAllocator>>,
+    clients: Arc<Mutex<HashMap<ClientId, ClientEntry>>>,
+    overlay_total: Arc<OverlayCounters>,
+}
+
+impl<S: State> Clone for ProxyHandle<S> {
+    fn clone(&self) -> Self {
+        Self {
+            state_tx: self.state_tx.clone(),
+            events_tx: self.events_tx.clone(),
+            inputs_tx: self.inputs_tx.clone(),
+            stopped: self.stopped.clone(),
+            client_ids: self.client_ids.clone(),
+            clients: self.clients.clone(),
+            overlay_total: self.overlay_total.clone(),
+        }
+    }
+}
+
+impl<S: State> fmt::Debug for ProxyHandle<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyHandle")
+            .field("events_tx", &self.events_tx)
+            .field("inputs_tx", &self.inputs_tx)
+            .field("client_ids", &self.client_ids)
+            .field("clients", &self.clients)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: State> ProxyHandle<S> {
+    /// Updates the state through `f` and tells the clients named by the returned
+    /// [Redraw] to draw their icon again.
+    fn modify_state(&self, f: impl FnOnce(&mut S) -> Redraw) {
+        // the state is updated either way, but only the clients affected are told
+        let mut redraw = Redraw::None;
+        self.state_tx.send_if_modified(|state| {
+            redraw = f(state);
+            redraw == Redraw::All
+        });
+        if let Redraw::Only(ids) = redraw {
+            let clients = self.clients.lock().unwrap();
+            for client in ids.iter().filter_map(|id| clients.get(id)) {
+                client.redraw.notify_one();
             }
         }
     }
+
+    /// Reads the current state. The state cannot change while `f` runs, so `f` should
+    /// return quickly.
+    pub fn with_state<R>(&self, f: impl FnOnce(&S) -> R) -> R {
+        f(&self.state_tx.borrow())
+    }
+
+    /// Modifies the state, bypassing [State::handle_event]. If `f` returns `true`, the
+    /// icons of all clients are updated, like after a handled event.
+    pub fn update_state(&self, f: impl FnOnce(&mut S) -> bool) {
+        self.state_tx.send_if_modified(f);
+    }
+
+    /// Returns the number of messages exchanged so far by a connected client.
+    pub fn message_stats(&self, id: ClientId) -> Option<MessageStats> {
+        let clients = self.clients.lock().unwrap();
+        clients.get(&id).map(|c| c.counters.snapshot())
+    }
+
+    /// Returns what the overlay did for a connected client so far.
+    pub fn overlay_stats(&self, id: ClientId) -> Option<OverlayStats> {
+        let clients = self.clients.lock().unwrap();
+        clients.get(&id).map(|c| c.overlay.snapshot())
+    }
+
+    /// Returns what the overlay did for all clients so far, including disconnected ones.
+    pub fn total_overlay_stats(&self) -> OverlayStats {
+        self.overlay_total.snapshot()
+    }
+
+    /// Returns when a connected client completed its handshake and when it was last active,
+    /// e.g. to disconnect idle clients.
+    pub fn session_info(&self, id: ClientId) -> Option<SessionInfo> {
+        let clients = self.clients.lock().unwrap();
+        clients.get(&id).and_then(|c| c.counters.session_info())
+    }
+
+    /// Closes the connection of a client, which results in an [Event::Disconnect].
+    /// Does nothing if the client is already gone.
+    pub fn disconnect(&self, id: ClientId) {
+        if let Some(client) = self.clients.lock().unwrap().get(&id) {
+            client.shutdown.notify_one();
+        }
+    }
+
+    pub fn client_ids(&self) -> ClientIds {
+        let client_ids = self.client_ids.lock().unwrap();
+        ClientIds {
+            max: client_ids.next,
+            free: client_ids.free.len(),
+        }
+    }
+
+    /// Subscribes to all events from now on, independently of the [State]. Events are
+    /// skipped if the subscriber falls too far behind. The stream ends once the proxy has
+    /// stopped.
+    pub fn events(&self) -> impl Stream<Item = Event> + Send + Unpin + 'static {
+        self.subscribe(&self.events_tx, "events")
+    }
+
+    /// Subscribes to the keyboard, pointer and clipboard input of all clients from now on,
+    /// e.g. to keep an audit log. Input is recorded as received, also if it is not
+    /// forwarded to the server. Input is skipped if the subscriber falls too far behind.
+    /// The stream ends once the proxy has stopped.
+    pub fn inputs(&self) -> impl Stream<Item = Input> + Send + Unpin + 'static {
+        self.subscribe(&self.inputs_tx, "inputs")
+    }
+
+    fn subscribe<T: Clone + Send + 'static>(
+        &self,
+        tx: &broadcast::Sender<Option<T>>,
+        what: &'static str,
+    ) -> impl Stream<Item = T> + Send + Unpin + 'static {
+        let rx = tx.subscribe();
+        // subscribed too late to see the end
+        let stopped = self.stopped.load(Ordering::Acquire);
+        BroadcastStream::new(rx)
+            .take(if stopped { 0 } else { usize::MAX })
+            .filter_map(move |item| match item {
+                Ok(item) => Some(item),
+                Err(BroadcastStreamRecvError::Lagged(n)) => {
+                    warn!("skipped {n} {what}");
+                    None
+                }
+            })
+            .map_while(|item| item)
+    }
+}
+
+/// What the proxy keeps about a connected client.
+#[derive(Debug, Default)]
+struct ClientEntry {
+    counters: Arc<MessageCounters>,
+    overlay: Arc<OverlayCounters>,
+    shutdown: Arc<Notify>,
+    redraw: Arc<Notify>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIds {
+    /// All ids handed out so far are below this value.
+    pub max: ClientId,
+    /// Number of ids below `max` which are free to be reused.
+    pub free: usize,
+}
+
+#[derive(Debug, Default)]
+struct ClientIdAllocator {
+    recycle: bool,
+    next: ClientId,
+    free: BTreeSet<ClientId>,
+}
+
+impl ClientIdAllocator {
+    fn allocate(&mut self) -> ClientId {
+        if let Some(id) = self.free.pop_first() {
+            return id;
+        }
+
+        let id = self.next;
+        self.next += 1;
+        id
+    }
+
+    fn release(&mut self, id: ClientId) {
+        if self.recycle {
+            self.free.insert(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::rfb::{Buttons, Rectangle, Version, S2C};
+    use crate::testing::*;
+
+    #[tokio::test]
+    async fn forwards_an_update_with_the_icon() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        assert_eq!(client.init, server_init());
+        eventually(|| proxy.events() == [Event::Connect { id: 0 }]).await;
+
+        client.request(false).await;
+        let request = server.read_request().await;
+        assert_eq!(
+            request,
+            C2S::FramebufferUpdateRequest {
+                incremental: false,
+                x: 0,
+                y: 0,
+                width: 64,
+                height: 48,
+            }
+        );
+        server.send_raw(10, 10, 4, 4, 0x55).await;
+
+        let update = client.read_update().await;
+        let raw = |x, y, width, height| Rectangle {
+            x,
+            y,
+            width,
+            height,
+            encoding: Encoding::Raw,
+        };
+        assert_eq!(
+            update,
+            [
+                (raw(10, 10, 4, 4), Bytes::from(vec![0x55; 64])),
+                // red in the server's little endian format with blue in the lowest byte
+                (raw(0, 0, 2, 2), Bytes::from([0, 0, 0xff, 0].repeat(4))),
+            ]
+        );
+
+        drop(client);
+        eventually(|| proxy.events().last() == Some(&Event::Disconnect { id: 0 })).await;
+        assert!(server.rx.read_message::<C2S>().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_end_once_the_proxy_stops() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let events = proxy.handle.events();
+        let inputs = proxy.handle.inputs();
+        let (mut client, _server) = proxy.connect().await;
+        let key = C2S::KeyEvent {
+            down: true,
+            key: 0x61,
+        };
+        client.send(key.clone()).await;
+        drop(client);
+        eventually(|| proxy.events().last() == Some(&Event::Disconnect { id: 0 })).await;
+
+        proxy.task.abort();
+        let events: Vec<_> = soon(events.collect()).await;
+        assert_eq!(
+            events,
+            [Event::Connect { id: 0 }, Event::Disconnect { id: 0 }]
+        );
+        let inputs: Vec<_> = soon(inputs.collect()).await;
+        assert_eq!(inputs.len(), 1);
+        assert_eq!((inputs[0].id, &inputs[0].message), (0, &key));
+
+        // subscribers which come too late get nothing
+        assert_eq!(soon(proxy.handle.events().next()).await, None);
+    }
+
+    #[tokio::test]
+    async fn input_is_recorded_after_redaction() {
+        struct Redacting;
+        impl State for Redacting {
+            fn icon(&self, _id: ClientId) -> Icon {
+                Icon::solid(2, 2, [0xff; 4])
+            }
+            fn handle_event(&mut self, _event: Event) -> Redraw {
+                Redraw::None
+            }
+            fn enable_input(&self, _id: ClientId) -> bool {
+                true
+            }
+            fn redact_input(&self, _id: ClientId, input: &mut C2S) {
+                if let C2S::KeyEvent { key, .. } = input {
+                    *key = '*' as u32;
+                }
+            }
+        }
+
+        let proxy = TestProxy::start(Redacting, Config::default()).await;
+        let mut inputs = proxy.handle.inputs();
+        let (mut client, mut server) = proxy.connect().await;
+        let key = |down, key| C2S::KeyEvent { down, key };
+        let typed = [
+            key(true, 'h' as u32),
+            key(false, 'h' as u32),
+            key(true, 'i' as u32),
+            key(false, 'i' as u32),
+            C2S::PointerEvent {
+                button_mask: Buttons(1),
+                x: 30,
+                y: 20,
+            },
+            C2S::CutText("pasted".to_string()),
+        ];
+        for message in &typed {
+            client.send(message.clone()).await;
+            // the server gets the input as it was sent
+            assert_eq!(&server.read().await, message);
+        }
+
+        let start = SystemTime::now() - Duration::from_secs(5);
+        for message in typed {
+            let input = soon(inputs.next()).await.unwrap();
+            assert_eq!(input.id, 0);
+            assert!(input.time > start && input.time <= SystemTime::now());
+            let expected = match message {
+                C2S::KeyEvent { down, .. } => key(down, '*' as u32),
+                message => message,
+            };
+            assert_eq!(input.message, expected);
+        }
+    }
+
+    async fn reconnect(recycle_client_ids: bool) -> Vec<Event> {
+        let config = Config {
+            recycle_client_ids,
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let (client, _server) = proxy.connect().await;
+        drop(client);
+        eventually(|| proxy.events().len() == 2).await;
+        assert_eq!(
+            proxy.handle.client_ids(),
+            ClientIds {
+                max: 1,
+                free: recycle_client_ids.into(),
+            }
+        );
+        let (_client, _server) = proxy.connect().await;
+        eventually(|| proxy.events().len() == 3).await;
+        proxy.events()
+    }
+
+    #[tokio::test]
+    async fn client_ids_are_only_recycled_if_enabled() {
+        let connected = |id| {
+            [
+                Event::Connect { id: 0 },
+                Event::Disconnect { id: 0 },
+                Event::Connect { id },
+            ]
+        };
+        assert_eq!(reconnect(false).await, connected(1));
+        assert_eq!(reconnect(true).await, connected(0));
+    }
+
+    #[tokio::test]
+    async fn messages_are_counted_per_type() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+
+        for x in [30, 40, 50] {
+            client
+                .send(C2S::PointerEvent {
+                    button_mask: Buttons(0),
+                    x,
+                    y: 30,
+                })
+                .await;
+        }
+        for down in [true, false] {
+            client.send(C2S::KeyEvent { down, key: 0x61 }).await;
+        }
+        client.request(false).await;
+        server.read_request().await;
+        server.tx.write_message(S2C::Bell).await.unwrap();
+        server.send_raw(10, 10, 1, 1, 0).await;
+        assert_eq!(client.read().await, S2C::Bell);
+        client.read_update().await;
+
+        let stats = proxy.handle.message_stats(0).unwrap();
+        let counts = |counts: &BTreeMap<_, _>| {
+            counts
+                .iter()
+                .filter(|(_, &count)| count > 0)
+                .map(|(&name, &count)| (name, count))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            counts(&stats.client),
+            [
+                ("FramebufferUpdateRequest", 1),
+                ("KeyEvent", 2),
+                ("PointerEvent", 3)
+            ]
+        );
+        assert_eq!(
+            counts(&stats.server),
+            [("Bell", 1), ("FramebufferUpdate", 1)]
+        );
+        assert_eq!(stats.client.len(), C2S::KINDS.len());
+        assert_eq!(proxy.handle.message_stats(1), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn activity_is_tracked_per_client() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = without_skipping(proxy.connect()).await;
+        let connected = proxy.handle.session_info(0).unwrap();
+        assert_eq!(connected.last_activity, connected.connected_at);
+        assert_eq!(proxy.handle.session_info(1), None);
+
+        tokio::time::advance(Duration::from_millis(20)).await;
+        assert_eq!(
+            proxy.handle.session_info(0).unwrap().idle_time(),
+            Duration::from_millis(20)
+        );
+        client
+            .send(C2S::KeyEvent {
+                down: true,
+                key: 0x61,
+            })
+            .await;
+        without_skipping(server.read()).await;
+        let info = proxy.handle.session_info(0).unwrap();
+        assert_eq!(info.connected_at, connected.connected_at);
+        assert_eq!(
+            info.last_activity,
+            connected.last_activity + Duration::from_millis(20)
+        );
+        assert_eq!(info.idle_time(), Duration::ZERO);
+
+        drop(client);
+        eventually(|| proxy.handle.session_info(0).is_none()).await;
+    }
+
+    #[tokio::test]
+    async fn clients_leaving_during_the_handshake_are_cleaned_up() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut rx, tx) = TestClient::connect_raw(proxy.addr).await;
+        let mut server = proxy.server.accept_raw().await;
+        server.tx.write_message(Version::new(3, 8)).await.unwrap();
+        let _: Version = rx.read_message().await.unwrap();
+        drop((rx, tx));
+
+        // the connection to the server is closed and the proxy carries on
+        assert_closed(&mut server.rx).await;
+        eventually(|| proxy.events() == [Event::Disconnect { id: 0 }]).await;
+        assert!(!proxy.task.is_finished());
+        let (mut client, mut server) = proxy.connect().await;
+        client
+            .send(C2S::KeyEvent {
+                down: true,
+                key: 0x61,
+            })
+            .await;
+        server.read().await;
+    }
+
+    #[test]
+    fn disconnects_are_told_apart_from_failures() {
+        let io = |kind| Error::Io(io::Error::from(kind));
+        assert!(io(io::ErrorKind::UnexpectedEof).is_disconnect());
+        assert!(io(io::ErrorKind::ConnectionReset).is_disconnect());
+        assert!(!io(io::ErrorKind::ConnectionRefused).is_disconnect());
+        assert!(!Error::Protocol("invalid".to_string()).is_disconnect());
+    }
+
+    #[tokio::test]
+    async fn clients_are_distributed_across_backends() {
+        async fn start(balance: Balance) -> (TestProxy<TestState>, TestServer) {
+            let (first, second) = (TestServer::bind().await, TestServer::bind().await);
+            let config = Config {
+                balance,
+                ..Default::default()
+            };
+            let backends = vec![first.addr, second.addr];
+            let addr = "127.0.0.1:0".parse().unwrap();
+            let proxy = Proxy::bind_backends(addr, backends, TestState::default(), config)
+                .await
+                .unwrap();
+            (TestProxy::run(proxy, first), second)
+        }
+
+        /// Connects a client, expecting it at the given server.
+        async fn connect(
+            proxy: &TestProxy<TestState>,
+            server: &TestServer,
+        ) -> (TestClient, ServerConn) {
+            soon(async {
+                tokio::join!(
+                    async { TestClient::connect(proxy.addr).await.unwrap() },
+                    server.accept(server_init()),
+                )
+            })
+            .await
+        }
+
+        let (proxy, second) = start(Balance::RoundRobin).await;
+        let mut clients = Vec::new();
+        for server in [&proxy.server, &second, &proxy.server, &second] {
+            clients.push(connect(&proxy, server).await);
+        }
+
+        let (proxy, second) = start(Balance::LeastConnections).await;
+        let first_client = connect(&proxy, &proxy.server).await;
+        let _second_client = connect(&proxy, &second).await;
+        let _third_client = connect(&proxy, &proxy.server).await;
+        drop(first_client);
+        eventually(|| proxy.events().contains(&Event::Disconnect { id: 0 })).await;
+        // one client left at each server, so the earlier one is picked
+        let _fourth_client = connect(&proxy, &proxy.server).await;
+        connect(&proxy, &second).await;
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let res = Proxy::bind_backends(addr, vec![], TestState::default(), Config::default()).await;
+        assert!(matches!(res, Err(Error::Config(_))), "{:?}", res.err());
+    }
+
+    #[tokio::test]
+    async fn last_update_is_delivered_before_the_close() {
+        let config = Config {
+            linger: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let init = ServerInit {
+            framebuffer_width: 1000,
+            framebuffer_height: 1000,
+            ..server_init()
+        };
+        let (mut client, mut server) = proxy.connect_with(init).await;
+        client.request(false).await;
+        server.read_request().await;
+        // far more than the socket buffers hold
+        server.send_raw(0, 0, 1000, 1000, 7).await;
+        drop(server);
+
+        let update = client.read_update().await;
+        assert_eq!(update[0].1, vec![7; 4_000_000]);
+        assert_closed(&mut client.rx).await;
+    }
+
+    #[tokio::test]
+    async fn overlay_activity_is_counted() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        let (_other, _other_server) = proxy.connect().await;
+        assert_eq!(proxy.handle.overlay_stats(0), Some(OverlayStats::default()));
+        assert_eq!(proxy.handle.overlay_stats(2), None);
+
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(30, 30, 1, 1, 0).await;
+        client.read_update().await;
+        let pointer = |buttons, x| C2S::PointerEvent {
+            button_mask: Buttons(buttons),
+            x,
+            y: 1,
+        };
+        // one click on the icon and one next to it
+        for x in [1, 20] {
+            client.send(pointer(1, x)).await;
+            client.send(pointer(0, x)).await;
+        }
+        // only the release on the icon is held back
+        for forwarded in [pointer(1, 1), pointer(1, 20), pointer(0, 20)] {
+            assert_eq!(server.read().await, forwarded);
+        }
+        eventually(|| proxy.events().contains(&Event::Action { id: 0 })).await;
+
+        proxy.handle.update_state(|_| true);
+        let drawn = OverlayStats {
+            icons_drawn: 1,
+            clicks_captured: 1,
+            clicks_forwarded: 1,
+            redraws: 1,
+        };
+        eventually(|| proxy.handle.overlay_stats(0) == Some(drawn)).await;
+        let other = OverlayStats {
+            redraws: 1,
+            ..Default::default()
+        };
+        eventually(|| proxy.handle.overlay_stats(1) == Some(other)).await;
+        let total = OverlayStats {
+            redraws: 2,
+            ..drawn
+        };
+        assert_eq!(proxy.handle.total_overlay_stats(), total);
+    }
+
+    #[tokio::test]
+    async fn only_the_given_client_is_disconnected() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut first, mut first_server) = proxy.connect().await;
+        let (mut second, mut second_server) = proxy.connect().await;
+        eventually(|| proxy.events().len() == 2).await;
+
+        proxy.handle.disconnect(0);
+        assert_closed(&mut first.rx).await;
+        assert_closed(&mut first_server.rx).await;
+        eventually(|| proxy.events().last() == Some(&Event::Disconnect { id: 0 })).await;
+
+        // unknown and gone clients are ignored
+        proxy.handle.disconnect(0);
+        proxy.handle.disconnect(7);
+        second.request(false).await;
+        second_server.read_request().await;
+        second_server.send_raw(10, 10, 1, 1, 0).await;
+        assert_eq!(second.read_update().await.len(), 2);
+        assert_eq!(
+            proxy.events(),
+            [
+                Event::Connect { id: 0 },
+                Event::Connect { id: 1 },
+                Event::Disconnect { id: 0 }
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnected_clients_are_told_why() {
+        let config = Config {
+            farewell_message: Some("Bye".to_string()),
+            farewell_grace: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let (mut client, _server) = without_skipping(proxy.connect()).await;
+        eventually(|| !proxy.events().is_empty()).await;
+
+        let start = tokio::time::Instant::now();
+        proxy.handle.disconnect(0);
+        let (text, update) =
+            without_skipping(async { (client.read().await, client.read_update().await) }).await;
+        assert_eq!(text, S2C::CutText("Bye".to_string()));
+        let icon = Icon::text("Bye", [0xff; 4], [0x80, 0, 0, 0xff]);
+        assert_eq!(update.len(), 1);
+        assert_eq!(
+            (update[0].0.width, update[0].0.height),
+            (icon.width, icon.height)
+        );
+
+        tokio::time::advance(Duration::from_millis(199)).await;
+        settle().await;
+        assert_eq!(proxy.events(), [Event::Connect { id: 0 }]);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        without_skipping(assert_closed(&mut client.rx)).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sessions_end_on_time_while_active() {
+        let config = Config {
+            max_session_duration: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let start = tokio::time::Instant::now();
+        let (mut client, _server) = without_skipping(proxy.connect()).await;
+
+        let key = C2S::KeyEvent { down: true, key: 1 };
+        while start.elapsed() < Duration::from_millis(290) {
+            client.tx.write_message(key.clone()).await.unwrap();
+            tokio::time::advance(Duration::from_millis(10)).await;
+        }
+        settle().await;
+        assert_eq!(proxy.events(), [Event::Connect { id: 0 }]);
+
+        client.tx.write_message(key).await.unwrap();
+        tokio::time::advance(Duration::from_millis(10)).await;
+        without_skipping(assert_closed(&mut client.rx)).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+        eventually(|| proxy.events().last() == Some(&Event::Disconnect { id: 0 })).await;
+    }
+
+    #[tokio::test]
+    async fn muted_clients_do_not_get_bells() {
+        #[derive(Default)]
+        struct Muted(Vec<Event>);
+        impl State for Muted {
+            fn icon(&self, _id: ClientId) -> Icon {
+                Icon::solid(2, 2, [0xff; 4])
+            }
+            fn handle_event(&mut self, event: Event) -> Redraw {
+                self.0.push(event);
+                Redraw::None
+            }
+            fn enable_input(&self, _id: ClientId) -> bool {
+                true
+            }
+            fn forward_bell(&self, id: ClientId) -> bool {
+                id != 1
+            }
+        }
+
+        let proxy = TestProxy::start(Muted::default(), Config::default()).await;
+        let mut sessions = vec![proxy.connect().await, proxy.connect().await];
+        for (_, server) in &mut sessions {
+            server.tx.queue_message(S2C::Bell);
+            server.tx.queue_message(S2C::CutText("after".to_string()));
+            server.tx.flush().await.unwrap();
+        }
+        let after = S2C::CutText("after".to_string());
+        let (unmuted, _) = &mut sessions[0];
+        assert_eq!(unmuted.read().await, S2C::Bell);
+        assert_eq!(unmuted.read().await, after);
+        let (muted, _) = &mut sessions[1];
+        assert_eq!(muted.read().await, after);
+
+        // both bells are reported
+        let bells = || {
+            proxy.handle.with_state(|state| {
+                let bells = state.0.iter().filter(|e| matches!(e, Event::Bell { .. }));
+                bells.cloned().collect::<Vec<_>>()
+            })
+        };
+        eventually(|| bells().len() == 2).await;
+        assert!(bells().contains(&Event::Bell { id: 1 }));
+    }
+
+    #[tokio::test]
+    async fn ports_have_their_own_states() {
+        struct Port(u8);
+        impl State for Port {
+            fn icon(&self, id: ClientId) -> Icon {
+                Icon::solid(2, 2, [self.0, id as u8, 0, 0xff])
+            }
+            fn handle_event(&mut self, _event: Event) -> Redraw {
+                Redraw::None
+            }
+            fn enable_input(&self, _id: ClientId) -> bool {
+                true
+            }
+        }
+
+        // as set up by run_proxies
+        let mut proxies: Vec<TestProxy<Box<dyn State>>> = Vec::new();
+        for port in [1, 2] {
+            let server = TestServer::bind().await;
+            let state: Box<dyn State> = Box::new(Port(port));
+            let addr = "127.0.0.1:0".parse().unwrap();
+            let mut proxy = Proxy::bind(addr, server.addr, state, Config::default())
+                .await
+                .unwrap();
+            if let Some(first) = proxies.first() {
+                proxy.share_client_ids(&first.handle);
+            }
+            proxies.push(TestProxy::run(proxy, server));
+        }
+
+        for (port, proxy) in proxies.iter().enumerate() {
+            let (mut client, mut server) = proxy.connect().await;
+            client.request(false).await;
+            server.read_request().await;
+            server.send_raw(10, 10, 1, 1, 0).await;
+            let update = client.read_update().await;
+            // the second client gets the next id, although it connected to another port
+            let (red, id) = (port as u8 + 1, port as u8);
+            assert_eq!(update[1].1, Bytes::from([0, id, red, 0].repeat(4)));
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_given_clients_are_redrawn() {
+        /// Changes the icon of every client on each click, but only redraws the clicking one.
+        struct Clicks(u8);
+        impl State for Clicks {
+            fn icon(&self, _id: ClientId) -> Icon {
+                Icon::solid(2, 2, [self.0, 0, 0, 0xff])
+            }
+            fn handle_event(&mut self, event: Event) -> Redraw {
+                match event {
+                    Event::Action { id } => {
+                        self.0 += 1;
+                        Redraw::Only(vec![id])
+                    }
+                    _ => Redraw::None,
+                }
+            }
+            fn enable_input(&self, _id: ClientId) -> bool {
+                true
+            }
+        }
+
+        let proxy = TestProxy::start(Clicks(0), Config::default()).await;
+        let mut sessions = vec![proxy.connect().await, proxy.connect().await];
+        for (client, server) in &mut sessions {
+            client.request(false).await;
+            server.read_request().await;
+            server.send_raw(10, 10, 1, 1, 0).await;
+            assert_eq!(client.read_update().await.len(), 2);
+            client.request(true).await;
+            server.read_request().await;
+        }
+
+        let (clicking, _) = &mut sessions[0];
+        for buttons in [1, 0] {
+            clicking
+                .send(C2S::PointerEvent {
+                    button_mask: Buttons(buttons),
+                    x: 1,
+                    y: 1,
+                })
+                .await;
+        }
+        let update = clicking.read_update().await;
+        assert_eq!(update.len(), 1);
+        assert_eq!(update[0].1, Bytes::from([0, 0, 1, 0].repeat(4)));
+        let (watching, _) = &mut sessions[1];
+        assert_silent(&mut watching.rx).await;
+    }
+
+    #[tokio::test]
+    async fn ephemeral_ports_are_reported() {
+        let server = TestServer::bind().await;
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let proxy = Proxy::bind(addr, server.addr, TestState::default(), Config::default())
+            .await
+            .unwrap();
+        let bound = proxy.local_addr().unwrap();
+        assert_eq!(bound.ip(), addr.ip());
+        assert_ne!(bound.port(), 0);
+
+        let proxy = TestProxy::run(proxy, server);
+        assert_eq!(proxy.addr, bound);
+        let (client, _server) = proxy.connect().await;
+        assert_eq!(client.init, server_init());
+    }
+
+    #[tokio::test]
+    async fn clients_get_their_own_icon() {
+        struct PerClient;
+        impl State for PerClient {
+            fn icon(&self, id: ClientId) -> Icon {
+                Icon::solid(2, 2, [id as u8 + 1, 0, 0, 0xff])
+            }
+            fn handle_event(&mut self, _event: Event) -> Redraw {
+                Redraw::None
+            }
+            fn enable_input(&self, _id: ClientId) -> bool {
+                true
+            }
+        }
+
+        let proxy = TestProxy::start(PerClient, Config::default()).await;
+        let mut sessions = vec![proxy.connect().await, proxy.connect().await];
+        for (client, server) in &mut sessions {
+            client.request(false).await;
+            server.read_request().await;
+        }
+        for (_, server) in &mut sessions {
+            server.send_raw(0, 0, 4, 4, 0).await;
+        }
+        for (id, (client, _)) in sessions.iter_mut().enumerate() {
+            let update = client.read_update().await;
+            let red = id as u8 + 1;
+            assert_eq!(update[1].1, Bytes::from([0, 0, red, 0].repeat(4)));
+        }
+    }
+
+    #[tokio::test]
+    async fn view_only_clients_get_a_grey_icon() {
+        let config = Config {
+            grey_view_only: true,
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(TestState::default(), config).await;
+        let (mut client, mut server) = proxy.connect().await;
+        // the 2x2 icon as sent in the default pixel format
+        let pixels = |pixel: [u8; 4]| Bytes::from(pixel.repeat(4));
+        let red = pixels([0, 0, 0xff, 0]);
+        let grey = pixels([76, 76, 76, 0]);
+
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(30, 30, 1, 1, 0).await;
+        assert_eq!(client.read_update().await[1].1, red);
+
+        for (input, icon) in [(false, grey), (true, red)] {
+            client.request(true).await;
+            server.read_request().await;
+            proxy.handle.update_state(|state| {
+                state.input = input;
+                true
+            });
+            let update = client.read_update().await;
+            assert_eq!(update.last().unwrap().1, icon);
+        }
+    }
+
+    #[tokio::test]
+    async fn ticks_can_blink_the_icon() {
+        /// Switches between a red and a black icon on each tick.
+        struct Blink(bool);
+
+        impl State for Blink {
+            fn icon(&self, _id: ClientId) -> Icon {
+                let red = if self.0 { 0xff } else { 0 };
+                Icon::solid(2, 2, [red, 0, 0, 0xff])
+            }
+
+            fn handle_event(&mut self, _event: Event) -> Redraw {
+                Redraw::None
+            }
+
+            fn enable_input(&self, _id: ClientId) -> bool {
+                true
+            }
+
+            fn tick(&mut self) -> Redraw {
+                self.0 = !self.0;
+                Redraw::All
+            }
+        }
+
+        let config = Config {
+            tick_interval: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(Blink(false), config).await;
+        let (mut client, mut server) = proxy.connect().await;
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(30, 30, 1, 1, 0).await;
+        let mut icon = client.read_update().await[1].1.clone();
+
+        // the icon as sent in the default pixel format
+        let red = Bytes::from([0, 0, 0xff, 0].repeat(4));
+        let black = Bytes::from(vec![0; 16]);
+        for _ in 0..4 {
+            client.request(true).await;
+            let update = client.read_update().await;
+            let (_, next) = update.last().unwrap().clone();
+            let expected = if icon == red { &black } else { &red };
+            assert_eq!(next, expected);
+            icon = next;
+        }
+    }
+
+    #[tokio::test]
+    async fn icon_can_be_hidden_from_view_only_clients() {
+        let config = Config {
+            hide_icon_when_view_only: true,
+            ..Default::default()
+        };
+        let proxy = TestProxy::start(Lock::default(), config).await;
+        proxy.handle.update_state(|lock| lock.grant(0));
+        let (mut holder, mut holder_server) = proxy.connect().await;
+        let (mut viewer, mut viewer_server) = proxy.connect().await;
+        for (client, server) in [
+            (&mut holder, &mut holder_server),
+            (&mut viewer, &mut viewer_server),
+        ] {
+            client.request(false).await;
+            server.read_request().await;
+            server.send_raw(30, 30, 1, 1, 0).await;
+        }
+        let icon = proxy.handle.with_state(|lock| lock.icon(0));
+        let icon_rect = Rectangle {
+            x: 0,
+            y: 0,
+            width: icon.width,
+            height: icon.height,
+            encoding: Encoding::Raw,
+        };
+        assert_eq!(holder.read_update().await[1].0, icon_rect);
+        assert_eq!(viewer.read_update().await.len(), 1);
+
+        // the icon moves along with the lock
+        holder.request(true).await;
+        viewer.request(true).await;
+        holder_server.read_request().await;
+        viewer_server.read_request().await;
+        proxy.handle.update_state(|lock| lock.grant(1));
+        assert_eq!(viewer.read_update().await[0].0, icon_rect);
+        // the area under the former holder's icon is requested from the server again
+        holder.request(true).await;
+        let C2S::FramebufferUpdateRequest {
+            incremental,
+            x,
+            y,
+            width,
+            height,
+        } = holder_server.read_request().await
+        else {
+            unreachable!();
+        };
+        assert!(!incremental);
+        assert_eq!((x, y, width, height), (0, 0, icon.width, icon.height));
+    }
+
+    #[tokio::test]
+    async fn state_outlives_connected_clients() {
+        let proxy = TestProxy::start(TestState::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(30, 30, 1, 1, 0).await;
+        let red = Bytes::from([0, 0, 0xff, 0].repeat(4));
+        assert_eq!(client.read_update().await[1].1, red);
+        drop((client, server));
+        eventually(|| proxy.events().last() == Some(&Event::Disconnect { id: 0 })).await;
+
+        // changed while no client is connected
+        proxy.handle.update_state(|state| {
+            state.icon = Icon::solid(2, 2, [0, 0, 0xff, 0xff]);
+            true
+        });
+        let (mut client, mut server) = proxy.connect().await;
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(30, 30, 1, 1, 0).await;
+        let blue = Bytes::from([0xff, 0, 0, 0].repeat(4));
+        assert_eq!(client.read_update().await[1].1, blue);
+        // the events of the first client were still recorded
+        assert_eq!(
+            proxy.events(),
+            [
+                Event::Connect { id: 0 },
+                Event::Disconnect { id: 0 },
+                Event::Connect { id: 1 }
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn input_keeps_the_lock_from_timing_out() {
+        let config = Config {
+            tick_interval: Some(Duration::from_millis(20)),
+            ..Config::default()
+        };
+        let lock = Lock::new(LockPolicy {
+            queue: false,
+            idle_timeout: Some(Duration::from_millis(200)),
+        });
+        let proxy = TestProxy::start(lock, config).await;
+        let (mut client, mut server) = proxy.connect().await;
+        proxy.handle.update_state(|lock| lock.grant(0));
+
+        for _ in 0..8 {
+            client.send(C2S::KeyEvent { down: true, key: 1 }).await;
+            server.read().await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(proxy.handle.with_state(|lock| lock.holder()), Some(0));
+        eventually(|| proxy.handle.with_state(|lock| lock.holder()).is_none()).await;
+    }
+
+    #[tokio::test]
+    async fn state_can_be_set_through_the_handle() {
+        // the icon as sent in the default pixel format
+        let pixels = |lock: &Lock| {
+            let icon = lock.icon(0);
+            let pixels = icon.rgba_data.chunks(4).flat_map(|p| [p[2], p[1], p[0], 0]);
+            Bytes::from_iter(pixels)
+        };
+
+        let proxy = TestProxy::start(Lock::default(), Config::default()).await;
+        let (mut client, mut server) = proxy.connect().await;
+        client.request(false).await;
+        server.read_request().await;
+        server.send_raw(30, 30, 1, 1, 0).await;
+        let update = client.read_update().await;
+        assert_eq!(update[1].1, pixels(&Lock::default()));
+
+        client.request(true).await;
+        server.read_request().await;
+        proxy.handle.update_state(|lock| lock.grant(0));
+        let update = client.read_update().await;
+        let granted = proxy.handle.with_state(|lock| lock.clone());
+        assert_eq!(granted.holder(), Some(0));
+        assert_ne!(pixels(&granted), pixels(&Lock::default()));
+        assert_eq!(update.last().unwrap().1, pixels(&granted));
+
+        // nothing changes unless asked to
+        client.request(true).await;
+        proxy.handle.update_state(|lock| {
+            lock.grant(1);
+            false
+        });
+        assert_silent(&mut client.rx).await;
+    }
 }
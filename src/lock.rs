@@ -0,0 +1,338 @@
+//! A [State] which lets one client at a time control the session.
+
+use std::collections::VecDeque;
+
+use tokio::time::{Duration, Instant};
+
+use crate::{ClientId, Event, Icon, Input, Redraw, State};
+
+/// How a [Lock] passes from one client to another.
+#[derive(Debug, Clone, Default)]
+pub struct LockPolicy {
+    /// Let other clients click their icon while the lock is held to wait for it, and again
+    /// to stop waiting. A released lock goes to the client which has waited longest.
+    /// Otherwise only the holder's clicks count, and the lock is free once released.
+    pub queue: bool,
+    /// Release the lock once its holder had no input for this long. Requires
+    /// [crate::Config::tick_interval], which also limits how precisely the timeout is kept.
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Gives the input of the session to one client at a time. Clicking the icon takes a free
+/// lock and releases a held one. Everyone else only watches, so the icon shows who holds
+/// the lock.
+///
+/// Admins can take the lock away with [Lock::grant] and [Lock::release] through
+/// [crate::ProxyHandle::update_state].
+#[derive(Debug, Clone)]
+pub struct Lock {
+    policy: LockPolicy,
+    holder: Option<ClientId>,
+    waiters: VecDeque<ClientId>,
+    /// Last input of the holder, or when it took the lock.
+    last_input: Instant,
+}
+
+impl Lock {
+    pub fn new(policy: LockPolicy) -> Self {
+        Self {
+            policy,
+            holder: None,
+            waiters: VecDeque::new(),
+            last_input: Instant::now(),
+        }
+    }
+
+    pub fn policy(&self) -> &LockPolicy {
+        &self.policy
+    }
+
+    pub fn holder(&self) -> Option<ClientId> {
+        self.holder
+    }
+
+    /// Returns the clients waiting for the lock, longest waiting first.
+    pub fn waiters(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.waiters.iter().copied()
+    }
+
+    /// Notes that a client sent input, which keeps the holder from timing out. Input of
+    /// other clients is ignored. Called for all input by [State::handle_input].
+    pub fn record_input(&mut self, id: ClientId) {
+        if self.holder == Some(id) {
+            self.last_input = Instant::now();
+        }
+    }
+
+    /// Gives the lock to a client, taking it from the current holder without queueing the
+    /// latter. Returns whether the holder changed.
+    pub fn grant(&mut self, id: ClientId) -> bool {
+        if self.holder == Some(id) {
+            return false;
+        }
+        self.waiters.retain(|&waiter| waiter != id);
+        self.take(id);
+        true
+    }
+
+    /// Releases the lock, passing it to the next waiter if there is one. Returns whether
+    /// the lock was held.
+    pub fn release(&mut self) -> bool {
+        if self.holder.is_none() {
+            return false;
+        }
+        match self.waiters.pop_front() {
+            Some(next) => self.take(next),
+            None => self.holder = None,
+        }
+        true
+    }
+
+    fn take(&mut self, id: ClientId) {
+        self.holder = Some(id);
+        self.last_input = Instant::now();
+    }
+
+    fn icon_kind(&self, id: ClientId) -> IconKind {
+        match self.holder {
+            None => IconKind::Nobody,
+            Some(holder) if holder == id => IconKind::Me,
+            _ => match self.waiters.iter().position(|&waiter| waiter == id) {
+                Some(i) => IconKind::Waiting(i + 1),
+                None => IconKind::Peer,
+            },
+        }
+    }
+}
+
+impl Default for Lock {
+    fn default() -> Self {
+        Self::new(LockPolicy::default())
+    }
+}
+
+impl State for Lock {
+    fn icon(&self, id: ClientId) -> Icon {
+        self.icon_kind(id).icon()
+    }
+
+    fn handle_event(&mut self, event: Event) -> Redraw {
+        match event {
+            Event::Action { id } => match self.holder {
+                None => {
+                    self.take(id);
+                    Redraw::All
+                }
+                Some(holder) if holder == id => self.release().into(),
+                Some(_) if self.policy.queue => {
+                    if let Some(i) = self.waiters.iter().position(|&waiter| waiter == id) {
+                        self.waiters.remove(i);
+                        // the waiters behind it move up
+                        Redraw::All
+                    } else {
+                        self.waiters.push_back(id);
+                        Redraw::Only(vec![id])
+                    }
+                }
+                Some(_) => Redraw::None,
+            },
+            Event::Disconnect { id } => {
+                let waited = self.waiters.len();
+                self.waiters.retain(|&waiter| waiter != id);
+                if self.holder == Some(id) {
+                    self.release();
+                    Redraw::All
+                } else {
+                    (self.waiters.len() != waited).into()
+                }
+            }
+            Event::Connect { .. }
+            | Event::Encodings { .. }
+            | Event::Clipboard { .. }
+            | Event::Bell { .. }
+            | Event::SlowClient { .. } => Redraw::None,
+        }
+    }
+
+    fn enable_input(&self, id: ClientId) -> bool {
+        self.holder == Some(id)
+    }
+
+    fn icon_interactive(&self, id: ClientId) -> bool {
+        // only the holder can release a lock
+        self.policy.queue || self.icon_kind(id) != IconKind::Peer
+    }
+
+    fn handle_input(&mut self, input: &Input) -> Redraw {
+        self.record_input(input.id);
+        Redraw::None
+    }
+
+    fn tick(&mut self) -> Redraw {
+        match self.policy.idle_timeout {
+            Some(timeout) if self.holder.is_some() && self.last_input.elapsed() >= timeout => {
+                self.release().into()
+            }
+            _ => Redraw::None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum IconKind {
+    Me,
+    Peer,
+    Nobody,
+    /// Position in the queue, starting at 1.
+    Waiting(usize),
+}
+
+impl IconKind {
+    fn icon(self) -> Icon {
+        let bg = match self {
+            IconKind::Me => [0, 0x80, 0, 0xff],
+            IconKind::Peer => [0xc0, 0, 0, 0xff],
+            IconKind::Nobody => [0, 0, 0xc0, 0xff],
+            IconKind::Waiting(_) => [0xc0, 0x80, 0, 0xff],
+        };
+        let text = match self {
+            IconKind::Me => "MINE".to_string(),
+            IconKind::Peer => "LOCKED".to_string(),
+            IconKind::Nobody => "FREE".to_string(),
+            IconKind::Waiting(position) => format!("WAIT {position}"),
+        };
+
+        Icon::text(&text, [0xff; 4], bg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use tokio::time::advance;
+
+    use super::*;
+    use crate::rfb::C2S;
+
+    fn queueing() -> Lock {
+        Lock::new(LockPolicy {
+            queue: true,
+            idle_timeout: None,
+        })
+    }
+
+    fn key(id: ClientId) -> Input {
+        Input {
+            id,
+            time: SystemTime::now(),
+            message: C2S::KeyEvent { down: true, key: 1 },
+        }
+    }
+
+    #[test]
+    fn waiters_get_the_lock_in_order() {
+        let mut lock = queueing();
+        assert_eq!(lock.handle_event(Event::Action { id: 0 }), Redraw::All);
+        for id in 1..4 {
+            let redraw = lock.handle_event(Event::Action { id });
+            assert_eq!(redraw, Redraw::Only(vec![id]));
+        }
+        assert_eq!(lock.waiters().collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(lock.icon_kind(3), IconKind::Waiting(3));
+
+        // clicking again stops waiting
+        assert_eq!(lock.handle_event(Event::Action { id: 2 }), Redraw::All);
+        assert_eq!(lock.waiters().collect::<Vec<_>>(), [1, 3]);
+        assert_eq!(lock.icon_kind(3), IconKind::Waiting(2));
+        assert_eq!(lock.icon_kind(2), IconKind::Peer);
+
+        assert_eq!(lock.handle_event(Event::Action { id: 0 }), Redraw::All);
+        assert_eq!(lock.holder(), Some(1));
+        assert!(lock.enable_input(1) && !lock.enable_input(0));
+        lock.handle_event(Event::Action { id: 1 });
+        lock.handle_event(Event::Action { id: 3 });
+        assert_eq!(lock.holder(), None);
+        assert_eq!(lock.waiters().count(), 0);
+    }
+
+    #[test]
+    fn only_the_holder_counts_without_a_queue() {
+        let mut lock = Lock::default();
+        lock.handle_event(Event::Action { id: 0 });
+        assert_eq!(lock.handle_event(Event::Action { id: 1 }), Redraw::None);
+        assert_eq!(lock.waiters().count(), 0);
+        assert!(lock.icon_interactive(0) && !lock.icon_interactive(1));
+
+        lock.handle_event(Event::Action { id: 0 });
+        assert_eq!(lock.holder(), None);
+        assert!(lock.icon_interactive(1));
+    }
+
+    #[test]
+    fn disconnected_clients_are_removed() {
+        let mut lock = queueing();
+        for id in 0..4 {
+            lock.handle_event(Event::Action { id });
+        }
+        assert_eq!(lock.handle_event(Event::Disconnect { id: 2 }), Redraw::All);
+        assert_eq!(lock.waiters().collect::<Vec<_>>(), [1, 3]);
+        // neither holding nor waiting
+        assert_eq!(lock.handle_event(Event::Disconnect { id: 5 }), Redraw::None);
+
+        assert_eq!(lock.handle_event(Event::Disconnect { id: 0 }), Redraw::All);
+        assert_eq!(lock.holder(), Some(1));
+        assert_eq!(lock.waiters().collect::<Vec<_>>(), [3]);
+    }
+
+    #[test]
+    fn admins_can_grant_and_release() {
+        let mut lock = queueing();
+        assert!(!lock.release());
+        for id in 0..3 {
+            lock.handle_event(Event::Action { id });
+        }
+
+        // the previous holder is not queued, a waiter leaves the queue
+        assert!(lock.grant(2));
+        assert!(!lock.grant(2));
+        assert_eq!(lock.holder(), Some(2));
+        assert_eq!(lock.waiters().collect::<Vec<_>>(), [1]);
+
+        assert!(lock.release());
+        assert_eq!(lock.holder(), Some(1));
+        assert!(lock.release());
+        assert_eq!(lock.holder(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_holders_lose_the_lock() {
+        let mut lock = Lock::new(LockPolicy {
+            queue: true,
+            idle_timeout: Some(Duration::from_millis(100)),
+        });
+        lock.handle_event(Event::Action { id: 0 });
+        lock.handle_event(Event::Action { id: 1 });
+
+        // input of the holder keeps the lock, that of others does not
+        for _ in 0..3 {
+            advance(Duration::from_millis(50)).await;
+            assert_eq!(lock.handle_input(&key(0)), Redraw::None);
+            assert_eq!(lock.tick(), Redraw::None);
+        }
+        advance(Duration::from_millis(99)).await;
+        lock.handle_input(&key(1));
+        assert_eq!(lock.tick(), Redraw::None);
+        advance(Duration::from_millis(1)).await;
+        assert_eq!(lock.tick(), Redraw::All);
+        assert_eq!(lock.holder(), Some(1));
+
+        // the timeout starts over for the next holder
+        advance(Duration::from_millis(99)).await;
+        assert_eq!(lock.tick(), Redraw::None);
+        advance(Duration::from_millis(1)).await;
+        assert_eq!(lock.tick(), Redraw::All);
+        assert_eq!(lock.holder(), None);
+        assert_eq!(lock.tick(), Redraw::None);
+    }
+}
@@ -0,0 +1,701 @@
+//! Mirror mode: a single connection to the server is shared by all clients, which can
+//! only watch. The proxy keeps a copy of the framebuffer, so clients joining later get
+//! the full picture, and sends every client the damaged areas in its own pixel format.
+
+use std::{
+    future::pending,
+    mem,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
+use bytes::Bytes;
+use log::{debug, warn};
+use tokio::{
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    select,
+    sync::broadcast,
+    task::JoinHandle,
+    time::{sleep, Duration, Instant},
+};
+
+use crate::client::{ascii_name, minor_version, read_limited, within, Client};
+use crate::rfb::{io::RfbIo, *};
+use crate::stats::OverlayCounters;
+use crate::{Config, Error, Event, HandshakeInfo, HandshakePhase, Icon, Result, State};
+
+/// Damaged areas are collapsed into their bounding box beyond this number.
+const MAX_DAMAGE_RECTS: usize = 64;
+
+/// The shared connection to the server.
+pub(crate) struct Session {
+    /// Sent to every client, with the pixel format the framebuffer is kept in.
+    server_init: ServerInit,
+    framebuffer: RwLock<Vec<u8>>,
+    damage_tx: broadcast::Sender<Rectangle>,
+}
+
+impl Session {
+    /// Connects to the server and starts mirroring its framebuffer. The returned task
+    /// fails once the connection to the server is lost.
+    pub(crate) async fn connect(
+        addr: SocketAddr,
+        config: &Config,
+    ) -> Result<(Arc<Self>, JoinHandle<Result<()>>)> {
+        let server = TcpStream::connect(addr).await?;
+        let (server_rx, server_tx) = server.into_split();
+        let server_rx = RfbIo::new(server_rx)
+            .capturing(config.capture_len())
+            .with_read_timeout(config.read_timeout);
+        let (mut server_rx, mut server_tx) = (server_rx, RfbIo::new(server_tx));
+
+        let mut server_init = handshake(&mut server_rx, &mut server_tx).await?;
+        server_init.pixel_format = PixelFormat::default();
+        if let Some(name) = &config.server_name_override {
+            server_init.name = ascii_name(name);
+        }
+
+        let size = server_init.framebuffer_width as usize
+            * server_init.framebuffer_height as usize
+            * server_init.pixel_format.bytes_per_pixel();
+        let (damage_tx, _) = broadcast::channel(256);
+        let session = Arc::new(Self {
+            server_init,
+            framebuffer: RwLock::new(vec![0; size]),
+            damage_tx,
+        });
+
+        let min_interval = config
+            .max_updates_per_sec
+            .filter(|n| *n > 0)
+            .map(|n| Duration::from_secs(1) / n);
+        let task = tokio::spawn(session.clone().run(
+            server_rx,
+            server_tx,
+            min_interval.unwrap_or_default(),
+        ));
+        Ok((session, task))
+    }
+
+    async fn run(
+        self: Arc<Self>,
+        mut server_rx: RfbIo<OwnedReadHalf>,
+        mut server_tx: RfbIo<OwnedWriteHalf>,
+        min_interval: Duration,
+    ) -> Result<()> {
+        let format = self.server_init.pixel_format.clone();
+        server_tx
+            .write_message(C2S::SetPixelFormat(format.clone()))
+            .await?;
+        // Raw is the only encoding which does not depend on what a client has seen before
+        server_tx
+            .write_message(C2S::SetEncodings(vec![Encoding::Raw]))
+            .await?;
+
+        let mut incremental = false;
+        loop {
+            let full = self.full_rect();
+            let requested_at = Instant::now();
+            server_tx
+                .write_message(C2S::FramebufferUpdateRequest {
+                    incremental,
+                    x: full.x,
+                    y: full.y,
+                    width: full.width,
+                    height: full.height,
+                })
+                .await?;
+            incremental = true;
+
+            loop {
+                match server_rx.read_message().await? {
+                    S2C::FramebufferUpdate { count } => {
+                        for _ in 0..count {
+                            let rect: Rectangle = server_rx.read_message().await?;
+                            if rect.encoding != Encoding::Raw {
+                                return Err(Error::Protocol(format!(
+                                    "unexpected encoding {:?}",
+                                    rect.encoding
+                                )));
+                            }
+                            let data = server_rx.read_data(rect.payload_size(&format)?).await?;
+                            self.draw(&rect, &data);
+                            // there may be no clients
+                            let _ = self.damage_tx.send(rect);
+                        }
+                        break;
+                    }
+                    // bells, clipboard and colour maps are not mirrored
+                    message => debug!("ignoring {message:?}"),
+                }
+            }
+
+            sleep(min_interval.saturating_sub(requested_at.elapsed())).await;
+        }
+    }
+
+    fn format(&self) -> &PixelFormat {
+        &self.server_init.pixel_format
+    }
+
+    fn full_rect(&self) -> Rectangle {
+        Rectangle {
+            x: 0,
+            y: 0,
+            width: self.server_init.framebuffer_width,
+            height: self.server_init.framebuffer_height,
+            encoding: Encoding::Raw,
+        }
+    }
+
+    /// Clips a rectangle to the framebuffer, returning `None` if nothing is left.
+    fn clip(&self, rect: &Rectangle) -> Option<Rectangle> {
+        let full = self.full_rect();
+        let x = rect.x.min(full.width);
+        let y = rect.y.min(full.height);
+        let width = rect.width.min(full.width - x);
+        let height = rect.height.min(full.height - y);
+        (width > 0 && height > 0).then_some(Rectangle {
+            x,
+            y,
+            width,
+            height,
+            encoding: Encoding::Raw,
+        })
+    }
+
+    /// Copies the pixels of a Raw rectangle into the framebuffer.
+    fn draw(&self, rect: &Rectangle, data: &[u8]) {
+        let Some(clipped) = self.clip(rect) else {
+            return;
+        };
+
+        let bpp = self.format().bytes_per_pixel();
+        let stride = self.server_init.framebuffer_width as usize * bpp;
+        let src_stride = rect.width as usize * bpp;
+        let row_size = clipped.width as usize * bpp;
+        let mut framebuffer = self.framebuffer.write().unwrap();
+        for (row, src) in data
+            .chunks_exact(src_stride)
+            .take(clipped.height as usize)
+            .enumerate()
+        {
+            let start = (clipped.y as usize + row) * stride + clipped.x as usize * bpp;
+            framebuffer[start..start + row_size].copy_from_slice(&src[..row_size]);
+        }
+    }
+
+    /// Returns the pixels of a rectangle within the framebuffer, row by row.
+    fn pixels(&self, rect: &Rectangle) -> Vec<u8> {
+        let bpp = self.format().bytes_per_pixel();
+        let stride = self.server_init.framebuffer_width as usize * bpp;
+        let row_size = rect.width as usize * bpp;
+        let framebuffer = self.framebuffer.read().unwrap();
+        let mut pixels = Vec::with_capacity(row_size * rect.height as usize);
+        for row in 0..rect.height as usize {
+            let start = (rect.y as usize + row) * stride + rect.x as usize * bpp;
+            pixels.extend_from_slice(&framebuffer[start..start + row_size]);
+        }
+        pixels
+    }
+}
+
+/// Performs the handshake with the server as a client without authentication.
+async fn handshake(
+    server_rx: &mut RfbIo<OwnedReadHalf>,
+    server_tx: &mut RfbIo<OwnedWriteHalf>,
+) -> Result<ServerInit> {
+    let server_version: Version = server_rx.read_message().await?;
+    let Some(version) = minor_version(&server_version) else {
+        return Err(Error::Protocol(format!(
+            "unsupported server version {server_version:?}"
+        )));
+    };
+    server_tx.write_message(Version::new(3, version)).await?;
+
+    let sec_type = if version == 3 {
+        let sec_type: SecurityResult = server_rx.read_message().await?;
+        sec_type.0
+    } else {
+        let sec_types: SecurityTypes = server_rx.read_message().await?;
+        if sec_types.types().contains(&1) {
+            server_tx.write_message(SecurityType(1)).await?;
+            1
+        } else if sec_types.types().is_empty() {
+            0
+        } else {
+            return Err(Error::Protocol(format!(
+                "server requires authentication: {sec_types:?}"
+            )));
+        }
+    };
+
+    match sec_type {
+        0 => return Err(Error::Protocol(server_rx.read_message().await?)),
+        1 => {}
+        _ => {
+            return Err(Error::Protocol(format!(
+                "unsupported security type {sec_type}"
+            )))
+        }
+    }
+
+    if version == 8 {
+        let sec_res: SecurityResult = server_rx.read_message().await?;
+        if sec_res.0 != 0 {
+            return Err(Error::Protocol(server_rx.read_message().await?));
+        }
+    }
+
+    // other clients of the server must stay connected
+    server_tx.write_message(ClientInit { shared: true }).await?;
+    server_rx.read_message().await
+}
+
+impl<S: State> Client<S> {
+    /// Serves a view-only client from a mirrored session.
+    pub(crate) async fn mirror(&self, stream: TcpStream, session: Arc<Session>) -> Result<()> {
+        let peer_addr = stream.peer_addr()?;
+        // deprecated in newer tokio versions for blocking the thread on close, which is
+        // what the option asks for
+        #[allow(deprecated)]
+        if let Some(linger) = self.config.linger {
+            stream.set_linger(Some(linger))?;
+        }
+        let (client_rx, client_tx) = stream.into_split();
+        let client_rx = RfbIo::new(client_rx)
+            .capturing(self.config.capture_len())
+            .with_read_timeout(self.config.read_timeout);
+        let (mut client_rx, mut client_tx) = (client_rx, self.client_writer(client_tx));
+
+        let deadline = Instant::now() + self.config.handshake_timeout;
+        let (peer_addr, version) = within(deadline, async {
+            let peer_addr = self.read_proxy_header(&mut client_rx, peer_addr).await?;
+            client_tx.write_message(Version::new(3, 8)).await?;
+            let client_version: Version = client_rx.read_message().await?;
+            let version = minor_version(&client_version).ok_or_else(|| {
+                Error::Protocol(format!("unsupported client version {client_version:?}"))
+            })?;
+            Ok((peer_addr, version))
+        })
+        .await
+        .map_err(|e| e.in_phase(HandshakePhase::Version))?;
+
+        let info = within(deadline, async {
+            if version == 3 {
+                client_tx.write_message(SecurityResult(1)).await?;
+            } else {
+                client_tx.write_message(SecurityTypes::new(&[1])?).await?;
+                let sec_type: SecurityType = client_rx.read_message().await?;
+                if sec_type.0 != 1 {
+                    return Err(Error::Protocol(format!(
+                        "unsupported security type {}",
+                        sec_type.0
+                    )));
+                }
+            }
+
+            let info = HandshakeInfo {
+                peer_addr,
+                version: (3, version),
+                security_type: 1,
+                shared: None,
+            };
+            // only 3.8 sends a result for the None type
+            self.authorize(&info, version == 8, &mut client_tx).await?;
+            if version == 8 {
+                client_tx.write_message(SecurityResult(0)).await?;
+            }
+            Ok(info)
+        })
+        .await
+        .map_err(|e| e.in_phase(HandshakePhase::Security))?;
+
+        let format = within(deadline, async {
+            let client_init: ClientInit = client_rx.read_message().await?;
+            let info = HandshakeInfo {
+                shared: Some(client_init.shared),
+                ..info
+            };
+            self.authorize(&info, false, &mut client_tx).await?;
+            let server_init = self
+                .state_rx
+                .borrow()
+                .rewrite_server_init(self.id, session.server_init.clone());
+            if !server_init.pixel_format.true_colour {
+                return Err(Error::Protocol(
+                    "colour maps are not supported when mirroring".to_string(),
+                ));
+            }
+            // the framebuffer of the session cannot be resized for a single viewer
+            let server_init = ServerInit {
+                framebuffer_width: session.server_init.framebuffer_width,
+                framebuffer_height: session.server_init.framebuffer_height,
+                ..server_init
+            };
+            client_tx.write_message(server_init.clone()).await?;
+            Ok(server_init.pixel_format)
+        })
+        .await
+        .map_err(|e| e.in_phase(HandshakePhase::Init))?;
+
+        self.counters.connected();
+        self.send_event(Event::Connect { id: self.id }).await;
+
+        let mut viewer = Viewer {
+            client: self.clone(),
+            session: session.clone(),
+            client_rx,
+            client_tx,
+            format,
+            damage: vec![session.full_rect()],
+            requested: false,
+            sent_icon: None,
+            pointer: None,
+            mouse_pressed: false,
+            pressed_on_icon: false,
+        };
+        let res = viewer.handle().await;
+        let _ = viewer.client_tx.shutdown().await;
+        res
+    }
+}
+
+/// A view-only client of a mirrored session.
+struct Viewer<S: State> {
+    client: Client<S>,
+    session: Arc<Session>,
+    client_rx: RfbIo<OwnedReadHalf>,
+    client_tx: RfbIo<OwnedWriteHalf>,
+    format: PixelFormat,
+    /// Areas changed since the last update sent to the client.
+    damage: Vec<Rectangle>,
+    requested: bool,
+    sent_icon: Option<Icon>,
+    pointer: Option<(u16, u16)>,
+    mouse_pressed: bool,
+    /// Whether the current press of the left button started on the icon.
+    pressed_on_icon: bool,
+}
+
+impl<S: State> Viewer<S> {
+    async fn handle(&mut self) -> Result<()> {
+        let mut damage_rx = self.session.damage_tx.subscribe();
+        let mut state_rx = self.client.state_rx.clone();
+
+        loop {
+            select! {
+                m = read_limited(
+                    &mut self.client_rx,
+                    &self.client.config,
+                    6,
+                    C2S::read_from,
+                    C2S::CutText,
+                ) => {
+                    if let Some(m) = m? {
+                        self.handle_message(m)?;
+                    }
+                },
+                damage = damage_rx.recv() => match damage {
+                    Ok(rect) => self.add_damage(rect),
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        self.damage = vec![self.session.full_rect()];
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(Error::Protocol("mirrored session closed".to_string()));
+                    }
+                },
+                Ok(_) = state_rx.changed() => {
+                    self.client.count_overlay(OverlayCounters::redraw);
+                },
+                _ = self.client.redraw.notified() => {
+                    self.client.count_overlay(OverlayCounters::redraw);
+                },
+                _ = self.client.farewell.notified() => {
+                    self.send_farewell().await?;
+                    // keep the message on screen until the connection is closed
+                    return pending().await;
+                },
+            }
+
+            if self.requested {
+                self.send_update().await?;
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: C2S) -> Result<()> {
+        self.client.counters.count_c2s(&message);
+        self.client.send_input(&message);
+        match message {
+            C2S::SetPixelFormat(format) => {
+                if !format.true_colour {
+                    return Err(Error::Protocol(
+                        "colour maps are not supported when mirroring".to_string(),
+                    ));
+                }
+                self.format = format;
+                self.damage = vec![self.session.full_rect()];
+            }
+            C2S::FramebufferUpdateRequest {
+                incremental,
+                x,
+                y,
+                width,
+                height,
+            } => {
+                self.requested = true;
+                if !incremental {
+                    self.add_damage(Rectangle {
+                        x,
+                        y,
+                        width,
+                        height,
+                        encoding: Encoding::Raw,
+                    });
+                }
+            }
+            C2S::PointerEvent { button_mask, x, y } => {
+                let mouse_pressed_new = button_mask.left();
+                let click = self.mouse_pressed && !mouse_pressed_new;
+                let press = !self.mouse_pressed && mouse_pressed_new;
+                self.mouse_pressed = mouse_pressed_new;
+                self.pointer = Some((x, y));
+
+                if press {
+                    self.pressed_on_icon = self.icon().in_bounds(x, y);
+                }
+
+                if click && self.client.icon_interactive() && self.client.overlay_enabled() {
+                    let pressed =
+                        self.pressed_on_icon || !self.client.config.require_press_in_bounds;
+                    if pressed && self.icon().in_bounds(x, y) {
+                        self.client.send_action();
+                        self.client.count_overlay(|o| o.click(true));
+                    }
+                }
+            }
+            // only Raw rectangles are sent, which every client supports
+            C2S::SetEncodings(encodings) => self.client.send_encodings(&encodings),
+            // all clients are view-only
+            C2S::KeyEvent { .. } | C2S::Gii(_) => {}
+            C2S::CutText(text) => self.client.send_clipboard(&text, false),
+            C2S::EnableContinuousUpdates { .. } => {
+                warn!("continuous updates are not supported when mirroring");
+            }
+        }
+        Ok(())
+    }
+
+    fn add_damage(&mut self, rect: Rectangle) {
+        let Some(rect) = self.session.clip(&rect) else {
+            return;
+        };
+
+        self.damage.push(rect);
+        if self.damage.len() > MAX_DAMAGE_RECTS {
+            let (x, y) = self
+                .damage
+                .iter()
+                .fold((u16::MAX, u16::MAX), |(x, y), r| (x.min(r.x), y.min(r.y)));
+            let (right, bottom) = self.damage.iter().fold((0, 0), |(right, bottom), r| {
+                (right.max(r.x + r.width), bottom.max(r.y + r.height))
+            });
+            self.damage = vec![Rectangle {
+                x,
+                y,
+                width: right - x,
+                height: bottom - y,
+                encoding: Encoding::Raw,
+            }];
+        }
+    }
+
+    /// Returns the icon of the client at the position it is to be drawn at.
+    fn icon(&self) -> Icon {
+        let full = self.session.full_rect();
+        self.client
+            .icon()
+            .placed(self.pointer, (full.width, full.height))
+    }
+
+    async fn send_farewell(&mut self) -> Result<()> {
+        let Some((text, icon)) = self.client.farewell_message() else {
+            return Ok(());
+        };
+
+        self.client_tx.write_message(S2C::CutText(text)).await?;
+        self.client_tx
+            .write_message(S2C::FramebufferUpdate { count: 1 })
+            .await?;
+        let rect = Rectangle {
+            x: icon.x,
+            y: icon.y,
+            width: icon.width,
+            height: icon.height,
+            encoding: Encoding::Raw,
+        };
+        self.client_tx.write_message(rect).await?;
+        self.client_tx.write_data(icon.pixels(&self.format)).await
+    }
+
+    async fn send_update(&mut self) -> Result<()> {
+        if self.client.icon_hidden() {
+            // the framebuffer below the icon is restored with this update
+            if let Some(old) = self.sent_icon.take() {
+                self.add_damage(Rectangle {
+                    x: old.x,
+                    y: old.y,
+                    width: old.width,
+                    height: old.height,
+                    encoding: Encoding::Raw,
+                });
+            }
+        }
+        let icon = (self.client.overlay_enabled() && !self.client.icon_hidden())
+            .then(|| self.icon())
+            .filter(|icon| {
+                let overwritten = self
+                    .damage
+                    .iter()
+                    .any(|r| icon.intersects(r.x, r.y, r.width, r.height));
+                overwritten || self.sent_icon.as_ref() != Some(icon)
+            });
+        if self.damage.is_empty() && icon.is_none() {
+            return Ok(());
+        }
+
+        let rects = mem::take(&mut self.damage);
+        let message = S2C::FramebufferUpdate {
+            count: (rects.len() + icon.is_some() as usize).try_into().unwrap(),
+        };
+        self.client.counters.count_s2c(&message);
+        self.client_tx.write_message(message).await?;
+
+        for rect in rects {
+            let pixels = self.session.pixels(&rect);
+            let pixels = self.session.format().convert(&pixels, &self.format);
+            self.client_tx.write_message(rect).await?;
+            self.client_tx.write_data(Bytes::from(pixels)).await?;
+        }
+
+        if let Some(icon) = icon {
+            let rect = Rectangle {
+                x: icon.x,
+                y: icon.y,
+                width: icon.width,
+                height: icon.height,
+                encoding: Encoding::Raw,
+            };
+            self.client_tx.write_message(rect.clone()).await?;
+            self.client_tx.write_data(icon.pixels(&self.format)).await?;
+            self.client.count_overlay(OverlayCounters::icon_drawn);
+
+            // restore the framebuffer below the previous icon with the next update
+            if let Some(old) = self.sent_icon.replace(icon) {
+                let old = Rectangle {
+                    x: old.x,
+                    y: old.y,
+                    width: old.width,
+                    height: old.height,
+                    encoding: Encoding::Raw,
+                };
+                if old != rect {
+                    self.add_damage(old);
+                }
+            }
+        }
+
+        self.requested = false;
+        debug!("sent mirrored update to client {}", self.client.id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::testing::*;
+    use crate::Proxy;
+
+    #[tokio::test]
+    async fn viewers_share_one_server_connection() {
+        let server = TestServer::bind().await;
+        let (proxy, mut conn) = tokio::join!(
+            Proxy::bind_mirror(
+                "127.0.0.1:0".parse().unwrap(),
+                server.addr,
+                TestState::default(),
+                Config::default(),
+            ),
+            server.accept(server_init()),
+        );
+        let proxy = TestProxy::run(proxy.unwrap(), server);
+        assert_eq!(
+            conn.read().await,
+            C2S::SetPixelFormat(PixelFormat::default())
+        );
+        assert_eq!(conn.read().await, C2S::SetEncodings(vec![Encoding::Raw]));
+        conn.read_request().await;
+
+        let mut viewers = Vec::new();
+        for _ in 0..2 {
+            let mut viewer = soon(TestClient::connect(proxy.addr)).await.unwrap();
+            viewer.request(false).await;
+            let update = viewer.read_update().await;
+            assert_eq!(update[0].0, session_rect(0, 0, 64, 48));
+            viewer.request(true).await;
+            viewers.push(viewer);
+        }
+        // no connection of their own
+        let second = timeout(Duration::from_millis(200), proxy.server.accept_raw()).await;
+        assert!(second.is_err());
+
+        conn.send_raw(10, 10, 4, 4, 0x55).await;
+        for viewer in &mut viewers {
+            assert_eq!(
+                viewer.read_update().await,
+                [(session_rect(10, 10, 4, 4), Bytes::from(vec![0x55; 64]))]
+            );
+        }
+    }
+
+    fn session_rect(x: u16, y: u16, width: u16, height: u16) -> Rectangle {
+        Rectangle {
+            x,
+            y,
+            width,
+            height,
+            encoding: Encoding::Raw,
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_options_are_refused() {
+        let server = TestServer::bind().await;
+        let configs = [
+            Config {
+                backend_version: Some((3, 8)),
+                ..Default::default()
+            },
+            Config {
+                security_types: Some(vec![1]),
+                ..Default::default()
+            },
+            Config {
+                overlay_margin: 8,
+                ..Default::default()
+            },
+        ];
+        for config in configs {
+            let addr = "127.0.0.1:0".parse().unwrap();
+            let res = Proxy::bind_mirror(addr, server.addr, TestState::default(), config).await;
+            assert!(matches!(res, Err(Error::Config(_))));
+        }
+    }
+}
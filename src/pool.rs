@@ -0,0 +1,78 @@
+//! Distribution of client connections across several servers.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{Error, Result};
+
+/// How [crate::run_proxy_with_backends] picks the server for a new client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Balance {
+    /// Each server in turn.
+    #[default]
+    RoundRobin,
+    /// The server with the fewest connected clients, the earliest one on a tie.
+    LeastConnections,
+}
+
+#[derive(Debug)]
+pub(crate) struct Pool {
+    backends: Vec<SocketAddr>,
+    connections: Vec<AtomicUsize>,
+    next: AtomicUsize,
+    balance: Balance,
+}
+
+impl Pool {
+    /// Fails with [Error::Config] if there are no backends.
+    pub fn new(backends: Vec<SocketAddr>, balance: Balance) -> Result<Self> {
+        if backends.is_empty() {
+            return Err(Error::Config("no backends given".to_string()));
+        }
+        Ok(Self {
+            connections: backends.iter().map(|_| AtomicUsize::new(0)).collect(),
+            backends,
+            next: AtomicUsize::new(0),
+            balance,
+        })
+    }
+
+    /// Picks the server for a new client, which counts as connected to it until the
+    /// returned lease is dropped.
+    pub fn acquire(self: &Arc<Self>) -> Lease {
+        let index = match self.balance {
+            Balance::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % self.backends.len(),
+            Balance::LeastConnections => (0..self.backends.len())
+                .min_by_key(|i| self.connections[*i].load(Ordering::Relaxed))
+                .unwrap(),
+        };
+        self.connections[index].fetch_add(1, Ordering::Relaxed);
+        Lease {
+            pool: self.clone(),
+            index,
+        }
+    }
+}
+
+/// A client connected to one of the servers of a [Pool].
+pub(crate) struct Lease {
+    pool: Arc<Pool>,
+    index: usize,
+}
+
+impl Lease {
+    pub fn addr(&self) -> SocketAddr {
+        self.pool.backends[self.index]
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.pool.connections[self.index].fetch_sub(1, Ordering::Relaxed);
+    }
+}
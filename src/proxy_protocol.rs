@@ -0,0 +1,229 @@
+//! Parsing of the PROXY protocol header a load balancer sends ahead of the connection
+//! it forwards, see <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::rfb::{ensure_size, DecodeError, Message};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Maximum length of a version 1 header including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// A version 1 or 2 PROXY protocol header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// Address of the original client, `None` if the load balancer connected on its own
+    /// behalf or the address family is unknown.
+    pub source: Option<SocketAddr>,
+}
+
+impl Message for ProxyHeader {
+    fn read_from(buf: &mut Bytes) -> Result<Self, DecodeError> {
+        ensure_size(buf, V2_SIGNATURE.len())?;
+        if buf.starts_with(V2_SIGNATURE) {
+            read_v2(buf)
+        } else if buf.starts_with(b"PROXY ") {
+            read_v1(buf)
+        } else {
+            Err(DecodeError::InvalidProxyHeader)
+        }
+    }
+
+    fn write_to(&self, buf: &mut BytesMut) {
+        buf.put_slice(V2_SIGNATURE);
+        match self.source {
+            None => {
+                buf.put_u8(0x20);
+                buf.put_u8(0x00);
+                buf.put_u16(0);
+            }
+            Some(SocketAddr::V4(addr)) => {
+                buf.put_u8(0x21);
+                buf.put_u8(0x11);
+                buf.put_u16(12);
+                buf.put_slice(&addr.ip().octets());
+                buf.put_bytes(0, 4);
+                buf.put_u16(addr.port());
+                buf.put_u16(0);
+            }
+            Some(SocketAddr::V6(addr)) => {
+                buf.put_u8(0x21);
+                buf.put_u8(0x21);
+                buf.put_u16(36);
+                buf.put_slice(&addr.ip().octets());
+                buf.put_bytes(0, 16);
+                buf.put_u16(addr.port());
+                buf.put_u16(0);
+            }
+        }
+    }
+}
+
+/// Parses a line like `PROXY TCP4 192.0.2.1 192.0.2.2 56324 5900\r\n`.
+fn read_v1(buf: &mut Bytes) -> Result<ProxyHeader, DecodeError> {
+    let Some(end) = buf.windows(2).position(|w| w == b"\r\n") else {
+        return Err(if buf.len() >= V1_MAX_LEN {
+            DecodeError::InvalidProxyHeader
+        } else {
+            DecodeError::InsufficientBytes
+        });
+    };
+
+    let line = buf.split_to(end + 2);
+    let line = std::str::from_utf8(&line[..end]).map_err(|_| DecodeError::InvalidProxyHeader)?;
+    let fields: Vec<_> = line.split(' ').collect();
+    let source = match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| DecodeError::InvalidProxyHeader)?;
+            let port = src_port
+                .parse()
+                .map_err(|_| DecodeError::InvalidProxyHeader)?;
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(DecodeError::InvalidProxyHeader),
+    };
+    Ok(ProxyHeader { source })
+}
+
+fn read_v2(buf: &mut Bytes) -> Result<ProxyHeader, DecodeError> {
+    ensure_size(buf, 16)?;
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    ensure_size(buf, 16 + len)?;
+
+    let mut header = buf.split_to(16 + len);
+    header.advance(V2_SIGNATURE.len());
+    let version_command = header.get_u8();
+    let family = header.get_u8();
+    let _len = header.get_u16();
+
+    if version_command >> 4 != 2 {
+        return Err(DecodeError::InvalidProxyHeader);
+    }
+
+    // LOCAL connections are health checks of the load balancer
+    let source = match (version_command & 0x0f, family >> 4) {
+        (0, _) => None,
+        (1, 1) if len >= 12 => {
+            let mut src = [0; 4];
+            header.copy_to_slice(&mut src);
+            header.advance(4);
+            Some(SocketAddr::new(
+                Ipv4Addr::from(src).into(),
+                header.get_u16(),
+            ))
+        }
+        (1, 2) if len >= 36 => {
+            let mut src = [0; 16];
+            header.copy_to_slice(&mut src);
+            header.advance(16);
+            Some(SocketAddr::new(
+                Ipv6Addr::from(src).into(),
+                header.get_u16(),
+            ))
+        }
+        (1, _) => None,
+        _ => return Err(DecodeError::InvalidProxyHeader),
+    };
+    Ok(ProxyHeader { source })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> (Result<ProxyHeader, DecodeError>, Bytes) {
+        let mut buf = Bytes::copy_from_slice(bytes);
+        (ProxyHeader::read_from(&mut buf), buf)
+    }
+
+    fn source(addr: &str) -> ProxyHeader {
+        ProxyHeader {
+            source: Some(addr.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn v1_headers_are_parsed() {
+        let cases = [
+            (
+                &b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 5900\r\n"[..],
+                source("192.0.2.1:56324"),
+            ),
+            (
+                b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 5900\r\n",
+                source("[2001:db8::1]:56324"),
+            ),
+            (b"PROXY UNKNOWN\r\n", ProxyHeader { source: None }),
+            (
+                b"PROXY UNKNOWN ffff:f::1 ffff:f::2 1 2\r\n",
+                ProxyHeader { source: None },
+            ),
+        ];
+        for (bytes, header) in cases {
+            // the RFB handshake following the header is left alone
+            let (res, rest) = parse(&[bytes, b"RFB 003.008\n"].concat());
+            assert_eq!(res.unwrap(), header);
+            assert_eq!(rest, &b"RFB 003.008\n"[..]);
+        }
+    }
+
+    #[test]
+    fn v2_headers_round_trip() {
+        let headers = [
+            source("192.0.2.1:56324"),
+            source("[2001:db8::1]:56324"),
+            // LOCAL
+            ProxyHeader { source: None },
+        ];
+        for header in headers {
+            let mut buf = BytesMut::new();
+            header.write_to(&mut buf);
+            buf.put_slice(b"RFB");
+            let (res, rest) = parse(&buf);
+            assert_eq!(res.unwrap(), header);
+            assert_eq!(rest, &b"RFB"[..]);
+        }
+
+        // PROXY with an unspecified address family
+        let unknown = [&V2_SIGNATURE[..], &[0x21, 0x00, 0, 0]].concat();
+        assert_eq!(parse(&unknown).0.unwrap(), ProxyHeader { source: None });
+    }
+
+    #[test]
+    fn incomplete_and_invalid_headers() {
+        let mut v2 = BytesMut::new();
+        source("192.0.2.1:56324").write_to(&mut v2);
+        let v1 = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 5900\r\n";
+        for truncated in [&v2[..20], &v2[..14], &v1[..30], &v1[..v1.len() - 1]] {
+            let (res, rest) = parse(truncated);
+            assert!(
+                matches!(res, Err(DecodeError::InsufficientBytes)),
+                "{res:?}"
+            );
+            // nothing is consumed until the header is complete
+            assert_eq!(rest.len(), truncated.len());
+        }
+
+        let no_crlf = [&b"PROXY UNKNOWN "[..], &[b'x'; V1_MAX_LEN]].concat();
+        let mut wrong_version = v2.clone();
+        wrong_version[12] = 0x11;
+        let invalid = [
+            &no_crlf[..],
+            b"RFB 003.008\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324\r\n",
+            b"PROXY TCP4 192.0.2.300 192.0.2.2 56324 5900\r\n",
+            b"PROXY UDP4 192.0.2.1 192.0.2.2 56324 5900\r\n",
+            &wrong_version,
+        ];
+        for bytes in invalid {
+            let (res, _) = parse(bytes);
+            assert!(
+                matches!(res, Err(DecodeError::InvalidProxyHeader)),
+                "{res:?}"
+            );
+        }
+    }
+}
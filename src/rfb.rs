@@ -1,3 +1,5 @@
+//! Messages of the RFB protocol and their encoding, see [io] for sending them over a stream.
+
 use std::string::FromUtf8Error;
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
@@ -15,9 +17,45 @@ pub enum DecodeError {
     UnsupportedC2S(u8),
     #[error("unsupported server message")]
     UnsupportedS2C(u8),
+    #[error("could not inflate ZRLE data")]
+    Inflate(#[from] flate2::DecompressError),
+    #[error("invalid ZRLE data")]
+    InvalidZrle,
+    #[error("unsupported ZRLE subencoding")]
+    UnsupportedZrleSubencoding(u8),
+    #[error("unsupported Tight compression")]
+    UnsupportedTightCompression(u8),
+    #[error("unsupported Tight filter")]
+    UnsupportedTightFilter(u8),
+    #[error("too many encodings")]
+    TooManyEncodings(u16),
+    #[error("unsupported encoding")]
+    UnsupportedEncoding(Encoding),
+    #[error("unsupported bits per pixel")]
+    UnsupportedBitsPerPixel(u8),
+    #[error("rectangle too large")]
+    RectangleTooLarge,
+    #[error("too many colour map entries")]
+    TooManyColors(u16),
+    #[error("desktop name too long")]
+    NameTooLong(usize),
+    #[error("too many security types")]
+    TooManySecurityTypes(usize),
+    #[error("invalid PROXY protocol header")]
+    InvalidProxyHeader,
+    #[error("invalid pixel format")]
+    InvalidPixelFormat(PixelFormat),
 }
 
-fn ensure_size(buf: &Bytes, size: usize) -> Result<(), DecodeError> {
+/// Upper bound on the number of encodings a client may send in `SetEncodings`.
+/// Real clients send a few dozen at most.
+const MAX_ENCODINGS: usize = 512;
+
+/// Upper bound on the length of the desktop name in `ServerInit`, so a server cannot make
+/// the proxy buffer gigabytes during the handshake.
+const MAX_NAME_LEN: usize = 0x10000;
+
+pub(crate) fn ensure_size(buf: &Bytes, size: usize) -> Result<(), DecodeError> {
     if buf.len() >= size {
         Ok(())
     } else {
@@ -30,6 +68,67 @@ pub trait Message: Sized {
     fn write_to(&self, buf: &mut BytesMut);
 }
 
+/// Reads a `CutText` message of the given type (6 from clients, 3 from servers) if its
+/// text is longer than `max_len` bytes, keeping only the first `keep` bytes of the text.
+/// Returns the text and the number of bytes left of it, which still have to be skipped.
+/// Nothing is consumed for other messages.
+pub(crate) fn read_long_cut_text(
+    buf: &mut Bytes,
+    message_type: u8,
+    max_len: usize,
+    keep: usize,
+) -> Result<Option<(String, usize)>, DecodeError> {
+    ensure_size(buf, 1)?;
+    if buf[0] != message_type {
+        return Ok(None);
+    }
+    ensure_size(buf, 8)?;
+    let len = u32::from_be_bytes(buf[4..8].try_into().unwrap()) as usize;
+    if len <= max_len {
+        return Ok(None);
+    }
+
+    let keep = keep.min(len);
+    ensure_size(buf, 8 + keep)?;
+    buf.advance(8);
+    let text = buf.split_to(keep);
+    // the cut may have split a character
+    let valid = match std::str::from_utf8(&text) {
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        _ => text.len(),
+    };
+    Ok(Some((
+        String::from_utf8(text[..valid].to_vec())?,
+        len - keep,
+    )))
+}
+
+/// Parses a single message from the start of `bytes`, returning it together with the
+/// number of bytes it took up.
+pub fn parse<M: Message>(bytes: &[u8]) -> Result<(M, usize), DecodeError> {
+    let mut buf = Bytes::copy_from_slice(bytes);
+    let message = M::read_from(&mut buf)?;
+    Ok((message, bytes.len() - buf.len()))
+}
+
+/// Parses a client message, see [parse].
+pub fn parse_c2s(bytes: &[u8]) -> Result<(C2S, usize), DecodeError> {
+    parse(bytes)
+}
+
+/// Parses a server message, see [parse]. For a `FramebufferUpdate`, only the header is
+/// parsed, the rectangles following it can be parsed as [Rectangle]s.
+pub fn parse_s2c(bytes: &[u8]) -> Result<(S2C, usize), DecodeError> {
+    parse(bytes)
+}
+
+/// Encodes a single message the way it is sent over the wire.
+pub fn serialize(message: &impl Message) -> Bytes {
+    let mut buf = BytesMut::new();
+    message.write_to(&mut buf);
+    buf.freeze()
+}
+
 /* All strings in VNC are either ASCII or Latin-1, both of which
 are embedded in Unicode. */
 impl Message for String {
@@ -63,6 +162,22 @@ impl Message for Version {
     }
 }
 
+impl Version {
+    pub fn new(major: u16, minor: u16) -> Self {
+        Self(Bytes::from(format!("RFB {major:03}.{minor:03}\n")))
+    }
+
+    /// Parses a `RFB xxx.yyy\n` string into its major and minor number.
+    pub fn parse(&self) -> Option<(u16, u16)> {
+        let s = std::str::from_utf8(&self.0).ok()?;
+        let (major, minor) = s
+            .strip_prefix("RFB ")?
+            .strip_suffix('\n')?
+            .split_once('.')?;
+        Some((major.parse().ok()?, minor.parse().ok()?))
+    }
+}
+
 /// ```text
 /// +--------------------------+-------------+--------------------------+
 /// | No. of bytes             | Type        | Description              |
@@ -73,7 +188,20 @@ impl Message for Version {
 /// +--------------------------+-------------+--------------------------+
 /// ```
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct SecurityTypes(pub Bytes);
+pub struct SecurityTypes(Bytes);
+
+impl SecurityTypes {
+    pub fn new(types: &[u8]) -> Result<Self, DecodeError> {
+        if types.len() > u8::MAX as usize {
+            return Err(DecodeError::TooManySecurityTypes(types.len()));
+        }
+        Ok(Self(Bytes::copy_from_slice(types)))
+    }
+
+    pub fn types(&self) -> &[u8] {
+        &self.0
+    }
+}
 
 impl Message for SecurityTypes {
     fn read_from(buf: &mut Bytes) -> Result<Self, DecodeError> {
@@ -85,8 +213,8 @@ impl Message for SecurityTypes {
     }
 
     fn write_to(&self, buf: &mut BytesMut) {
-        let count = self.0.len().try_into().unwrap();
-        buf.put_u8(count);
+        // the count is checked by the constructors
+        buf.put_u8(self.0.len() as u8);
         buf.put(self.0.as_ref());
     }
 }
@@ -206,6 +334,9 @@ impl Message for PixelFormat {
             blue_shift: buf.get_u8(),
         };
         let _pad = buf.split_to(3);
+        if !pixel_format.is_valid() {
+            return Err(DecodeError::InvalidPixelFormat(pixel_format));
+        }
         Ok(pixel_format)
     }
 
@@ -224,6 +355,113 @@ impl Message for PixelFormat {
     }
 }
 
+/// 32 bit little endian true colour with the blue channel in the lowest byte,
+/// as commonly used by VNC servers such as TigerVNC.
+impl Default for PixelFormat {
+    fn default() -> Self {
+        Self::true_colour_32(16, 8, 0)
+    }
+}
+
+impl PixelFormat {
+    /// 32 bit little endian true colour with the bytes of a pixel in RGBA order.
+    pub fn rgba8888() -> Self {
+        Self::true_colour_32(0, 8, 16)
+    }
+
+    /// 16 bit little endian true colour with 5 bits of red and blue and 6 bits of green.
+    pub fn rgb565() -> Self {
+        Self {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: false,
+            true_colour: true,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+        }
+    }
+
+    fn true_colour_32(red_shift: u8, green_shift: u8, blue_shift: u8) -> Self {
+        Self {
+            bits_per_pixel: 32,
+            depth: 24,
+            big_endian: false,
+            true_colour: true,
+            red_max: 255,
+            green_max: 255,
+            blue_max: 255,
+            red_shift,
+            green_shift,
+            blue_shift,
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.bits_per_pixel as usize / 8
+    }
+
+    /// Whether pixels of this format can be read and converted: they take 1, 2 or 4 bytes,
+    /// and true colour channels lie within a pixel.
+    pub fn is_valid(&self) -> bool {
+        let shifts = [self.red_shift, self.green_shift, self.blue_shift];
+        matches!(self.bits_per_pixel, 8 | 16 | 32)
+            && (!self.true_colour || shifts.iter().all(|&shift| shift < self.bits_per_pixel))
+    }
+
+    /// Converts true colour pixel data from this format into another one,
+    /// scaling each colour channel to the target's range.
+    pub fn convert(&self, data: &[u8], to: &PixelFormat) -> Vec<u8> {
+        if self == to {
+            return data.to_vec();
+        }
+
+        fn scale(value: u32, from_max: u16, to_max: u16) -> u32 {
+            match from_max {
+                0 => 0,
+                m => (value * to_max as u32 + m as u32 / 2) / m as u32,
+            }
+        }
+
+        let mut out =
+            Vec::with_capacity(data.len() / self.bytes_per_pixel() * to.bytes_per_pixel());
+        for pixel in data.chunks_exact(self.bytes_per_pixel()) {
+            let value = self.read_pixel(pixel);
+            let r = (value >> self.red_shift) & self.red_max as u32;
+            let g = (value >> self.green_shift) & self.green_max as u32;
+            let b = (value >> self.blue_shift) & self.blue_max as u32;
+            let value = scale(r, self.red_max, to.red_max) << to.red_shift
+                | scale(g, self.green_max, to.green_max) << to.green_shift
+                | scale(b, self.blue_max, to.blue_max) << to.blue_shift;
+            to.write_pixel(value, &mut out);
+        }
+        out
+    }
+
+    fn read_pixel(&self, pixel: &[u8]) -> u32 {
+        let mut bytes = [0; 4];
+        if self.big_endian {
+            bytes[4 - pixel.len()..].copy_from_slice(pixel);
+            u32::from_be_bytes(bytes)
+        } else {
+            bytes[..pixel.len()].copy_from_slice(pixel);
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    fn write_pixel(&self, value: u32, out: &mut Vec<u8>) {
+        let len = self.bytes_per_pixel();
+        if self.big_endian {
+            out.extend_from_slice(&value.to_be_bytes()[4 - len..]);
+        } else {
+            out.extend_from_slice(&value.to_le_bytes()[..len]);
+        }
+    }
+}
+
 /// ```text
 /// +--------------+--------------+------------------------------+
 /// | No. of bytes | Type [Value] | Description                  |
@@ -246,10 +484,20 @@ pub struct ServerInit {
 impl Message for ServerInit {
     fn read_from(buf: &mut Bytes) -> Result<Self, DecodeError> {
         ensure_size(buf, 4)?;
+        let framebuffer_width = buf.get_u16();
+        let framebuffer_height = buf.get_u16();
+        let pixel_format = PixelFormat::read_from(buf)?;
+        // checked before waiting for the whole name, which is parsed again from the start
+        // whenever more of it arrives
+        ensure_size(buf, 4)?;
+        let name_len = (&buf[..4]).get_u32() as usize;
+        if name_len > MAX_NAME_LEN {
+            return Err(DecodeError::NameTooLong(name_len));
+        }
         Ok(ServerInit {
-            framebuffer_width: buf.get_u16(),
-            framebuffer_height: buf.get_u16(),
-            pixel_format: PixelFormat::read_from(buf)?,
+            framebuffer_width,
+            framebuffer_height,
+            pixel_format,
             name: String::read_from(buf)?,
         })
     }
@@ -298,42 +546,134 @@ pub enum Encoding {
     CopyRect,
     Rre,
     Hextile,
+    Tight,
     Trle,
     Zrle,
     Cursor,
     DesktopSize,
+    ExtendedDesktopSize,
+    ContinuousUpdates,
+    /// The JPEG quality level pseudo-encoding, from 0 (lowest) to 9 (highest).
+    JpegQuality(u8),
+    /// The compression level pseudo-encoding, from 0 (fastest) to 9 (smallest).
+    CompressionLevel(u8),
+    Gii,
+}
+
+/// Encodings the proxy advertises to the server, in order of preference.
+///
+/// Raw, CopyRect and Cursor are fully understood, so their pixel data can be converted
+/// between pixel formats. ZRLE is only passed through as it is.
+pub fn supported_encodings() -> &'static [Encoding] {
+    &[
+        Encoding::Raw,
+        Encoding::Cursor,
+        Encoding::CopyRect,
+        Encoding::Zrle,
+    ]
+}
+
+impl Encoding {
+    pub fn from_i32(encoding: i32) -> Self {
+        match encoding {
+            0 => Encoding::Raw,
+            1 => Encoding::CopyRect,
+            2 => Encoding::Rre,
+            5 => Encoding::Hextile,
+            7 => Encoding::Tight,
+            15 => Encoding::Trle,
+            16 => Encoding::Zrle,
+            -239 => Encoding::Cursor,
+            -223 => Encoding::DesktopSize,
+            -308 => Encoding::ExtendedDesktopSize,
+            -313 => Encoding::ContinuousUpdates,
+            -305 => Encoding::Gii,
+            n @ -32..=-23 => Encoding::JpegQuality((n + 32) as u8),
+            n @ -256..=-247 => Encoding::CompressionLevel((n + 256) as u8),
+            n => Encoding::Unknown(n),
+        }
+    }
+
+    pub fn to_i32(self) -> i32 {
+        match self {
+            Encoding::Raw => 0,
+            Encoding::CopyRect => 1,
+            Encoding::Rre => 2,
+            Encoding::Hextile => 5,
+            Encoding::Tight => 7,
+            Encoding::Trle => 15,
+            Encoding::Zrle => 16,
+            Encoding::Cursor => -239,
+            Encoding::DesktopSize => -223,
+            Encoding::ExtendedDesktopSize => -308,
+            Encoding::ContinuousUpdates => -313,
+            Encoding::Gii => -305,
+            Encoding::JpegQuality(level) => -32 + level as i32,
+            Encoding::CompressionLevel(level) => -256 + level as i32,
+            Encoding::Unknown(n) => n,
+        }
+    }
 }
 
 impl Message for Encoding {
     fn read_from(buf: &mut Bytes) -> Result<Self, DecodeError> {
         ensure_size(buf, 4)?;
-        let encoding = buf.get_i32();
-        match encoding {
-            0 => Ok(Encoding::Raw),
-            1 => Ok(Encoding::CopyRect),
-            2 => Ok(Encoding::Rre),
-            5 => Ok(Encoding::Hextile),
-            15 => Ok(Encoding::Trle),
-            16 => Ok(Encoding::Zrle),
-            -239 => Ok(Encoding::Cursor),
-            -223 => Ok(Encoding::DesktopSize),
-            n => Ok(Encoding::Unknown(n)),
-        }
+        Ok(Encoding::from_i32(buf.get_i32()))
     }
 
     fn write_to(&self, buf: &mut BytesMut) {
-        let encoding = match self {
-            &Encoding::Raw => 0,
-            &Encoding::CopyRect => 1,
-            &Encoding::Rre => 2,
-            &Encoding::Hextile => 5,
-            Encoding::Trle => 15,
-            &Encoding::Zrle => 16,
-            &Encoding::Cursor => -239,
-            &Encoding::DesktopSize => -223,
-            &Encoding::Unknown(n) => n,
-        };
-        buf.put_i32(encoding);
+        buf.put_i32(self.to_i32());
+    }
+}
+
+/// A pointer button, by its bit in a [Buttons] mask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Left = 0,
+    Middle = 1,
+    Right = 2,
+    WheelUp = 3,
+    WheelDown = 4,
+    WheelLeft = 5,
+    WheelRight = 6,
+}
+
+/// The buttons held down in a `PointerEvent`, one bit per [Button]. Wheel movements are
+/// sent as a press and a release of the wheel buttons.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Buttons(pub u8);
+
+impl Buttons {
+    pub fn pressed(self, button: Button) -> bool {
+        self.0 & (1 << button as u8) != 0
+    }
+
+    pub fn left(self) -> bool {
+        self.pressed(Button::Left)
+    }
+
+    pub fn middle(self) -> bool {
+        self.pressed(Button::Middle)
+    }
+
+    pub fn right(self) -> bool {
+        self.pressed(Button::Right)
+    }
+
+    pub fn wheel_up(self) -> bool {
+        self.pressed(Button::WheelUp)
+    }
+
+    pub fn wheel_down(self) -> bool {
+        self.pressed(Button::WheelDown)
+    }
+
+    pub fn wheel_left(self) -> bool {
+        self.pressed(Button::WheelLeft)
+    }
+
+    pub fn wheel_right(self) -> bool {
+        self.pressed(Button::WheelRight)
     }
 }
 
@@ -400,7 +740,11 @@ pub enum C2S {
     /// | 2            | U16          | y-position   |
     /// +--------------+--------------+--------------+
     /// ```
-    PointerEvent { button_mask: u8, x: u16, y: u16 },
+    PointerEvent {
+        button_mask: Buttons,
+        x: u16,
+        y: u16,
+    },
     /// ```text
     /// +--------------+--------------+--------------+
     /// | No. of bytes | Type [Value] | Description  |
@@ -413,6 +757,65 @@ pub enum C2S {
     /// ```
     CutText(String),
     // extensions
+    /// ```text
+    /// +--------------+--------------+--------------+
+    /// | No. of bytes | Type [Value] | Description  |
+    /// +--------------+--------------+--------------+
+    /// | 1            | U8 [150]     | message-type |
+    /// | 1            | U8           | enable-flag  |
+    /// | 2            | U16          | x-position   |
+    /// | 2            | U16          | y-position   |
+    /// | 2            | U16          | width        |
+    /// | 2            | U16          | height       |
+    /// +--------------+--------------+--------------+
+    /// ```
+    EnableContinuousUpdates {
+        enable: bool,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    },
+    /// ```text
+    /// +--------------+--------------+--------------------+
+    /// | No. of bytes | Type [Value] | Description        |
+    /// +--------------+--------------+--------------------+
+    /// | 1            | U8 [253]     | message-type       |
+    /// | 1            | U8           | endian-and-subtype |
+    /// | 2            | U16          | length             |
+    /// | length       | U8 array     | payload            |
+    /// +--------------+--------------+--------------------+
+    /// ```
+    /// The top bit of endian-and-subtype is set if the length and the payload are big
+    /// endian. The payload is only passed through.
+    Gii(Gii),
+}
+
+impl C2S {
+    /// Names of all message types, indexed by [C2S::kind].
+    pub const KINDS: [&'static str; 8] = [
+        "SetPixelFormat",
+        "SetEncodings",
+        "FramebufferUpdateRequest",
+        "KeyEvent",
+        "PointerEvent",
+        "CutText",
+        "EnableContinuousUpdates",
+        "Gii",
+    ];
+
+    pub fn kind(&self) -> usize {
+        match self {
+            C2S::SetPixelFormat(_) => 0,
+            C2S::SetEncodings(_) => 1,
+            C2S::FramebufferUpdateRequest { .. } => 2,
+            C2S::KeyEvent { .. } => 3,
+            C2S::PointerEvent { .. } => 4,
+            C2S::CutText(_) => 5,
+            C2S::EnableContinuousUpdates { .. } => 6,
+            C2S::Gii(_) => 7,
+        }
+    }
 }
 
 impl Message for C2S {
@@ -426,9 +829,13 @@ impl Message for C2S {
                 Ok(C2S::SetPixelFormat(PixelFormat::read_from(buf)?))
             }
             2 => {
-                ensure_size(buf, 1)?;
+                ensure_size(buf, 3)?;
                 let _pad = buf.split_to(1);
                 let count = buf.get_u16();
+                if count as usize > MAX_ENCODINGS {
+                    return Err(DecodeError::TooManyEncodings(count));
+                }
+                ensure_size(buf, count as usize * 4)?;
                 let encodings = (0..count)
                     .map(|_| Encoding::read_from(buf))
                     .collect::<Result<_, _>>()?;
@@ -454,7 +861,7 @@ impl Message for C2S {
             5 => {
                 ensure_size(buf, 5)?;
                 Ok(C2S::PointerEvent {
-                    button_mask: buf.get_u8(),
+                    button_mask: Buttons(buf.get_u8()),
                     x: buf.get_u16(),
                     y: buf.get_u16(),
                 })
@@ -464,6 +871,17 @@ impl Message for C2S {
                 let _pad = buf.split_to(3);
                 Ok(C2S::CutText(String::read_from(buf)?))
             }
+            150 => {
+                ensure_size(buf, 9)?;
+                Ok(C2S::EnableContinuousUpdates {
+                    enable: buf.get_u8() != 0,
+                    x: buf.get_u16(),
+                    y: buf.get_u16(),
+                    width: buf.get_u16(),
+                    height: buf.get_u16(),
+                })
+            }
+            253 => Ok(C2S::Gii(Gii::read_from(buf)?)),
             m => Err(DecodeError::UnsupportedC2S(m)),
         }
     }
@@ -505,17 +923,76 @@ impl Message for C2S {
             }
             C2S::PointerEvent { button_mask, x, y } => {
                 buf.put_u8(5);
-                buf.put_u8(*button_mask);
+                buf.put_u8(button_mask.0);
                 buf.put_u16(*x);
                 buf.put_u16(*y);
             }
             C2S::CutText(text) => {
+                buf.put_u8(6);
+                buf.put_bytes(0, 3);
                 String::write_to(text, buf);
             }
+            C2S::EnableContinuousUpdates {
+                enable,
+                x,
+                y,
+                width,
+                height,
+            } => {
+                buf.put_u8(150);
+                buf.put_u8(if *enable { 1 } else { 0 });
+                buf.put_u16(*x);
+                buf.put_u16(*y);
+                buf.put_u16(*width);
+                buf.put_u16(*height);
+            }
+            C2S::Gii(gii) => {
+                buf.put_u8(253);
+                gii.write_to(buf);
+            }
         }
     }
 }
 
+/// A message of the General Input Interface extension, following the message type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gii {
+    pub big_endian: bool,
+    pub subtype: u8,
+    pub payload: Bytes,
+}
+
+impl Message for Gii {
+    fn read_from(buf: &mut Bytes) -> Result<Self, DecodeError> {
+        ensure_size(buf, 3)?;
+        let endian_and_subtype = buf.get_u8();
+        let big_endian = endian_and_subtype & 0x80 != 0;
+        let length = if big_endian {
+            buf.get_u16()
+        } else {
+            buf.get_u16_le()
+        };
+        ensure_size(buf, length as usize)?;
+        Ok(Gii {
+            big_endian,
+            subtype: endian_and_subtype & 0x7f,
+            payload: buf.split_to(length as usize),
+        })
+    }
+
+    fn write_to(&self, buf: &mut BytesMut) {
+        buf.put_u8((self.big_endian as u8) << 7 | self.subtype & 0x7f);
+        // more bytes cannot have been read from a message
+        let length = self.payload.len().min(u16::MAX as usize);
+        if self.big_endian {
+            buf.put_u16(length as u16);
+        } else {
+            buf.put_u16_le(length as u16);
+        }
+        buf.put(&self.payload[..length]);
+    }
+}
+
 /// ```text
 /// +--------------+--------------+---------------+
 /// | No. of bytes | Type [Value] | Description   |
@@ -558,17 +1035,32 @@ impl Message for Rectangle {
 }
 
 impl Rectangle {
-    pub fn payload_size(&self, format: &PixelFormat) -> usize {
+    /// Size of the data following the rectangle header for encodings with a fixed size.
+    /// Fails for other encodings, for pixel formats other than 8, 16 or 32 bits per pixel,
+    /// which would otherwise be read as zero bytes per pixel, and for sizes which overflow
+    /// a `usize`.
+    pub fn payload_size(&self, format: &PixelFormat) -> Result<usize, DecodeError> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let pixels = || {
+            let bytes_per_pixel = match format.bits_per_pixel {
+                8 | 16 | 32 => format.bytes_per_pixel(),
+                bpp => return Err(DecodeError::UnsupportedBitsPerPixel(bpp)),
+            };
+            width
+                .checked_mul(height)
+                .and_then(|n| n.checked_mul(bytes_per_pixel))
+                .ok_or(DecodeError::RectangleTooLarge)
+        };
         match self.encoding {
-            Encoding::Raw => {
-                self.width as usize * self.height as usize * (format.bits_per_pixel / 8) as usize
-            }
+            Encoding::Raw => pixels(),
             Encoding::Cursor => {
-                (self.width as usize * self.height as usize * (format.bits_per_pixel / 8) as usize)
-                    + (((self.width as usize + 7) / 8) * self.height as usize)
+                let pixels = pixels()?;
+                let mask = width.div_ceil(8).checked_mul(height);
+                mask.and_then(|mask| mask.checked_add(pixels))
+                    .ok_or(DecodeError::RectangleTooLarge)
             }
-            Encoding::CopyRect => 4,
-            e => unimplemented!("encoding: {e:?}"),
+            Encoding::CopyRect => Ok(4),
+            e => Err(DecodeError::UnsupportedEncoding(e)),
         }
     }
 }
@@ -617,10 +1109,62 @@ pub enum S2C {
     /// +--------------+--------------+--------------+
     /// ```
     CutText(String),
+    // extensions
+    /// ```text
+    /// +--------------+--------------+--------------+
+    /// | No. of bytes | Type [Value] | Description  |
+    /// +--------------+--------------+--------------+
+    /// | 1            | U8 [150]     | message-type |
+    /// +--------------+--------------+--------------+
+    /// ```
+    EndOfContinuousUpdates,
+    /// ```text
+    /// +--------------+--------------+--------------------+
+    /// | No. of bytes | Type [Value] | Description        |
+    /// +--------------+--------------+--------------------+
+    /// | 1            | U8 [253]     | message-type       |
+    /// | 1            | U8           | endian-and-subtype |
+    /// | 2            | U16          | length             |
+    /// | length       | U8 array     | payload            |
+    /// +--------------+--------------+--------------------+
+    /// ```
+    /// The top bit of endian-and-subtype is set if the length and the payload are big
+    /// endian. The payload is only passed through.
+    Gii(Gii),
 }
 
-impl Message for S2C {
-    fn read_from(buf: &mut Bytes) -> Result<Self, DecodeError> {
+impl S2C {
+    /// Names of all message types, indexed by [S2C::kind].
+    pub const KINDS: [&'static str; 6] = [
+        "FramebufferUpdate",
+        "SetColorMapEntries",
+        "Bell",
+        "CutText",
+        "EndOfContinuousUpdates",
+        "Gii",
+    ];
+
+    /// Whether a byte is the type of a message the server may send.
+    pub fn is_message_type(message_type: u8) -> bool {
+        matches!(message_type, 0..=3 | 150 | 253)
+    }
+
+    pub fn kind(&self) -> usize {
+        match self {
+            S2C::FramebufferUpdate { .. } => 0,
+            S2C::SetColorMapEntries { .. } => 1,
+            S2C::Bell => 2,
+            S2C::CutText(_) => 3,
+            S2C::EndOfContinuousUpdates => 4,
+            S2C::Gii(_) => 5,
+        }
+    }
+}
+
+impl S2C {
+    /// Reads a message like [Message::read_from], but fails on a `SetColorMapEntries`
+    /// message with more than `max_colors` entries instead of buffering it.
+    pub fn read_limited(buf: &mut Bytes, max_colors: u16) -> Result<Self, DecodeError> {
         ensure_size(buf, 1)?;
         let message_type = buf.get_u8();
         match message_type {
@@ -632,11 +1176,19 @@ impl Message for S2C {
                 })
             }
             1 => {
+                ensure_size(buf, 5)?;
                 let _pad = buf.get_u8();
                 let first_color = buf.get_u16();
-                let count = buf.get_u16() as usize;
-                ensure_size(buf, count * 3 * 2)?;
-                let colors = buf.split_to(count * 3 * 2);
+                let count = buf.get_u16();
+                if count > max_colors {
+                    return Err(DecodeError::TooManyColors(count));
+                }
+                // 16 bits per colour channel, which overflows a 16 bit usize
+                let size = (count as usize)
+                    .checked_mul(3 * 2)
+                    .ok_or(DecodeError::TooManyColors(count))?;
+                ensure_size(buf, size)?;
+                let colors = buf.split_to(size);
                 Ok(S2C::SetColorMapEntries {
                     first_color,
                     colors,
@@ -648,9 +1200,17 @@ impl Message for S2C {
                 let _pad = buf.split_to(3);
                 Ok(S2C::CutText(String::read_from(buf)?))
             }
+            150 => Ok(S2C::EndOfContinuousUpdates),
+            253 => Ok(S2C::Gii(Gii::read_from(buf)?)),
             m => Err(DecodeError::UnsupportedS2C(m)),
         }
     }
+}
+
+impl Message for S2C {
+    fn read_from(buf: &mut Bytes) -> Result<Self, DecodeError> {
+        Self::read_limited(buf, u16::MAX)
+    }
 
     fn write_to(&self, buf: &mut BytesMut) {
         match self {
@@ -666,9 +1226,10 @@ impl Message for S2C {
                 buf.put_u8(1);
                 buf.put_u8(0);
                 buf.put_u16(*first_color);
-                let count = colors.len() / 6;
-                buf.put_u16(count.try_into().unwrap());
-                buf.put(colors.as_ref());
+                // more colours cannot have been read from a message
+                let count = (colors.len() / 6).min(u16::MAX as usize);
+                buf.put_u16(count as u16);
+                buf.put(&colors[..count * 6]);
             }
             S2C::Bell => {
                 buf.put_u8(2);
@@ -678,6 +1239,13 @@ impl Message for S2C {
                 buf.put_bytes(0, 3);
                 String::write_to(text, buf);
             }
+            S2C::EndOfContinuousUpdates => {
+                buf.put_u8(150);
+            }
+            S2C::Gii(gii) => {
+                buf.put_u8(253);
+                gii.write_to(buf);
+            }
         }
     }
 }
@@ -707,38 +1275,141 @@ impl Message for Zrle {
     }
 }
 
+/// Reading and writing RFB messages over any async stream.
 pub mod io {
-    use bytes::{Bytes, BytesMut};
+    use bytes::{Buf, Bytes, BytesMut};
     use std::{io, mem};
-    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        time::{timeout_at, Duration, Instant},
+    };
 
     use super::{DecodeError, Message};
-    use crate::Result;
+    use crate::{Error, Result};
 
+    /// Reads and writes [Message]s over a stream, which can be anything implementing
+    /// [AsyncRead] and/or [AsyncWrite], e.g. a TCP connection, a TLS stream or an
+    /// in-memory [tokio::io::duplex] pipe. Split streams can be wrapped half by half.
+    ///
+    /// Bytes are read from the stream in chunks, so a buffer may hold the beginning of the
+    /// next message after the current one. If a message cannot be parsed because it is
+    /// incomplete, nothing is consumed and parsing starts over once more bytes have
+    /// arrived, so no bytes are lost between messages. Messages are written right away,
+    /// unless they are queued with [RfbIo::queue_message] to be written together.
     pub struct RfbIo<S> {
         stream: S,
+        /// Bytes read but not consumed yet.
         buf: BytesMut,
+        /// Kept separately from `buf`, so one stream can be used in both directions.
+        write_buf: BytesMut,
+        /// Number of bytes still to be dropped as they arrive, see [RfbIo::read_skipping].
+        skip: usize,
+        /// Number of bytes of an undecodable message to include in the error.
+        capture: usize,
+        /// Time the rest of a message may take to arrive, see [RfbIo::with_read_timeout].
+        read_timeout: Option<Duration>,
+        /// When the first bytes of the message being read arrived.
+        partial_since: Option<Instant>,
+        /// See [RfbIo::on_slow_write].
+        slow_write: Option<(Duration, SlowWriteHook)>,
+        /// Whether the last write took longer than the threshold.
+        writing_slowly: bool,
     }
 
+    type SlowWriteHook = Box<dyn Fn(Duration) + Send>;
+
     impl<S> RfbIo<S> {
         pub fn new(stream: S) -> Self {
             Self {
                 stream,
                 buf: BytesMut::with_capacity(0x1000),
+                write_buf: BytesMut::new(),
+                skip: 0,
+                capture: 0,
+                read_timeout: None,
+                partial_since: None,
+                slow_write: None,
+                writing_slowly: false,
+            }
+        }
+
+        /// Makes a decode error include up to `len` bytes of the message which could not
+        /// be decoded, see [Error::DecodeCaptured].
+        pub fn capturing(self, len: usize) -> Self {
+            Self {
+                capture: len,
+                ..self
+            }
+        }
+
+        /// Makes reading a message fail with [io::ErrorKind::TimedOut] if the rest of it does
+        /// not arrive within `timeout` of its beginning.
+        pub fn with_read_timeout(self, timeout: Option<Duration>) -> Self {
+            Self {
+                read_timeout: timeout,
+                ..self
             }
         }
+
+        /// Calls `f` with the time a write took once it takes `threshold` or longer, e.g.
+        /// because the peer does not keep up. `f` is not called again until a write has
+        /// been faster than that.
+        pub fn on_slow_write(
+            self,
+            threshold: Duration,
+            f: impl Fn(Duration) + Send + 'static,
+        ) -> Self {
+            Self {
+                slow_write: Some((threshold, Box::new(f))),
+                ..self
+            }
+        }
+
+        /// Reports a write which took `elapsed` if it was slow, see [RfbIo::on_slow_write].
+        /// Called by the write methods, and for data written to the stream directly.
+        pub fn check_write_time(&mut self, elapsed: Duration) {
+            let Some((threshold, f)) = &self.slow_write else {
+                return;
+            };
+            let slow = elapsed >= *threshold;
+            if slow && !self.writing_slowly {
+                f(elapsed);
+            }
+            self.writing_slowly = slow;
+        }
+
+        /// Returns the stream, dropping any bytes which have been read but not consumed.
+        pub fn into_inner(self) -> S {
+            self.stream
+        }
+
+        /// Returns the stream, e.g. to read from it directly after [RfbIo::take_buffered].
+        pub fn get_ref(&self) -> &S {
+            &self.stream
+        }
     }
 
     impl<S: AsyncRead + Unpin> RfbIo<S> {
         pub async fn read_message<M: Message>(&mut self) -> Result<M> {
+            self.read_with(M::read_from).await
+        }
+
+        /// Reads a message with a parser which depends on more than the bytes received,
+        /// such as the pixel format. The parser may be called repeatedly until enough
+        /// bytes have arrived.
+        pub async fn read_with<M>(
+            &mut self,
+            mut parse: impl FnMut(&mut Bytes) -> std::result::Result<M, DecodeError>,
+        ) -> Result<M> {
             loop {
+                self.drop_skipped();
                 if !self.buf.is_empty() {
                     // temporarily take out self.buf (leaving behind an empty buffer)
                     let buf = mem::take(&mut self.buf).freeze();
                     // create an RC copy for reading and leave buf untouched
                     let mut read_buf = buf.clone();
 
-                    match M::read_from(&mut read_buf) {
+                    match parse(&mut read_buf) {
                         Ok(msg) => {
                             // successfully read a message from read_buf
                             // throw away buf and put read_buf back into self.buf
@@ -746,39 +1417,104 @@ pub mod io {
                             // converting read_buf back into a BytesMut may copy
                             // if msg holds references into the original buf
                             self.buf = read_buf.into();
+                            self.partial_since = None;
 
                             return Ok(msg);
                         }
                         Err(DecodeError::InsufficientBytes) => {}
+                        Err(e) if self.capture > 0 => {
+                            let bytes = &buf[..buf.len().min(self.capture)];
+                            return Err(Error::DecodeCaptured {
+                                source: e,
+                                bytes: bytes.iter().map(|b| format!("{b:02x}")).collect(),
+                            });
+                        }
                         Err(e) => return Err(e.into()),
                     }
 
                     // we need more data to fully parse a message
                     // throw away read_buf to discard the cursor
                     drop(read_buf);
-                    // conversion to BytesMut should never fail as no other
-                    // references can exist at this point
+                    // payloads read earlier may still share the allocation, in which
+                    // case the unparsed bytes are copied
                     self.buf = buf
                         .try_into_mut()
-                        .expect("buf not unique after partial parse");
+                        .unwrap_or_else(|buf| BytesMut::from(&buf[..]));
+                }
+
+                // the timeout only runs while a message is incomplete
+                if self.buf.is_empty() && self.skip == 0 {
+                    self.partial_since = None;
+                } else if self.partial_since.is_none() {
+                    self.partial_since = Some(Instant::now());
                 }
 
                 // this will reclaim memory if possible
                 self.buf.reserve(0x100);
-                let bytes_read = self.stream.read_buf(&mut self.buf).await?;
+                let read = self.stream.read_buf(&mut self.buf);
+                let bytes_read = match self.read_timeout.zip(self.partial_since) {
+                    Some((limit, since)) => timeout_at(since + limit, read)
+                        .await
+                        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??,
+                    None => read.await?,
+                };
                 if 0 == bytes_read {
                     return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
                 }
             }
         }
 
+        /// Like [RfbIo::read_with], but the parser also returns a number of bytes following
+        /// what it consumed which are dropped as they arrive instead of being buffered,
+        /// e.g. the rest of an oversized message.
+        pub async fn read_skipping<M>(
+            &mut self,
+            parse: impl FnMut(&mut Bytes) -> std::result::Result<(M, usize), DecodeError>,
+        ) -> Result<M> {
+            let (message, skip) = self.read_with(parse).await?;
+            self.skip += skip;
+            Ok(message)
+        }
+
+        fn drop_skipped(&mut self) {
+            let len = self.skip.min(self.buf.len());
+            self.buf.advance(len);
+            self.skip -= len;
+        }
+
+        /// Returns up to `len` bytes which have been read but not consumed yet.
+        pub fn take_buffered(&mut self, len: usize) -> Bytes {
+            self.drop_skipped();
+            let len = len.min(self.buf.len());
+            self.buf.split_to(len).freeze()
+        }
+
+        /// Whether the rest of a message is being dropped, see [RfbIo::read_skipping].
+        pub fn is_skipping(&self) -> bool {
+            self.skip > 0
+        }
+
+        /// Returns the next byte without consuming it, if it has been received already.
+        pub fn peek_buffered(&self) -> Option<u8> {
+            match self.skip {
+                0 => self.buf.first().copied(),
+                _ => None,
+            }
+        }
+
+        /// Reads `len` bytes of data which do not form a message, such as pixel data.
         pub async fn read_data(&mut self, len: usize) -> Result<Bytes> {
+            self.drop_skipped();
             self.buf.reserve(len);
-            while self.buf.len() < len {
+            while self.skip > 0 || self.buf.len() < len {
                 let bytes_read = self.stream.read_buf(&mut self.buf).await?;
                 if 0 == bytes_read {
-                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    return Err(Error::TruncatedPayload {
+                        expected: len,
+                        got: self.buf.len(),
+                    });
                 }
+                self.drop_skipped();
             }
 
             let payload = self.buf.split_to(len).freeze();
@@ -787,16 +1523,953 @@ pub mod io {
     }
 
     impl<S: AsyncWrite + Unpin> RfbIo<S> {
+        /// Writes a message along with any queued ones.
         pub async fn write_message<M: Message>(&mut self, message: M) -> Result<()> {
-            self.buf.clear();
-            message.write_to(&mut self.buf);
-            self.stream.write_all(&self.buf).await?;
-            Ok(())
+            self.queue_message(message);
+            self.flush().await
         }
 
+        /// Writes data which does not form a message, such as pixel data, after any queued
+        /// messages.
         pub async fn write_data(&mut self, data: Bytes) -> Result<()> {
+            self.flush().await?;
+            let start = Instant::now();
             self.stream.write_all(&data).await?;
+            self.check_write_time(start.elapsed());
             Ok(())
         }
+
+        /// Appends a message to those written by the next [RfbIo::flush], so several
+        /// messages go out in a single write.
+        pub fn queue_message<M: Message>(&mut self, message: M) {
+            message.write_to(&mut self.write_buf);
+        }
+
+        /// Writes all queued messages.
+        pub async fn flush(&mut self) -> Result<()> {
+            if !self.write_buf.is_empty() {
+                let start = Instant::now();
+                self.stream.write_all(&self.write_buf).await?;
+                self.check_write_time(start.elapsed());
+                self.write_buf.clear();
+            }
+            Ok(())
+        }
+
+        /// Writes all queued messages and then shuts down the stream for writing, so the
+        /// peer sees the end of the stream after everything written so far.
+        pub async fn shutdown(&mut self) -> Result<()> {
+            self.flush().await?;
+            self.stream.shutdown().await?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(message: impl Message) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        message.write_to(&mut buf);
+        buf.to_vec()
+    }
+
+    #[test]
+    fn pixel_formats_are_byte_exact() {
+        #[rustfmt::skip]
+        let cases = [
+            (PixelFormat::default(), [32, 24, 0, 1, 0, 255, 0, 255, 0, 255, 16, 8, 0, 0, 0, 0]),
+            (PixelFormat::rgba8888(), [32, 24, 0, 1, 0, 255, 0, 255, 0, 255, 0, 8, 16, 0, 0, 0]),
+            (PixelFormat::rgb565(), [16, 16, 0, 1, 0, 31, 0, 63, 0, 31, 11, 5, 0, 0, 0, 0]),
+        ];
+        for (format, bytes) in cases {
+            assert_eq!(encode(format.clone()), bytes);
+            let decoded = PixelFormat::read_from(&mut Bytes::copy_from_slice(&bytes)).unwrap();
+            assert_eq!(decoded, format);
+        }
+    }
+
+    #[test]
+    fn invalid_pixel_formats_are_rejected() {
+        let colour_map = PixelFormat {
+            bits_per_pixel: 8,
+            depth: 8,
+            true_colour: false,
+            ..Default::default()
+        };
+        let valid = [colour_map.clone(), PixelFormat::rgb565()];
+        for format in valid {
+            let res = PixelFormat::read_from(&mut Bytes::from(encode(format.clone())));
+            assert_eq!(res.unwrap(), format);
+        }
+
+        let invalid = [
+            PixelFormat {
+                red_shift: 32,
+                ..Default::default()
+            },
+            PixelFormat {
+                blue_shift: 16,
+                ..PixelFormat::rgb565()
+            },
+            PixelFormat {
+                bits_per_pixel: 24,
+                ..Default::default()
+            },
+            PixelFormat {
+                bits_per_pixel: 0,
+                ..colour_map
+            },
+        ];
+        for format in invalid {
+            let message = encode(C2S::SetPixelFormat(format.clone()));
+            let res = C2S::read_from(&mut Bytes::from(message));
+            assert!(
+                matches!(res, Err(DecodeError::InvalidPixelFormat(ref f)) if *f == format),
+                "{res:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_colour_map_headers_need_more_bytes() {
+        for len in 1..6 {
+            let mut buf = Bytes::copy_from_slice(&[1, 0, 0, 0, 0, 1][..len]);
+            let res = S2C::read_from(&mut buf);
+            assert!(
+                matches!(res, Err(DecodeError::InsufficientBytes)),
+                "{res:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn colour_maps_are_capped() {
+        const HEADER: [u8; 6] = [1, 0, 0, 0, 0xff, 0xff];
+        let mut buf = Bytes::from_static(&HEADER);
+        let res = S2C::read_limited(&mut buf, 256);
+        assert!(
+            matches!(res, Err(DecodeError::TooManyColors(0xffff))),
+            "{res:?}"
+        );
+
+        // without a cap, the whole map is waited for
+        let mut buf = Bytes::from_static(&HEADER);
+        let res = S2C::read_limited(&mut buf, u16::MAX);
+        assert!(
+            matches!(res, Err(DecodeError::InsufficientBytes)),
+            "{res:?}"
+        );
+        let mut buf = BytesMut::from(&HEADER[..]);
+        buf.resize(HEADER.len() + 0xffff * 6, 0);
+        let Ok(S2C::SetColorMapEntries {
+            first_color,
+            colors,
+        }) = S2C::read_from(&mut buf.freeze())
+        else {
+            panic!("expected the colour map");
+        };
+        assert_eq!((first_color, colors.len()), (0, 0xffff * 6));
+    }
+
+    #[test]
+    fn single_messages_are_parsed() {
+        #[rustfmt::skip]
+        let c2s: [&[u8]; 8] = [
+            &[0, 0, 0, 0, 32, 24, 0, 1, 0, 255, 0, 255, 0, 255, 16, 8, 0, 0, 0, 0],
+            &[2, 0, 0, 2, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0x11],
+            &[3, 1, 0, 1, 0, 2, 0, 3, 0, 4],
+            &[4, 1, 0, 0, 0, 0, 0xff, 0x0d],
+            &[5, 1, 0, 10, 0, 20],
+            &[6, 0, 0, 0, 0, 0, 0, 2, b'h', b'i'],
+            &[150, 1, 0, 0, 0, 0, 0, 64, 0, 48],
+            &[253, 0x81, 0, 2, 7, 8],
+        ];
+        #[rustfmt::skip]
+        let s2c: [&[u8]; 6] = [
+            &[0, 0, 0, 5],
+            &[1, 0, 0, 1, 0, 1, 0, 0, 0xff, 0xff, 0, 0],
+            &[2],
+            &[3, 0, 0, 0, 0, 0, 0, 1, b'x'],
+            &[150],
+            &[253, 1, 2, 0, 7, 8],
+        ];
+
+        // one after the other, each one consuming exactly its own bytes
+        let stream = c2s.concat();
+        let mut rest = &stream[..];
+        for bytes in c2s {
+            let (message, len) = parse_c2s(rest).unwrap();
+            assert_eq!(len, bytes.len(), "{message:?}");
+            assert_eq!(serialize(&message), bytes);
+            rest = &rest[len..];
+        }
+        let stream = s2c.concat();
+        let mut rest = &stream[..];
+        for bytes in s2c {
+            let (message, len) = parse_s2c(rest).unwrap();
+            assert_eq!(len, bytes.len(), "{message:?}");
+            assert_eq!(serialize(&message), bytes);
+            rest = &rest[len..];
+        }
+
+        let res = parse_c2s(&c2s[0][..19]);
+        assert!(
+            matches!(res, Err(DecodeError::InsufficientBytes)),
+            "{res:?}"
+        );
+        let res = parse_c2s(&[1]);
+        assert!(
+            matches!(res, Err(DecodeError::UnsupportedC2S(1))),
+            "{res:?}"
+        );
+        let res = parse_s2c(&[4]);
+        assert!(
+            matches!(res, Err(DecodeError::UnsupportedS2C(4))),
+            "{res:?}"
+        );
+    }
+
+    #[test]
+    fn payload_sizes_are_checked() {
+        let rect = |width, height, encoding| Rectangle {
+            x: 0,
+            y: 0,
+            width,
+            height,
+            encoding,
+        };
+        let format = PixelFormat::default();
+        assert_eq!(rect(3, 2, Encoding::Raw).payload_size(&format).unwrap(), 24);
+        // 4 bytes per pixel plus a mask of one byte per row
+        assert_eq!(
+            rect(3, 2, Encoding::Cursor).payload_size(&format).unwrap(),
+            26
+        );
+
+        for bits_per_pixel in [0, 4, 24] {
+            let format = PixelFormat {
+                bits_per_pixel,
+                ..Default::default()
+            };
+            for encoding in [Encoding::Raw, Encoding::Cursor] {
+                let res = rect(3, 2, encoding).payload_size(&format);
+                assert!(
+                    matches!(res, Err(DecodeError::UnsupportedBitsPerPixel(bpp)) if bpp == bits_per_pixel),
+                    "{res:?}"
+                );
+            }
+            assert_eq!(
+                rect(3, 2, Encoding::CopyRect)
+                    .payload_size(&format)
+                    .unwrap(),
+                4
+            );
+        }
+
+        // found by fuzzing, which panicked on encodings without a fixed size
+        for encoding in [Encoding::Hextile, Encoding::Zrle, Encoding::Unknown(1234)] {
+            let res = rect(3, 2, encoding).payload_size(&format);
+            assert!(
+                matches!(res, Err(DecodeError::UnsupportedEncoding(e)) if e == encoding),
+                "{res:?}"
+            );
+        }
+
+        let huge = rect(u16::MAX, u16::MAX, Encoding::Cursor).payload_size(&format);
+        if cfg!(target_pointer_width = "64") {
+            let max = u16::MAX as usize;
+            assert_eq!(huge.unwrap(), max * max * 4 + max.div_ceil(8) * max);
+        } else {
+            assert!(
+                matches!(huge, Err(DecodeError::RectangleTooLarge)),
+                "{huge:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn long_names_are_rejected_up_front() {
+        let mut init = BytesMut::from(&[0, 64, 0, 48][..]);
+        PixelFormat::default().write_to(&mut init);
+        for len in [MAX_NAME_LEN as u32 + 1, u32::MAX] {
+            let mut buf = init.clone();
+            buf.put_u32(len);
+            let res = ServerInit::read_from(&mut buf.freeze());
+            assert!(
+                matches!(res, Err(DecodeError::NameTooLong(l)) if l == len as usize),
+                "{res:?}"
+            );
+        }
+
+        let mut buf = init.clone();
+        buf.put_u32(MAX_NAME_LEN as u32);
+        let res = ServerInit::read_from(&mut buf.clone().freeze());
+        assert!(
+            matches!(res, Err(DecodeError::InsufficientBytes)),
+            "{res:?}"
+        );
+        buf.put_bytes(b'x', MAX_NAME_LEN);
+        let init = ServerInit::read_from(&mut buf.freeze()).unwrap();
+        assert_eq!(init.name.len(), MAX_NAME_LEN);
+    }
+
+    #[test]
+    fn encoding_counts_are_checked_up_front() {
+        // two encodings announced, one sent
+        let mut buf = Bytes::from_static(&[2, 0, 0, 2, 0, 0, 0, 0]);
+        let res = C2S::read_from(&mut buf);
+        assert!(
+            matches!(res, Err(DecodeError::InsufficientBytes)),
+            "{res:?}"
+        );
+
+        let mut buf = Bytes::from_static(&[2, 0, 0xff, 0xff]);
+        let res = C2S::read_from(&mut buf);
+        assert!(
+            matches!(res, Err(DecodeError::TooManyEncodings(0xffff))),
+            "{res:?}"
+        );
+
+        let mut buf = BytesMut::from(&[2, 0, 2, 0][..]);
+        buf.resize(4 + MAX_ENCODINGS * 4, 0);
+        let Ok(C2S::SetEncodings(encodings)) = C2S::read_from(&mut buf.freeze()) else {
+            panic!("expected the encodings");
+        };
+        assert_eq!(encodings, [Encoding::Raw; MAX_ENCODINGS]);
+    }
+
+    /// Checks that every prefix of the encoded message is too short to be parsed.
+    fn assert_needs_every_byte<M: Message + Clone + PartialEq + std::fmt::Debug>(message: M) {
+        let bytes = encode(message.clone());
+        for len in 0..bytes.len() {
+            let mut buf = Bytes::copy_from_slice(&bytes[..len]);
+            let res = M::read_from(&mut buf);
+            assert!(
+                matches!(res, Err(DecodeError::InsufficientBytes)),
+                "{message:?} cut to {len} bytes: {res:?}"
+            );
+        }
+        let (parsed, len) = parse::<M>(&bytes).unwrap();
+        assert_eq!((parsed, len), (message, bytes.len()));
+    }
+
+    #[test]
+    fn truncated_messages_need_more_bytes() {
+        let rect = Rectangle {
+            x: 1,
+            y: 2,
+            width: 3,
+            height: 4,
+            encoding: Encoding::Raw,
+        };
+        let gii = Gii {
+            big_endian: true,
+            subtype: 1,
+            payload: Bytes::from_static(&[1, 2, 3]),
+        };
+
+        assert_needs_every_byte(Version::new(3, 8));
+        assert_needs_every_byte(SecurityTypes::new(&[1, 2]).unwrap());
+        assert_needs_every_byte(SecurityType(2));
+        assert_needs_every_byte(SecurityResult(1));
+        assert_needs_every_byte(ClientInit { shared: true });
+        assert_needs_every_byte("reason".to_string());
+        assert_needs_every_byte(PixelFormat::rgb565());
+        assert_needs_every_byte(ServerInit {
+            framebuffer_width: 64,
+            framebuffer_height: 48,
+            pixel_format: PixelFormat::default(),
+            name: "name".to_string(),
+        });
+        assert_needs_every_byte(rect);
+        assert_needs_every_byte(CopyRect { src_x: 1, src_y: 2 });
+
+        assert_needs_every_byte(C2S::SetPixelFormat(PixelFormat::default()));
+        assert_needs_every_byte(C2S::SetEncodings(vec![Encoding::Raw, Encoding::Cursor]));
+        assert_needs_every_byte(C2S::FramebufferUpdateRequest {
+            incremental: true,
+            x: 1,
+            y: 2,
+            width: 3,
+            height: 4,
+        });
+        assert_needs_every_byte(C2S::KeyEvent { down: true, key: 1 });
+        assert_needs_every_byte(C2S::PointerEvent {
+            button_mask: Buttons(1),
+            x: 1,
+            y: 2,
+        });
+        assert_needs_every_byte(C2S::CutText("text".to_string()));
+        assert_needs_every_byte(C2S::EnableContinuousUpdates {
+            enable: true,
+            x: 1,
+            y: 2,
+            width: 3,
+            height: 4,
+        });
+        assert_needs_every_byte(C2S::Gii(gii.clone()));
+
+        assert_needs_every_byte(S2C::FramebufferUpdate { count: 2 });
+        assert_needs_every_byte(S2C::SetColorMapEntries {
+            first_color: 1,
+            colors: Bytes::from_static(&[0, 1, 0, 2, 0, 3]),
+        });
+        assert_needs_every_byte(S2C::Bell);
+        assert_needs_every_byte(S2C::CutText("text".to_string()));
+        assert_needs_every_byte(S2C::EndOfContinuousUpdates);
+        assert_needs_every_byte(S2C::Gii(gii));
+    }
+
+    #[test]
+    fn limits_are_checked_before_waiting_for_the_rest() {
+        let mut init = BytesMut::from(&[0, 64, 0, 48][..]);
+        PixelFormat::default().write_to(&mut init);
+        let name_len = |len: u32| [&init[..], &len.to_be_bytes()].concat();
+
+        // headers announcing too much fail right away, others wait for the rest
+        let encodings = |count: u16| {
+            let [a, b] = count.to_be_bytes();
+            C2S::read_from(&mut Bytes::from(vec![2, 0, a, b]))
+        };
+        assert!(matches!(
+            encodings(513),
+            Err(DecodeError::TooManyEncodings(513))
+        ));
+        assert!(matches!(
+            encodings(512),
+            Err(DecodeError::InsufficientBytes)
+        ));
+
+        let colors = |count: u16| {
+            let [a, b] = count.to_be_bytes();
+            S2C::read_limited(&mut Bytes::from(vec![1, 0, 0, 0, a, b]), 256)
+        };
+        assert!(matches!(colors(257), Err(DecodeError::TooManyColors(257))));
+        assert!(matches!(colors(256), Err(DecodeError::InsufficientBytes)));
+
+        let name = |len| ServerInit::read_from(&mut Bytes::from(name_len(len)));
+        assert!(matches!(
+            name(0x10001),
+            Err(DecodeError::NameTooLong(0x10001))
+        ));
+        assert!(matches!(name(0x10000), Err(DecodeError::InsufficientBytes)));
+    }
+
+    #[test]
+    fn long_cut_text_is_detected_from_its_header() {
+        let message = encode(S2C::CutText("x".repeat(100)));
+        for len in 0..8 {
+            let mut buf = Bytes::copy_from_slice(&message[..len]);
+            let res = read_long_cut_text(&mut buf, 3, 10, 10);
+            assert!(
+                matches!(res, Err(DecodeError::InsufficientBytes)),
+                "{res:?}"
+            );
+        }
+
+        // kept text is waited for, the rest is left to be skipped
+        let mut buf = Bytes::copy_from_slice(&message[..17]);
+        let res = read_long_cut_text(&mut buf, 3, 10, 10);
+        assert!(
+            matches!(res, Err(DecodeError::InsufficientBytes)),
+            "{res:?}"
+        );
+        let mut buf = Bytes::copy_from_slice(&message[..18]);
+        let (text, rest) = read_long_cut_text(&mut buf, 3, 10, 10).unwrap().unwrap();
+        assert_eq!((text.len(), rest), (10, 90));
+        let mut buf = Bytes::copy_from_slice(&message[..8]);
+        let (text, rest) = read_long_cut_text(&mut buf, 3, 10, 0).unwrap().unwrap();
+        assert_eq!((text.len(), rest), (0, 100));
+
+        // short text and other messages are parsed as usual
+        let mut buf = Bytes::copy_from_slice(&message);
+        assert_eq!(read_long_cut_text(&mut buf, 3, 100, 100).unwrap(), None);
+        let mut buf = Bytes::from_static(&[2]);
+        assert_eq!(read_long_cut_text(&mut buf, 3, 10, 10).unwrap(), None);
+    }
+
+    #[test]
+    fn oversized_messages_are_not_written() {
+        let res = SecurityTypes::new(&[1; 300]);
+        assert!(
+            matches!(res, Err(DecodeError::TooManySecurityTypes(300))),
+            "{res:?}"
+        );
+        let types = SecurityTypes::new(&[1; 255]).unwrap();
+        assert_eq!(encode(types)[..2], [255, 1]);
+
+        // a trailing partial entry is left out, along with the count
+        let entries = S2C::SetColorMapEntries {
+            first_color: 3,
+            colors: Bytes::from_static(&[0, 1, 0, 2, 0, 3, 9]),
+        };
+        assert_eq!(encode(entries), [1, 0, 0, 3, 0, 1, 0, 1, 0, 2, 0, 3]);
+    }
+
+    #[test]
+    fn encodings_round_trip() {
+        for n in -400..=400 {
+            assert_eq!(Encoding::from_i32(n).to_i32(), n);
+        }
+        for n in [i32::MIN, -0x10000, 0x10000, i32::MAX] {
+            assert_eq!(Encoding::from_i32(n), Encoding::Unknown(n));
+            assert_eq!(Encoding::Unknown(n).to_i32(), n);
+        }
+        assert_eq!(Encoding::from_i32(16), Encoding::Zrle);
+        assert_eq!(Encoding::from_i32(-239), Encoding::Cursor);
+        assert_eq!(Encoding::Tight.to_i32(), 7);
+    }
+
+    #[test]
+    fn gii_version_messages_round_trip() {
+        // the server's versions, 1 to 1, in big endian
+        let server = [253, 0x81, 0, 4, 0, 1, 0, 1];
+        let message = S2C::read_from(&mut Bytes::copy_from_slice(&server)).unwrap();
+        let S2C::Gii(gii) = &message else {
+            panic!("expected a GII message, got {message:?}");
+        };
+        assert!(gii.big_endian);
+        assert_eq!(gii.subtype, 1);
+        assert_eq!(gii.payload, [0, 1, 0, 1][..]);
+        assert_eq!(encode(message), server);
+
+        // the client's version, 1, in little endian
+        let client = [253, 1, 2, 0, 1, 0];
+        let message = C2S::read_from(&mut Bytes::copy_from_slice(&client)).unwrap();
+        let C2S::Gii(gii) = &message else {
+            panic!("expected a GII message, got {message:?}");
+        };
+        assert!(!gii.big_endian);
+        assert_eq!(gii.subtype, 1);
+        assert_eq!(gii.payload, [1, 0][..]);
+        assert_eq!(encode(message), client);
+        assert_eq!(Encoding::from_i32(-305), Encoding::Gii);
+    }
+
+    #[test]
+    fn quality_and_compression_levels_are_mapped() {
+        assert_eq!(Encoding::from_i32(-23), Encoding::JpegQuality(9));
+        assert_eq!(Encoding::from_i32(-32), Encoding::JpegQuality(0));
+        assert_eq!(Encoding::from_i32(-256), Encoding::CompressionLevel(0));
+        assert_eq!(Encoding::from_i32(-247), Encoding::CompressionLevel(9));
+        assert_eq!(Encoding::JpegQuality(5).to_i32(), -27);
+        assert_eq!(Encoding::CompressionLevel(5).to_i32(), -251);
+        // just outside the ranges
+        for n in [-33, -22, -257, -246] {
+            assert_eq!(Encoding::from_i32(n), Encoding::Unknown(n));
+        }
+
+        let encodings = vec![Encoding::JpegQuality(9), Encoding::CompressionLevel(0)];
+        assert_eq!(
+            encode(C2S::SetEncodings(encodings)),
+            [2, 0, 0, 2, 0xff, 0xff, 0xff, 0xe9, 0xff, 0xff, 0xff, 0]
+        );
+    }
+
+    #[test]
+    fn buttons_are_read_from_their_bits() {
+        let none = Buttons::default();
+        assert!(!none.left() && !none.middle() && !none.right());
+        assert!(!none.wheel_up() && !none.wheel_down());
+        assert!(!none.wheel_left() && !none.wheel_right());
+
+        let cases = [
+            (0b0000_0001, Buttons::left as fn(Buttons) -> bool),
+            (0b0000_0010, Buttons::middle),
+            (0b0000_0100, Buttons::right),
+            (0b0000_1000, Buttons::wheel_up),
+            (0b0001_0000, Buttons::wheel_down),
+            (0b0010_0000, Buttons::wheel_left),
+            (0b0100_0000, Buttons::wheel_right),
+        ];
+        for (i, (mask, accessor)) in cases.iter().enumerate() {
+            assert!(accessor(Buttons(*mask)), "{mask:#010b}");
+            assert!(!accessor(Buttons(!*mask)), "{mask:#010b}");
+            for (other, _) in cases.iter().skip(i + 1) {
+                assert!(!accessor(Buttons(*other)), "{mask:#010b} {other:#010b}");
+            }
+        }
+
+        // left and right held while scrolling down
+        let mask = Buttons(0b0001_0101);
+        assert!(mask.pressed(Button::Left) && mask.pressed(Button::Right));
+        assert!(mask.pressed(Button::WheelDown));
+        assert!(!mask.pressed(Button::Middle) && !mask.pressed(Button::WheelUp));
+
+        // the wire format is the mask as is
+        let event = C2S::PointerEvent {
+            button_mask: mask,
+            x: 1,
+            y: 2,
+        };
+        assert_eq!(encode(event.clone()), [5, 0b0001_0101, 0, 1, 0, 2]);
+        let mut buf = Bytes::from_static(&[5, 0b0001_0101, 0, 1, 0, 2]);
+        assert_eq!(C2S::read_from(&mut buf).unwrap(), event);
+    }
+
+    /// Checks that `message` is written as `bytes` and read back from them.
+    #[track_caller]
+    fn assert_layout<M: Message + PartialEq + std::fmt::Debug>(message: M, bytes: &[u8]) {
+        assert_eq!(&serialize(&message)[..], bytes, "{message:?}");
+        assert_eq!(parse::<M>(bytes).unwrap(), (message, bytes.len()));
+    }
+
+    /// The default pixel format, 32 bit little endian true colour.
+    const FORMAT: [u8; 16] = [32, 24, 0, 1, 0, 255, 0, 255, 0, 255, 16, 8, 0, 0, 0, 0];
+
+    #[test]
+    fn handshake_messages_have_their_layout() {
+        assert_layout(Version::new(3, 8), b"RFB 003.008\n");
+        assert_layout(SecurityTypes::new(&[1, 2]).unwrap(), &[2, 1, 2]);
+        assert_layout(SecurityType(2), &[2]);
+        assert_layout(SecurityResult(1), &[0, 0, 0, 1]);
+        assert_layout(ClientInit { shared: true }, &[1]);
+        assert_layout(ClientInit { shared: false }, &[0]);
+        assert_layout("abc".to_string(), &[0, 0, 0, 3, b'a', b'b', b'c']);
+
+        let mut bytes = vec![0x01, 0x02, 0x03, 0x04];
+        bytes.extend(FORMAT);
+        bytes.extend([0, 0, 0, 2, b'h', b'i']);
+        let init = ServerInit {
+            framebuffer_width: 0x0102,
+            framebuffer_height: 0x0304,
+            pixel_format: PixelFormat::default(),
+            name: "hi".to_string(),
+        };
+        assert_layout(init, &bytes);
+    }
+
+    #[test]
+    fn rectangles_have_their_layout() {
+        let rect = Rectangle {
+            x: 0x0102,
+            y: 0x0304,
+            width: 0x0506,
+            height: 0x0708,
+            encoding: Encoding::Zrle,
+        };
+        assert_layout(rect, &[1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 16]);
+        let cursor = Rectangle {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+            encoding: Encoding::Cursor,
+        };
+        assert_layout(cursor, &[0, 0, 0, 0, 0, 1, 0, 1, 0xff, 0xff, 0xff, 0x11]);
+        let copy = CopyRect {
+            src_x: 0x0102,
+            src_y: 0x0304,
+        };
+        assert_layout(copy, &[1, 2, 3, 4]);
+
+        // ZRLE data is only passed through
+        let zrle = encode(Zrle(Bytes::from_static(&[9, 8, 7])));
+        assert_eq!(zrle, [0, 0, 0, 3, 9, 8, 7]);
+        let mut buf = Bytes::from(zrle.clone());
+        assert_eq!(encode(Zrle::read_from(&mut buf).unwrap()), zrle);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn server_messages_have_their_layout() {
+        assert_layout(S2C::FramebufferUpdate { count: 5 }, &[0, 0, 0, 5]);
+        assert_layout(S2C::FramebufferUpdate { count: 0x0102 }, &[0, 0, 1, 2]);
+        let colors = S2C::SetColorMapEntries {
+            first_color: 0x0102,
+            colors: Bytes::from_static(&[1, 2, 3, 4, 5, 6]),
+        };
+        assert_layout(colors, &[1, 0, 1, 2, 0, 1, 1, 2, 3, 4, 5, 6]);
+        assert_layout(S2C::Bell, &[2]);
+        assert_layout(
+            S2C::CutText("hi".to_string()),
+            &[3, 0, 0, 0, 0, 0, 0, 2, b'h', b'i'],
+        );
+        assert_layout(S2C::EndOfContinuousUpdates, &[150]);
+        let gii = Gii {
+            big_endian: false,
+            subtype: 1,
+            payload: Bytes::from_static(&[1, 0]),
+        };
+        assert_layout(S2C::Gii(gii), &[253, 1, 2, 0, 1, 0]);
+    }
+
+    #[test]
+    fn client_messages_have_their_layout() {
+        let mut bytes = vec![0, 0, 0, 0];
+        bytes.extend(FORMAT);
+        assert_layout(C2S::SetPixelFormat(PixelFormat::default()), &bytes);
+        assert_layout(
+            C2S::SetEncodings(vec![Encoding::Raw, Encoding::Cursor]),
+            &[2, 0, 0, 2, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0x11],
+        );
+        let request = C2S::FramebufferUpdateRequest {
+            incremental: true,
+            x: 0x0102,
+            y: 0x0304,
+            width: 0x0506,
+            height: 0x0708,
+        };
+        assert_layout(request, &[3, 1, 1, 2, 3, 4, 5, 6, 7, 8]);
+        let key = C2S::KeyEvent {
+            down: true,
+            key: 0xff0d,
+        };
+        assert_layout(key, &[4, 1, 0, 0, 0, 0, 0xff, 0x0d]);
+        let pointer = C2S::PointerEvent {
+            button_mask: Buttons(0b100),
+            x: 0x0102,
+            y: 0x0304,
+        };
+        assert_layout(pointer, &[5, 4, 1, 2, 3, 4]);
+        assert_layout(
+            C2S::CutText("hi".to_string()),
+            &[6, 0, 0, 0, 0, 0, 0, 2, b'h', b'i'],
+        );
+        let continuous = C2S::EnableContinuousUpdates {
+            enable: false,
+            x: 0x0102,
+            y: 0x0304,
+            width: 0x0506,
+            height: 0x0708,
+        };
+        assert_layout(continuous, &[150, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        let gii = Gii {
+            big_endian: true,
+            subtype: 1,
+            payload: Bytes::from_static(&[0, 1]),
+        };
+        assert_layout(C2S::Gii(gii), &[253, 0x81, 0, 2, 0, 1]);
+    }
+
+    mod io {
+        use tokio::{
+            io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+            time::{timeout, Duration, Instant},
+        };
+
+        use super::super::{io::RfbIo, *};
+        use super::encode;
+        use crate::Error;
+
+        #[tokio::test(start_paused = true)]
+        async fn incomplete_messages_time_out() {
+            let (client, mut server) = duplex(0x100);
+            let mut client = RfbIo::new(client).with_read_timeout(Some(Duration::from_secs(10)));
+
+            // idle connections are not affected
+            let idle = timeout(Duration::from_secs(60), client.read_message::<S2C>()).await;
+            assert!(idle.is_err());
+
+            // the first half of a CutText message
+            server.write_all(&[3, 0, 0, 0, 0, 0]).await.unwrap();
+            let start = Instant::now();
+            let res = client.read_message::<S2C>().await;
+            let Err(Error::Io(e)) = res else {
+                panic!("expected a timeout, got {res:?}");
+            };
+            assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+            assert_eq!(start.elapsed(), Duration::from_secs(10));
+        }
+
+        #[tokio::test]
+        async fn undecodable_bytes_are_captured_on_request() {
+            let (client, mut server) = duplex(0x100);
+            let mut client = RfbIo::new(client).capturing(3);
+            server.write_all(&[250, 0x12, 0xab, 0xff]).await.unwrap();
+            let res = client.read_message::<S2C>().await;
+            let Err(Error::DecodeCaptured { source, bytes }) = &res else {
+                panic!("expected the captured bytes, got {res:?}");
+            };
+            assert!(matches!(source, DecodeError::UnsupportedS2C(250)));
+            assert_eq!(bytes, "fa12ab");
+            assert!(res.unwrap_err().to_string().contains("fa12ab"));
+
+            let (client, mut server) = duplex(0x100);
+            let mut client = RfbIo::new(client);
+            server.write_all(&[250, 0x12, 0xab, 0xff]).await.unwrap();
+            let res = client.read_message::<S2C>().await;
+            assert!(
+                matches!(res, Err(Error::Decode(DecodeError::UnsupportedS2C(250)))),
+                "{res:?}"
+            );
+        }
+
+        #[tokio::test]
+        async fn messages_are_kept_across_reads_and_directions() {
+            let (client, server) = duplex(0x100);
+            let mut client = RfbIo::new(client);
+            let mut server = RfbIo::new(server);
+
+            // two messages in one write, the second one cut in half
+            let cut_text = encode(S2C::CutText("hello".to_string()));
+            let mut first = encode(S2C::Bell);
+            first.extend_from_slice(&cut_text[..6]);
+            server.write_data(first.into()).await.unwrap();
+            assert_eq!(client.read_message::<S2C>().await.unwrap(), S2C::Bell);
+
+            // writing does not disturb what has been read already
+            client
+                .write_message(C2S::KeyEvent { down: true, key: 1 })
+                .await
+                .unwrap();
+            let key = server.read_message::<C2S>().await.unwrap();
+            assert_eq!(key, C2S::KeyEvent { down: true, key: 1 });
+
+            let rest = Bytes::copy_from_slice(&cut_text[6..]);
+            let (read, written) = tokio::join!(client.read_message::<S2C>(), async {
+                tokio::task::yield_now().await;
+                server.write_data(rest).await
+            });
+            written.unwrap();
+            assert_eq!(read.unwrap(), S2C::CutText("hello".to_string()));
+        }
+
+        #[tokio::test]
+        async fn names_arriving_byte_by_byte_are_parsed() {
+            // the pipe holds a single byte, so each read gets one
+            let (client, mut server) = duplex(1);
+            let mut client = RfbIo::new(client);
+            let init = ServerInit {
+                framebuffer_width: 64,
+                framebuffer_height: 48,
+                pixel_format: PixelFormat::default(),
+                name: "desktop ".repeat(100),
+            };
+
+            let bytes = encode(init.clone());
+            let (written, read) = tokio::join!(
+                server.write_all(&bytes),
+                client.read_message::<ServerInit>()
+            );
+            written.unwrap();
+            assert_eq!(read.unwrap(), init);
+        }
+
+        /// Writes some data which the reader only catches up with after `delay` ms.
+        async fn write_slowly(tx: &mut RfbIo<DuplexStream>, rx: &mut DuplexStream, delay: u64) {
+            let data = Bytes::from(vec![1; 64]);
+            let (written, read) = tokio::join!(tx.write_data(data), async {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                let mut buf = [0; 64];
+                rx.read_exact(&mut buf).await
+            });
+            written.unwrap();
+            read.unwrap();
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn slow_writes_are_reported_once() {
+            let (tx, mut rx) = duplex(16);
+            let reported = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let mut tx = RfbIo::new(tx).on_slow_write(Duration::from_millis(100), {
+                let reported = reported.clone();
+                move |elapsed| reported.lock().unwrap().push(elapsed)
+            });
+
+            write_slowly(&mut tx, &mut rx, 200).await;
+            assert_eq!(*reported.lock().unwrap(), [Duration::from_millis(200)]);
+            // still slow
+            write_slowly(&mut tx, &mut rx, 300).await;
+            assert_eq!(reported.lock().unwrap().len(), 1);
+            write_slowly(&mut tx, &mut rx, 0).await;
+            write_slowly(&mut tx, &mut rx, 150).await;
+            assert_eq!(
+                *reported.lock().unwrap(),
+                [Duration::from_millis(200), Duration::from_millis(150)]
+            );
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn queued_messages_count_as_writes() {
+            let (tx, mut rx) = duplex(4);
+            let reported = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let mut tx = RfbIo::new(tx).on_slow_write(Duration::from_millis(100), {
+                let reported = reported.clone();
+                move |elapsed| reported.lock().unwrap().push(elapsed)
+            });
+
+            tx.queue_message(S2C::CutText("hello".to_string()));
+            let (flushed, read) = tokio::join!(tx.flush(), async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let mut buf = [0; 13];
+                rx.read_exact(&mut buf).await
+            });
+            flushed.unwrap();
+            read.unwrap();
+            assert_eq!(*reported.lock().unwrap(), [Duration::from_millis(100)]);
+        }
+
+        /// Records every write separately.
+        #[derive(Default)]
+        struct Writes(Vec<Vec<u8>>);
+
+        impl tokio::io::AsyncWrite for Writes {
+            fn poll_write(
+                mut self: std::pin::Pin<&mut Self>,
+                _cx: &mut std::task::Context<'_>,
+                buf: &[u8],
+            ) -> std::task::Poll<std::io::Result<usize>> {
+                self.0.push(buf.to_vec());
+                std::task::Poll::Ready(Ok(buf.len()))
+            }
+
+            fn poll_flush(
+                self: std::pin::Pin<&mut Self>,
+                _cx: &mut std::task::Context<'_>,
+            ) -> std::task::Poll<std::io::Result<()>> {
+                std::task::Poll::Ready(Ok(()))
+            }
+
+            fn poll_shutdown(
+                self: std::pin::Pin<&mut Self>,
+                _cx: &mut std::task::Context<'_>,
+            ) -> std::task::Poll<std::io::Result<()>> {
+                std::task::Poll::Ready(Ok(()))
+            }
+        }
+
+        #[tokio::test]
+        async fn queued_messages_are_written_together() {
+            let mut io = RfbIo::new(Writes::default());
+            let messages = [
+                S2C::FramebufferUpdate { count: 1 },
+                S2C::Bell,
+                S2C::CutText("text".to_string()),
+            ];
+            for message in messages.clone() {
+                io.queue_message(message);
+            }
+            io.flush().await.unwrap();
+            // nothing left to write
+            io.flush().await.unwrap();
+            let combined: Vec<u8> = messages.into_iter().flat_map(encode).collect();
+            assert_eq!(io.into_inner().0, [combined]);
+
+            // data goes out after the queued messages
+            let mut io = RfbIo::new(Writes::default());
+            io.queue_message(S2C::Bell);
+            io.write_data(Bytes::from_static(&[1, 2])).await.unwrap();
+            io.write_message(S2C::Bell).await.unwrap();
+            assert_eq!(io.into_inner().0, [vec![2], vec![1, 2], vec![2]]);
+        }
+
+        #[tokio::test]
+        async fn truncated_payloads_report_their_length() {
+            let (client, mut server) = duplex(0x100);
+            let mut client = RfbIo::new(client);
+
+            // half of a 4x4 Raw rectangle at 32 bits per pixel
+            server.write_all(&[0; 32]).await.unwrap();
+            drop(server);
+            let res = client.read_data(64).await;
+            let Err(Error::TruncatedPayload { expected, got }) = res else {
+                panic!("expected a truncated payload, got {res:?}");
+            };
+            assert_eq!((expected, got), (64, 32));
+        }
     }
 }
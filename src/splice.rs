@@ -0,0 +1,83 @@
+//! Forwarding of data from one TCP connection to another with `splice(2)`, which moves
+//! the data through a pipe inside the kernel instead of copying it through the proxy.
+
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    ptr,
+};
+
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant};
+
+/// Most data moved at once, the default capacity of a pipe.
+const PIPE_SIZE: usize = 64 << 10;
+
+/// The pipe data is moved through, which can be reused for any number of transfers.
+#[derive(Debug)]
+pub(crate) struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    pub fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for the two descriptors returned
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the descriptors were just created and are owned by nothing else
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        Ok(Self { read, write })
+    }
+
+    /// Moves `len` bytes from `from` to `to` and returns the time spent writing to `to`.
+    /// The pipe is empty again once this returns successfully.
+    pub async fn forward(
+        &self,
+        from: &TcpStream,
+        to: &TcpStream,
+        len: usize,
+    ) -> io::Result<Duration> {
+        let mut remaining = len;
+        let mut writing = Duration::ZERO;
+        while remaining > 0 {
+            let mut in_pipe = from
+                .async_io(Interest::READABLE, || {
+                    splice(
+                        from.as_raw_fd(),
+                        self.write.as_raw_fd(),
+                        remaining.min(PIPE_SIZE),
+                    )
+                })
+                .await?;
+            if in_pipe == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            remaining -= in_pipe;
+
+            let start = Instant::now();
+            while in_pipe > 0 {
+                in_pipe -= to
+                    .async_io(Interest::WRITABLE, || {
+                        splice(self.read.as_raw_fd(), to.as_raw_fd(), in_pipe)
+                    })
+                    .await?;
+            }
+            writing += start.elapsed();
+        }
+        Ok(writing)
+    }
+}
+
+fn splice(from: i32, to: i32, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    // SAFETY: both descriptors are open for the duration of the call
+    let n = unsafe { libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, flags) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
@@ -0,0 +1,158 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::time::{Duration, Instant};
+
+use crate::rfb::{C2S, S2C};
+
+/// Counts the messages of each type received by a client connection and keeps track of
+/// when they were received.
+#[derive(Debug)]
+pub(crate) struct MessageCounters {
+    c2s: [AtomicU64; C2S::KINDS.len()],
+    s2c: [AtomicU64; S2C::KINDS.len()],
+    /// Times are stored as nanoseconds since `created` plus one, so they can be updated
+    /// atomically and zero is never mistaken for a time.
+    created: Instant,
+    /// Zero until the handshake has completed.
+    connected_at: AtomicU64,
+    last_activity: AtomicU64,
+}
+
+impl Default for MessageCounters {
+    fn default() -> Self {
+        Self {
+            c2s: Default::default(),
+            s2c: Default::default(),
+            created: Instant::now(),
+            connected_at: AtomicU64::new(0),
+            last_activity: AtomicU64::new(0),
+        }
+    }
+}
+
+impl MessageCounters {
+    pub fn count_c2s(&self, message: &C2S) {
+        self.c2s[message.kind()].fetch_add(1, Ordering::Relaxed);
+        self.touch(&self.last_activity);
+    }
+
+    pub fn count_s2c(&self, message: &S2C) {
+        self.s2c[message.kind()].fetch_add(1, Ordering::Relaxed);
+        self.touch(&self.last_activity);
+    }
+
+    /// Marks the handshake as completed.
+    pub fn connected(&self) {
+        self.touch(&self.connected_at);
+        self.touch(&self.last_activity);
+    }
+
+    fn touch(&self, time: &AtomicU64) {
+        let nanos = self.created.elapsed().as_nanos() + 1;
+        time.store(nanos.try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    /// Returns `None` until the handshake has completed.
+    pub fn session_info(&self) -> Option<SessionInfo> {
+        let time = |nanos| self.created + Duration::from_nanos(nanos - 1);
+        match self.connected_at.load(Ordering::Relaxed) {
+            0 => None,
+            connected_at => Some(SessionInfo {
+                connected_at: time(connected_at),
+                last_activity: time(self.last_activity.load(Ordering::Relaxed)),
+            }),
+        }
+    }
+
+    pub fn snapshot(&self) -> MessageStats {
+        fn collect(names: &[&'static str], counters: &[AtomicU64]) -> BTreeMap<&'static str, u64> {
+            names
+                .iter()
+                .zip(counters)
+                .map(|(name, count)| (*name, count.load(Ordering::Relaxed)))
+                .collect()
+        }
+
+        MessageStats {
+            client: collect(&C2S::KINDS, &self.c2s),
+            server: collect(&S2C::KINDS, &self.s2c),
+        }
+    }
+}
+
+/// Number of messages of each type, keyed by message name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageStats {
+    /// Messages sent by the client.
+    pub client: BTreeMap<&'static str, u64>,
+    /// Messages sent by the server.
+    pub server: BTreeMap<&'static str, u64>,
+}
+
+/// When a client connected and when it was last active.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionInfo {
+    /// When the handshake completed.
+    pub connected_at: Instant,
+    /// When the last message was received from the client or forwarded to it.
+    pub last_activity: Instant,
+}
+
+impl SessionInfo {
+    /// How long no messages have been exchanged.
+    pub fn idle_time(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+}
+
+/// Counts what the overlay did for a client, or for all clients together.
+#[derive(Debug, Default)]
+pub(crate) struct OverlayCounters {
+    icons_drawn: AtomicU64,
+    clicks_captured: AtomicU64,
+    clicks_forwarded: AtomicU64,
+    redraws: AtomicU64,
+}
+
+impl OverlayCounters {
+    pub fn icon_drawn(&self) {
+        self.icons_drawn.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn click(&self, captured: bool) {
+        let count = match captured {
+            true => &self.clicks_captured,
+            false => &self.clicks_forwarded,
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn redraw(&self) {
+        self.redraws.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> OverlayStats {
+        OverlayStats {
+            icons_drawn: self.icons_drawn.load(Ordering::Relaxed),
+            clicks_captured: self.clicks_captured.load(Ordering::Relaxed),
+            clicks_forwarded: self.clicks_forwarded.load(Ordering::Relaxed),
+            redraws: self.redraws.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What the overlay did, as opposed to the messages passing through, see [MessageStats].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OverlayStats {
+    /// Icon rectangles sent to the client.
+    pub icons_drawn: u64,
+    /// Clicks on the icon turned into an [crate::Event::Action].
+    pub clicks_captured: u64,
+    /// Clicks forwarded to the server.
+    pub clicks_forwarded: u64,
+    /// Times the icon was checked for changes after the state changed.
+    pub redraws: u64,
+}
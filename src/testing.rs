@@ -0,0 +1,431 @@
+//! An in-process VNC server and client for tests, which talk to a real [Proxy] over
+//! loopback connections, the same way a viewer and a server would.
+
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use tokio::{
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    select,
+    sync::{broadcast, mpsc, watch},
+    task::{yield_now, JoinHandle},
+    time::timeout,
+};
+
+use crate::client::Client;
+use crate::rfb::{io::RfbIo, *};
+use crate::{ClientId, Config, Event, Icon, Proxy, ProxyHandle, Redraw, Result, State, Target};
+
+/// How long a test waits for something which should happen right away.
+const PATIENCE: Duration = Duration::from_secs(5);
+
+/// Fails the test if `f` does not complete in time, instead of letting it hang.
+pub(crate) async fn soon<T>(f: impl Future<Output = T>) -> T {
+    timeout(PATIENCE, f).await.expect("timed out")
+}
+
+/// Waits until `f` returns `true`, polling it.
+pub(crate) async fn eventually(mut f: impl FnMut() -> bool) {
+    soon(async {
+        while !f() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+}
+
+/// Awaits `f` while the clock is paused. A task waiting on a connection lets the clock
+/// skip ahead to the next timer, as the runtime does not count loopback traffic as pending
+/// work, so this keeps yielding until `f` completes instead. Tests pause the clock only
+/// once the handshake is done for the same reason.
+pub(crate) async fn without_skipping<T>(f: impl Future<Output = T>) -> T {
+    tokio::pin!(f);
+    for _ in 0..1_000_000 {
+        select! {
+            biased;
+            res = &mut f => return res,
+            _ = yield_now() => {}
+        }
+    }
+    panic!("timed out");
+}
+
+/// Lets the tasks of the proxy handle what they have been told, without letting time pass.
+pub(crate) async fn settle() {
+    for _ in 0..100 {
+        yield_now().await;
+    }
+}
+
+/// Fails if anything arrives on the connection within a short time.
+pub(crate) async fn assert_silent(rx: &mut RfbIo<OwnedReadHalf>) {
+    let res = timeout(Duration::from_millis(200), rx.read_data(1)).await;
+    assert!(res.is_err(), "unexpected data: {res:?}");
+}
+
+/// Reads until the connection is closed, failing if anything but the end arrives.
+pub(crate) async fn assert_closed(rx: &mut RfbIo<OwnedReadHalf>) {
+    let res = soon(rx.read_data(1)).await;
+    assert!(res.is_err(), "unexpected data: {res:?}");
+}
+
+/// The framebuffer of the test server, 64x48 pixels in the default pixel format.
+pub(crate) fn server_init() -> ServerInit {
+    ServerInit {
+        framebuffer_width: 64,
+        framebuffer_height: 48,
+        pixel_format: PixelFormat::default(),
+        name: "test".to_string(),
+    }
+}
+
+/// A state with a fixed icon which records the events it receives.
+#[derive(Debug, Clone)]
+pub(crate) struct TestState {
+    pub icon: Icon,
+    pub input: bool,
+    pub icon_interactive: bool,
+    pub preferred_pixel_format: Option<PixelFormat>,
+    pub encoding_events: bool,
+    pub events: Vec<Event>,
+}
+
+impl Default for TestState {
+    fn default() -> Self {
+        Self {
+            icon: Icon::solid(2, 2, [0xff, 0, 0, 0xff]),
+            input: true,
+            icon_interactive: true,
+            preferred_pixel_format: None,
+            encoding_events: false,
+            events: Vec::new(),
+        }
+    }
+}
+
+impl State for TestState {
+    fn icon(&self, _id: ClientId) -> Icon {
+        self.icon.clone()
+    }
+
+    fn handle_event(&mut self, event: Event) -> Redraw {
+        self.events.push(event);
+        Redraw::None
+    }
+
+    fn enable_input(&self, _id: ClientId) -> bool {
+        self.input
+    }
+
+    fn icon_interactive(&self, _id: ClientId) -> bool {
+        self.icon_interactive
+    }
+
+    fn preferred_pixel_format(&self) -> Option<PixelFormat> {
+        self.preferred_pixel_format.clone()
+    }
+
+    fn wants_encoding_events(&self) -> bool {
+        self.encoding_events
+    }
+}
+
+/// A running proxy in front of a [TestServer].
+pub(crate) struct TestProxy<S: State> {
+    pub addr: SocketAddr,
+    pub handle: ProxyHandle<S>,
+    pub server: TestServer,
+    pub task: JoinHandle<Result<()>>,
+}
+
+impl<S: State> TestProxy<S> {
+    pub async fn start(state: S, config: Config) -> Self {
+        let server = TestServer::bind().await;
+        let proxy = Proxy::bind("127.0.0.1:0".parse().unwrap(), server.addr, state, config)
+            .await
+            .unwrap();
+        Self::run(proxy, server)
+    }
+
+    pub fn run(proxy: Proxy<S>, server: TestServer) -> Self {
+        let addr = proxy.local_addr().unwrap();
+        let handle = proxy.handle();
+        let task = tokio::spawn(proxy.run());
+        Self {
+            addr,
+            handle,
+            server,
+            task,
+        }
+    }
+
+    /// Connects a client through the proxy, with both handshakes completed.
+    pub async fn connect(&self) -> (TestClient, ServerConn) {
+        self.connect_with(server_init()).await
+    }
+
+    /// Like [TestProxy::connect], with the server sending the given `ServerInit`.
+    pub async fn connect_with(&self, init: ServerInit) -> (TestClient, ServerConn) {
+        soon(async {
+            tokio::join!(
+                async { TestClient::connect(self.addr).await.unwrap() },
+                self.server.accept(init),
+            )
+        })
+        .await
+    }
+}
+
+impl TestProxy<TestState> {
+    /// Returns the events the state has received so far.
+    pub fn events(&self) -> Vec<Event> {
+        self.handle.with_state(|state| state.events.clone())
+    }
+}
+
+/// A single [Client] connection run without a [Proxy], so that the result of the
+/// connection can be checked.
+pub(crate) struct ClientTask {
+    /// The viewer's end of the connection.
+    pub rx: RfbIo<OwnedReadHalf>,
+    pub tx: RfbIo<OwnedWriteHalf>,
+    pub task: JoinHandle<Result<()>>,
+    pub events: mpsc::Receiver<Event>,
+}
+
+impl ClientTask {
+    pub async fn spawn<S: State>(state: S, config: Config, target: Target) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let viewer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let (event_tx, events) = mpsc::channel(16);
+        let (_, state_rx) = watch::channel(state);
+        let client = Client {
+            id: 0,
+            event_tx,
+            state_rx,
+            config: Arc::new(config),
+            counters: Default::default(),
+            shutdown: Default::default(),
+            redraw: Default::default(),
+            farewell: Default::default(),
+            inputs_tx: broadcast::channel(16).0,
+            events_closed: Default::default(),
+            overlay: Default::default(),
+            overlay_total: Default::default(),
+        };
+        let task = tokio::spawn(client.handle(stream, target));
+
+        let (rx, tx) = viewer.into_split();
+        Self {
+            rx: RfbIo::new(rx),
+            tx: RfbIo::new(tx),
+            task,
+            events,
+        }
+    }
+
+    /// Waits for the connection to end and returns its result.
+    pub async fn result(&mut self) -> Result<()> {
+        soon(&mut self.task).await.unwrap()
+    }
+}
+
+/// Listens for connections from the proxy and plays the server.
+pub(crate) struct TestServer {
+    pub addr: SocketAddr,
+    listener: TcpListener,
+}
+
+impl TestServer {
+    pub async fn bind() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Self {
+            addr: listener.local_addr().unwrap(),
+            listener,
+        }
+    }
+
+    /// Accepts a connection without any handshake, to be scripted by the test.
+    pub async fn accept_raw(&self) -> ServerConn {
+        let (stream, _) = soon(self.listener.accept()).await.unwrap();
+        let (rx, tx) = stream.into_split();
+        ServerConn {
+            rx: RfbIo::new(rx),
+            tx: RfbIo::new(tx),
+            format: PixelFormat::default(),
+        }
+    }
+
+    /// Accepts a connection and completes a 3.8 handshake with the None security type.
+    pub async fn accept(&self, init: ServerInit) -> ServerConn {
+        let mut conn = self.accept_raw().await;
+        conn.tx.write_message(Version::new(3, 8)).await.unwrap();
+        let _: Version = conn.rx.read_message().await.unwrap();
+        conn.tx
+            .write_message(SecurityTypes::new(&[1]).unwrap())
+            .await
+            .unwrap();
+        let SecurityType(1) = conn.rx.read_message().await.unwrap() else {
+            panic!("proxy chose a security type which was not offered");
+        };
+        conn.tx.write_message(SecurityResult(0)).await.unwrap();
+        let _: ClientInit = conn.rx.read_message().await.unwrap();
+        conn.format = init.pixel_format.clone();
+        conn.tx.write_message(init).await.unwrap();
+        conn
+    }
+}
+
+/// The server's end of a connection from the proxy.
+pub(crate) struct ServerConn {
+    pub rx: RfbIo<OwnedReadHalf>,
+    pub tx: RfbIo<OwnedWriteHalf>,
+    /// Pixel format of the pixel data sent by [ServerConn::send_raw].
+    pub format: PixelFormat,
+}
+
+impl ServerConn {
+    pub async fn read(&mut self) -> C2S {
+        soon(self.rx.read_message()).await.unwrap()
+    }
+
+    /// Reads messages until a `FramebufferUpdateRequest`, which is returned.
+    pub async fn read_request(&mut self) -> C2S {
+        loop {
+            let message = self.read().await;
+            if let C2S::FramebufferUpdateRequest { .. } = message {
+                return message;
+            }
+        }
+    }
+
+    /// Sends an update with a single Raw rectangle filled with one byte value.
+    pub async fn send_raw(&mut self, x: u16, y: u16, width: u16, height: u16, fill: u8) {
+        let rect = Rectangle {
+            x,
+            y,
+            width,
+            height,
+            encoding: Encoding::Raw,
+        };
+        let len = rect.payload_size(&self.format).unwrap();
+        self.send_update(vec![(rect, Bytes::from(vec![fill; len]))])
+            .await;
+    }
+
+    /// Sends an update made of the given rectangles and their payloads.
+    pub async fn send_update(&mut self, rects: Vec<(Rectangle, Bytes)>) {
+        let count = rects.len().try_into().unwrap();
+        self.tx.queue_message(S2C::FramebufferUpdate { count });
+        for (rect, payload) in rects {
+            self.tx.write_message(rect).await.unwrap();
+            self.tx.write_data(payload).await.unwrap();
+        }
+        self.tx.flush().await.unwrap();
+    }
+}
+
+/// A viewer connected to the proxy.
+pub(crate) struct TestClient {
+    pub rx: RfbIo<OwnedReadHalf>,
+    pub tx: RfbIo<OwnedWriteHalf>,
+    pub init: ServerInit,
+    /// Pixel format the client asked for, which payloads are parsed with.
+    pub format: PixelFormat,
+}
+
+impl TestClient {
+    /// Connects without any handshake, to be scripted by the test.
+    pub async fn connect_raw(addr: SocketAddr) -> (RfbIo<OwnedReadHalf>, RfbIo<OwnedWriteHalf>) {
+        let (rx, tx) = TcpStream::connect(addr).await.unwrap().into_split();
+        (RfbIo::new(rx), RfbIo::new(tx))
+    }
+
+    /// Connects and completes a 3.8 handshake with the None security type.
+    pub async fn connect(addr: SocketAddr) -> crate::Result<Self> {
+        let (mut rx, mut tx) = Self::connect_raw(addr).await;
+        let _: Version = rx.read_message().await?;
+        tx.write_message(Version::new(3, 8)).await?;
+        let types: SecurityTypes = rx.read_message().await?;
+        assert!(types.types().contains(&1), "None not offered: {types:?}");
+        tx.write_message(SecurityType(1)).await?;
+        let result: SecurityResult = rx.read_message().await?;
+        if result.0 != 0 {
+            let reason: String = rx.read_message().await?;
+            return Err(crate::Error::Protocol(reason));
+        }
+        tx.write_message(ClientInit { shared: true }).await?;
+        let init: ServerInit = rx.read_message().await?;
+        Ok(Self {
+            rx,
+            tx,
+            format: init.pixel_format.clone(),
+            init,
+        })
+    }
+
+    pub async fn send(&mut self, message: C2S) {
+        self.tx.write_message(message).await.unwrap();
+    }
+
+    /// Asks for pixel data in another format, which later payloads are parsed with.
+    pub async fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.format = format.clone();
+        self.send(C2S::SetPixelFormat(format)).await;
+    }
+
+    /// Requests an update of the whole framebuffer.
+    pub async fn request(&mut self, incremental: bool) {
+        self.send(C2S::FramebufferUpdateRequest {
+            incremental,
+            x: 0,
+            y: 0,
+            width: self.init.framebuffer_width,
+            height: self.init.framebuffer_height,
+        })
+        .await;
+    }
+
+    pub async fn read(&mut self) -> S2C {
+        soon(self.rx.read_message()).await.unwrap()
+    }
+
+    /// Reads the next message, which has to be a `FramebufferUpdate`, along with its
+    /// rectangles and their payloads.
+    pub async fn read_update(&mut self) -> Vec<(Rectangle, Bytes)> {
+        let S2C::FramebufferUpdate { count } = self.read().await else {
+            panic!("expected a framebuffer update");
+        };
+        let mut rects = Vec::new();
+        for _ in 0..count {
+            let rect: Rectangle = soon(self.rx.read_message()).await.unwrap();
+            let payload = soon(self.read_payload(&rect)).await;
+            rects.push((rect, payload));
+        }
+        rects
+    }
+
+    async fn read_payload(&mut self, rect: &Rectangle) -> Bytes {
+        let len = match rect.encoding {
+            Encoding::Zrle => {
+                let len = self.rx.read_data(4).await.unwrap();
+                let len = u32::from_be_bytes(len[..].try_into().unwrap());
+                return self.rx.read_data(len as usize).await.unwrap();
+            }
+            Encoding::DesktopSize => 0,
+            Encoding::ExtendedDesktopSize => {
+                let screens = self.rx.read_data(4).await.unwrap();
+                return self.rx.read_data(screens[0] as usize * 16).await.unwrap();
+            }
+            _ => rect.payload_size(&self.format).unwrap(),
+        };
+        self.rx.read_data(len).await.unwrap()
+    }
+}
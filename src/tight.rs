@@ -0,0 +1,211 @@
+//! Framing of Tight encoded rectangles, which are passed through without decoding.
+//!
+//! Tight rectangles carry no overall length, so the size of a rectangle has to be
+//! worked out from its compression control byte. Basic compression applies one of three
+//! filters before compressing with one of four zlib streams:
+//!
+//! - the copy filter sends the pixels as they are,
+//! - the palette filter sends a palette followed by one index per pixel, or one bit per
+//!   pixel packed into rows if the palette has two colours,
+//! - the gradient filter sends the prediction error of each pixel, which has the same
+//!   size as the pixels.
+//!
+//! Data shorter than 12 bytes is sent uncompressed and without a length. The zlib
+//! streams persist between rectangles, which does not matter here as the client inflates
+//! the data and the proxy never alters it.
+
+use bytes::{Buf, Bytes};
+
+use crate::rfb::{ensure_size, DecodeError, PixelFormat};
+
+/// Data shorter than this is not compressed.
+const MIN_COMPRESS: usize = 12;
+
+const FILL: u8 = 0x08;
+const JPEG: u8 = 0x09;
+/// Basic compression with an explicit filter id.
+const EXPLICIT_FILTER: u8 = 0x04;
+
+const COPY_FILTER: u8 = 0;
+const PALETTE_FILTER: u8 = 1;
+const GRADIENT_FILTER: u8 = 2;
+
+/// Reads a whole Tight rectangle of the given size in the server's pixel format, returning
+/// it exactly as it was sent.
+pub(crate) fn read_rect(
+    buf: &mut Bytes,
+    width: u16,
+    height: u16,
+    format: &PixelFormat,
+) -> Result<Bytes, DecodeError> {
+    let mut rest = buf.clone();
+    ensure_size(&rest, 1)?;
+    let control = rest.get_u8();
+    let tpixel = tpixel_size(format);
+    let (width, height) = (width as usize, height as usize);
+
+    match control >> 4 {
+        FILL => skip(&mut rest, tpixel)?,
+        JPEG => {
+            let len = read_compact_len(&mut rest)?;
+            skip(&mut rest, len)?;
+        }
+        c if c & 0x08 == 0 => {
+            let filter = if c & EXPLICIT_FILTER != 0 {
+                ensure_size(&rest, 1)?;
+                rest.get_u8()
+            } else {
+                COPY_FILTER
+            };
+            let data_size = match filter {
+                COPY_FILTER | GRADIENT_FILTER => width * height * tpixel,
+                PALETTE_FILTER => {
+                    ensure_size(&rest, 1)?;
+                    let colors = rest.get_u8() as usize + 1;
+                    skip(&mut rest, colors * tpixel)?;
+                    if colors == 2 {
+                        width.div_ceil(8) * height
+                    } else {
+                        width * height
+                    }
+                }
+                f => return Err(DecodeError::UnsupportedTightFilter(f)),
+            };
+            if data_size < MIN_COMPRESS {
+                skip(&mut rest, data_size)?;
+            } else {
+                let len = read_compact_len(&mut rest)?;
+                skip(&mut rest, len)?;
+            }
+        }
+        c => return Err(DecodeError::UnsupportedTightCompression(c)),
+    }
+
+    let len = buf.len() - rest.len();
+    Ok(buf.split_to(len))
+}
+
+/// Size of a pixel on the wire, which is three bytes for 32 bit pixels holding
+/// 8 bits per colour.
+fn tpixel_size(format: &PixelFormat) -> usize {
+    let is_888 = format.true_colour
+        && format.bits_per_pixel == 32
+        && format.depth == 24
+        && [format.red_max, format.green_max, format.blue_max] == [255; 3];
+    if is_888 {
+        3
+    } else {
+        format.bytes_per_pixel()
+    }
+}
+
+/// Reads a length of up to 22 bits, sent in one to three bytes with 7 bits in each
+/// but the last, least significant bits first. The high bit marks a following byte.
+fn read_compact_len(buf: &mut Bytes) -> Result<usize, DecodeError> {
+    let mut len = 0;
+    for i in 0..3 {
+        ensure_size(buf, 1)?;
+        let b = buf.get_u8() as usize;
+        if i == 2 {
+            return Ok(len | b << 14);
+        }
+        len |= (b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            break;
+        }
+    }
+    Ok(len)
+}
+
+fn skip(buf: &mut Bytes, len: usize) -> Result<(), DecodeError> {
+    ensure_size(buf, len)?;
+    buf.advance(len);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a rectangle followed by other data, returning the length of the rectangle.
+    fn rect_len(rect: &[u8], width: u16, height: u16) -> usize {
+        let mut buf = Bytes::from([rect, b"next"].concat());
+        let read = read_rect(&mut buf, width, height, &PixelFormat::default()).unwrap();
+        assert_eq!(buf, &b"next"[..]);
+        read.len()
+    }
+
+    /// A basic compression control byte with an explicit filter.
+    const BASIC: u8 = EXPLICIT_FILTER << 4;
+
+    #[test]
+    fn palette_filtered_rects() {
+        // two colours, a bit per pixel, short enough to be sent uncompressed
+        let palette = [0xff; 6];
+        let rect = [&[BASIC, PALETTE_FILTER, 1][..], &palette, &[0b1010_0000; 4]].concat();
+        assert_eq!(rect_len(&rect, 4, 4), rect.len());
+
+        // sixteen colours, a byte per pixel, compressed to 200 bytes
+        let palette = [0; 16 * 3];
+        let rect = [
+            &[BASIC, PALETTE_FILTER, 15][..],
+            &palette,
+            &[0xc8, 0x01],
+            &[0; 200],
+        ]
+        .concat();
+        assert_eq!(rect_len(&rect, 8, 8), rect.len());
+    }
+
+    #[test]
+    fn gradient_filtered_rects() {
+        // 12 bytes of prediction errors are compressed
+        let rect = [&[BASIC | 0x10, GRADIENT_FILTER, 5][..], &[0; 5]].concat();
+        assert_eq!(rect_len(&rect, 2, 2), rect.len());
+
+        // a single pixel is not
+        let rect = [BASIC, GRADIENT_FILTER, 1, 2, 3];
+        assert_eq!(rect_len(&rect, 1, 1), rect.len());
+    }
+
+    #[test]
+    fn copy_filtered_and_other_rects() {
+        // the copy filter is implied without an explicit one, here with a three byte length
+        let rect = [&[0x20, 0xa0, 0x9c, 0x01][..], &[0; 20000]].concat();
+        assert_eq!(rect_len(&rect, 100, 100), rect.len());
+        let rect = [BASIC, COPY_FILTER, 1, 2, 3];
+        assert_eq!(rect_len(&rect, 1, 1), rect.len());
+
+        let fill = [FILL << 4, 1, 2, 3];
+        assert_eq!(rect_len(&fill, 100, 100), fill.len());
+        let jpeg = [&[JPEG << 4, 0xac, 0x02][..], &[0; 300]].concat();
+        assert_eq!(rect_len(&jpeg, 100, 100), jpeg.len());
+    }
+
+    #[test]
+    fn incomplete_and_unsupported_rects() {
+        let rect = [
+            &[BASIC, PALETTE_FILTER, 15][..],
+            &[0; 16 * 3],
+            &[0xc8, 0x01],
+        ]
+        .concat();
+        for len in 0..rect.len() {
+            let mut buf = Bytes::copy_from_slice(&rect[..len]);
+            let res = read_rect(&mut buf, 8, 8, &PixelFormat::default());
+            assert!(matches!(res, Err(DecodeError::InsufficientBytes)));
+            // nothing is consumed
+            assert_eq!(buf.len(), len);
+        }
+
+        let mut buf = Bytes::from_static(&[BASIC, 3, 0]);
+        let res = read_rect(&mut buf, 1, 1, &PixelFormat::default());
+        assert!(matches!(res, Err(DecodeError::UnsupportedTightFilter(3))));
+        let mut buf = Bytes::from_static(&[0xa0]);
+        let res = read_rect(&mut buf, 1, 1, &PixelFormat::default());
+        assert!(matches!(
+            res,
+            Err(DecodeError::UnsupportedTightCompression(0x0a))
+        ));
+    }
+}
@@ -0,0 +1,553 @@
+//! Decoding and encoding of ZRLE encoded rectangles (RFC 6143, 7.7.6).
+//!
+//! All ZRLE rectangles of a connection share a single zlib stream, so the
+//! compressed data of a rectangle can only be inflated with the dictionary
+//! built up by all previous rectangles. Inflating each rectangle on its own
+//! works for the very first one and fails (or silently produces garbage) for
+//! every rectangle after that. A [ZrleDecoder] must therefore be kept for the
+//! whole lifetime of a connection and be fed every ZRLE rectangle in order.
+//! For the same reason, a client can only be sent ZRLE rectangles encoded by a
+//! [ZrleEncoder] if it never gets any from the server.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use crate::rfb::{DecodeError, PixelFormat};
+
+pub(crate) const TILE_SIZE: usize = 64;
+
+pub struct ZrleDecoder {
+    inflate: Decompress,
+}
+
+impl Default for ZrleDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ZrleDecoder {
+    pub fn new() -> Self {
+        Self {
+            inflate: Decompress::new(true),
+        }
+    }
+
+    /// Decodes the zlib data of a single ZRLE rectangle into pixels in the
+    /// given pixel format, row by row.
+    pub fn decode(
+        &mut self,
+        zlib_data: &[u8],
+        width: u16,
+        height: u16,
+        format: &PixelFormat,
+    ) -> Result<Vec<u8>, DecodeError> {
+        let pixel_size = format.bits_per_pixel as usize / 8;
+        let stride = width as usize * pixel_size;
+        let mut pixels = vec![0; height as usize * stride];
+
+        self.walk(zlib_data, width, height, format, |tile, data, _| {
+            let row_size = tile.width * pixel_size;
+            for (row, data) in data.chunks_exact(row_size).enumerate() {
+                let start = (tile.y + row) * stride + tile.x * pixel_size;
+                pixels[start..start + row_size].copy_from_slice(data);
+            }
+        })?;
+
+        Ok(pixels)
+    }
+
+    /// Returns the number of bytes each tile of a ZRLE rectangle takes up in the inflated
+    /// data, row by row, e.g. to find where a server and the decoder disagree. Like
+    /// [ZrleDecoder::decode], this fails unless the tiles take up exactly all the data.
+    pub fn tile_sizes(
+        &mut self,
+        zlib_data: &[u8],
+        width: u16,
+        height: u16,
+        format: &PixelFormat,
+    ) -> Result<Vec<usize>, DecodeError> {
+        let mut sizes = Vec::new();
+        self.walk(zlib_data, width, height, format, |_, _, size| {
+            sizes.push(size)
+        })?;
+        Ok(sizes)
+    }
+
+    /// Decodes the tiles of a rectangle in order, passing each one to `f` along with its
+    /// pixels and the number of inflated bytes it was decoded from.
+    fn walk(
+        &mut self,
+        zlib_data: &[u8],
+        width: u16,
+        height: u16,
+        format: &PixelFormat,
+        mut f: impl FnMut(Tile, Vec<u8>, usize),
+    ) -> Result<(), DecodeError> {
+        if !matches!(format.bits_per_pixel, 8 | 16 | 32) {
+            return Err(DecodeError::UnsupportedBitsPerPixel(format.bits_per_pixel));
+        }
+        let mut buf = Bytes::from(self.inflate(zlib_data)?);
+
+        let cpixel = CPixel::new(format);
+        let (width, height) = (width as usize, height as usize);
+        for y in (0..height).step_by(TILE_SIZE) {
+            for x in (0..width).step_by(TILE_SIZE) {
+                let tile = Tile {
+                    x,
+                    y,
+                    width: TILE_SIZE.min(width - x),
+                    height: TILE_SIZE.min(height - y),
+                };
+                let before = buf.len();
+                let data = decode_tile(&mut buf, tile.width, tile.height, &cpixel)?;
+                f(tile, data, before - buf.len());
+            }
+        }
+
+        // the tiles were read differently than they were written, so
+        // the data of later rectangles would be misread as well
+        if !buf.is_empty() {
+            return Err(DecodeError::InvalidZrle);
+        }
+        Ok(())
+    }
+
+    fn inflate(&mut self, zlib_data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        let mut out = Vec::with_capacity(zlib_data.len() * 4);
+        let start = self.inflate.total_in();
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity().max(0x1000));
+            }
+
+            let (in_before, out_before) = (self.inflate.total_in(), out.len());
+            let consumed = (in_before - start) as usize;
+            let status = self.inflate.decompress_vec(
+                &zlib_data[consumed..],
+                &mut out,
+                FlushDecompress::Sync,
+            )?;
+
+            // all input is consumed and all pending output has been flushed
+            let consumed = (self.inflate.total_in() - start) as usize;
+            if status == Status::StreamEnd
+                || (consumed == zlib_data.len() && out.len() < out.capacity())
+            {
+                return Ok(out);
+            }
+
+            if self.inflate.total_in() == in_before && out.len() == out_before {
+                return Err(DecodeError::InvalidZrle);
+            }
+        }
+    }
+}
+
+/// Encodes pixels as ZRLE rectangles sharing one zlib stream. Tiles are written as raw
+/// CPIXELs, leaving the compression to zlib.
+pub(crate) struct ZrleEncoder {
+    deflate: Compress,
+    /// Compressed tiles of the rectangle being encoded.
+    out: Vec<u8>,
+    tiles: Vec<u8>,
+}
+
+impl ZrleEncoder {
+    pub fn new() -> Self {
+        Self {
+            deflate: Compress::new(Compression::fast(), true),
+            out: Vec::new(),
+            tiles: Vec::new(),
+        }
+    }
+
+    /// Encodes pixels in the client's pixel format, row by row, into the data following
+    /// the header of a ZRLE rectangle.
+    pub fn encode(&mut self, pixels: &[u8], width: u16, format: &PixelFormat) -> Bytes {
+        self.add_rows(pixels, width, format);
+        self.finish()
+    }
+
+    /// Adds the next rows of the rectangle being encoded. All but the last rows added
+    /// have to be a multiple of [TILE_SIZE] rows.
+    pub fn add_rows(&mut self, pixels: &[u8], width: u16, format: &PixelFormat) {
+        let cpixel = CPixel::new(format);
+        let width = width as usize;
+        let stride = width * cpixel.pixel_size;
+        if stride == 0 {
+            return;
+        }
+
+        self.tiles.clear();
+        let height = pixels.len() / stride;
+        for y in (0..height).step_by(TILE_SIZE) {
+            for x in (0..width).step_by(TILE_SIZE) {
+                let tile_width = TILE_SIZE.min(width - x);
+                // raw
+                self.tiles.push(0);
+                for row in y..height.min(y + TILE_SIZE) {
+                    let start = row * stride + x * cpixel.pixel_size;
+                    let row = &pixels[start..start + tile_width * cpixel.pixel_size];
+                    for pixel in row.chunks_exact(cpixel.pixel_size) {
+                        cpixel.write(pixel, &mut self.tiles);
+                    }
+                }
+            }
+        }
+
+        let tiles = std::mem::take(&mut self.tiles);
+        self.deflate(&tiles, FlushCompress::None);
+        self.tiles = tiles;
+    }
+
+    /// Returns the length and zlib data of the rows added since the last call.
+    pub fn finish(&mut self) -> Bytes {
+        self.deflate(&[], FlushCompress::Sync);
+        let mut data = BytesMut::with_capacity(4 + self.out.len());
+        data.put_u32(self.out.len().try_into().unwrap());
+        data.put_slice(&self.out);
+        self.out.clear();
+        data.freeze()
+    }
+
+    fn deflate(&mut self, input: &[u8], flush: FlushCompress) {
+        let start = self.deflate.total_in();
+        loop {
+            if self.out.len() == self.out.capacity() {
+                self.out.reserve(self.out.capacity().max(0x1000));
+            }
+
+            let consumed = (self.deflate.total_in() - start) as usize;
+            self.deflate
+                .compress_vec(&input[consumed..], &mut self.out, flush)
+                .expect("the stream is never finished");
+
+            // all input is consumed and all pending output has been flushed
+            let consumed = (self.deflate.total_in() - start) as usize;
+            if consumed == input.len() && self.out.len() < self.out.capacity() {
+                return;
+            }
+        }
+    }
+}
+
+/// Position and size of a tile within its rectangle.
+struct Tile {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+/// Describes how a compressed pixel (CPIXEL) maps onto a regular pixel.
+struct CPixel {
+    /// Size of a pixel in the client's pixel format.
+    pixel_size: usize,
+    /// Size of a CPIXEL on the wire.
+    size: usize,
+    /// Offset of the CPIXEL bytes within a pixel.
+    offset: usize,
+}
+
+impl CPixel {
+    fn new(format: &PixelFormat) -> Self {
+        let pixel_size = format.bits_per_pixel as usize / 8;
+        let mask = (format.red_max as u32) << format.red_shift
+            | (format.green_max as u32) << format.green_shift
+            | (format.blue_max as u32) << format.blue_shift;
+
+        // 32 bit true colour pixels are sent in three bytes if all colour bits fit
+        if format.true_colour && format.bits_per_pixel == 32 && format.depth <= 24 {
+            let fits_low = mask & 0xff00_0000 == 0;
+            let fits_high = mask & 0x0000_00ff == 0;
+            if fits_low || fits_high {
+                let offset = if fits_low == format.big_endian { 1 } else { 0 };
+                return Self {
+                    pixel_size,
+                    size: 3,
+                    offset,
+                };
+            }
+        }
+
+        Self {
+            pixel_size,
+            size: pixel_size,
+            offset: 0,
+        }
+    }
+
+    fn read(&self, buf: &mut Bytes) -> Result<Vec<u8>, DecodeError> {
+        ensure_size(buf, self.size)?;
+        let mut pixel = vec![0; self.pixel_size];
+        buf.copy_to_slice(&mut pixel[self.offset..self.offset + self.size]);
+        Ok(pixel)
+    }
+
+    fn write(&self, pixel: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(&pixel[self.offset..self.offset + self.size]);
+    }
+
+    fn read_palette(&self, buf: &mut Bytes, len: usize) -> Result<Vec<Vec<u8>>, DecodeError> {
+        (0..len).map(|_| self.read(buf)).collect()
+    }
+}
+
+fn ensure_size(buf: &Bytes, size: usize) -> Result<(), DecodeError> {
+    if buf.len() >= size {
+        Ok(())
+    } else {
+        Err(DecodeError::InvalidZrle)
+    }
+}
+
+fn read_u8(buf: &mut Bytes) -> Result<u8, DecodeError> {
+    ensure_size(buf, 1)?;
+    Ok(buf.get_u8())
+}
+
+/// Reads a run length, encoded as a sequence of bytes terminated by a byte
+/// other than 255, whose sum plus one is the length.
+fn read_run_length(buf: &mut Bytes) -> Result<usize, DecodeError> {
+    let mut len = 1;
+    loop {
+        let b = read_u8(buf)?;
+        len += b as usize;
+        if b != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+fn palette_entry(palette: &[Vec<u8>], index: u8) -> Result<&[u8], DecodeError> {
+    palette
+        .get(index as usize)
+        .map(Vec::as_slice)
+        .ok_or(DecodeError::InvalidZrle)
+}
+
+fn decode_tile(
+    buf: &mut Bytes,
+    width: usize,
+    height: usize,
+    cpixel: &CPixel,
+) -> Result<Vec<u8>, DecodeError> {
+    let size = width * height * cpixel.pixel_size;
+    let mut tile = Vec::with_capacity(size);
+
+    let push_run = |tile: &mut Vec<u8>, pixel: &[u8], len: usize| {
+        if tile.len() + len * pixel.len() > size {
+            return Err(DecodeError::InvalidZrle);
+        }
+        for _ in 0..len {
+            tile.extend_from_slice(pixel);
+        }
+        Ok(())
+    };
+
+    match read_u8(buf)? {
+        // raw
+        0 => {
+            for _ in 0..width * height {
+                tile.extend(cpixel.read(buf)?);
+            }
+        }
+        // solid
+        1 => {
+            let pixel = cpixel.read(buf)?;
+            push_run(&mut tile, &pixel, width * height)?;
+        }
+        // packed palette
+        n @ 2..=16 => {
+            let palette = cpixel.read_palette(buf, n as usize)?;
+            let bits = match n {
+                2 => 1,
+                3..=4 => 2,
+                _ => 4,
+            };
+            let row_bytes = (width * bits).div_ceil(8);
+            for _ in 0..height {
+                ensure_size(buf, row_bytes)?;
+                let row = buf.split_to(row_bytes);
+                for x in 0..width {
+                    let bit = x * bits;
+                    let index = (row[bit / 8] >> (8 - bits - bit % 8)) & ((1 << bits) - 1);
+                    tile.extend_from_slice(palette_entry(&palette, index)?);
+                }
+            }
+        }
+        // plain RLE
+        128 => {
+            while tile.len() < size {
+                let pixel = cpixel.read(buf)?;
+                let len = read_run_length(buf)?;
+                push_run(&mut tile, &pixel, len)?;
+            }
+        }
+        // palette RLE
+        n @ 130..=255 => {
+            let palette = cpixel.read_palette(buf, n as usize - 128)?;
+            while tile.len() < size {
+                let index = read_u8(buf)?;
+                let len = if index & 0x80 != 0 {
+                    read_run_length(buf)?
+                } else {
+                    1
+                };
+                push_run(&mut tile, palette_entry(&palette, index & 0x7f)?, len)?;
+            }
+        }
+        n => return Err(DecodeError::UnsupportedZrleSubencoding(n)),
+    }
+
+    Ok(tile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compresses the tiles of a rectangle as the next part of a zlib stream.
+    fn compress(stream: &mut Compress, tiles: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(tiles.len() + 64);
+        stream
+            .compress_vec(tiles, &mut out, FlushCompress::Sync)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn rectangles_share_one_stream() {
+        let format = PixelFormat::default();
+        let mut stream = Compress::new(Compression::default(), true);
+        // solid tiles of 3 byte CPIXELs
+        let first = compress(&mut stream, &[1, 0, 0, 0xff]);
+        let second = compress(&mut stream, &[1, 1, 2, 3]);
+
+        let mut decoder = ZrleDecoder::new();
+        let pixels = decoder.decode(&first, 2, 2, &format).unwrap();
+        assert_eq!(pixels, [0, 0, 0xff, 0].repeat(4));
+        let pixels = decoder.decode(&second, 2, 1, &format).unwrap();
+        assert_eq!(pixels, [1, 2, 3, 0].repeat(2));
+
+        // without the dictionary of the first rectangle
+        assert!(ZrleDecoder::new().decode(&second, 2, 1, &format).is_err());
+    }
+
+    #[test]
+    fn tiles_are_walked_with_their_sizes() {
+        let format = PixelFormat::default();
+        let (red, blue) = ([0, 0, 0xff], [0xff, 0, 0]);
+        let tiles = |tiles: &[&[u8]]| {
+            let mut stream = Compress::new(Compression::default(), true);
+            compress(&mut stream, &tiles.concat())
+        };
+
+        // a solid tile, then a raw one of the remaining 16x8 pixels
+        let mut raw = vec![0];
+        raw.extend(blue.repeat(16 * 8));
+        let data = tiles(&[&[1], &red, &raw]);
+        let sizes = ZrleDecoder::new()
+            .tile_sizes(&data, 80, 8, &format)
+            .unwrap();
+        assert_eq!(sizes, [4, 1 + 16 * 8 * 3]);
+        let pixels = ZrleDecoder::new().decode(&data, 80, 8, &format).unwrap();
+        assert_eq!(pixels[..4], [0, 0, 0xff, 0]);
+        assert_eq!(pixels[79 * 4..80 * 4], [0xff, 0, 0, 0]);
+
+        // 2x2 tiles of red, red, blue, red in each of the other subencodings
+        let expected = [red, red, blue, red]
+            .map(|p| [p[0], p[1], p[2], 0])
+            .concat();
+        let packed: &[&[u8]] = &[&[2], &red, &blue, &[0b0000_0000, 0b1000_0000]];
+        let plain_rle: &[&[u8]] = &[&[128], &red, &[1], &blue, &[0], &red, &[0]];
+        let palette_rle: &[&[u8]] = &[&[130], &red, &blue, &[0x80, 1, 1, 0]];
+        for (tile, size) in [(packed, 9), (plain_rle, 13), (palette_rle, 11)] {
+            let data = tiles(tile);
+            let sizes = ZrleDecoder::new().tile_sizes(&data, 2, 2, &format).unwrap();
+            assert_eq!(sizes, [size]);
+            let pixels = ZrleDecoder::new().decode(&data, 2, 2, &format).unwrap();
+            assert_eq!(pixels, expected);
+        }
+    }
+
+    #[test]
+    fn encoded_rectangles_are_decoded() {
+        // partial tiles at the right and bottom edges
+        let (width, height) = (70, 65);
+        for format in [PixelFormat::default(), PixelFormat::rgb565()] {
+            let size = format.bytes_per_pixel();
+            let pixels: Vec<u8> = (0..width * height)
+                .flat_map(|i| {
+                    let pixel = (i as u32 * 0x010203).to_le_bytes();
+                    // the padding byte of 32 bit pixels is not sent
+                    [pixel[0], pixel[1], pixel[2], 0][..size].to_vec()
+                })
+                .collect();
+
+            let mut encoder = ZrleEncoder::new();
+            let mut decoder = ZrleDecoder::new();
+            let first = encoder.encode(&pixels, width as u16, &format);
+            // a row of tiles at a time, continuing the stream
+            let stride = width * size;
+            encoder.add_rows(&pixels[..TILE_SIZE * stride], width as u16, &format);
+            encoder.add_rows(&pixels[TILE_SIZE * stride..], width as u16, &format);
+            let second = encoder.finish();
+
+            for data in [first, second] {
+                let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+                assert_eq!(len, data.len() - 4);
+                let decoded = decoder
+                    .decode(&data[4..], width as u16, height as u16, &format)
+                    .unwrap();
+                assert!(decoded == pixels, "{format:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn miscounted_tiles_are_rejected() {
+        let format = PixelFormat::default();
+        let mut stream = Compress::new(Compression::default(), true);
+        // a solid tile followed by a stray byte
+        let data = compress(&mut stream, &[1, 1, 2, 3, 0]);
+        let res = ZrleDecoder::new().tile_sizes(&data, 2, 2, &format);
+        assert!(matches!(res, Err(DecodeError::InvalidZrle)), "{res:?}");
+
+        // a run longer than the tile
+        let mut stream = Compress::new(Compression::default(), true);
+        let data = compress(&mut stream, &[128, 1, 2, 3, 4]);
+        let res = ZrleDecoder::new().decode(&data, 2, 2, &format);
+        assert!(matches!(res, Err(DecodeError::InvalidZrle)), "{res:?}");
+
+        // subencodings 17 to 127 and 129 are not defined
+        for subencoding in [17, 127, 129] {
+            let mut stream = Compress::new(Compression::default(), true);
+            let data = compress(&mut stream, &[subencoding]);
+            let res = ZrleDecoder::new().decode(&data, 2, 2, &format);
+            assert!(
+                matches!(res, Err(DecodeError::UnsupportedZrleSubencoding(n)) if n == subencoding),
+                "{res:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn odd_pixel_sizes_are_rejected() {
+        // found by fuzzing, which panicked on a pixel format of zero bits per pixel
+        let mut stream = Compress::new(Compression::default(), true);
+        let data = compress(&mut stream, &[1, 0]);
+        for bits_per_pixel in [0, 4, 24] {
+            let format = PixelFormat {
+                bits_per_pixel,
+                ..Default::default()
+            };
+            let res = ZrleDecoder::new().decode(&data, 2, 2, &format);
+            assert!(
+                matches!(res, Err(DecodeError::UnsupportedBitsPerPixel(bpp)) if bpp == bits_per_pixel),
+                "{res:?}"
+            );
+        }
+    }
+}